@@ -1,12 +1,17 @@
 use pprof::criterion::{Output, PProfProfiler};
-use std::hint::black_box;
+use std::{hint::black_box, time::Instant};
 
 use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
-use range_alloc::{RangeAlloc, tests};
+use range_alloc::{
+    Policy, RangeAlloc,
+    instrument::Instrumented,
+    metrics::{self, ScenarioMetrics},
+    testkit,
+};
 
 fn repeatedly_alloc_page(c: &mut Criterion) {
-    let mut a = tests::new_linear();
-    tests::setup(&mut a);
+    let mut a = testkit::new_linear();
+    testkit::setup(&mut a);
 
     c.bench_function("alloc-and-immediately-free", |b| {
         b.iter(|| {
@@ -26,13 +31,13 @@ fn repeatedly_alloc_page(c: &mut Criterion) {
 
     c.bench_function("alloc-aligned-2-and-immediately-free", |b| {
         b.iter(|| {
-            tests::alloc_aligned(&mut a);
+            testkit::alloc_aligned(&mut a);
         })
     });
 
     c.bench_function("alloc_different_configurations", |b| {
         b.iter(|| {
-            tests::alloc_different_configurations(&mut a);
+            testkit::alloc_different_configurations(&mut a);
         });
     });
 
@@ -40,13 +45,13 @@ fn repeatedly_alloc_page(c: &mut Criterion) {
         ($group:expr, $alloc:ident => $setup:expr ; $e:expr) => {{
             let mut group = c.benchmark_group($group);
 
-            let mut $alloc = tests::new_linear();
+            let mut $alloc = testkit::new_linear();
             $setup;
             group.bench_function(BenchmarkId::new("linear", 1), |b| {
                 b.iter(|| $e);
             });
 
-            let mut $alloc = tests::new_btree();
+            let mut $alloc = testkit::new_btree();
             $setup;
             group.bench_function(BenchmarkId::new("btree", 1), |b| {
                 b.iter(|| $e);
@@ -54,7 +59,7 @@ fn repeatedly_alloc_page(c: &mut Criterion) {
         }};
     }
 
-    compare!("alloc_different_configurations", a => tests::setup(&mut a); tests::alloc_different_configurations(&mut a));
+    compare!("alloc_different_configurations", a => testkit::setup(&mut a); testkit::alloc_different_configurations(&mut a));
     compare!("alloc_aligned", a => {
         a.add_range(0x7ff000, 4096 * 4096, ())
             .expect("can add range");
@@ -65,15 +70,125 @@ fn repeatedly_alloc_page(c: &mut Criterion) {
         a.add_range(0xffff0000, 4096 * 4096 * 4096, ())
             .expect("can add range");
         let alignments = [8, 2, 9, 1, 3, 0, 6, 5, 7].map(|x| 4096 << x);
-        tests::allocate_n(&mut a, std::iter::once(4096), alignments.into_iter(), 50000);
+        testkit::allocate_n(&mut a, std::iter::once(4096), alignments.into_iter(), 50000);
         // panic!("{:?} / {:?}", a.space(), a.total_space());
-    }; tests::alloc_aligned(&mut a));
+    }; testkit::alloc_aligned(&mut a));
+
+    // the free tree's node size, see `btree::RangeAllocator::with_fanout`
+    let mut group = c.benchmark_group("btree_fanout");
+    macro_rules! fanout {
+        ($($n:literal),*) => {$({
+            let mut a = testkit::new_btree().with_fanout::<$n>();
+            testkit::setup(&mut a);
+            group.bench_function(BenchmarkId::new("btree", $n), |b| {
+                b.iter(|| testkit::alloc_different_configurations(&mut a));
+            });
+        })*};
+    }
+    fanout!(4, 6, 16, 64);
+}
+
+/// next-fit resumes at the linear backend's cursor instead of rescanning the exhausted head
+fn linear_policy(c: &mut Criterion) {
+    let mut group = c.benchmark_group("linear_policy");
+    for policy in [Policy::FirstFit, Policy::NextFit] {
+        let mut a = testkit::new_linear();
+        a.set_policy(policy);
+        testkit::setup(&mut a);
+        group.bench_function(BenchmarkId::new("linear", format!("{policy:?}")), |b| {
+            b.iter(|| testkit::alloc_different_configurations(&mut a));
+        });
+    }
+}
+
+/// criterion only reports throughput, so the tail latencies are measured separately and printed
+fn latency_percentiles(_c: &mut Criterion) {
+    macro_rules! report {
+        ($name:expr, $alloc:expr) => {{
+            let start = Instant::now();
+            let clock = move || start.elapsed().as_nanos() as u64;
+            let mut a = Instrumented::new($alloc, clock);
+            testkit::setup(&mut a);
+            for _ in 0..100 {
+                testkit::alloc_different_configurations(&mut a);
+            }
+            let stats = a.stats();
+            for (op, latencies) in [("alloc", &stats.alloc), ("free", &stats.free)] {
+                let s = latencies.summary();
+                eprintln!(
+                    "{}/{op}: n={} p50={}ns p99={}ns max={}ns",
+                    $name, s.count, s.p50, s.p99, s.max
+                );
+            }
+        }};
+    }
+
+    report!("linear", testkit::new_linear());
+    report!("btree", testkit::new_btree());
+}
+
+/// writes per-scenario metrics as JSON to `$RANGE_ALLOC_METRICS`, or
+/// `target/range-alloc-metrics.json`, so downstream CI can track regressions
+fn json_metrics(_c: &mut Criterion) {
+    let mut results = Vec::new();
+
+    macro_rules! scenario {
+        ($scenario:expr, $backend:expr, $alloc:expr, $a:ident => $workload:expr) => {{
+            let start = Instant::now();
+            let clock = move || start.elapsed().as_nanos() as u64;
+            let mut $a = Instrumented::new($alloc, clock);
+            testkit::setup(&mut $a);
+            $a.reset_stats();
+
+            let start = Instant::now();
+            $workload;
+            let elapsed_ns = start.elapsed().as_nanos() as u64;
+
+            let stats = $a.stats();
+            let all = [&stats.add_range, &stats.alloc, &stats.free];
+            let inner = $a.inner();
+            results.push(ScenarioMetrics {
+                scenario: $scenario.into(),
+                backend: $backend.into(),
+                ops: all.iter().map(|l| l.count() as u64).sum(),
+                elapsed_ns,
+                fragmentation: inner.fragmentation(),
+                metadata_bytes: inner.metadata_bytes(),
+                max_latency_ns: all.iter().map(|l| l.max()).max().unwrap_or(0),
+            });
+        }};
+    }
+
+    macro_rules! both {
+        ($scenario:expr, $a:ident => $workload:expr) => {
+            scenario!($scenario, "linear", testkit::new_linear(), $a => $workload);
+            scenario!($scenario, "btree", testkit::new_btree(), $a => $workload);
+        };
+    }
+
+    both!("alloc_different_configurations", a => for _ in 0..100 {
+        testkit::alloc_different_configurations(&mut a);
+    });
+    both!("fragmented_alloc_aligned", a => {
+        a.add_range(0xffff0000, 4096 * 4096 * 4096, ())
+            .expect("can add range");
+        let alignments = [8, 2, 9, 1, 3, 0, 6, 5, 7].map(|x| 4096 << x);
+        testkit::allocate_n(&mut a, std::iter::once(4096), alignments.into_iter(), 5000);
+        for _ in 0..100 {
+            testkit::alloc_aligned(&mut a);
+        }
+    });
+
+    let path = std::env::var("RANGE_ALLOC_METRICS")
+        .unwrap_or_else(|_| "target/range-alloc-metrics.json".into());
+    std::fs::write(&path, metrics::to_json(&results)).expect("can write metrics");
+    eprintln!("wrote allocator metrics to {path}");
 }
 
 criterion_group!(
     name=benches;
     config = Criterion::default().with_profiler(PProfProfiler::new(100, Output::Flamegraph(None)));
-    targets=repeatedly_alloc_page
+    targets=repeatedly_alloc_page, linear_policy, latency_percentiles, json_metrics
 
 );
 criterion_main!(benches);
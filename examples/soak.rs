@@ -0,0 +1,188 @@
+//! runs a seeded endless workload against a backend, checking its invariants and printing stats
+//! every so often, until interrupted with Ctrl-C. Meant for hours-long runs to spot metadata
+//! growth or fragmentation creep that short tests do not show.
+//!
+//! ```text
+//! cargo run --release --example soak -- [linear|btree] [seed] [ops between reports]
+//! ```
+
+use std::{
+    env,
+    process::ExitCode,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
+};
+
+use range_alloc::{RangeAlloc, Result, btree, linear};
+
+const REGIONS: [(usize, usize); 3] = [
+    (0x10_0000, 0x100_0000),
+    (0x200_0000, 0x40_0000),
+    (0x400_0000, 0x400_0000),
+];
+
+/// the backend specific parts the soak test looks at
+trait Backend: RangeAlloc<Tag = ()> {
+    fn check_invariants(&self) -> Result<()>;
+    fn fragmentation(&self) -> f64;
+    fn metadata_bytes(&self) -> usize;
+}
+
+impl Backend for linear::RangeAllocator<()> {
+    fn check_invariants(&self) -> Result<()> {
+        self.check_invariants()
+    }
+    fn fragmentation(&self) -> f64 {
+        self.fragmentation()
+    }
+    fn metadata_bytes(&self) -> usize {
+        self.metadata_bytes()
+    }
+}
+
+impl Backend for btree::RangeAllocator<()> {
+    fn check_invariants(&self) -> Result<()> {
+        self.check_invariants()
+    }
+    fn fragmentation(&self) -> f64 {
+        self.fragmentation()
+    }
+    fn metadata_bytes(&self) -> usize {
+        self.metadata_bytes()
+    }
+}
+
+/// xorshift, so a seed always replays the same workload
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    ops: u64,
+    allocs: u64,
+    failed: u64,
+    frees: u64,
+    peak_metadata: usize,
+    peak_fragmentation: f64,
+}
+
+fn soak(mut a: impl Backend, seed: u64, report_every: u64, stop: &AtomicBool) -> Result<Stats> {
+    for (base, size) in REGIONS {
+        a.add_range(base, size, ())?;
+    }
+    let mut rng = Rng(seed.max(1));
+    let mut live: Vec<(usize, usize)> = Vec::new();
+    let mut stats = Stats::default();
+    let start = Instant::now();
+
+    while !stop.load(Ordering::Relaxed) {
+        // drifts between filling up and draining, so both full and empty phases are covered
+        let phase = (stats.ops / 100_000) % 4;
+        let alloc_percent = [70, 55, 45, 30][phase as usize];
+        if live.is_empty() || rng.below(100) < alloc_percent {
+            // mostly small, sometimes large and strongly aligned
+            let max_order = if rng.below(16) == 0 { 10 } else { 4 };
+            let size = 0x1000 << rng.below(max_order);
+            let alignment = 0x1000 << rng.below(3);
+            stats.allocs += 1;
+            match a.alloc(size, alignment) {
+                Ok((_, base)) => live.push((base, size)),
+                Err(_) => stats.failed += 1,
+            }
+        } else {
+            let (base, size) = live.swap_remove(rng.below(live.len()));
+            a.free(base, size)?;
+            stats.frees += 1;
+        }
+        stats.ops += 1;
+
+        if stats.ops.is_multiple_of(report_every) {
+            a.check_invariants()?;
+            let (metadata, fragmentation) = (a.metadata_bytes(), a.fragmentation());
+            stats.peak_metadata = stats.peak_metadata.max(metadata);
+            stats.peak_fragmentation = stats.peak_fragmentation.max(fragmentation);
+            println!(
+                "{:>8.0}s {:>12} ops  {:>7} live  {:>5.1}% used  fragmentation {:.3}  \
+                 metadata {} bytes  {} failed",
+                start.elapsed().as_secs_f64(),
+                stats.ops,
+                live.len(),
+                a.utilization() * 100.0,
+                fragmentation,
+                metadata,
+                stats.failed,
+            );
+        }
+    }
+
+    a.check_invariants()?;
+    for (base, size) in live {
+        a.free(base, size)?;
+    }
+    a.check_invariants()?;
+    assert!(a.is_empty(), "space was lost: {:#x} free", a.space());
+    Ok(stats)
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let backend = args.first().map_or("linear", String::as_str);
+    let seed = args.get(1).and_then(|s| s.parse().ok()).unwrap_or(1);
+    let report_every = args
+        .get(2)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1_000_000);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let handler = Arc::clone(&stop);
+    ctrlc::set_handler(move || handler.store(true, Ordering::Relaxed))
+        .expect("can install the Ctrl-C handler");
+
+    println!("soaking the {backend} backend with seed {seed}, Ctrl-C to stop");
+    let start = Instant::now();
+    let stats = match backend {
+        "linear" => soak(linear::RangeAllocator::new(), seed, report_every, &stop),
+        "btree" => soak(btree::RangeAllocator::new(), seed, report_every, &stop),
+        _ => {
+            eprintln!("unknown backend {backend}, expected linear or btree");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match stats {
+        Ok(stats) => {
+            let secs = start.elapsed().as_secs_f64();
+            println!(
+                "done after {secs:.0}s: {} ops ({:.0}/s), {} allocations of which {} failed, \
+                 {} frees, peak metadata {} bytes, peak fragmentation {:.3}",
+                stats.ops,
+                stats.ops as f64 / secs,
+                stats.allocs,
+                stats.failed,
+                stats.frees,
+                stats.peak_metadata,
+                stats.peak_fragmentation,
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("soak test failed: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
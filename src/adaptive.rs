@@ -0,0 +1,228 @@
+//! switching the allocation policy at runtime
+//!
+//! [`Adaptive`] wraps a backend and watches its fragmentation and allocation failure rate. When
+//! either crosses its upper threshold the backend is switched to [`Policy::BestFit`], and once both
+//! have dropped below their lower thresholds it goes back to [`Policy::FirstFit`]. The gap between
+//! the thresholds keeps the policy from flapping around a single value.
+
+use core::ops::Range;
+
+use crate::{ErrorKind, Policy, RangeAlloc, Result, address::Address, btree, linear};
+
+/// a backend whose policy can be changed and whose fragmentation can be inspected
+pub trait PolicyControl {
+    fn policy(&self) -> Policy;
+
+    fn set_policy(&mut self, policy: Policy);
+
+    /// [`metrics::fragmentation_score`](crate::metrics::fragmentation_score) of the free space
+    fn fragmentation(&self) -> f64;
+}
+
+impl<Tag, A: Address> PolicyControl for linear::RangeAllocator<Tag, A> {
+    fn policy(&self) -> Policy {
+        self.policy()
+    }
+
+    fn set_policy(&mut self, policy: Policy) {
+        self.set_policy(policy)
+    }
+
+    fn fragmentation(&self) -> f64 {
+        self.fragmentation()
+    }
+}
+
+impl<Tag, A: Address> PolicyControl for btree::RangeAllocator<Tag, A> {
+    fn policy(&self) -> Policy {
+        self.policy()
+    }
+
+    fn set_policy(&mut self, policy: Policy) {
+        self.set_policy(policy)
+    }
+
+    fn fragmentation(&self) -> f64 {
+        self.fragmentation()
+    }
+}
+
+/// when [`Adaptive`] switches policies. Rates and scores are in `0.0..=1.0`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    /// switch to best-fit once the fragmentation score reaches this
+    pub fragmentation_high: f64,
+    /// fragmentation score below which first-fit may be used again
+    pub fragmentation_low: f64,
+    /// switch to best-fit once this fraction of allocations in a window fails
+    pub failure_rate_high: f64,
+    /// failure rate below which first-fit may be used again
+    pub failure_rate_low: f64,
+    /// number of allocations after which the state is evaluated. Has to be non-zero
+    pub window: usize,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            fragmentation_high: 0.5,
+            fragmentation_low: 0.25,
+            failure_rate_high: 0.1,
+            failure_rate_low: 0.01,
+            window: 64,
+        }
+    }
+}
+
+/// a policy change made by [`Adaptive`], together with the measurements that caused it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Switch {
+    pub from: Policy,
+    pub to: Policy,
+    pub fragmentation: f64,
+    pub failure_rate: f64,
+}
+
+/// wraps a backend and switches between first-fit and best-fit depending on how fragmented it
+/// is, calling `on_switch` for every change.
+///
+/// the state is only evaluated at the end of every window of allocations, so the cost of
+/// computing the fragmentation score is spread over the window. Allocations that fail because of
+/// invalid arguments do not count towards the failure rate
+pub struct Adaptive<A, F> {
+    inner: A,
+    thresholds: Thresholds,
+    on_switch: F,
+    allocs: usize,
+    failures: usize,
+}
+
+impl<A: PolicyControl, F: FnMut(&Switch)> Adaptive<A, F> {
+    /// starts out in first-fit mode, whatever policy `inner` was using
+    pub fn new(mut inner: A, thresholds: Thresholds, on_switch: F) -> Self {
+        assert!(thresholds.window > 0, "window has to be non-zero");
+        inner.set_policy(Policy::FirstFit);
+        Adaptive {
+            inner,
+            thresholds,
+            on_switch,
+            allocs: 0,
+            failures: 0,
+        }
+    }
+
+    pub fn thresholds(&self) -> &Thresholds {
+        &self.thresholds
+    }
+
+    pub fn policy(&self) -> Policy {
+        self.inner.policy()
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+
+    fn record<T>(&mut self, res: &Result<T>) {
+        self.allocs += 1;
+        if let Err(e) = res
+            && matches!(
+                e.kind(),
+                ErrorKind::OutOfSpace
+                    | ErrorKind::Overconstrained { .. }
+                    | ErrorKind::RequestExceedsAnyRegion { .. }
+            )
+        {
+            self.failures += 1;
+        }
+        if self.allocs >= self.thresholds.window {
+            self.evaluate();
+        }
+    }
+
+    /// decides on the policy for the next window and starts it
+    fn evaluate(&mut self) {
+        let t = self.thresholds;
+        let fragmentation = self.inner.fragmentation();
+        let failure_rate = self.failures as f64 / self.allocs as f64;
+        self.allocs = 0;
+        self.failures = 0;
+
+        let from = self.inner.policy();
+        let to = if fragmentation >= t.fragmentation_high || failure_rate >= t.failure_rate_high {
+            Policy::BestFit
+        } else if fragmentation < t.fragmentation_low && failure_rate < t.failure_rate_low {
+            Policy::FirstFit
+        } else {
+            from
+        };
+        if to == from {
+            return;
+        }
+
+        log::debug!(
+            "switching from {from:?} to {to:?} at fragmentation {fragmentation}, failure rate {failure_rate}"
+        );
+        self.inner.set_policy(to);
+        (self.on_switch)(&Switch {
+            from,
+            to,
+            fragmentation,
+            failure_rate,
+        });
+    }
+}
+
+impl<Addr, A, F> RangeAlloc<Addr> for Adaptive<A, F>
+where
+    Addr: Address,
+    A: RangeAlloc<Addr> + PolicyControl,
+    F: FnMut(&Switch),
+{
+    type Tag = A::Tag;
+
+    fn add_range(&mut self, base: Addr, size: Addr, range_tag: Self::Tag) -> Result<()> {
+        self.inner.add_range(base, size, range_tag)
+    }
+
+    fn alloc(&mut self, min_size: Addr, alignment: Addr) -> Result<(Self::Tag, Addr)> {
+        let res = self.inner.alloc(min_size, alignment);
+        self.record(&res);
+        res
+    }
+
+    fn alloc_within(
+        &mut self,
+        min_size: Addr,
+        alignment: Addr,
+        window: Range<Addr>,
+    ) -> Result<(Self::Tag, Addr)> {
+        let res = self.inner.alloc_within(min_size, alignment, window);
+        self.record(&res);
+        res
+    }
+
+    fn alloc_fixed(&mut self, base: Addr, size: Addr) -> Result<(Self::Tag, Addr)> {
+        self.inner.alloc_fixed(base, size)
+    }
+
+    fn free(&mut self, base: Addr, size: Addr) -> Result<()> {
+        self.inner.free(base, size)
+    }
+
+    fn total_space(&self) -> Addr {
+        self.inner.total_space()
+    }
+
+    fn space(&self) -> Addr {
+        self.inner.space()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
@@ -0,0 +1,97 @@
+//! the integer type addresses and sizes are expressed in
+//!
+//! every allocator is generic over an [`Address`], defaulting to `usize`. A narrower or wider
+//! type can be used to manage an address space that is not the host's, e.g. a 64-bit guest
+//! physical address space from a 32-bit host.
+
+use core::{
+    fmt,
+    hash::Hash,
+    iter::Sum,
+    ops::{Add, AddAssign, BitAnd, Not, Sub, SubAssign},
+};
+
+use crate::linear::BASE_PAGE_SIZE;
+
+/// an unsigned integer type usable as address and size
+pub trait Address:
+    Copy
+    + Ord
+    + Hash
+    + Default
+    + fmt::Debug
+    + fmt::Display
+    + fmt::LowerHex
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + AddAssign
+    + SubAssign
+    + BitAnd<Output = Self>
+    + Not<Output = Self>
+    + Sum
+    + TryFrom<u64>
+    + 'static
+{
+    const ZERO: Self;
+    const ONE: Self;
+    const MAX: Self;
+    /// [`BASE_PAGE_SIZE`] in this type
+    const BASE_PAGE: Self;
+
+    fn checked_add(self, rhs: Self) -> Option<Self>;
+
+    fn checked_sub(self, rhs: Self) -> Option<Self>;
+
+    fn saturating_add(self, rhs: Self) -> Self;
+
+    fn is_power_of_two(self) -> bool;
+
+    /// the value widened to 64 bits, for statistics and error reports
+    fn to_u64(self) -> u64;
+
+    /// the smallest multiple of `alignment` that is `>= self`, if it fits. `alignment` has to be a
+    /// power of two or 0, which leaves `self` unchanged
+    fn round_up(self, alignment: Self) -> Option<Self> {
+        let mask = alignment.max(Self::ONE) - Self::ONE;
+        self.checked_add(mask).map(|n| n & !mask)
+    }
+
+    /// the largest multiple of `alignment` that is `<= self`. `alignment` has to be a power of two
+    /// or 0, which leaves `self` unchanged
+    fn round_down(self, alignment: Self) -> Self {
+        self & !(alignment.max(Self::ONE) - Self::ONE)
+    }
+}
+
+macro_rules! impl_address {
+    ($($t:ty),*) => {$(
+        impl Address for $t {
+            const ZERO: Self = 0;
+            const ONE: Self = 1;
+            const MAX: Self = <$t>::MAX;
+            const BASE_PAGE: Self = BASE_PAGE_SIZE as $t;
+
+            fn checked_add(self, rhs: Self) -> Option<Self> {
+                <$t>::checked_add(self, rhs)
+            }
+
+            fn checked_sub(self, rhs: Self) -> Option<Self> {
+                <$t>::checked_sub(self, rhs)
+            }
+
+            fn saturating_add(self, rhs: Self) -> Self {
+                <$t>::saturating_add(self, rhs)
+            }
+
+            fn is_power_of_two(self) -> bool {
+                <$t>::is_power_of_two(self)
+            }
+
+            fn to_u64(self) -> u64 {
+                self as u64
+            }
+        }
+    )*};
+}
+
+impl_address!(u32, u64, usize);
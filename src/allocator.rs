@@ -0,0 +1,267 @@
+//! where the backends keep their nodes
+//!
+//! both backends and [`Heap`](crate::collections::heap::Heap) take an allocator parameter `M`
+//! for the nodes that grow with the number of free blocks. With the `allocator-api` feature,
+//! which needs a nightly compiler, [`Allocator`] is `core::alloc::Allocator` and their `new_in`
+//! constructors take any of them, so allocating and freeing never goes to the global heap.
+//!
+//! Only the nodes go to `M`. The rest of the bookkeeping stays on the global heap: the regions
+//! and their attributes, reservations, pins and deadlines, and the tracked allocations along
+//! with the index of free blocks the linear backend keeps for them. It changes when regions are
+//! added, removed or reserved, and with every allocation only while tracking is enabled.
+//!
+//! Without the feature, [`Allocator`] is a stand-in with the same methods that only [`Global`]
+//! and references to it implement.
+
+#[cfg(feature = "allocator-api")]
+pub use alloc::alloc::{AllocError, Allocator, Global};
+#[cfg(not(feature = "allocator-api"))]
+pub use stand_in::{AllocError, Allocator, Global};
+
+#[cfg(not(feature = "allocator-api"))]
+mod stand_in {
+    use core::{alloc::Layout, fmt, ptr::NonNull};
+
+    /// the allocator could not satisfy a request
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct AllocError;
+
+    impl fmt::Display for AllocError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("memory allocation failed")
+        }
+    }
+
+    mod sealed {
+        pub trait Sealed {}
+    }
+
+    /// `core::alloc::Allocator`, reduced to what the crate uses
+    ///
+    /// # Safety
+    ///
+    /// as for `core::alloc::Allocator`: allocated blocks stay valid until they are deallocated
+    pub unsafe trait Allocator: sealed::Sealed {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError>;
+
+        /// # Safety
+        ///
+        /// `ptr` has to be a block allocated with `layout` by this allocator
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+    }
+
+    impl<A: Allocator + ?Sized> sealed::Sealed for &A {}
+
+    // SAFETY: forwards to `A`, which upholds the contract
+    unsafe impl<A: Allocator + ?Sized> Allocator for &A {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            (**self).allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            // SAFETY: the caller passes a block from `allocate` with the same layout
+            unsafe { (**self).deallocate(ptr, layout) }
+        }
+    }
+
+    /// the global heap
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct Global;
+
+    impl sealed::Sealed for Global {}
+
+    // SAFETY: the blocks come from the global allocator and go back to it
+    unsafe impl Allocator for Global {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            let ptr = if layout.size() == 0 {
+                NonNull::new(core::ptr::without_provenance_mut(layout.align()))
+            } else {
+                // SAFETY: the layout is not zero-sized
+                NonNull::new(unsafe { alloc::alloc::alloc(layout) })
+            };
+            ptr.map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
+                .ok_or(AllocError)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            if layout.size() != 0 {
+                // SAFETY: the caller passes a block from `allocate` with the same layout
+                unsafe { alloc::alloc::dealloc(ptr.as_ptr(), layout) };
+            }
+        }
+    }
+}
+
+/// `T` itself, in a way that names `M`, so [`Vec`] can take an allocator it ignores
+#[cfg(not(feature = "allocator-api"))]
+pub(crate) trait Ignoring<M> {
+    type Itself;
+}
+
+#[cfg(not(feature = "allocator-api"))]
+impl<T, M> Ignoring<M> for T {
+    type Itself = T;
+}
+
+/// a `Vec` in `M`. Without the feature that can only be the global heap
+#[cfg(feature = "allocator-api")]
+pub(crate) type Vec<T, M> = alloc::vec::Vec<T, M>;
+#[cfg(not(feature = "allocator-api"))]
+pub(crate) type Vec<T, M> = alloc::vec::Vec<<T as Ignoring<M>>::Itself>;
+
+/// an empty [`Vec`] in `alloc`
+#[cfg(feature = "allocator-api")]
+pub(crate) fn vec_in<T, M: Allocator>(alloc: M) -> Vec<T, M> {
+    alloc::vec::Vec::new_in(alloc)
+}
+
+/// an empty [`Vec`] in `alloc`
+#[cfg(not(feature = "allocator-api"))]
+pub(crate) fn vec_in<T, M: Allocator>(_: M) -> Vec<T, M> {
+    alloc::vec::Vec::new()
+}
+
+#[cfg(all(test, feature = "allocator-api"))]
+mod tests {
+    use core::{
+        alloc::{GlobalAlloc, Layout},
+        cell::Cell,
+        ptr::NonNull,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use std::alloc::System;
+
+    use super::{AllocError, Allocator};
+    use crate::{Direction, RangeAlloc, btree, collections::heap::Heap, linear};
+
+    /// the system allocator, bypassing the global heap, counting the bytes it hands out
+    #[derive(Default)]
+    struct Counting(AtomicUsize);
+
+    unsafe impl Allocator for Counting {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.0.fetch_add(layout.size(), Ordering::Relaxed);
+            System.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.0.fetch_sub(layout.size(), Ordering::Relaxed);
+            unsafe { System.deallocate(ptr, layout) }
+        }
+    }
+
+    /// the global heap of the test binary, counting what each thread allocates from it
+    struct CountingGlobal;
+
+    std::thread_local! {
+        static GLOBAL_ALLOCS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    // SAFETY: the blocks come from the system allocator and go back to it
+    unsafe impl GlobalAlloc for CountingGlobal {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            // the thread local is gone while its thread shuts down
+            let _ = GLOBAL_ALLOCS.try_with(|count| count.set(count.get() + 1));
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingGlobal = CountingGlobal;
+
+    /// the number of allocations this thread made from the global heap so far
+    fn global_allocs() -> usize {
+        GLOBAL_ALLOCS.with(Cell::get)
+    }
+
+    fn fragment(a: &mut impl RangeAlloc<Tag = ()>) {
+        a.add_range(0, 0x1000, ()).expect("can add range");
+        for base in (0..0x1000).step_by(2) {
+            a.alloc_fixed(base, 1).expect("can allocate");
+        }
+    }
+
+    /// allocates and frees in a fragmented region, which only splits and merges free blocks
+    fn churn(a: &mut impl RangeAlloc<Tag = ()>) {
+        for base in (0..0x1000).step_by(2) {
+            a.free(base, 1).expect("can free");
+        }
+        for _ in 0..0x400 {
+            a.alloc(1, 1).expect("has space");
+        }
+    }
+
+    #[test]
+    fn free_blocks_stay_off_the_global_heap() {
+        let counting = Counting::default();
+
+        let before = global_allocs();
+        let a = linear::RangeAllocator::<(), usize, _>::new_in(&counting);
+        let b = btree::RangeAllocator::<(), usize, 6, _>::new_in(&counting);
+        assert_eq!(global_allocs(), before);
+        drop((a, b));
+
+        let mut a = linear::RangeAllocator::<(), usize, _>::with_granularity_in(
+            crate::units::Alignment::ONE,
+            &counting,
+        );
+        fragment(&mut a);
+        // one large block among many small ones, which the size classes skip
+        a.add_range(0x1_0000, 0x1000, ()).expect("can add range");
+        let before = global_allocs();
+        // the searches that collect their candidates keep them in `M` too
+        for direction in [Direction::TopDown, Direction::BottomUp] {
+            a.set_direction(direction);
+            let (_, x) = a.alloc(0x100, 1).expect("has space");
+            a.free(x, 0x100).expect("can free");
+        }
+        churn(&mut a);
+        assert_eq!(global_allocs(), before);
+
+        let mut a = btree::RangeAllocator::<(), usize, 6, _>::with_granularity_in(
+            crate::units::Alignment::ONE,
+            &counting,
+        );
+        fragment(&mut a);
+        let before = global_allocs();
+        churn(&mut a);
+        assert_eq!(global_allocs(), before);
+    }
+
+    #[test]
+    fn nodes_in_an_allocator() {
+        let counting = Counting::default();
+        let mut a = linear::RangeAllocator::<(), usize, _>::with_granularity_in(
+            crate::units::Alignment::ONE,
+            &counting,
+        );
+        fragment(&mut a);
+        assert!(counting.0.load(Ordering::Relaxed) >= 0x800 * size_of::<usize>());
+        drop(a);
+        assert_eq!(counting.0.load(Ordering::Relaxed), 0);
+
+        let mut a = btree::RangeAllocator::<(), usize, 6, _>::with_granularity_in(
+            crate::units::Alignment::ONE,
+            &counting,
+        );
+        fragment(&mut a);
+        assert!(counting.0.load(Ordering::Relaxed) >= 0x800 * size_of::<usize>());
+        let a = a.with_fanout::<16>();
+        assert!(counting.0.load(Ordering::Relaxed) >= 0x800 * size_of::<usize>());
+        drop(a);
+        assert_eq!(counting.0.load(Ordering::Relaxed), 0);
+
+        let mut heap = Heap::new_in(&counting);
+        for x in 0..100 {
+            heap.insert(x);
+        }
+        assert_eq!(heap.pop(), Some(99));
+        assert!(counting.0.load(Ordering::Relaxed) > 0);
+        drop(heap);
+        assert_eq!(counting.0.load(Ordering::Relaxed), 0);
+    }
+}
@@ -1,92 +1,732 @@
-use core::fmt;
-use std::{collections::BTreeMap, ptr::NonNull};
+//! the B-tree backend, keeping free extents in a [`BMap`] keyed by base address
+//!
+//! every node of the tree knows the largest free extent below it, so a search skips all the
+//! subtrees whose extents are too small for the request instead of looking at each of them.
 
-use tinyvec::{Array, ArrayVec, array_vec};
+use alloc::{boxed::Box, collections::BTreeMap, format, vec::Vec};
+use core::{cell::Cell, fmt, ops::Range, ptr::NonNull};
 
-use crate::{Error, RangeAlloc, Result, linear::BASE_PAGE_SIZE, round_up};
-
-/// maximum number of buckets per node
-pub(super) const B: usize = 6;
-
-const CAPACITY: usize = B;
+use crate::{
+    Complexity, ComplexityClass, Direction, Error, ErrorKind, Event, Placement, Policy, RangeAlloc,
+    RegionAttrs, RegionId, Request, Result,
+    address::Address,
+    allocator::{Allocator, Global},
+    collections::{BMap, Measured, RangeSet},
+    common::{self, Bookkeeping, Common},
+    linear,
+    linear::BASE_PAGE_SIZE,
+    map::{self, MapEntry, RegionInfo, RegionKind},
+    merged_regions, notify,
+    raw::{RawParts, Snapshot, ensure},
+    round_up,
+    units::{Alignment, Size},
+    verify::{self, Discrepancy},
+};
 
 #[derive(Debug, Default, PartialEq, Eq)]
-struct Entry<Tag> {
-    size: usize,
+struct Entry<Tag, A> {
+    size: A,
     tag: Tag,
 }
 
-type EntryWithBase<'a, Tag> = (&'a usize, &'a Entry<Tag>);
+impl<Tag, A: Copy> Entry<Tag, A> {
+    /// the region at `base`, with its tag by reference
+    fn at(&self, base: A, kind: RegionKind) -> MapEntry<&Tag, A> {
+        MapEntry {
+            base,
+            size: self.size,
+            tag: &self.tag,
+            kind,
+        }
+    }
+}
 
-pub struct RangeAllocator<Tag> {
-    tree: BTreeMap<usize, Entry<Tag>>,
-    regions: BTreeMap<usize, Entry<Tag>>,
-    total_space: usize,
-    free_space: usize,
+/// a free extent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Free<A> {
+    size: A,
+    /// when the extent became free
+    epoch: u64,
 }
 
-struct P<'a, Tag>(&'a BTreeMap<usize, Entry<Tag>>);
-impl<T> fmt::Debug for P<'_, T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut list = f.debug_list();
-        for i in self.0 {
-            list.entry(&format!("{:x}:{}", i.0, i.1.size));
-        }
+/// the free tree is searched by size
+impl<A: Address> Measured for Free<A> {
+    fn measure(&self) -> u64 {
+        self.size.to_u64()
+    }
+}
 
-        list.finish();
+type FreeWithBase<'a, A> = (&'a A, &'a Free<A>);
 
-        Ok(())
-    }
+pub struct RangeAllocator<Tag, A = usize, const B: usize = 6, M: Allocator + Clone = Global> {
+    /// free extents by base. Tags are only stored once per region in `regions`, so a zero-sized
+    /// tag adds nothing to the free tree and no clones happen when splitting or merging
+    tree: BMap<A, Free<A>, B, M>,
+    regions: BTreeMap<A, Entry<Tag, A>>,
+    /// regions that are part of the memory map but never allocatable
+    reserved_regions: BTreeMap<A, Entry<Tag, A>>,
+    /// attributes of the regions that were added with non-default ones, keyed by region base
+    region_attrs: BTreeMap<A, RegionAttrs<A>>,
+    /// where [`Policy::NextFit`] resumes searching: the end of the latest allocation, or its
+    /// start when going [top-down](Direction::TopDown)
+    cursor: A,
+    common: Common<Tag, A>,
 }
 
 impl<T: Default> RangeAllocator<T> {
     pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// the constructors build allocators with the default fan-out, which
+/// [`with_fanout`](RangeAllocator::with_fanout) changes
+impl<Tag, A: Address> RangeAllocator<Tag, A> {
+    common::shared_methods!(constructors);
+}
+
+impl<Tag: Default + Clone + fmt::Debug, A: Address> RangeAllocator<Tag, A> {
+    /// rebuilds an allocator from a [`snapshot`](Self::snapshot) of either backend, checked like
+    /// [`from_raw_parts`](Self::from_raw_parts)
+    pub fn restore(snapshot: Snapshot<Tag, A>) -> Result<Self> {
+        Self::from_raw_parts(snapshot)
+    }
+
+    /// rebuilds an allocator from `parts`, which may come from either backend. Fails with
+    /// [`ErrorKind::Inconsistent`] if they do not describe a valid allocator
+    pub fn from_raw_parts(mut parts: RawParts<Tag, A>) -> Result<Self> {
+        parts.validate()?;
+        let mut a = RangeAllocator {
+            common: Common::from_parts(&mut parts),
+            region_attrs: parts.region_attrs.into_iter().collect(),
+            ..Self::default()
+        };
+
+        for region in parts.regions {
+            let entry = Entry {
+                size: region.size,
+                tag: region.tag,
+            };
+            match region.kind {
+                RegionKind::Usable => {
+                    a.common.region_free.insert(region.base, A::ZERO);
+                    a.regions.insert(region.base, entry);
+                }
+                RegionKind::Reserved => {
+                    a.reserved_regions.insert(region.base, entry);
+                }
+            }
+        }
+        for (range, epoch) in parts.free {
+            let size = range.end - range.start;
+            a.tree.insert(range.start, Free { size, epoch });
+            *a.common.region_free_mut(range.start) += size;
+        }
+        Ok(a)
+    }
+}
+
+#[cfg(feature = "allocator-api")]
+impl<Tag, A: Address, M: Allocator + Clone> RangeAllocator<Tag, A, 6, M> {
+    /// an allocator that keeps the nodes of its free tree in `alloc`, and the rest of its
+    /// bookkeeping on the global heap, see the [`allocator`](crate::allocator) module
+    pub fn new_in(alloc: M) -> Self {
+        Self::empty_in(alloc)
+    }
+
+    /// [`with_granularity`](RangeAllocator::with_granularity) with the free tree in `alloc`
+    pub fn with_granularity_in(granularity: Alignment<A>, alloc: M) -> Self {
+        let mut a = Self::empty_in(alloc);
+        a.common.granularity = granularity;
+        a
+    }
+}
+
+#[cfg(feature = "allocator-api")]
+impl<Tag, A: Address, const B: usize, M: Allocator + Clone> RangeAllocator<Tag, A, B, M> {
+    pub fn allocator(&self) -> &M {
+        self.tree.allocator()
+    }
+}
+
+impl<T, A: Address, const B: usize, M: Allocator + Clone> RangeAllocator<T, A, B, M> {
+    fn empty_in(alloc: M) -> Self {
         RangeAllocator {
-            tree: BTreeMap::new(), // TODO: new_in
+            tree: BMap::new_in(alloc),
             regions: BTreeMap::new(),
-            total_space: 0,
-            free_space: 0,
+            reserved_regions: BTreeMap::new(),
+            region_attrs: BTreeMap::new(),
+            cursor: A::ZERO,
+            common: Common::new(),
+        }
+    }
+
+    /// the same allocator with free tree nodes of up to `N` entries. Small nodes make updates
+    /// cheap, large ones make the tree shallower and lookups more cache-friendly
+    pub fn with_fanout<const N: usize>(self) -> RangeAllocator<T, A, N, M> {
+        let mut tree = BMap::new_in(self.tree.allocator().clone());
+        for (&base, &free) in &self.tree {
+            tree.insert(base, free);
+        }
+        RangeAllocator {
+            tree,
+            regions: self.regions,
+            reserved_regions: self.reserved_regions,
+            region_attrs: self.region_attrs,
+            cursor: self.cursor,
+            common: self.common,
         }
     }
 
     fn before_and_after(
         &self,
-        base: usize,
-        size: usize,
-    ) -> (Option<EntryWithBase<'_, T>>, Option<EntryWithBase<'_, T>>) {
+        base: A,
+        size: A,
+    ) -> (Option<FreeWithBase<'_, A>>, Option<FreeWithBase<'_, A>>) {
         (
             self.tree.range(..base).next_back(),
             self.tree.range(base + size..).next(),
         )
     }
+
+    /// the region `addr` was added with, if any
+    fn region_of(&self, addr: A) -> Option<(&A, &Entry<T, A>)> {
+        self.regions
+            .range(..=addr)
+            .next_back()
+            .filter(|&(&base, region)| addr < base + region.size)
+    }
+
+    /// the base and attributes of the region containing `addr`, if it has non-default ones
+    fn attrs_at(&self, addr: A) -> Option<(A, &RegionAttrs<A>)> {
+        if self.region_attrs.is_empty() {
+            return None;
+        }
+        let (&base, _) = self.region_of(addr)?;
+        self.region_attrs.get(&base).map(|attrs| (base, attrs))
+    }
 }
 
-impl<Tag: Default + Clone + fmt::Debug> RangeAlloc for RangeAllocator<Tag> {
-    type Tag = Tag;
+impl<Tag, A: Address, const B: usize, M: Allocator + Clone> RangeAllocator<Tag, A, B, M> {
+    /// allocating searches the free blocks in address order, skipping those too small in the
+    /// tree. Only blocks that are large enough but fail the alignment are passed one by one, which
+    /// is linear in the worst case. Fixed allocations and frees look their neighbours up in the
+    /// free tree
+    pub const COMPLEXITY: ComplexityClass = ComplexityClass {
+        alloc: Complexity::Linear,
+        alloc_fixed: Complexity::Logarithmic,
+        free: Complexity::Logarithmic,
+    };
 
-    /// adds a range to the allocator from which the allocator may pick
-    fn add_range(&mut self, base: usize, size: usize, range_tag: Tag) -> Result<()> {
-        self.free_space += size;
-        self.total_space += size;
-
-        let (before, after) = self.before_and_after(base, 0);
-        if let Some(before) = before {
-            if before.0 + before.1.size > base {
-                return Err(Error::cause("adding overlapping region"));
+    /// the number of free blocks examined so far while searching for a place. Lookups in the
+    /// free tree are not counted
+    pub fn steps(&self) -> usize {
+        self.common.steps.get()
+    }
+
+    common::shared_methods!(state);
+
+    /// number of regions, usable and reserved
+    pub fn region_count(&self) -> usize {
+        self.regions.len() + self.reserved_regions.len()
+    }
+
+    /// number of separate free extents
+    pub fn free_extent_count(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// size of the largest usable region, 0 without any
+    pub fn largest_region(&self) -> A {
+        self.regions
+            .values()
+            .map(|region| region.size)
+            .max()
+            .unwrap_or(A::ZERO)
+    }
+
+    /// size of the largest free extent within `range`, counting only the part inside it, 0
+    /// without any. Takes `O(log n)` steps, since only the extents at either end of `range` can
+    /// stick out of it
+    pub fn largest_free_in(&self, range: Range<A>) -> A {
+        if range.start >= range.end {
+            return A::ZERO;
+        }
+        let clipped = |(&base, free): (&A, &Free<A>)| {
+            let start = base.max(range.start);
+            let end = (base + free.size).min(range.end);
+            if start < end { end - start } else { A::ZERO }
+        };
+        let before = self.tree.range(..range.start).next_back();
+        let last = self.tree.range(range.clone()).next_back();
+        let inner = last.and_then(|(&last, _)| self.tree.max_in(range.start..last));
+        [before, last, inner]
+            .into_iter()
+            .flatten()
+            .map(clipped)
+            .max()
+            .unwrap_or(A::ZERO)
+    }
+
+    /// two touching usable regions that together span at least `size`, for a request that
+    /// failed with [`ErrorKind::RequestExceedsAnyRegion`]. Blocks of different regions are never
+    /// merged, so the caller has to remove both regions and add their span as one
+    pub fn bridging_regions(&self, size: A) -> Option<Range<A>> {
+        let regions: Vec<Range<A>> = self
+            .regions
+            .iter()
+            .map(|(&base, region)| base..base + region.size)
+            .collect();
+        crate::bridging_pair(&regions, size)
+    }
+
+    /// [`metrics::fragmentation_score`](crate::metrics::fragmentation_score) of the free space
+    pub fn fragmentation(&self) -> f64 {
+        crate::metrics::fragmentation_score(self.tree.values().map(|free| free.size.to_u64()))
+    }
+
+    /// estimated bytes used for bookkeeping. Counts keys and values only, not the internal
+    /// nodes of the maps
+    pub fn metadata_bytes(&self) -> usize {
+        let regions = self.regions.len() + self.reserved_regions.len();
+        size_of::<Self>()
+            + self.tree.len() * size_of::<(A, Free<A>)>()
+            + regions * size_of::<(A, Entry<Tag, A>)>()
+            + self.region_attrs.len() * size_of::<(A, RegionAttrs<A>)>()
+            + self.common.reserved.len() * 2 * size_of::<usize>()
+    }
+
+    /// where `request`, which has to be normalized, would be placed under `policy`
+    fn place(
+        &self,
+        request: Request<A>,
+        policy: Policy,
+        window: &Range<A>,
+    ) -> Result<Placement<A>> {
+        let examined = Cell::new(0);
+        let placement = self.search(request, policy, window, &examined);
+        self.common.steps.add(examined.get());
+        placement
+    }
+
+    /// [`place`](Self::place), counting the free blocks looked at in `examined`
+    fn search(
+        &self,
+        request: Request<A>,
+        policy: Policy,
+        window: &Range<A>,
+        examined: &Cell<usize>,
+    ) -> Result<Placement<A>> {
+        let constraints = |base: A| {
+            self.attrs_at(base)
+                .map_or((request.alignment, request.size), |(_, attrs)| {
+                    attrs.apply(request.alignment, request.size)
+                })
+        };
+        let region_policy = |base: A| {
+            self.attrs_at(base)
+                .and_then(|(region_base, attrs)| attrs.policy.map(|p| (region_base, p)))
+        };
+
+        // the free blocks overlapping the window, starting with the one containing its start
+        let first = self
+            .tree
+            .range(..=window.start)
+            .next_back()
+            .map_or(window.start, |(&base, _)| base);
+        let end = window.end.max(first);
+        // next-fit starts with the block containing the cursor, or the next one in the search
+        // direction, and wraps around
+        let resume = match (policy, self.common.direction) {
+            (Policy::NextFit, Direction::BottomUp) => self
+                .tree
+                .range(..=self.cursor)
+                .next_back()
+                .filter(|&(&base, free)| base + free.size > self.cursor)
+                .map_or(self.cursor, |(&base, _)| base)
+                .clamp(first, end),
+            (Policy::NextFit, Direction::TopDown) => self.cursor.clamp(first, end),
+            (_, Direction::BottomUp) => first,
+            (_, Direction::TopDown) => end,
+        };
+        // the constraints only ever grow the size, so smaller blocks are skipped in the tree
+        let min = request.size.to_u64();
+        let blocks = || {
+            let below = self.tree.range_at_least(first..resume, min);
+            let above = self.tree.range_at_least(resume..end, min);
+            let count = |_: &FreeWithBase<'_, A>| examined.set(examined.get() + 1);
+            let (up, down) = match self.common.direction {
+                Direction::BottomUp => (Some(above.chain(below)), None),
+                Direction::TopDown => (None, Some(below.rev().chain(above.rev()))),
+            };
+            let up = up.into_iter().flatten();
+            up.chain(down.into_iter().flatten()).inspect(count)
+        };
+
+        let placements = blocks().filter_map(|(&base, free)| {
+            let (alignment, size) = constraints(base);
+            let block = base..base + free.size;
+            Placement::within_window(
+                block,
+                window,
+                alignment,
+                size,
+                self.common.granularity,
+                self.common.direction,
+            )
+            .map(|p| (p, free.epoch))
+        });
+        if let Some(placement) = policy.select_per_region(placements, region_policy) {
+            return Ok(placement);
+        }
+
+        // some block has enough space for the request, but does not satisfy the constraints
+        if blocks().any(|(&base, free)| {
+            let start = base.max(window.start);
+            let end = (base + free.size).min(window.end);
+            start < end && constraints(base).1 <= end - start
+        }) {
+            Err(Error::new(ErrorKind::Overconstrained {
+                size: request.size.to_u64(),
+                alignment: request.alignment.to_u64(),
+            }))
+        } else {
+            let largest_region = self.largest_region();
+            if largest_region != A::ZERO && request.size > largest_region {
+                Err(Error::new(ErrorKind::RequestExceedsAnyRegion {
+                    size: request.size.to_u64(),
+                    largest_region: largest_region.to_u64(),
+                }))
+            } else {
+                Err(Error::new(ErrorKind::OutOfSpace))
             }
         }
+    }
 
-        if let Some(after) = after {
-            if base + size > *after.0 {
-                return Err(Error::cause("adding overlapping region"));
+    /// the free blocks that have not changed since before `epoch`, i.e. have been idle the longest
+    pub fn cold_free_ranges(&self, epoch: u64) -> Vec<Range<A>> {
+        self.tree
+            .iter()
+            .filter(|(_, free)| free.epoch < epoch)
+            .map(|(&base, free)| base..base + free.size)
+            .collect()
+    }
+}
+
+impl<Tag: Default + Clone + fmt::Debug, A: Address, const B: usize, M: Allocator + Clone>
+    RangeAllocator<Tag, A, B, M>
+{
+    common::shared_methods!(bookkeeping);
+
+    /// adds a range whose allocations have to satisfy `attrs`
+    pub fn add_range_with(
+        &mut self,
+        base: A,
+        size: A,
+        range_tag: Tag,
+        attrs: RegionAttrs<A>,
+    ) -> Result<()> {
+        attrs.validate()?;
+        self.add_range(base, size, range_tag)?;
+        if attrs != RegionAttrs::default() {
+            self.region_attrs.insert(base, attrs);
+        }
+        Ok(())
+    }
+
+    /// overrides the policy used within `region`, or reverts it to the allocator's policy if
+    /// `policy` is `None`
+    pub fn set_region_policy(&mut self, region: RegionId<A>, policy: Option<Policy>) -> Result<()> {
+        let base = region.base();
+        if !self.regions.contains_key(&base) {
+            return Err(Error::new(ErrorKind::NotOwned));
+        }
+        let attrs = self.region_attrs.entry(base).or_default();
+        attrs.policy = policy;
+        if *attrs == RegionAttrs::default() {
+            self.region_attrs.remove(&base);
+        }
+        Ok(())
+    }
+
+    /// the policy `region` uses instead of the allocator's, if any
+    pub fn region_policy(&self, region: RegionId<A>) -> Option<Policy> {
+        self.region_attrs
+            .get(&region.base())
+            .and_then(|attrs| attrs.policy)
+    }
+
+    /// the size of the usable region `region`, like [`total_space`](RangeAlloc::total_space) for
+    /// the whole allocator. `None` if no usable region starts there
+    pub fn total_space_in_region(&self, region: RegionId<A>) -> Option<A> {
+        self.regions.get(&region.base()).map(|entry| entry.size)
+    }
+
+    /// adds a region that is part of the memory map but is never handed out, e.g. an MMIO hole.
+    /// It does not count towards `total_space`
+    pub fn add_range_reserved(&mut self, base: A, size: A, range_tag: Tag) -> Result<()> {
+        Size::new(size)?;
+        if base.checked_add(size).is_none() {
+            return Err(Error::new(ErrorKind::Overflow));
+        }
+        if self.overlapping_region(base..base + size).is_some() {
+            return Err(Error::new(ErrorKind::OverlappingRange));
+        }
+        self.room_for_region()?;
+
+        self.reserved_regions.insert(
+            base,
+            Entry {
+                size,
+                tag: range_tag,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// extends the usable region starting at `base` to `new_size` at its end, e.g. when the heap
+    /// behind it was grown in place, and adds the new space to the free space. Unlike removing and
+    /// re-adding the region, this works while it has allocations. Fails with
+    /// [`ErrorKind::NotOwned`] if no usable region starts at `base`, [`ErrorKind::InvalidSize`] if
+    /// `new_size` is smaller than the region and [`ErrorKind::OverlappingRange`] if the new space
+    /// overlaps another region
+    pub fn grow_region(&mut self, base: A, new_size: A) -> Result<()> {
+        let new_end = base
+            .checked_add(new_size)
+            .ok_or_else(|| Error::new(ErrorKind::Overflow))?;
+        let old_end = self
+            .region(base)
+            .filter(|region| region.kind == RegionKind::Usable)
+            .map(|region| region.end())
+            .ok_or_else(|| Error::new(ErrorKind::NotOwned))?;
+        if new_end < old_end {
+            return Err(Error::new(ErrorKind::InvalidSize));
+        }
+        if new_end == old_end {
+            return Ok(());
+        }
+        if self.overlapping_region(old_end..new_end).is_some() {
+            return Err(Error::new(ErrorKind::OverlappingRange));
+        }
+
+        // the new space is freed like an allocation, which merges it with a free block at the old
+        // end or adds a free extent
+        if !self.overlaps_free(old_end - A::ONE..old_end) {
+            self.room_for_free_extent()?;
+        }
+        let region = self.regions.get_mut(&base).expect("region exists");
+        let tag = region.tag.clone();
+        region.size = new_size;
+        self.give_back(old_end, new_end - old_end, false)
+            .expect("there is room for the new space");
+        self.common.total_space += new_end - old_end;
+        let range = old_end..new_end;
+        notify(
+            &mut self.common.observer,
+            Event::AddRange { range, tag: &tag },
+        );
+        Ok(())
+    }
+
+    /// adds the usable regions of a memory map at once, e.g. from UEFI or multiboot, with a single
+    /// overlap check instead of one per [`add_range`](RangeAlloc::add_range). The entries are
+    /// sorted and touching ones with equal tags merged. Nothing is added if any of them is empty
+    /// or overlaps another one or an existing region, or if they exceed the limits
+    pub fn add_regions<I>(&mut self, entries: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (A, A, Tag)>,
+        Tag: PartialEq,
+    {
+        let mut existing: Vec<_> = self
+            .regions
+            .iter()
+            .chain(&self.reserved_regions)
+            .map(|(&base, region)| base..base + region.size)
+            .collect();
+        existing.sort_by_key(|region| region.start);
+        let regions = merged_regions(entries, &existing)?;
+        self.common.limits.admit(
+            existing.len() + regions.len(),
+            self.free_extent_count() + regions.len(),
+        )?;
+        for (range, tag) in regions {
+            self.push_region(range.start, range.end - range.start, tag);
+        }
+        Ok(())
+    }
+
+    /// all regions, usable and reserved, sorted by base. The order is guaranteed, regions never
+    /// overlap, so two allocators with the same regions export equal maps
+    pub fn export_map(&self) -> Vec<MapEntry<Tag, A>> {
+        let entry = |kind| {
+            move |(&base, region): (&A, &Entry<Tag, A>)| MapEntry {
+                base,
+                size: region.size,
+                tag: region.tag.clone(),
+                kind,
             }
+        };
+        let mut map: Vec<_> = self
+            .regions
+            .iter()
+            .map(entry(RegionKind::Usable))
+            .chain(
+                self.reserved_regions
+                    .iter()
+                    .map(entry(RegionKind::Reserved)),
+            )
+            .collect();
+        map.sort_by_key(|entry| entry.base);
+        map
+    }
+
+    /// checks the bookkeeping for consistency, which is slow, e.g. every so often in long-running
+    /// tests. Fails with [`ErrorKind::Inconsistent`] if anything does not add up, located at the
+    /// check that failed: free extents sorted, apart and coalesced within their regions, and the
+    /// space counters matching the extents
+    pub fn check_invariants(&self) -> Result<()> {
+        self.raw_parts().validate()?;
+
+        let mut region_free: BTreeMap<_, _> =
+            self.regions.keys().map(|&base| (base, A::ZERO)).collect();
+        for (&base, free) in &self.tree {
+            let (_, space) = region_free
+                .range_mut(..=base)
+                .next_back()
+                .ok_or_else(|| Error::inconsistent())?;
+            *space += free.size;
         }
+        ensure(region_free == self.common.region_free)
+    }
 
+    /// the region, usable or reserved, that starts at `base`
+    pub fn region(&self, base: A) -> Option<RegionInfo<'_, Tag, A>> {
+        let usable = self
+            .regions
+            .get(&base)
+            .map(|region| region.at(base, RegionKind::Usable));
+        usable.or_else(|| {
+            self.reserved_regions
+                .get(&base)
+                .map(|region| region.at(base, RegionKind::Reserved))
+        })
+    }
+
+    /// the region, usable or reserved, that `addr` belongs to
+    pub fn region_containing(&self, addr: A) -> Option<MapEntry<Tag, A>> {
+        [
+            (&self.regions, RegionKind::Usable),
+            (&self.reserved_regions, RegionKind::Reserved),
+        ]
+        .into_iter()
+        .find_map(|(map, kind)| {
+            let (&base, region) = map.range(..=addr).next_back()?;
+            (addr < base + region.size).then(|| MapEntry {
+                base,
+                size: region.size,
+                tag: region.tag.clone(),
+                kind,
+            })
+        })
+    }
+
+    /// takes the usable region starting at `base` away, e.g. when its memory is unplugged. Fails
+    /// with [`ErrorKind::NotFree`] while any part of it is allocated, and with
+    /// [`ErrorKind::Pinned`] while any part of it is pinned. Reservations inside the region are
+    /// dropped along with it
+    pub fn remove_range(&mut self, base: A) -> Result<()> {
+        let Some(region) = self.regions.get(&base) else {
+            return Err(Error::new(ErrorKind::NotOwned));
+        };
+        let range = base..base + region.size;
+        self.common.check_unpinned(range.clone())?;
+        let first = self
+            .tree
+            .range(..=base)
+            .next_back()
+            .map_or(base, |(&free_base, _)| free_base);
+        let free: Vec<_> = self
+            .tree
+            .range(first..range.end)
+            .map(|(&free_base, free)| {
+                free_base.max(range.start)..(free_base + free.size).min(range.end)
+            })
+            .filter(|free| free.start < free.end)
+            .collect();
+        let reserved = self
+            .common
+            .reserved
+            .intersect(&RangeSet::from_iter([range.clone()]));
+        let free_space: A = free.iter().map(|free| free.end - free.start).sum();
+        if free_space + reserved.covered() != region.size {
+            return Err(Error::new(ErrorKind::NotFree));
+        }
+
+        for free in free {
+            self.carve(free.start, free.end - free.start)?;
+        }
+        self.common.reserved.remove(range.clone());
+        self.common.holds.remove(range.clone());
+        self.region_attrs.remove(&base);
+        self.regions.remove(&base);
+        self.common.region_free.remove(&base);
+        self.common.total_space -= range.end - range.start;
+        Ok(())
+    }
+
+    /// frees everything allocated from the regions tagged `tag`, e.g. when the VM they belong to
+    /// is torn down, and returns how much that was. Reservations stay in place. Nothing is freed
+    /// if any of the regions contains a pinned range
+    pub fn free_all_with_tag(&mut self, tag: &Tag) -> Result<A>
+    where
+        Tag: PartialEq,
+    {
+        let regions: Vec<_> = self
+            .regions
+            .iter()
+            .filter(|(_, region)| region.tag == *tag)
+            .map(|(&base, region)| base..base + region.size)
+            .collect();
+        let free: RangeSet<A> = self
+            .tree
+            .iter()
+            .map(|(&base, free)| base..base + free.size)
+            .collect();
+        self.free_allocated_in(regions, &free)
+    }
+
+    /// compares the allocator's view with the ranges that are `allocated` according to an
+    /// external source of truth, e.g. the page tables
+    pub fn verify_against(
+        &self,
+        allocated: impl IntoIterator<Item = Range<A>>,
+    ) -> Vec<Discrepancy<A>> {
+        let regions = self
+            .regions
+            .iter()
+            .map(|(&base, region)| base..base + region.size)
+            .collect();
+        let free = self
+            .tree
+            .iter()
+            .map(|(&base, free)| base..base + free.size)
+            .collect();
+        verify::diff(&regions, &free, &self.common.reserved, allocated)
+    }
+
+    /// adds the usable region `base..base + size`, which is known not to overlap any other
+    fn push_region(&mut self, base: A, size: A, range_tag: Tag) {
+        self.common.free_space += size;
+        self.common.total_space += size;
+        self.common.region_free.insert(base, size);
+
+        self.common.epoch += 1;
         self.tree.insert(
             base,
-            Entry {
+            Free {
                 size,
-                tag: range_tag.clone(),
+                epoch: self.common.epoch,
             },
         );
         self.regions.insert(
@@ -97,210 +737,522 @@ impl<Tag: Default + Clone + fmt::Debug> RangeAlloc for RangeAllocator<Tag> {
             },
         );
 
+        let (range, tag) = (base..base + size, &self.regions[&base].tag);
+        notify(&mut self.common.observer, Event::AddRange { range, tag });
+    }
+}
+
+impl<Tag: Default + Clone + fmt::Debug, A: Address, const B: usize, M: Allocator + Clone>
+    RangeAlloc<A> for RangeAllocator<Tag, A, B, M>
+{
+    type Tag = Tag;
+
+    /// adds a range to the allocator from which the allocator may pick
+    fn add_range(&mut self, base: A, size: A, range_tag: Tag) -> Result<()> {
+        Size::new(size)?;
+        if base.checked_add(size).is_none() {
+            return Err(Error::new(ErrorKind::Overflow));
+        }
+        if self.overlapping_region(base..base + size).is_some() {
+            return Err(Error::new(ErrorKind::OverlappingRange));
+        }
+        self.room_for_region()?;
+        self.room_for_free_extent()?;
+
+        self.push_region(base, size, range_tag);
         Ok(())
     }
 
-    /// allocates a range. The range will not be handed out again until it has been freed
-    fn alloc(&mut self, min_size: usize, alignment: usize) -> Result<(Tag, usize)> {
-        if !alignment.is_power_of_two() {
-            return Err(Error::cause("not power of two"));
+    common::shared_methods!(range_alloc);
+}
+
+impl<Tag: Default + Clone + fmt::Debug, A: Address, const B: usize, M: Allocator + Clone>
+    Bookkeeping<A> for RangeAllocator<Tag, A, B, M>
+{
+    fn common(&self) -> &Common<Tag, A> {
+        &self.common
+    }
+
+    fn common_mut(&mut self) -> &mut Common<Tag, A> {
+        &mut self.common
+    }
+
+    fn region_count(&self) -> usize {
+        RangeAllocator::region_count(self)
+    }
+
+    fn free_extent_count(&self) -> usize {
+        RangeAllocator::free_extent_count(self)
+    }
+
+    fn regions(&self) -> impl Iterator<Item = RegionInfo<'_, Tag, A>> {
+        self.shown().0
+    }
+
+    fn region_containing(&self, addr: A) -> Option<MapEntry<Tag, A>> {
+        RangeAllocator::region_containing(self, addr)
+    }
+
+    fn attrs_at(&self, addr: A) -> Option<(A, &RegionAttrs<A>)> {
+        RangeAllocator::attrs_at(self, addr)
+    }
+
+    fn remove_range(&mut self, base: A) -> Result<()> {
+        RangeAllocator::remove_range(self, base)
+    }
+
+    fn overlapping_region(&self, range: Range<A>) -> Option<RegionId<A>> {
+        [&self.regions, &self.reserved_regions]
+            .iter()
+            .filter_map(|map| map.range(..range.end).next_back())
+            .find(|&(&region_base, region)| region_base + region.size > range.start)
+            .map(|(&region_base, _)| RegionId(region_base))
+    }
+
+    fn merge_at(&mut self, boundary: A)
+    where
+        Tag: PartialEq,
+    {
+        let Some((&first, before)) = self.regions.range(..boundary).next_back() else {
+            return;
+        };
+        let Some(after) = self.regions.get(&boundary) else {
+            return;
+        };
+        if first + before.size != boundary
+            || before.tag != after.tag
+            || self.region_attrs.contains_key(&first)
+            || self.region_attrs.contains_key(&boundary)
+        {
+            return;
         }
-        if !alignment.is_power_of_two() {
-            return Err(Error::cause("not power of two"));
+
+        let second = self.regions.remove(&boundary).expect("region exists");
+        self.regions.get_mut(&first).expect("region exists").size += second.size;
+        let free = self
+            .common
+            .region_free
+            .remove(&boundary)
+            .expect("region exists");
+        *self.common.region_free_mut(first) += free;
+
+        let Some(&after) = self.tree.get(&boundary) else {
+            return;
+        };
+        if let Some((&start, &before)) = self.tree.range(..boundary).next_back()
+            && start + before.size == boundary
+        {
+            let merged = Free {
+                size: before.size + after.size,
+                epoch: before.epoch.max(after.epoch),
+            };
+            self.tree.insert(start, merged);
+            self.tree.remove(&boundary);
         }
-        let min_size = round_up!(min_size, BASE_PAGE_SIZE);
+    }
 
-        let mut any_can_fit = false;
+    fn overlaps_free(&self, range: Range<A>) -> bool {
+        self.tree
+            .range(..range.end)
+            .next_back()
+            .is_some_and(|(&free_base, free)| free_base + free.size > range.start)
+    }
 
-        let candidate = self
+    fn raw_parts(&self) -> RawParts<Tag, A> {
+        let free = self
             .tree
-            .range_mut(usize::MIN..usize::MAX) // TODO: use address range constraints
-            .find(|(base, node)| {
-                let base = *base;
-                if min_size > node.size {
-                    return false;
-                }
-                // this node has enough space for the request, but does it satisfy the constraints?
-                any_can_fit = true;
+            .iter()
+            .map(|(&base, free)| (base..base + free.size, free.epoch))
+            .collect();
+        let region_attrs = self
+            .region_attrs
+            .iter()
+            .map(|(&base, &attrs)| (base, attrs))
+            .collect();
+        self.common.raw_parts(self.export_map(), region_attrs, free)
+    }
 
-                let aligned = round_up!(base, alignment);
-                let spill = aligned - base;
+    fn is_free(&self, base: A, size: A) -> bool {
+        self.tree
+            .range(..=base)
+            .next_back()
+            .is_some_and(|(&free_base, free)| base + size <= free_base + free.size)
+    }
 
-                if spill > node.size {
-                    // aligned base is outside of allocation
-                    return false;
-                }
+    fn carve(&mut self, base: A, size: A) -> Result<()> {
+        if !self.is_free(base, size) {
+            return Err(Error::new(ErrorKind::NotFree));
+        }
+        let (&free_base, &free) = self.tree.range(..=base).next_back().expect("range is free");
 
-                if min_size > node.size - spill {
-                    // not enough space in this allocation
-                    return false;
-                }
+        let before = base - free_base;
+        let after = free_base + free.size - (base + size);
+        if before > A::ZERO && after > A::ZERO {
+            self.room_for_free_extent()?;
+        }
 
-                true
-            });
+        if before > A::ZERO {
+            self.tree.insert(
+                free_base,
+                Free {
+                    size: before,
+                    ..free
+                },
+            );
+        } else {
+            self.tree.remove(&free_base);
+        }
+        if after > A::ZERO {
+            self.tree.insert(
+                base + size,
+                Free {
+                    size: after,
+                    ..free
+                },
+            );
+        }
+        self.common.take_space(base, size);
 
-        let Some((base, candidate)) = candidate else {
-            if any_can_fit {
-                return Err(Error::cause("has space but overconstrained"));
-            } else {
-                return Err(Error::cause("no space"));
-            }
-        };
+        Ok(())
+    }
 
-        let base = *base;
+    fn place_and_take(
+        &mut self,
+        min_size: A,
+        alignment: A,
+        window: Range<A>,
+    ) -> Result<(Tag, Range<A>)> {
+        let min_size = self.common.whole_size(min_size)?;
+        let request = Request::new(min_size, alignment).normalized(self.common.granularity)?;
+        let placement = self.place(request, self.common.policy, &window)?;
+        let granularity = self.common.granularity.get();
+
+        let base = placement.block.start;
         let free_start = base;
-        let after_free = base + candidate.size;
+        let after_free = placement.block.end;
 
-        let allocated_start = round_up!(free_start, alignment);
-        let after_allocated = round_up!(allocated_start + min_size, BASE_PAGE_SIZE);
+        let allocated_start = placement.base;
+        let after_allocated = placement.base + placement.size;
 
-        fn chunk_between(start: usize, end: usize) -> Option<(usize, usize)> {
-            if end - start >= BASE_PAGE_SIZE {
+        // remainders smaller than the granularity stay part of the allocation
+        fn chunk_between<A: Address>(start: A, end: A, granularity: A) -> Option<(A, A)> {
+            if end - start >= granularity {
                 Some((start, end))
             } else {
                 None
             }
         }
 
-        let free_chunk_before = chunk_between(free_start, allocated_start);
-        let free_chunk_after = chunk_between(after_allocated, after_free);
+        let free_chunk_before = chunk_between(free_start, allocated_start, granularity);
+        let free_chunk_after = chunk_between(after_allocated, after_free, granularity);
+        if free_chunk_before.is_some() && free_chunk_after.is_some() {
+            self.room_for_free_extent()?;
+        }
+        let candidate = *self
+            .tree
+            .get(&base)
+            .expect("placements are made in free blocks");
 
-        let tag = candidate.tag.clone();
-        let (addr, _size) = match (free_chunk_before, free_chunk_after) {
+        let (addr, size) = match (free_chunk_before, free_chunk_after) {
             (None, None) => {
-                self.free_space -= candidate.size;
                 self.tree.remove(&base);
                 (free_start, after_free - free_start)
             }
             (None, Some(after)) => {
-                // TODO: this case is way more common than (Some(before), None).
-                // We should consider allocating at the end of the range in order to
-                // trigger the cheap case more often
-                let entry = self
+                // TODO: this case is way more common than (Some(before), None), which only
+                // [`Direction::TopDown`] hits regularly. Allocating at the end of the range by
+                // default would trigger the cheap case more often
+                let free = self
                     .tree
                     .remove(&base)
                     .expect("base is definitely contained in map");
                 let new_size = after.1 - after.0;
-                self.free_space -= (entry.size - new_size);
                 self.tree.insert(
                     after.0,
-                    Entry {
-                        size: after.1 - after.0,
-                        ..entry
+                    Free {
+                        size: new_size,
+                        ..free
                     },
                 );
                 (free_start, after_allocated - free_start)
             }
             (Some(before), None) => {
-                candidate.size = before.1 - before.0;
+                let size = before.1 - before.0;
+                self.tree.insert(base, Free { size, ..candidate });
                 (allocated_start, after_free - allocated_start)
             }
             (Some(before), Some(after)) => {
                 let before_size = before.1 - before.0;
                 let after_size = after.1 - after.0;
-                let allocation_size = candidate.size - before_size - after_size;
-                candidate.size = before_size;
+                let before_free = Free {
+                    size: before_size,
+                    ..candidate
+                };
+                self.tree.insert(base, before_free);
 
-                let tag = candidate.tag.clone();
-                self.tree.insert(
-                    after.0,
-                    Entry {
-                        size: after_size,
-                        tag,
-                    },
-                );
+                let after_free = Free {
+                    size: after_size,
+                    ..candidate
+                };
+                self.tree.insert(after.0, after_free);
 
                 (allocated_start, after_allocated - allocated_start)
             }
         };
+        self.common.take_space(addr, size);
 
-        Ok((tag, addr))
-    }
+        let (_, region) = self
+            .region_of(addr)
+            .expect("free space is always inside a region");
+        let tag = region.tag.clone();
+        if let Some(allocations) = &mut self.common.allocations {
+            allocations.insert(addr..addr + size, tag.clone());
+        }
+        self.cursor = match self.common.direction {
+            Direction::BottomUp => addr + size,
+            Direction::TopDown => addr,
+        };
 
-    // /// allocates a range at the given base address. Fails if that address is already allocated.
-    // fn alloc_fixed(&mut self, base: usize, size: usize) -> Result<(Tag, usize)> {
-    //     Err(Error::unimplemented())
-    // }
+        // the guard is not part of what the caller gets to use
+        Ok((tag, addr..addr + size - self.common.guard))
+    }
 
-    /// frees a previously handed out range
-    fn free(&mut self, base: usize, size: usize) -> Result<()> {
+    fn give_back(&mut self, base: A, size: A, strict: bool) -> Result<A> {
         let source = self
             .regions
             .range(..=base)
             .next_back()
-            .ok_or_else(|| Error::cause("no associated allocation"))?;
+            .ok_or_else(|| Error::new(ErrorKind::NotOwned))?;
+        let mut size = Size::new(size)?.round_up(self.common.granularity)?.get();
+        // `alloc` hands out remainders too small to be allocated on their own as part of the
+        // allocation, which happens at a region end that is not a multiple of the granularity
+        let region_end = *source.0 + source.1.size;
+        let end = base.saturating_add(size);
+        // only the rounding may run past the end of the region
+        if end > region_end && end - region_end >= self.common.granularity.get() {
+            return Err(Error::new(ErrorKind::NotOwned));
+        }
+        if end >= region_end
+            || (region_end - end < self.common.granularity.get() && !self.tree.contains_key(&end))
+        {
+            size = region_end.checked_sub(base).unwrap_or(size);
+        }
 
-        let is_in_source = |base, size: usize| {
-            (*source.0..source.0 + source.1.size).contains(&base)
-                && (*source.0..=source.0 + source.1.size).contains(&(base + size))
+        let range = base..base.saturating_add(size);
+        let freed_before = self.tree.range(..range.end).next_back();
+        if freed_before.is_some_and(|(&free_base, free)| free_base + free.size > base) {
+            return Err(Error::new(ErrorKind::DoubleFree));
+        }
+        if let Some(allocations) = self.common.allocations.as_ref().filter(|_| strict) {
+            allocations.check_allocated(range)?;
+        }
+
+        let is_in_source = |base, size: A| {
+            (*source.0..*source.0 + source.1.size).contains(&base)
+                && (*source.0..=*source.0 + source.1.size).contains(&(base + size))
         };
 
         let (before, after) = self.before_and_after(base, size);
 
         let before = before.filter(|before| {
-            before.0 + before.1.size == base && is_in_source(*before.0, before.1.size)
+            *before.0 + before.1.size == base && is_in_source(*before.0, before.1.size)
         });
         let after =
             after.filter(|after| base + size == *after.0 && is_in_source(*after.0, after.1.size));
 
+        let epoch = self.common.epoch + 1;
+
         match (before, after) {
             (None, None) => {
-                self.tree.insert(
-                    base,
-                    Entry {
-                        size,
-                        tag: source.1.tag.clone(),
-                    },
-                );
+                self.room_for_free_extent()?;
+                self.tree.insert(base, Free { size, epoch });
             }
-            (None, Some((&after_base, after))) => {
+            (None, Some((&after_base, _))) => {
                 let after = self
                     .tree
                     .remove(&after_base)
                     .expect("after is definitely in map");
                 self.tree.insert(
                     base,
-                    Entry {
+                    Free {
                         size: size + after.size,
-                        tag: after.tag,
+                        epoch,
                     },
                 );
             }
-            (Some((&before_base, before)), None) => {
-                let before = self
-                    .tree
-                    .get_mut(&before_base)
-                    .expect("before is definitely in map");
-                before.size += size;
+            (Some((&before_base, &before)), None) => {
+                let size = before.size + size;
+                self.tree.insert(before_base, Free { size, epoch });
             }
-            (Some((&before_base, before)), Some((&after_base, after))) => {
+            (Some((&before_base, &before)), Some((&after_base, _))) => {
                 let after = self
                     .tree
                     .remove(&after_base)
                     .expect("after is definitely in map");
-                let before = self
-                    .tree
-                    .get_mut(&before_base)
-                    .expect("before is definitely in map");
-                before.size += after.size + size;
+                let size = before.size + after.size + size;
+                self.tree.insert(before_base, Free { size, epoch });
             }
         }
-        self.free_space += size;
+        self.common.epoch = epoch;
+        self.common.give_space(base, size);
+        if let Some(allocations) = &mut self.common.allocations {
+            allocations.remove(base..base + size);
+        }
 
-        Ok(())
+        Ok(size)
     }
+}
 
-    fn total_space(&self) -> usize {
-        self.total_space
+impl<Tag, A: Address, const B: usize> Default for RangeAllocator<Tag, A, B> {
+    fn default() -> Self {
+        Self::empty_in(Global)
     }
+}
 
-    fn space(&self) -> usize {
-        self.free_space
+impl<Tag, A: Address, const B: usize, M: Allocator + Clone> RangeAllocator<Tag, A, B, M> {
+    /// the regions and the free extents for printing
+    fn shown(
+        &self,
+    ) -> (
+        impl Iterator<Item = MapEntry<&Tag, A>>,
+        impl Iterator<Item = Range<A>>,
+    ) {
+        let regions = self
+            .regions
+            .iter()
+            .map(|(&base, region)| region.at(base, RegionKind::Usable))
+            .chain(
+                self.reserved_regions
+                    .iter()
+                    .map(|(&base, region)| region.at(base, RegionKind::Reserved)),
+            );
+        let free = self.tree.iter().map(|(&base, free)| base..base + free.size);
+        (regions, free)
     }
 }
 
-impl<Tag: Default> Default for RangeAllocator<Tag> {
-    fn default() -> Self {
-        Self::new()
+/// the regions and free extents as `base..end (size)` lines
+impl<Tag: fmt::Debug, A: Address, const B: usize, M: Allocator + Clone> fmt::Debug
+    for RangeAllocator<Tag, A, B, M>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (regions, free) = self.shown();
+        map::debug_map(f, "btree::RangeAllocator", regions, free)
+    }
+}
+
+/// the memory map, a line per region
+impl<Tag: fmt::Debug, A: Address, const B: usize, M: Allocator + Clone> fmt::Display
+    for RangeAllocator<Tag, A, B, M>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (regions, free) = self.shown();
+        map::display_map(f, regions, free)
+    }
+}
+
+/// moves everything over, including the allocations and, if enabled, their tracking. E.g. to boot
+/// with the linear backend and switch once a heap is available
+impl<Tag: Default + Clone + fmt::Debug, A: Address> From<linear::RangeAllocator<Tag, A>>
+    for RangeAllocator<Tag, A>
+{
+    fn from(a: linear::RangeAllocator<Tag, A>) -> Self {
+        Self::from_raw_parts(a.into_raw_parts()).expect("the parts of an allocator are consistent")
+    }
+}
+
+/// the usable regions of a memory map, see [`add_regions`](RangeAllocator::add_regions). Panics
+/// if they are not valid, use `add_regions` to handle that
+impl<
+    Tag: Default + Clone + fmt::Debug + PartialEq,
+    A: Address,
+    const B: usize,
+    M: Allocator + Clone,
+> Extend<(A, A, Tag)> for RangeAllocator<Tag, A, B, M>
+{
+    fn extend<I: IntoIterator<Item = (A, A, Tag)>>(&mut self, entries: I) {
+        self.add_regions(entries)
+            .expect("regions are not empty and do not overlap");
+    }
+}
+
+/// an allocator with the usable regions of a memory map, see [`Extend`]
+impl<Tag: Default + Clone + fmt::Debug + PartialEq, A: Address, const B: usize>
+    FromIterator<(A, A, Tag)> for RangeAllocator<Tag, A, B>
+{
+    fn from_iter<I: IntoIterator<Item = (A, A, Tag)>>(entries: I) -> Self {
+        let mut a = Self::default();
+        a.extend(entries);
+        a
+    }
+}
+
+/// as its [`RawParts`]. Deserializing checks the parts like
+/// [`from_raw_parts`](RangeAllocator::from_raw_parts)
+#[cfg(feature = "serde")]
+impl<
+    Tag: Default + Clone + fmt::Debug + serde::Serialize,
+    A: Address + serde::Serialize,
+    const B: usize,
+    M: Allocator + Clone,
+> serde::Serialize for RangeAllocator<Tag, A, B, M>
+{
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> core::result::Result<S::Ok, S::Error> {
+        self.raw_parts().serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<
+    'de,
+    Tag: Default + Clone + fmt::Debug + serde::Deserialize<'de>,
+    A: Address + serde::Deserialize<'de>,
+    const B: usize,
+> serde::Deserialize<'de> for RangeAllocator<Tag, A, B>
+{
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> core::result::Result<Self, D::Error> {
+        RangeAllocator::from_raw_parts(RawParts::deserialize(deserializer)?)
+            .map(RangeAllocator::with_fanout)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_are_not_stored_per_free_extent() {
+        fn extent_size<K, V, const B: usize, M: Allocator + Clone>(_: &BMap<K, V, B, M>) -> usize {
+            size_of::<V>()
+        }
+
+        let mut a: RangeAllocator<[u64; 8]> = RangeAllocator::new();
+        a.add_range(0x10000, 16 * BASE_PAGE_SIZE, [1; 8]).unwrap();
+        let (tag, x) = a.alloc(BASE_PAGE_SIZE, BASE_PAGE_SIZE * 2).unwrap();
+        assert_eq!(tag, [1; 8]);
+        a.free(x, BASE_PAGE_SIZE).unwrap();
+
+        // however large the tag, a free extent is as large as one of an allocator without tags
+        let untagged: RangeAllocator<()> = RangeAllocator::new();
+        assert_eq!(extent_size(&a.tree), extent_size(&untagged.tree));
+        assert_eq!(a.tree.len(), 1);
+    }
+
+    #[test]
+    fn tag_comes_from_region() {
+        let mut a: RangeAllocator<u32> = RangeAllocator::new();
+        a.add_range(0x10000, 4 * BASE_PAGE_SIZE, 1).unwrap();
+        a.add_range(0x20000, 4 * BASE_PAGE_SIZE, 2).unwrap();
+
+        let (tag, x) = a.alloc(4 * BASE_PAGE_SIZE, BASE_PAGE_SIZE).unwrap();
+        assert_eq!((tag, x), (1, 0x10000));
+        let (tag, x) = a.alloc(BASE_PAGE_SIZE, BASE_PAGE_SIZE).unwrap();
+        assert_eq!((tag, x), (2, 0x20000));
     }
 }
@@ -0,0 +1,113 @@
+//! configuring an allocator in one place
+//!
+//! [`RangeAllocatorBuilder`] collects the settings that otherwise have to be applied one by one to
+//! a backend, and builds either backend from them: as its concrete type, or boxed behind
+//! [`RangeAlloc`] when the backend is only chosen at runtime, e.g. from a config file.
+
+use alloc::boxed::Box;
+use core::fmt;
+
+use crate::{Direction, Policy, RangeAlloc, address::Address, btree, linear, units::Alignment};
+
+/// the backend a [`RangeAllocatorBuilder`] builds
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// [`linear::RangeAllocator`], a list of free blocks
+    #[default]
+    Linear,
+    /// [`btree::RangeAllocator`], free blocks in a map ordered by address
+    Btree,
+}
+
+/// the settings of an allocator that has not been built yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RangeAllocatorBuilder<A = usize> {
+    backend: Backend,
+    policy: Policy,
+    direction: Direction,
+    granularity: Alignment<A>,
+    tracking: bool,
+}
+
+impl<A: Address> Default for RangeAllocatorBuilder<A> {
+    fn default() -> Self {
+        RangeAllocatorBuilder {
+            backend: Backend::default(),
+            policy: Policy::default(),
+            direction: Direction::default(),
+            granularity: Alignment::BASE_PAGE,
+            tracking: false,
+        }
+    }
+}
+
+impl RangeAllocatorBuilder {
+    /// the defaults of the backends: first-fit, bottom-up, page granular and without tracking.
+    /// Use [`default`](Self::default) for other address types
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<A: Address> RangeAllocatorBuilder<A> {
+    /// only used by [`build`](Self::build), the other build methods pick their backend themselves
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// allocations are rounded to multiples of `granularity`, see
+    /// [`linear::RangeAllocator::with_granularity`]
+    pub fn granularity(mut self, granularity: Alignment<A>) -> Self {
+        self.granularity = granularity;
+        self
+    }
+
+    /// sizes are used exactly as requested, see [`linear::RangeAllocator::exact`]
+    pub fn exact(self) -> Self {
+        self.granularity(Alignment::ONE)
+    }
+
+    /// whether the allocator starts out tracking its allocations
+    pub fn tracking(mut self, enabled: bool) -> Self {
+        self.tracking = enabled;
+        self
+    }
+
+    pub fn build_linear<Tag: Clone>(&self) -> linear::RangeAllocator<Tag, A> {
+        let mut a = linear::RangeAllocator::with_granularity(self.granularity);
+        a.set_policy(self.policy);
+        a.set_direction(self.direction);
+        a.set_tracking(self.tracking);
+        a
+    }
+
+    pub fn build_btree<Tag: Default + Clone + fmt::Debug>(&self) -> btree::RangeAllocator<Tag, A> {
+        let mut a = btree::RangeAllocator::with_granularity(self.granularity);
+        a.set_policy(self.policy);
+        a.set_direction(self.direction);
+        a.set_tracking(self.tracking);
+        a
+    }
+
+    /// the configured [`backend`](Self::backend)
+    pub fn build<Tag>(&self) -> Box<dyn RangeAlloc<A, Tag = Tag>>
+    where
+        Tag: Default + Clone + fmt::Debug + 'static,
+    {
+        match self.backend {
+            Backend::Linear => Box::new(self.build_linear()),
+            Backend::Btree => Box::new(self.build_btree()),
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! comparing coalescing strategies on a workload
+//!
+//! the backends merge a freed range with its free neighbours right away. [`compare`] replays a
+//! [`Trace`] once with that eager coalescing and once for each strategy that holds frees back,
+//! either until an allocation cannot be satisfied without them or until a batch has piled up, and
+//! reports the throughput and the fragmentation each one leaves behind. Run it on your own traces
+//! to see whether delaying frees pays off for your workload.
+
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use std::time::Instant;
+
+use crate::{
+    RangeAlloc, Result,
+    adaptive::PolicyControl,
+    trace::{Trace, TraceOp},
+};
+
+/// when freed ranges are given back to the backend, and so merged with their neighbours
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coalescing {
+    /// every free is passed on immediately
+    Eager,
+    /// frees are held back until an allocation fails, then all of them are passed on and the
+    /// allocation is retried
+    Deferred,
+    /// frees are passed on once this many have been held back, or when an allocation fails
+    Batched(usize),
+}
+
+impl Coalescing {
+    pub fn name(&self) -> String {
+        match self {
+            Coalescing::Eager => "eager".into(),
+            Coalescing::Deferred => "deferred".into(),
+            Coalescing::Batched(n) => format!("batched-{n}"),
+        }
+    }
+}
+
+/// the outcome of replaying a trace under one strategy
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub strategy: Coalescing,
+    /// number of allocations, frees and added regions
+    pub ops: u64,
+    pub elapsed_ns: u64,
+    /// [`fragmentation_score`](crate::metrics::fragmentation_score) at the end of the trace,
+    /// before the frees still held back are passed on
+    pub fragmentation: f64,
+    /// allocations that failed even after passing on all held back frees
+    pub failed: u64,
+    /// number of times held back frees were passed on
+    pub flushes: u64,
+}
+
+impl Report {
+    pub fn ops_per_sec(&self) -> f64 {
+        if self.elapsed_ns == 0 {
+            return 0.0;
+        }
+        self.ops as f64 * 1e9 / self.elapsed_ns as f64
+    }
+}
+
+/// replays `trace` under each of `strategies`, every time on a fresh backend from `new`
+pub fn compare<R>(
+    trace: &Trace,
+    strategies: &[Coalescing],
+    mut new: impl FnMut() -> R,
+) -> Vec<Report>
+where
+    R: RangeAlloc<u64, Tag = u64> + PolicyControl,
+{
+    strategies
+        .iter()
+        .map(|&strategy| run(trace, strategy, new()))
+        .collect()
+}
+
+/// replays `trace` against `a` under `strategy`. `fail` markers and `expect` lines are ignored,
+/// where an allocation ends up depends on the strategy
+pub fn run<R>(trace: &Trace, strategy: Coalescing, a: R) -> Report
+where
+    R: RangeAlloc<u64, Tag = u64> + PolicyControl,
+{
+    let mut replay = Replay {
+        a,
+        strategy,
+        pending: Vec::new(),
+        flushes: 0,
+    };
+    let mut allocations = BTreeMap::new();
+    let (mut ops, mut failed) = (0, 0);
+
+    let start = Instant::now();
+    for op in &trace.ops {
+        let allocated = match *op {
+            TraceOp::Add { region, base, size } => {
+                // a region that cannot be added makes the trace invalid for every strategy alike
+                let _ = replay.a.add_range(base, size, region);
+                None
+            }
+            TraceOp::Alloc {
+                id,
+                size,
+                alignment,
+                ..
+            } => Some((id, size, replay.retry(|a| a.alloc(size, alignment)))),
+            TraceOp::AllocFixed { id, base, size, .. } => {
+                Some((id, size, replay.retry(|a| a.alloc_fixed(base, size))))
+            }
+            TraceOp::Free { id } => {
+                if let Some(allocation) = allocations.remove(&id) {
+                    replay.free(allocation);
+                }
+                None
+            }
+            TraceOp::Expect { .. } => continue,
+        };
+        ops += 1;
+        match allocated {
+            Some((id, size, Ok((_, base)))) => {
+                allocations.insert(id, (base, size));
+            }
+            Some((_, _, Err(_))) => failed += 1,
+            None => {}
+        }
+    }
+    let elapsed_ns = start.elapsed().as_nanos() as u64;
+
+    Report {
+        strategy,
+        ops,
+        elapsed_ns,
+        fragmentation: replay.a.fragmentation(),
+        failed,
+        flushes: replay.flushes,
+    }
+}
+
+struct Replay<R> {
+    a: R,
+    strategy: Coalescing,
+    /// frees held back, as `(base, size)`
+    pending: Vec<(u64, u64)>,
+    flushes: u64,
+}
+
+impl<R: RangeAlloc<u64>> Replay<R> {
+    fn free(&mut self, allocation: (u64, u64)) {
+        self.pending.push(allocation);
+        match self.strategy {
+            Coalescing::Eager => self.flush(),
+            Coalescing::Batched(n) if self.pending.len() >= n => self.flush(),
+            Coalescing::Batched(_) | Coalescing::Deferred => {}
+        }
+    }
+
+    /// passes on the held back frees in address order, so neighbours merge in a single pass
+    fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        self.pending.sort_unstable();
+        for (base, size) in self.pending.drain(..) {
+            self.a
+                .free(base, size)
+                .expect("the trace frees each allocation once");
+        }
+        if self.strategy != Coalescing::Eager {
+            self.flushes += 1;
+        }
+    }
+
+    fn retry<T>(&mut self, mut alloc: impl FnMut(&mut R) -> Result<T>) -> Result<T> {
+        alloc(&mut self.a).or_else(|e| {
+            if self.pending.is_empty() {
+                return Err(e);
+            }
+            self.flush();
+            alloc(&mut self.a)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Coalescing, compare};
+    use crate::{btree, trace::Trace};
+
+    #[test]
+    fn strategies() {
+        // every page is freed and allocated again, which only fits once the frees are passed on
+        let trace: Trace = "add 1 0x1000 0x4000
+            alloc 1 0x1000 0x1000
+            alloc 2 0x1000 0x1000
+            alloc 3 0x2000 0x1000
+            free 1
+            free 2
+            free 3
+            alloc 4 0x4000 0x1000
+            free 4
+            alloc 5 0x5000 0x1000"
+            .parse()
+            .expect("trace is valid");
+        let strategies = [
+            Coalescing::Eager,
+            Coalescing::Deferred,
+            Coalescing::Batched(2),
+        ];
+
+        let reports = compare(
+            &trace,
+            &strategies,
+            btree::RangeAllocator::<u64, u64>::default,
+        );
+        let summary: alloc::vec::Vec<_> = reports
+            .iter()
+            .map(|r| (r.strategy, r.ops, r.failed, r.flushes))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (Coalescing::Eager, 10, 1, 0),
+                (Coalescing::Deferred, 10, 1, 2),
+                (Coalescing::Batched(2), 10, 1, 3),
+            ]
+        );
+        assert!(reports.iter().all(|r| r.fragmentation == 0.0));
+        assert_eq!(Coalescing::Batched(16).name(), "batched-16");
+    }
+}
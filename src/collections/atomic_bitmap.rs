@@ -0,0 +1,259 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::ops::Range;
+
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(not(loom))]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const BITS: usize = usize::BITS as usize;
+
+/// the bits of `range` that fall into word `word`
+fn word_mask(word: usize, range: &Range<usize>) -> usize {
+    let word_start = word * BITS;
+    let start = range.start.max(word_start) - word_start;
+    let end = range.end.min(word_start + BITS) - word_start;
+    let ones = |n: usize| if n == BITS { usize::MAX } else { (1 << n) - 1 };
+    ones(end) & !ones(start)
+}
+
+/// a fixed-size bitmap whose bits can be set and cleared concurrently through a shared reference
+///
+/// a set bit means the granule it stands for is in use. All read-modify-write operations work a
+/// word at a time, so claiming a free bit is a single compare-and-swap in the common case.
+/// Operations spanning several words are atomic per word only, see [`set_range`](Self::set_range).
+#[derive(Debug)]
+pub struct AtomicBitmap {
+    words: Box<[AtomicUsize]>,
+    len: usize,
+}
+
+impl AtomicBitmap {
+    /// a bitmap of `len` clear bits
+    pub fn new(len: usize) -> Self {
+        let words: Vec<_> = (0..len.div_ceil(BITS))
+            .map(|_| AtomicUsize::new(0))
+            .collect();
+        AtomicBitmap {
+            words: words.into_boxed_slice(),
+            len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn check(&self, range: &Range<usize>) {
+        assert!(
+            range.start <= range.end && range.end <= self.len,
+            "range {range:?} out of bounds for a bitmap of {} bits",
+            self.len
+        );
+    }
+
+    /// the words overlapping `range`
+    fn words(range: &Range<usize>) -> Range<usize> {
+        range.start / BITS..range.end.div_ceil(BITS)
+    }
+
+    pub fn get(&self, bit: usize) -> bool {
+        self.check(&(bit..bit + 1));
+        self.words[bit / BITS].load(Ordering::Acquire) & (1 << (bit % BITS)) != 0
+    }
+
+    /// number of set bits. Only a snapshot if the bitmap is modified concurrently
+    pub fn count_ones(&self) -> usize {
+        self.words
+            .iter()
+            .map(|w| w.load(Ordering::Relaxed).count_ones() as usize)
+            .sum()
+    }
+
+    /// the first clear bit at or after `from`, without claiming it
+    pub fn find_first_zero(&self, from: usize) -> Option<usize> {
+        let range = from.min(self.len)..self.len;
+        Self::words(&range).find_map(|word| {
+            let free = !self.words[word].load(Ordering::Acquire) & word_mask(word, &range);
+            (free != 0).then(|| word * BITS + free.trailing_zeros() as usize)
+        })
+    }
+
+    /// finds and sets the first clear bit at or after `from`. This is the lock-free path for
+    /// single-granule allocations: two threads never get the same bit
+    pub fn set_first_zero(&self, from: usize) -> Option<usize> {
+        let range = from.min(self.len)..self.len;
+        for word in Self::words(&range) {
+            let mask = word_mask(word, &range);
+            let mut current = self.words[word].load(Ordering::Relaxed);
+            loop {
+                let free = !current & mask;
+                if free == 0 {
+                    break;
+                }
+                let bit = free & free.wrapping_neg();
+                match self.words[word].compare_exchange_weak(
+                    current,
+                    current | bit,
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return Some(word * BITS + bit.trailing_zeros() as usize),
+                    Err(actual) => current = actual,
+                }
+            }
+        }
+        None
+    }
+
+    /// sets every bit of `range` if all of them are clear, returning whether it did.
+    ///
+    /// a range within one word is claimed atomically. Across words, the words are claimed in
+    /// ascending order and released again if a later one conflicts, so a concurrent observer can
+    /// see a partially claimed range, but two overlapping `set_range` calls never both succeed
+    pub fn set_range(&self, range: Range<usize>) -> bool {
+        self.check(&range);
+        let words = Self::words(&range);
+        for word in words.clone() {
+            let mask = word_mask(word, &range);
+            let claimed = self.words[word].fetch_update(Ordering::AcqRel, Ordering::Relaxed, |w| {
+                (w & mask == 0).then_some(w | mask)
+            });
+            if claimed.is_err() {
+                for claimed in words.start..word {
+                    self.words[claimed].fetch_and(!word_mask(claimed, &range), Ordering::Release);
+                }
+                return false;
+            }
+        }
+        true
+    }
+
+    /// clears every bit of `range`, returning whether all of them were set before
+    pub fn clear_range(&self, range: Range<usize>) -> bool {
+        self.check(&range);
+        Self::words(&range).fold(true, |all_set, word| {
+            let mask = word_mask(word, &range);
+            let previous = self.words[word].fetch_and(!mask, Ordering::Release);
+            all_set && previous & mask == mask
+        })
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use alloc::{format, vec::Vec};
+    use std::{sync::Arc, thread};
+
+    use proptest::prelude::*;
+
+    use super::AtomicBitmap;
+
+    #[test]
+    fn ranges_across_words() {
+        let bitmap = AtomicBitmap::new(200);
+        assert!(bitmap.set_range(60..130));
+        assert_eq!(bitmap.count_ones(), 70);
+        assert!(!bitmap.set_range(0..61));
+        assert!(!bitmap.set_range(129..140));
+        assert_eq!(bitmap.count_ones(), 70, "failed claims are rolled back");
+
+        assert_eq!(bitmap.find_first_zero(60), Some(130));
+        assert_eq!(bitmap.set_first_zero(60), Some(130));
+        assert!(bitmap.get(130));
+
+        assert!(bitmap.clear_range(60..131));
+        assert!(!bitmap.clear_range(60..61));
+        assert_eq!(bitmap.count_ones(), 0);
+
+        assert!(bitmap.set_range(0..200));
+        assert_eq!(bitmap.set_first_zero(0), None);
+        assert_eq!(bitmap.find_first_zero(0), None);
+    }
+
+    #[test]
+    fn concurrent_single_bits() {
+        let bitmap = Arc::new(AtomicBitmap::new(256));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let bitmap = bitmap.clone();
+                thread::spawn(move || {
+                    (0..64)
+                        .map(|_| bitmap.set_first_zero(0).expect("has free bits"))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut bits: Vec<_> = threads
+            .into_iter()
+            .flat_map(|t| t.join().expect("thread does not panic"))
+            .collect();
+        bits.sort();
+        assert_eq!(bits, (0..256).collect::<Vec<_>>());
+        assert_eq!(bitmap.set_first_zero(0), None);
+    }
+
+    proptest! {
+        #[test]
+        fn matches_model(ops in proptest::collection::vec((any::<bool>(), 0..150usize, 0..150usize), 0..50)) {
+            let bitmap = AtomicBitmap::new(150);
+            let mut model = [false; 150];
+            for (set, a, b) in ops {
+                let range = a.min(b)..a.max(b);
+                let bits = &mut model[range.clone()];
+                if set {
+                    let free = bits.iter().all(|&b| !b);
+                    prop_assert_eq!(bitmap.set_range(range), free);
+                    if free {
+                        bits.fill(true);
+                    }
+                } else {
+                    prop_assert_eq!(bitmap.clear_range(range), bits.iter().all(|&b| b));
+                    bits.fill(false);
+                }
+                prop_assert_eq!(bitmap.count_ones(), model.iter().filter(|&&b| b).count());
+                prop_assert_eq!(bitmap.find_first_zero(a), (a..150).find(|&i| !model[i]));
+            }
+        }
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::{sync::Arc, thread};
+
+    use super::AtomicBitmap;
+
+    #[test]
+    fn set_first_zero_is_exclusive() {
+        loom::model(|| {
+            let bitmap = Arc::new(AtomicBitmap::new(2));
+            let other = bitmap.clone();
+            let t = thread::spawn(move || other.set_first_zero(0));
+            let mine = bitmap.set_first_zero(0);
+            let theirs = t.join().unwrap();
+            assert_ne!(mine, theirs);
+            assert!(mine.is_some() && theirs.is_some());
+        });
+    }
+
+    #[test]
+    fn overlapping_set_range() {
+        loom::model(|| {
+            let bitmap = Arc::new(AtomicBitmap::new(128));
+            let other = bitmap.clone();
+            let t = thread::spawn(move || other.set_range(60..70));
+            let mine = bitmap.set_range(66..100);
+            let theirs = t.join().unwrap();
+            assert!(!(mine && theirs), "overlapping claims both succeeded");
+            if !mine && !theirs {
+                assert_eq!(bitmap.count_ones(), 0);
+            }
+        });
+    }
+}
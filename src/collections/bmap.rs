@@ -0,0 +1,679 @@
+use core::{
+    fmt, mem,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::allocator::{Allocator, Global, Vec, vec_in};
+
+/// what [`BMap`] keeps the maximum of for every subtree, e.g. the size of a free block, so that
+/// [`range_at_least`](BMap::range_at_least) can skip subtrees without a large enough value
+pub trait Measured {
+    fn measure(&self) -> u64;
+}
+
+macro_rules! measure_themselves {
+    ($($t:ty),*) => {$(
+        /// plain numbers are their own measure
+        impl Measured for $t {
+            fn measure(&self) -> u64 {
+                *self as u64
+            }
+        }
+    )*};
+}
+
+measure_themselves!(u8, u16, u32, u64, usize);
+
+/// an ordered map like `BTreeMap`, with the number of entries per node as the const generic `B`
+///
+/// nodes hold at most `B` entries and, except for the root, at least `B / 2`. Small nodes keep
+/// lookups to a few comparisons per level, large ones make the tree shallower and iteration more
+/// cache friendly, so the best `B` depends on the workload. Iterating looks each entry up from the
+/// root, which keeps the iterators free of allocations.
+///
+/// every node also knows the largest [measure](Measured) in its subtree. That is what `BTreeMap`
+/// can not offer: finding the next entry whose value is large enough takes `O(log n)` steps
+/// instead of a scan over all the smaller ones. Values are therefore only changed through
+/// [`insert`](Self::insert), there is no `get_mut`.
+///
+/// the nodes are allocated in `M`, see the [`allocator`](crate::allocator) module.
+#[derive(Clone)]
+pub struct BMap<K, V, const B: usize = 6, M: Allocator + Clone = Global> {
+    root: Option<Node<K, V, M>>,
+    len: usize,
+    alloc: M,
+}
+
+#[derive(Clone)]
+struct Node<K, V, M: Allocator + Clone> {
+    entries: Vec<(K, V), M>,
+    /// one more than `entries` for inner nodes, none for leaves
+    children: Vec<Node<K, V, M>, M>,
+    /// the largest measure of the entries in this subtree
+    max: u64,
+}
+
+impl<K, V: Measured, M: Allocator + Clone> Node<K, V, M> {
+    fn leaf(entry: (K, V), alloc: &M) -> Self {
+        let mut entries = vec_in::<(K, V), M>(alloc.clone());
+        entries.push(entry);
+        Node {
+            max: entries[0].1.measure(),
+            entries,
+            children: vec_in::<Self, M>(alloc.clone()),
+        }
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    /// recomputes `max` after the entries or children changed
+    fn update(&mut self) {
+        let entries = self.entries.iter().map(|(_, value)| value.measure());
+        let children = self.children.iter().map(|child| child.max);
+        // the root becomes empty for a moment when its last entry is removed
+        if let Some(max) = entries.chain(children).max() {
+            self.max = max;
+        }
+    }
+
+    /// whether the subtree may hold an entry with a measure of at least `min`
+    fn reaches(&self, min: Option<u64>) -> bool {
+        min.is_none_or(|min| self.max >= min)
+    }
+}
+
+/// whether `value` measures at least `min`
+fn fits<V: Measured>(value: &V, min: Option<u64>) -> bool {
+    min.is_none_or(|min| value.measure() >= min)
+}
+
+/// the median of a node that overflowed and the node split off after it
+type Split<K, V, M> = ((K, V), Node<K, V, M>);
+
+impl<K: Ord, V: Measured, M: Allocator + Clone> Node<K, V, M> {
+    /// inserts `entry`, returning the old value of its key, or how the node was split if it
+    /// overflowed
+    fn insert(&mut self, entry: (K, V), max: usize) -> Result<Option<V>, Split<K, V, M>> {
+        let old = match self.entries.binary_search_by(|(key, _)| key.cmp(&entry.0)) {
+            Ok(i) => Some(mem::replace(&mut self.entries[i].1, entry.1)),
+            Err(i) if self.is_leaf() => {
+                self.entries.insert(i, entry);
+                None
+            }
+            Err(i) => match self.children[i].insert(entry, max) {
+                Ok(old) => old,
+                Err((median, right)) => {
+                    self.entries.insert(i, median);
+                    self.children.insert(i + 1, right);
+                    None
+                }
+            },
+        };
+        if self.entries.len() <= max {
+            self.update();
+            return Ok(old);
+        }
+
+        let mid = self.entries.len() / 2;
+        let entries = self.entries.split_off(mid + 1);
+        let median = self.entries.pop().expect("the node overflowed");
+        // a leaf splits off its empty children, to get them in the same allocator
+        let children = self.children.split_off(self.children.len().min(mid + 1));
+        let mut right = Node {
+            entries,
+            children,
+            max: self.max,
+        };
+        self.update();
+        right.update();
+        Err((median, right))
+    }
+
+    /// removes the entry of `key`, leaving this node with possibly too few entries
+    fn remove(&mut self, key: &K, min: usize) -> Option<(K, V)> {
+        let removed = match self.entries.binary_search_by(|(k, _)| k.cmp(key)) {
+            Ok(i) if self.is_leaf() => self.entries.remove(i),
+            Ok(i) => {
+                // the predecessor takes the place of the removed entry
+                let predecessor = self.children[i].remove_last(min);
+                let removed = mem::replace(&mut self.entries[i], predecessor);
+                self.rebalance(i, min);
+                removed
+            }
+            Err(_) if self.is_leaf() => return None,
+            Err(i) => {
+                let removed = self.children[i].remove(key, min)?;
+                self.rebalance(i, min);
+                removed
+            }
+        };
+        self.update();
+        Some(removed)
+    }
+
+    fn remove_last(&mut self, min: usize) -> (K, V) {
+        let removed = if self.is_leaf() {
+            self.entries.pop().expect("only the root can be empty")
+        } else {
+            let last = self.children.len() - 1;
+            let removed = self.children[last].remove_last(min);
+            self.rebalance(last, min);
+            removed
+        };
+        self.update();
+        removed
+    }
+
+    /// gives child `i` at least `min` entries again, by taking one from a sibling or merging it
+    /// with one
+    fn rebalance(&mut self, i: usize, min: usize) {
+        if self.children[i].entries.len() >= min {
+            return;
+        }
+        if i > 0 && self.children[i - 1].entries.len() > min {
+            let (left, right) = self.children.split_at_mut(i);
+            let (left, child) = (&mut left[i - 1], &mut right[0]);
+            let entry = left.entries.pop().expect("the sibling has spare entries");
+            let separator = mem::replace(&mut self.entries[i - 1], entry);
+            child.entries.insert(0, separator);
+            if let Some(grandchild) = left.children.pop() {
+                child.children.insert(0, grandchild);
+            }
+            left.update();
+            child.update();
+        } else if i + 1 < self.children.len() && self.children[i + 1].entries.len() > min {
+            let (left, right) = self.children.split_at_mut(i + 1);
+            let (child, right) = (&mut left[i], &mut right[0]);
+            let entry = right.entries.remove(0);
+            let separator = mem::replace(&mut self.entries[i], entry);
+            child.entries.push(separator);
+            if !right.is_leaf() {
+                child.children.push(right.children.remove(0));
+            }
+            child.update();
+            right.update();
+        } else {
+            let i = i.min(self.children.len() - 2);
+            let right = self.children.remove(i + 1);
+            let separator = self.entries.remove(i);
+            let left = &mut self.children[i];
+            left.entries.push(separator);
+            left.entries.extend(right.entries);
+            left.children.extend(right.children);
+            left.update();
+        }
+    }
+
+    /// the first entry at or after `lower` that measures at least `min`
+    fn first_from(&self, lower: Bound<&K>, min: Option<u64>) -> Option<&(K, V)> {
+        if !self.reaches(min) {
+            return None;
+        }
+        let i = self.entries.partition_point(|(key, _)| below(key, lower));
+        (i..=self.entries.len()).find_map(|j| {
+            self.children
+                .get(j)
+                .and_then(|child| child.first_from(lower, min))
+                .or_else(|| self.entries.get(j).filter(|(_, value)| fits(value, min)))
+        })
+    }
+
+    /// the last entry at or before `upper` that measures at least `min`
+    fn last_before(&self, upper: Bound<&K>, min: Option<u64>) -> Option<&(K, V)> {
+        if !self.reaches(min) {
+            return None;
+        }
+        let i = self.entries.partition_point(|(key, _)| !above(key, upper));
+        (0..=i).rev().find_map(|j| {
+            self.children
+                .get(j)
+                .and_then(|child| child.last_before(upper, min))
+                .or_else(|| {
+                    let entry = j.checked_sub(1).map(|j| &self.entries[j]);
+                    entry.filter(|(_, value)| fits(value, min))
+                })
+        })
+    }
+
+    /// an entry of this subtree with the largest measure
+    fn max_entry(&self) -> &(K, V) {
+        match self
+            .entries
+            .iter()
+            .find(|(_, value)| value.measure() == self.max)
+        {
+            Some(entry) => entry,
+            None => self
+                .children
+                .iter()
+                .find(|child| child.max == self.max)
+                .expect("the maximum is in a child")
+                .max_entry(),
+        }
+    }
+
+    /// an entry between `lower` and `upper` with the largest measure. Only the subtrees on the
+    /// paths to the two bounds are searched, the ones in between count with their `max`
+    fn max_in(&self, lower: Bound<&K>, upper: Bound<&K>) -> Option<&(K, V)> {
+        if let (Bound::Unbounded, Bound::Unbounded) = (lower, upper) {
+            return Some(self.max_entry());
+        }
+        let i = self.entries.partition_point(|(key, _)| below(key, lower));
+        let j = self.entries.partition_point(|(key, _)| !above(key, upper));
+        if i > j {
+            return None;
+        }
+        let measure = |(_, value): &&(K, V)| value.measure();
+        let entries = self.entries[i..j].iter();
+        if self.is_leaf() {
+            return entries.max_by_key(measure);
+        }
+        let edges = if i == j {
+            [self.children[i].max_in(lower, upper), None]
+        } else {
+            [
+                self.children[i].max_in(lower, Bound::Unbounded),
+                self.children[j].max_in(Bound::Unbounded, upper),
+            ]
+        };
+        let inner = self.children[i + 1..j.max(i + 1)]
+            .iter()
+            .max_by_key(|child| child.max)
+            .map(Node::max_entry);
+        entries
+            .chain(inner)
+            .chain(edges.into_iter().flatten())
+            .max_by_key(measure)
+    }
+}
+
+/// whether `key` lies before the lower bound `lower`
+fn below<K: Ord>(key: &K, lower: Bound<&K>) -> bool {
+    match lower {
+        Bound::Included(lower) => key < lower,
+        Bound::Excluded(lower) => key <= lower,
+        Bound::Unbounded => false,
+    }
+}
+
+/// whether `key` lies after the upper bound `upper`
+fn above<K: Ord>(key: &K, upper: Bound<&K>) -> bool {
+    match upper {
+        Bound::Included(upper) => key > upper,
+        Bound::Excluded(upper) => key >= upper,
+        Bound::Unbounded => false,
+    }
+}
+
+impl<K, V, const B: usize> BMap<K, V, B> {
+    pub const fn new() -> Self {
+        Self::new_in(Global)
+    }
+}
+
+impl<K, V, const B: usize, M: Allocator + Clone> BMap<K, V, B, M> {
+    const MAX: usize = {
+        assert!(B >= 2, "nodes need room for at least two entries");
+        B
+    };
+    const MIN: usize = B / 2;
+
+    /// a map with its nodes in `alloc`
+    pub const fn new_in(alloc: M) -> Self {
+        BMap {
+            root: None,
+            len: 0,
+            alloc,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// number of levels, 0 for an empty map
+    pub fn depth(&self) -> usize {
+        let mut depth = 0;
+        let mut node = self.root.as_ref();
+        while let Some(n) = node {
+            depth += 1;
+            node = n.children.first();
+        }
+        depth
+    }
+
+    pub fn clear(&mut self) {
+        self.root = None;
+        self.len = 0;
+    }
+
+    pub fn allocator(&self) -> &M {
+        &self.alloc
+    }
+
+    /// the largest measure of all values, `None` for an empty map
+    pub fn max(&self) -> Option<u64> {
+        self.root.as_ref().map(|root| root.max)
+    }
+}
+
+impl<K: Ord, V: Measured, const B: usize, M: Allocator + Clone> BMap<K, V, B, M> {
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let mut node = self.root.as_ref()?;
+        loop {
+            match node.entries.binary_search_by(|(k, _)| k.cmp(key)) {
+                Ok(i) => return Some(&node.entries[i].1),
+                Err(i) => node = node.children.get(i)?,
+            }
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// inserts `value` under `key`, returning the value it replaces
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let Some(root) = &mut self.root else {
+            self.root = Some(Node::leaf((key, value), &self.alloc));
+            self.len = 1;
+            return None;
+        };
+        match root.insert((key, value), Self::MAX) {
+            Ok(old) => {
+                self.len += old.is_none() as usize;
+                old
+            }
+            Err((median, right)) => {
+                let left = self.root.take().expect("the root was just split");
+                let mut root = Node::leaf(median, &self.alloc);
+                root.max = root.max.max(left.max).max(right.max);
+                root.children.push(left);
+                root.children.push(right);
+                self.root = Some(root);
+                self.len += 1;
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let root = self.root.as_mut()?;
+        let (_, value) = root.remove(key, Self::MIN)?;
+        if root.entries.is_empty() {
+            self.root = root.children.pop();
+        }
+        self.len -= 1;
+        Some(value)
+    }
+
+    /// the entries with keys in `range`, in ascending order
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Iter<'_, K, V, B, M>
+    where
+        K: Clone,
+    {
+        Iter {
+            map: self,
+            front: range.start_bound().cloned(),
+            back: range.end_bound().cloned(),
+            min: None,
+        }
+    }
+
+    /// the entries with keys in `range` whose values measure at least `min`, in ascending order.
+    /// Subtrees without such a value are skipped as a whole
+    pub fn range_at_least<R: RangeBounds<K>>(&self, range: R, min: u64) -> Iter<'_, K, V, B, M>
+    where
+        K: Clone,
+    {
+        Iter {
+            min: Some(min),
+            ..self.range(range)
+        }
+    }
+
+    /// an entry with a key in `range` whose value has the largest measure, `None` if there are
+    /// no entries in `range`. Takes `O(log n)` steps
+    pub fn max_in<R: RangeBounds<K>>(&self, range: R) -> Option<(&K, &V)> {
+        let (key, value) = self
+            .root
+            .as_ref()?
+            .max_in(range.start_bound(), range.end_bound())?;
+        Some((key, value))
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V, B, M>
+    where
+        K: Clone,
+    {
+        self.range(..)
+    }
+
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &V> + '_
+    where
+        K: Clone,
+    {
+        self.iter().map(|(_, value)| value)
+    }
+}
+
+impl<K, V, const B: usize> Default for BMap<K, V, B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Clone + fmt::Debug, V: Measured + fmt::Debug, const B: usize, M: Allocator + Clone>
+    fmt::Debug for BMap<K, V, B, M>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K: Ord + Clone, V: Measured + PartialEq, const B: usize, M: Allocator + Clone> PartialEq
+    for BMap<K, V, B, M>
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl<K: Ord + Clone, V: Measured + Eq, const B: usize, M: Allocator + Clone> Eq
+    for BMap<K, V, B, M>
+{
+}
+
+impl<K: Ord, V: Measured, const B: usize> FromIterator<(K, V)> for BMap<K, V, B> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(entries: I) -> Self {
+        let mut map = Self::new();
+        for (key, value) in entries {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+impl<'a, K: Ord + Clone, V: Measured, const B: usize, M: Allocator + Clone> IntoIterator
+    for &'a BMap<K, V, B, M>
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V, B, M>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// the entries of a [`BMap`] within a range of keys, from either end
+pub struct Iter<'a, K, V, const B: usize, M: Allocator + Clone = Global> {
+    map: &'a BMap<K, V, B, M>,
+    /// the bounds of what is left
+    front: Bound<K>,
+    back: Bound<K>,
+    /// what the values have to measure at least
+    min: Option<u64>,
+}
+
+impl<'a, K: Ord + Clone, V: Measured, const B: usize, M: Allocator + Clone> Iterator
+    for Iter<'a, K, V, B, M>
+{
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = self
+            .map
+            .root
+            .as_ref()?
+            .first_from(self.front.as_ref(), self.min)?;
+        if above(key, self.back.as_ref()) {
+            return None;
+        }
+        self.front = Bound::Excluded(key.clone());
+        Some((key, value))
+    }
+}
+
+impl<K: Ord + Clone, V: Measured, const B: usize, M: Allocator + Clone> DoubleEndedIterator
+    for Iter<'_, K, V, B, M>
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (key, value) = self
+            .map
+            .root
+            .as_ref()?
+            .last_before(self.back.as_ref(), self.min)?;
+        if below(key, self.front.as_ref()) {
+            return None;
+        }
+        self.back = Bound::Excluded(key.clone());
+        Some((key, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{collections::BTreeMap, format, vec::Vec};
+    use core::ops::{Bound, RangeBounds};
+
+    use super::BMap;
+    use crate::allocator::Global;
+
+    /// checks the node sizes, the subtree maxima and that all leaves are at the same depth
+    fn check<const B: usize>(map: &BMap<u32, u32, B>) {
+        fn depth(
+            node: &super::Node<u32, u32, Global>,
+            max: usize,
+            min: usize,
+            root: bool,
+        ) -> usize {
+            assert!(node.entries.len() <= max);
+            assert!(root || node.entries.len() >= min);
+            let entries = node.entries.iter().map(|&(_, value)| u64::from(value));
+            let children = node.children.iter().map(|child| child.max);
+            assert_eq!(entries.chain(children).max(), Some(node.max));
+            if node.is_leaf() {
+                return 1;
+            }
+            assert_eq!(node.children.len(), node.entries.len() + 1);
+            let depths: Vec<_> = node
+                .children
+                .iter()
+                .map(|child| depth(child, max, min, false))
+                .collect();
+            assert!(depths.windows(2).all(|pair| pair[0] == pair[1]));
+            depths[0] + 1
+        }
+        if let Some(root) = &map.root {
+            assert_eq!(depth(root, B, B / 2, true), map.depth());
+        }
+    }
+
+    #[test]
+    fn ranges_from_both_ends() {
+        let map: BMap<u32, u32, 3> = (0..100).map(|k| (k * 2, k)).collect();
+        check(&map);
+        let keys: Vec<_> = map.range(10..=20).map(|(&k, _)| k).collect();
+        assert_eq!(keys, [10, 12, 14, 16, 18, 20]);
+        let keys: Vec<_> = map.range(11..20).rev().map(|(&k, _)| k).collect();
+        assert_eq!(keys, [18, 16, 14, 12]);
+        assert_eq!(map.range(..7).next_back(), Some((&6, &3)));
+        assert_eq!(map.range(199..).next(), None);
+
+        let mut both = map.range(..7);
+        assert_eq!(both.next(), Some((&0, &0)));
+        assert_eq!(both.next_back(), Some((&6, &3)));
+        assert_eq!(both.next_back(), Some((&4, &2)));
+        assert_eq!(both.next(), Some((&2, &1)));
+        assert_eq!(both.next(), None);
+        assert_eq!(both.next_back(), None);
+    }
+
+    use proptest::prelude::*;
+
+    fn matches_std<const B: usize>(ops: Vec<(bool, u32)>) -> Result<(), TestCaseError> {
+        let mut map = BMap::<u32, u32, B>::new();
+        let mut model = BTreeMap::new();
+        for (i, (insert, key)) in ops.into_iter().enumerate() {
+            let value = (key * 7 + i as u32) % 50;
+            if insert {
+                prop_assert_eq!(map.insert(key, value), model.insert(key, value));
+            } else {
+                prop_assert_eq!(map.remove(&key), model.remove(&key));
+            }
+            check(&map);
+        }
+        prop_assert_eq!(map.len(), model.len());
+        prop_assert!(map.iter().eq(model.iter()));
+        for key in 0..64 {
+            prop_assert_eq!(map.get(&key), model.get(&key));
+            prop_assert_eq!(map.range(..key).next_back(), model.range(..key).next_back());
+            prop_assert_eq!(map.range(key..).next(), model.range(key..).next());
+            let min = u64::from(key);
+            let large = |&(_, &value): &(&u32, &u32)| u64::from(value) >= min;
+            prop_assert!(
+                map.range_at_least(key / 2.., min)
+                    .eq(model.range(key / 2..).filter(large))
+            );
+            prop_assert!(
+                map.range_at_least(..key, min)
+                    .rev()
+                    .eq(model.range(..key).rev().filter(large))
+            );
+        }
+        prop_assert_eq!(map.max(), model.values().max().map(|&max| u64::from(max)));
+        // ties may be broken either way, but the entry has to lie in the range
+        let max_in = |range: (Bound<u32>, Bound<u32>)| {
+            let entry = map.max_in(range).map(|(&key, &value)| (key, value));
+            prop_assert!(entry.is_none_or(|(key, _)| range.contains(&key)));
+            let max = model.range(range).map(|(_, &value)| value).max();
+            prop_assert_eq!(entry.map(|(_, value)| value), max);
+            Ok(())
+        };
+        for start in (0..64).step_by(5) {
+            for end in (start..64).step_by(3) {
+                max_in((Bound::Included(start), Bound::Excluded(end)))?;
+                max_in((Bound::Unbounded, Bound::Included(end)))?;
+            }
+            max_in((Bound::Excluded(start), Bound::Unbounded))?;
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #[cfg_attr(miri, ignore)]
+        #[test]
+        fn matches_btree_map(ops in proptest::collection::vec((any::<bool>(), 0..64u32), 0..200)) {
+            matches_std::<2>(ops.clone())?;
+            matches_std::<3>(ops.clone())?;
+            matches_std::<6>(ops.clone())?;
+            matches_std::<16>(ops)?;
+        }
+    }
+}
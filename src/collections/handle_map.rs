@@ -0,0 +1,243 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+/// a key into a [`HandleMap`]
+///
+/// handles stay valid until their value is removed. After that the slot can be reused, but the
+/// generation stored in the handle no longer matches, so a stale handle never aliases a newer
+/// value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Handle {
+    index: usize,
+    generation: u32,
+}
+
+impl Handle {
+    /// the slot the handle refers to. Slots are reused, so this alone does not identify a value
+    pub fn index(self) -> usize {
+        self.index
+    }
+
+    pub fn generation(self) -> u32 {
+        self.generation
+    }
+}
+
+impl fmt::Display for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Slot {
+    /// odd while the slot is occupied
+    generation: u32,
+    /// position in `values`, only meaningful while occupied
+    index: usize,
+}
+
+impl Slot {
+    fn occupied(&self) -> bool {
+        self.generation % 2 == 1
+    }
+}
+
+/// a map from generational [`Handle`]s to values
+///
+/// the values are stored contiguously, so iterating is as fast as iterating a `Vec`. Lookups go
+/// through one level of indirection, inserting and removing are O(1).
+#[derive(Clone)]
+pub struct HandleMap<T> {
+    slots: Vec<Slot>,
+    /// the values and the slot each of them belongs to
+    values: Vec<T>,
+    owners: Vec<usize>,
+    /// vacant slots, reused last in first out
+    free: Vec<usize>,
+}
+
+impl<T> Default for HandleMap<T> {
+    fn default() -> Self {
+        Self {
+            slots: Vec::new(),
+            values: Vec::new(),
+            owners: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+}
+
+impl<T> HandleMap<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            slots: Vec::with_capacity(capacity),
+            values: Vec::with_capacity(capacity),
+            owners: Vec::with_capacity(capacity),
+            free: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn insert(&mut self, value: T) -> Handle {
+        let index = self.values.len();
+        let slot = self.free.pop().unwrap_or_else(|| {
+            self.slots.push(Slot {
+                generation: 0,
+                index: 0,
+            });
+            self.slots.len() - 1
+        });
+
+        let s = &mut self.slots[slot];
+        s.generation = s.generation.wrapping_add(1);
+        s.index = index;
+        self.values.push(value);
+        self.owners.push(slot);
+        Handle {
+            index: slot,
+            generation: s.generation,
+        }
+    }
+
+    /// the position of the handle's value in `values`, if it is still live
+    fn position(&self, handle: Handle) -> Option<usize> {
+        self.slots
+            .get(handle.index)
+            .filter(|s| s.occupied() && s.generation == handle.generation)
+            .map(|s| s.index)
+    }
+
+    pub fn contains(&self, handle: Handle) -> bool {
+        self.position(handle).is_some()
+    }
+
+    pub fn get(&self, handle: Handle) -> Option<&T> {
+        self.position(handle).map(|i| &self.values[i])
+    }
+
+    pub fn get_mut(&mut self, handle: Handle) -> Option<&mut T> {
+        self.position(handle).map(|i| &mut self.values[i])
+    }
+
+    /// removes the value, invalidating `handle` and every copy of it
+    pub fn remove(&mut self, handle: Handle) -> Option<T> {
+        let i = self.position(handle)?;
+
+        let value = self.values.swap_remove(i);
+        self.owners.swap_remove(i);
+        if let Some(&moved) = self.owners.get(i) {
+            self.slots[moved].index = i;
+        }
+
+        let slot = &mut self.slots[handle.index];
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(handle.index);
+        Some(value)
+    }
+
+    pub fn clear(&mut self) {
+        for slot in self.owners.drain(..) {
+            let s = &mut self.slots[slot];
+            s.generation = s.generation.wrapping_add(1);
+            self.free.push(slot);
+        }
+        self.values.clear();
+    }
+
+    /// the live handles, in storage order
+    pub fn handles(&self) -> impl Iterator<Item = Handle> + '_ {
+        self.owners.iter().map(|&slot| Handle {
+            index: slot,
+            generation: self.slots[slot].generation,
+        })
+    }
+
+    /// the live values and their handles, in storage order
+    pub fn iter(&self) -> impl Iterator<Item = (Handle, &T)> + '_ {
+        self.handles().zip(&self.values)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> + '_ {
+        self.values.iter()
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut T> + '_ {
+        self.values.iter_mut()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for HandleMap<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, vec::Vec};
+
+    use proptest::prelude::*;
+
+    use super::HandleMap;
+
+    #[test]
+    fn stale_handles() {
+        let mut map = HandleMap::new();
+        let a = map.insert("a");
+        let b = map.insert("b");
+        assert_eq!(map.remove(a), Some("a"));
+        assert_eq!(map.get(a), None);
+        assert_eq!(map.remove(a), None);
+
+        // the slot is reused, but the old handle does not see the new value
+        let c = map.insert("c");
+        assert_eq!(c.index(), a.index());
+        assert_ne!(c, a);
+        assert_eq!(map.get(a), None);
+        assert_eq!(map.get(c), Some(&"c"));
+        assert_eq!(map.get(b), Some(&"b"));
+        assert_eq!(map.len(), 2);
+    }
+
+    proptest! {
+        #[test]
+        fn matches_model(ops in proptest::collection::vec(any::<Option<u8>>(), 0..200)) {
+            let mut map = HandleMap::new();
+            let mut model = Vec::new();
+            let mut removed = Vec::new();
+            for (i, op) in ops.into_iter().enumerate() {
+                match op {
+                    Some(victim) if !model.is_empty() => {
+                        let (handle, value) = model.swap_remove(victim as usize % model.len());
+                        prop_assert_eq!(map.remove(handle), Some(value));
+                        removed.push(handle);
+                    }
+                    _ => model.push((map.insert(i), i)),
+                }
+                prop_assert_eq!(map.len(), model.len());
+                for &(handle, value) in &model {
+                    prop_assert_eq!(map.get(handle), Some(&value));
+                }
+                for &handle in &removed {
+                    prop_assert!(!map.contains(handle));
+                }
+            }
+            let mut values: Vec<_> = map.iter().map(|(handle, &v)| (handle, v)).collect();
+            values.sort();
+            model.sort();
+            prop_assert_eq!(values, model);
+        }
+    }
+}
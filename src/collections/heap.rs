@@ -4,6 +4,9 @@ use core::{
     ptr::{self, NonNull, addr_eq},
 };
 
+use super::pool::Pool;
+use crate::allocator::{Allocator, Global};
+
 type Link<T> = Option<NonNull<Node<T>>>;
 
 const HEAP_INVARIANT: &str = "invariant: we have a full heap";
@@ -24,19 +27,17 @@ fn as_ptr<T>(l: Link<T>) -> *const Node<T> {
 }
 
 impl<T> Node<T> {
-    fn new_boxed(value: T) -> NonNull<Self> {
-        let s = Self {
+    fn new(value: T) -> Self {
+        Self {
             value,
             left: None,
             right: None,
             parent: None,
-        };
-        let s = Box::into_raw(Box::new(s));
-        NonNull::new(s).unwrap()
+        }
     }
 }
 
-impl<T: fmt::Debug> Heap<T> {
+impl<T: fmt::Debug, M: Allocator> Heap<T, M> {
     fn swap_parent_child(&mut self, parent: NonNull<Node<T>>, child: NonNull<Node<T>>) {
         let mut parentp = parent;
         let mut childp = child;
@@ -161,9 +162,11 @@ impl<T: fmt::Debug> Heap<T> {
     }
 }
 
-pub struct Heap<T> {
+/// the nodes are allocated in `M`, see the [`allocator`](crate::allocator) module
+pub struct Heap<T, M: Allocator = Global> {
     root: Link<T>,
     len: usize,
+    nodes: Pool<Node<T>, M>,
 
     _d: PhantomData<T>,
 }
@@ -211,9 +214,17 @@ macro_rules! get_node_at {
 
 impl<T: fmt::Debug> Heap<T> {
     pub fn new() -> Self {
+        Self::new_in(Global)
+    }
+}
+
+impl<T: fmt::Debug, M: Allocator> Heap<T, M> {
+    /// a heap with its nodes in `alloc`
+    pub fn new_in(alloc: M) -> Self {
         Self {
             root: None,
             len: 0,
+            nodes: Pool::new_in(alloc),
             _d: PhantomData,
         }
     }
@@ -229,7 +240,7 @@ impl<T: fmt::Debug> Heap<T> {
     fn insert_at_bottom(&mut self, val: T) -> NonNull<Node<T>> {
         if self.root.is_none() {
             self.len += 1;
-            self.root = Some(Node::new_boxed(val));
+            self.root = Some(self.nodes.alloc(Node::new(val)));
             return self.root.expect("we just put it there");
         }
 
@@ -238,7 +249,7 @@ impl<T: fmt::Debug> Heap<T> {
         let cur = self.get_node_at_mut(loc / 2 - 1);
         let mut cur = cur.expect(HEAP_INVARIANT);
 
-        let mut new = Node::new_boxed(val);
+        let mut new = self.nodes.alloc(Node::new(val));
 
         let ret = if loc & 1 == 0 {
             let cur = unsafe { cur.as_mut() };
@@ -289,12 +300,12 @@ impl<T: fmt::Debug> Heap<T> {
         self.len -= 1;
     }
 
-    fn iter_ptr(&mut self) -> HeapIter<'_, T> {
+    fn iter_ptr(&mut self) -> HeapIter<'_, T, M> {
         HeapIter { heap: self, i: 0 }
     }
 }
 
-impl<T: Ord + fmt::Debug> Heap<T> {
+impl<T: Ord + fmt::Debug, M: Allocator> Heap<T, M> {
     pub fn insert(&mut self, v: T) {
         let mut new = self.insert_at_bottom(v);
         // let mut new = unsafe { new.as_mut() };
@@ -332,7 +343,7 @@ impl<T: Ord + fmt::Debug> Heap<T> {
             // removing a leaf (in this case root) is cheap
             self.remove_leaf(replacement);
 
-            let last = unsafe { Box::from_raw(node.as_ptr()) };
+            let last = unsafe { self.nodes.take(node) };
             return Some(last.value);
         }
 
@@ -344,13 +355,13 @@ impl<T: Ord + fmt::Debug> Heap<T> {
         self.remove_leaf(node);
 
         if self.root.is_none() {
-            let last = unsafe { Box::from_raw(node.as_ptr()) };
+            let last = unsafe { self.nodes.take(node) };
             return Some(last.value);
         }
 
         self.heapify_down(replacement);
 
-        let last = unsafe { Box::from_raw(node.as_ptr()) };
+        let last = unsafe { self.nodes.take(node) };
         Some(last.value)
     }
 
@@ -427,23 +438,23 @@ impl<T> Node<T> {
     }
 }
 
-impl<T> Drop for Heap<T> {
+impl<T, M: Allocator> Drop for Heap<T, M> {
     fn drop(&mut self) {
         // uses O(n) memory... can we avoid this?
         let Some(mut root) = self.root else { return };
 
-        fn free<T>(node: NonNull<Node<T>>) {
+        fn free<T, M: Allocator>(nodes: &mut Pool<Node<T>, M>, node: NonNull<Node<T>>) {
             if let Some(left) = unsafe { node.as_ref().left } {
-                free(left)
+                free(nodes, left)
             }
             if let Some(right) = unsafe { node.as_ref().right } {
-                free(right)
+                free(nodes, right)
             }
-            let _ = unsafe { Box::from_raw(node.as_ptr()) };
+            unsafe { nodes.release(node) };
         }
 
         if let Some(root) = self.root {
-            free(root)
+            free(&mut self.nodes, root)
         }
         // let Some(left) = (unsafe { root.as_mut().get_leftmost() }) else {
         //     return;
@@ -460,13 +471,13 @@ impl<T> Drop for Heap<T> {
     }
 }
 
-impl<T: fmt::Debug> fmt::Debug for Heap<T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl<T: fmt::Debug, M: Allocator> fmt::Debug for Heap<T, M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         fn inner<T: fmt::Debug>(
             node: &Node<T>,
             nextid: &mut u64,
             lim: u64,
-            f: &mut std::fmt::Formatter<'_>,
+            f: &mut fmt::Formatter<'_>,
         ) -> Result<u64, fmt::Error> {
             let me = *nextid;
             let me = node as *const _ as usize as u64;
@@ -516,12 +527,12 @@ impl<T: fmt::Debug> fmt::Debug for Heap<T> {
     }
 }
 
-struct HeapIter<'a, T> {
-    heap: &'a mut Heap<T>,
+struct HeapIter<'a, T, M: Allocator> {
+    heap: &'a mut Heap<T, M>,
     i: usize,
 }
 
-impl<'a, T: fmt::Debug> Iterator for HeapIter<'a, T> {
+impl<'a, T: fmt::Debug, M: Allocator> Iterator for HeapIter<'a, T, M> {
     type Item = NonNull<Node<T>>;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -533,6 +544,8 @@ impl<'a, T: fmt::Debug> Iterator for HeapIter<'a, T> {
 
 #[cfg(test)]
 mod tests {
+    use alloc::{format, vec::Vec};
+
     use crate::collections::heap::Heap;
 
     #[test]
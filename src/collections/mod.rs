@@ -1,2 +1,11 @@
+pub mod atomic_bitmap;
+pub mod bmap;
+pub mod handle_map;
 pub mod heap;
+pub(crate) mod pool;
+pub mod range_set;
 
+pub use atomic_bitmap::AtomicBitmap;
+pub use bmap::{BMap, Measured};
+pub use handle_map::{Handle, HandleMap};
+pub use range_set::RangeSet;
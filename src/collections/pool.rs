@@ -0,0 +1,209 @@
+//! storage for the nodes of the linear backend
+//!
+//! the backend links its nodes by pointer, so they must not move while they are live. A [`Pool`]
+//! hands out slots from chunks that are allocated once and never move or shrink, and keeps the
+//! slots given back for reuse. Splitting and merging free blocks therefore only goes to the
+//! allocator `M` when the pool has to grow, instead of for every node. The pool keeps no
+//! bookkeeping of its own elsewhere: the chunks fit in a fixed array, since each one doubles the
+//! capacity, and the slots given back are linked through the slots themselves.
+//!
+//! The nodes link to each other by pointer, not by index into the pool. Since slots never move,
+//! the pointers stay valid, and following one needs no lookup of the chunk an index falls into.
+//! Indices would only make the nodes smaller.
+
+use alloc::alloc::handle_alloc_error;
+use core::{alloc::Layout, mem::ManuallyDrop, ptr::NonNull};
+
+use crate::allocator::{Allocator, Global};
+
+/// the slots of the first chunk. Every further chunk is as large as all the previous ones together
+const FIRST_CHUNK: usize = 8;
+
+/// more chunks than this would not fit in the address space
+const MAX_CHUNKS: usize = usize::BITS as usize;
+
+/// a slot holds a value while it is live, and the next vacant slot while it is not
+#[repr(C)]
+union Slot<T> {
+    value: ManuallyDrop<T>,
+    next: Option<NonNull<Slot<T>>>,
+}
+
+pub(crate) struct Pool<T, M: Allocator = Global> {
+    /// the chunks, allocated in `alloc` and freed when the pool is dropped
+    chunks: [Option<NonNull<[Slot<T>]>>; MAX_CHUNKS],
+    chunk_count: usize,
+    /// slots of the last chunk that were never handed out start here
+    fresh: usize,
+    /// the slots given back, the last one first
+    vacant: Option<NonNull<Slot<T>>>,
+    capacity: usize,
+    len: usize,
+    alloc: M,
+}
+
+impl<T> Pool<T> {
+    pub(crate) const fn new() -> Self {
+        Self::new_in(Global)
+    }
+}
+
+impl<T, M: Allocator> Pool<T, M> {
+    /// a pool that takes its chunks from `alloc`
+    pub(crate) const fn new_in(alloc: M) -> Self {
+        Pool {
+            chunks: [None; MAX_CHUNKS],
+            chunk_count: 0,
+            fresh: 0,
+            vacant: None,
+            capacity: 0,
+            len: 0,
+            alloc,
+        }
+    }
+
+    pub(crate) fn allocator(&self) -> &M {
+        &self.alloc
+    }
+
+    /// number of slots, live or not
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// number of live values
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    fn last_chunk(&self) -> Option<NonNull<[Slot<T>]>> {
+        self.chunk_count
+            .checked_sub(1)
+            .and_then(|last| self.chunks[last])
+    }
+
+    /// slots of the last chunk that were never handed out
+    fn unused(&self) -> usize {
+        self.last_chunk()
+            .map_or(0, |chunk| chunk.len() - self.fresh)
+    }
+
+    /// moves `value` into a free slot. It stays there until [`release`](Self::release)d
+    pub(crate) fn alloc(&mut self, value: T) -> NonNull<T> {
+        let slot = match self.vacant {
+            Some(slot) => {
+                // SAFETY: vacant slots hold the next vacant one
+                self.vacant = unsafe { slot.as_ref().next };
+                slot
+            }
+            None => {
+                if self.unused() == 0 {
+                    self.grow();
+                }
+                let chunk = self.last_chunk().expect("the pool just grew");
+                // SAFETY: `fresh` is within the chunk, which is live until the pool is dropped
+                let slot = unsafe { chunk.cast::<Slot<T>>().add(self.fresh) };
+                self.fresh += 1;
+                slot
+            }
+        };
+        self.len += 1;
+        let slot = slot.cast::<T>();
+        // SAFETY: the slot is vacant, so nothing else refers to it, and the value lies at its
+        // start
+        unsafe { slot.write(value) };
+        slot
+    }
+
+    fn grow(&mut self) {
+        let len = self.capacity.max(FIRST_CHUNK);
+        let layout = Layout::array::<Slot<T>>(len).expect("the pool fits in the address space");
+        let Ok(chunk) = self.alloc.allocate(layout) else {
+            handle_alloc_error(layout)
+        };
+        self.chunks[self.chunk_count] = Some(NonNull::slice_from_raw_parts(chunk.cast(), len));
+        self.chunk_count += 1;
+        self.fresh = 0;
+        self.capacity += len;
+    }
+
+    /// keeps the now empty `slot` for reuse
+    fn vacate(&mut self, slot: NonNull<T>) {
+        let slot = slot.cast::<Slot<T>>();
+        // SAFETY: the slot comes from this pool and its value is gone
+        unsafe { slot.write(Slot { next: self.vacant }) };
+        self.vacant = Some(slot);
+        self.len -= 1;
+    }
+
+    /// moves the value out of `slot` and keeps the slot for reuse
+    ///
+    /// # Safety
+    ///
+    /// as for [`release`](Self::release)
+    pub(crate) unsafe fn take(&mut self, slot: NonNull<T>) -> T {
+        // SAFETY: the slot holds a live value, which the caller gives up
+        let value = unsafe { slot.read() };
+        self.vacate(slot);
+        value
+    }
+
+    /// drops the value in `slot` and keeps the slot for reuse
+    ///
+    /// # Safety
+    ///
+    /// `slot` has to come from [`alloc`](Self::alloc) on this pool and must not have been released
+    /// since. It must not be used afterwards
+    pub(crate) unsafe fn release(&mut self, slot: NonNull<T>) {
+        // SAFETY: the slot holds a live value, which the caller gives up
+        unsafe { slot.drop_in_place() };
+        self.vacate(slot);
+    }
+}
+
+/// frees the chunks. Values that were not released are leaked, not dropped
+impl<T, M: Allocator> Drop for Pool<T, M> {
+    fn drop(&mut self) {
+        for chunk in self.chunks[..self.chunk_count].iter().flatten() {
+            let layout =
+                Layout::array::<Slot<T>>(chunk.len()).expect("the chunk was allocated like this");
+            // SAFETY: the chunk came from `alloc` in `grow` and is freed only here
+            unsafe { self.alloc.deallocate(chunk.cast(), layout) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{rc::Rc, vec::Vec};
+
+    use super::Pool;
+
+    #[test]
+    fn reuses_slots() {
+        let mut pool = Pool::new();
+        let slots: Vec<_> = (0..20u64).map(|i| pool.alloc(i)).collect();
+        assert_eq!((pool.len(), pool.capacity()), (20, 32));
+        // slots stay where they are while the pool grows
+        for (i, slot) in slots.iter().enumerate() {
+            assert_eq!(unsafe { *slot.as_ref() }, i as u64);
+        }
+
+        unsafe { pool.release(slots[3]) };
+        unsafe { pool.release(slots[17]) };
+        assert_eq!(pool.len(), 18);
+        assert_eq!(pool.alloc(100), slots[17]);
+        assert_eq!(pool.alloc(101), slots[3]);
+        assert_eq!((pool.len(), pool.capacity()), (20, 32));
+    }
+
+    #[test]
+    fn drops_released_values() {
+        let value = Rc::new(());
+        let mut pool = Pool::new();
+        let slot = pool.alloc(Rc::clone(&value));
+        assert_eq!(Rc::strong_count(&value), 2);
+        unsafe { pool.release(slot) };
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+}
@@ -0,0 +1,275 @@
+use alloc::collections::BTreeMap;
+use core::ops::Range;
+
+use crate::address::Address;
+
+/// an ordered set of disjoint, non-adjacent ranges
+///
+/// inserting a range that touches or overlaps ranges already in the set merges them into one, so
+/// the set always stores the smallest number of ranges describing the covered addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeSet<A = usize> {
+    /// start -> end
+    map: BTreeMap<A, A>,
+}
+
+impl<A> Default for RangeSet<A> {
+    fn default() -> Self {
+        Self {
+            map: BTreeMap::new(),
+        }
+    }
+}
+
+impl<A: Address> RangeSet<A> {
+    pub fn new() -> Self {
+        Self {
+            map: BTreeMap::new(),
+        }
+    }
+
+    /// number of disjoint ranges in the set
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// number of addresses covered by the set
+    pub fn covered(&self) -> A {
+        self.map.iter().map(|(&start, &end)| end - start).sum()
+    }
+
+    /// iterates the ranges in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = Range<A>> + '_ {
+        self.map.iter().map(|(&start, &end)| start..end)
+    }
+
+    pub fn contains(&self, addr: A) -> bool {
+        self.map
+            .range(..=addr)
+            .next_back()
+            .is_some_and(|(_, &end)| addr < end)
+    }
+
+    /// whether any address in `range` is part of the set
+    pub fn overlaps(&self, range: Range<A>) -> bool {
+        if range.is_empty() {
+            return false;
+        }
+        self.map
+            .range(..range.end)
+            .next_back()
+            .is_some_and(|(_, &end)| end > range.start)
+    }
+
+    /// whether every address in `range` is part of the set
+    pub fn contains_range(&self, range: Range<A>) -> bool {
+        if range.is_empty() {
+            return true;
+        }
+        self.map
+            .range(..=range.start)
+            .next_back()
+            .is_some_and(|(_, &end)| range.end <= end)
+    }
+
+    /// adds `range` to the set, merging it with overlapping and adjacent ranges
+    pub fn insert(&mut self, range: Range<A>) {
+        if range.is_empty() {
+            return;
+        }
+        let mut start = range.start;
+        let mut end = range.end;
+
+        if let Some((&before_start, &before_end)) = self.map.range(..=start).next_back()
+            && before_end >= start
+        {
+            start = before_start;
+            end = end.max(before_end);
+        }
+
+        while let Some((&next_start, &next_end)) = self.map.range(start..=end).next() {
+            self.map.remove(&next_start);
+            end = end.max(next_end);
+        }
+
+        self.map.insert(start, end);
+    }
+
+    /// removes all addresses in `range` from the set
+    pub fn remove(&mut self, range: Range<A>) {
+        if range.is_empty() {
+            return;
+        }
+
+        if let Some((&before_start, &before_end)) = self.map.range(..range.start).next_back()
+            && before_end > range.start
+        {
+            self.map.insert(before_start, range.start);
+            if before_end > range.end {
+                self.map.insert(range.end, before_end);
+                return;
+            }
+        }
+
+        while let Some((&next_start, &next_end)) = self.map.range(range.start..range.end).next() {
+            self.map.remove(&next_start);
+            if next_end > range.end {
+                self.map.insert(range.end, next_end);
+                break;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    /// all addresses in `self` or `other`
+    pub fn union(&self, other: &RangeSet<A>) -> RangeSet<A> {
+        let mut res = self.clone();
+        res.extend(other.iter());
+        res
+    }
+
+    /// all addresses in `self` but not in `other`
+    pub fn subtract(&self, other: &RangeSet<A>) -> RangeSet<A> {
+        let mut res = self.clone();
+        for range in other.iter() {
+            res.remove(range);
+        }
+        res
+    }
+
+    /// all addresses in both `self` and `other`
+    pub fn intersect(&self, other: &RangeSet<A>) -> RangeSet<A> {
+        let mut res = RangeSet::new();
+        let mut a = self.iter().peekable();
+        let mut b = other.iter().peekable();
+        while let (Some(x), Some(y)) = (a.peek(), b.peek()) {
+            let start = x.start.max(y.start);
+            let end = x.end.min(y.end);
+            if start < end {
+                res.map.insert(start, end);
+            }
+            if x.end < y.end {
+                a.next();
+            } else {
+                b.next();
+            }
+        }
+        res
+    }
+}
+
+impl<A: Address> Extend<Range<A>> for RangeSet<A> {
+    fn extend<I: IntoIterator<Item = Range<A>>>(&mut self, iter: I) {
+        for range in iter {
+            self.insert(range);
+        }
+    }
+}
+
+impl<A: Address> FromIterator<Range<A>> for RangeSet<A> {
+    fn from_iter<I: IntoIterator<Item = Range<A>>>(iter: I) -> Self {
+        let mut set = RangeSet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, vec::Vec};
+
+    use super::RangeSet;
+
+    fn ranges(set: &RangeSet) -> Vec<(usize, usize)> {
+        set.iter().map(|r| (r.start, r.end)).collect()
+    }
+
+    #[test]
+    fn insert_coalesces() {
+        let mut set = RangeSet::new();
+        set.insert(0..10);
+        set.insert(20..30);
+        assert_eq!(ranges(&set), [(0, 10), (20, 30)]);
+
+        set.insert(10..15);
+        assert_eq!(ranges(&set), [(0, 15), (20, 30)]);
+
+        set.insert(14..20);
+        assert_eq!(ranges(&set), [(0, 30)]);
+        assert_eq!(set.covered(), 30);
+    }
+
+    #[test]
+    fn insert_swallows_contained_ranges() {
+        let mut set: RangeSet = [2..3, 5..6, 8..9].into_iter().collect();
+        set.insert(1..10);
+        assert_eq!(ranges(&set), [(1, 10)]);
+    }
+
+    #[test]
+    fn remove_splits() {
+        let mut set: RangeSet = core::iter::once(0..30).collect();
+        set.remove(10..20);
+        assert_eq!(ranges(&set), [(0, 10), (20, 30)]);
+
+        set.remove(5..25);
+        assert_eq!(ranges(&set), [(0, 5), (25, 30)]);
+
+        set.remove(0..100);
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn queries() {
+        let set: RangeSet = [0..10, 20..30].into_iter().collect();
+        assert!(set.contains(0));
+        assert!(!set.contains(10));
+        assert!(set.overlaps(5..25));
+        assert!(!set.overlaps(10..20));
+        assert!(set.contains_range(20..30));
+        assert!(!set.contains_range(5..25));
+    }
+
+    #[test]
+    fn set_operations() {
+        let a: RangeSet = [0..10, 20..30].into_iter().collect();
+        let b: RangeSet = core::iter::once(5..25).collect();
+
+        assert_eq!(ranges(&a.union(&b)), [(0, 30)]);
+        assert_eq!(ranges(&a.subtract(&b)), [(0, 5), (25, 30)]);
+        assert_eq!(ranges(&a.intersect(&b)), [(5, 10), (20, 25)]);
+    }
+
+    use proptest::prelude::*;
+    proptest! {
+        #[cfg_attr(miri, ignore)]
+        #[test]
+        fn matches_bitmap_model(ops in proptest::collection::vec((any::<bool>(), 0..64usize, 0..16usize), 0..50)) {
+            let mut set = RangeSet::new();
+            let mut model = [false; 80];
+            for (insert, start, len) in ops {
+                if insert {
+                    set.insert(start..start + len);
+                } else {
+                    set.remove(start..start + len);
+                }
+                model[start..start + len].fill(insert);
+            }
+
+            for (addr, &present) in model.iter().enumerate() {
+                prop_assert_eq!(set.contains(addr), present);
+            }
+            prop_assert_eq!(set.covered(), model.iter().filter(|x| **x).count());
+            // ranges must be disjoint and non-adjacent
+            let r: Vec<_> = set.iter().collect();
+            prop_assert!(r.windows(2).all(|w| w[0].end < w[1].start));
+        }
+    }
+}
@@ -0,0 +1,1191 @@
+//! what the backends share, apart from how they keep their regions and free extents
+//!
+//! [`Common`] is the state both backends keep the same way: the counters, the reservations and
+//! pins, the tracked allocations and the settings. [`Bookkeeping`] is implemented by each backend
+//! with a few lookups into its own structure, and builds the operations that need nothing else on
+//! top of them. `shared_methods!` then gives both backends the same public methods, forwarding to
+//! the code here.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{mem, ops::Range};
+
+use crate::{
+    AddRangeResult, Allocations, Direction, Error, ErrorKind, Event, Limits, Observer, Placement,
+    Policy, RangeAlloc, RegionAttrs, RegionId, Rejected, Request, Result, Stats, Steps,
+    address::Address,
+    check_holes,
+    collections::RangeSet,
+    compaction::Move,
+    map::{AddrState, MapEntry, RegionInfo, RegionKind},
+    notify,
+    raw::RawParts,
+    units::{Alignment, Size},
+};
+
+/// the state of a backend that does not depend on how it keeps its regions and free extents
+pub(crate) struct Common<Tag, A> {
+    pub(crate) reserved: RangeSet<A>,
+    /// ranges that administrative operations must not touch
+    pub(crate) pinned: RangeSet<A>,
+    /// the reservations made with a deadline, which is their tag
+    pub(crate) holds: Allocations<u64, A>,
+    /// the live allocations, if tracking is enabled
+    pub(crate) allocations: Option<Allocations<Tag, A>>,
+    /// whether frees have to lie within tracked allocations
+    pub(crate) strict: bool,
+    pub(crate) observer: Option<Observer<Tag, A>>,
+    /// every allocation is rounded to a multiple of this
+    pub(crate) granularity: Alignment<A>,
+    pub(crate) policy: Policy,
+    pub(crate) direction: Direction,
+    pub(crate) limits: Limits,
+    /// never-allocated space at the end of every allocation
+    pub(crate) guard: A,
+    /// incremented whenever space becomes free
+    pub(crate) epoch: u64,
+    pub(crate) total_space: A,
+    pub(crate) free_space: A,
+    /// free space of every usable region, keyed by region base
+    pub(crate) region_free: BTreeMap<A, A>,
+    pub(crate) steps: Steps,
+    pub(crate) stats: Stats,
+}
+
+impl<Tag, A: Address> Common<Tag, A> {
+    pub(crate) fn new() -> Self {
+        Common {
+            reserved: RangeSet::new(),
+            pinned: RangeSet::new(),
+            holds: Allocations::default(),
+            allocations: None,
+            strict: false,
+            observer: None,
+            granularity: Alignment::BASE_PAGE,
+            policy: Policy::FirstFit,
+            direction: Direction::BottomUp,
+            limits: Limits::default(),
+            guard: A::ZERO,
+            epoch: 0,
+            total_space: A::ZERO,
+            free_space: A::ZERO,
+            region_free: BTreeMap::new(),
+            steps: Steps::default(),
+            stats: Stats::default(),
+        }
+    }
+
+    /// the state kept in `parts`, taking the reservations, pins and tracked allocations out of
+    /// them. The free space of the regions is left to the backend, which adds up its free extents
+    pub(crate) fn from_parts(parts: &mut RawParts<Tag, A>) -> Self
+    where
+        Tag: Clone,
+    {
+        let mut common = Common {
+            reserved: mem::take(&mut parts.reserved).into_iter().collect(),
+            pinned: mem::take(&mut parts.pinned).into_iter().collect(),
+            granularity: parts.granularity,
+            policy: parts.policy,
+            direction: parts.direction,
+            limits: parts.limits,
+            guard: parts.guard,
+            epoch: parts.epoch,
+            total_space: parts.total_space,
+            free_space: parts.free_space,
+            ..Self::new()
+        };
+        for (range, deadline) in mem::take(&mut parts.holds) {
+            common.holds.insert(range, deadline);
+        }
+        common.allocations = parts.allocations.take().map(|allocations| {
+            let mut tracked = Allocations::default();
+            for (range, tag) in allocations {
+                tracked.insert(range, tag);
+            }
+            tracked
+        });
+        // whatever is neither free nor reserved was allocated before the parts were taken
+        let allocated = common.total_space - common.free_space - common.reserved.covered();
+        common.stats.grown(allocated.to_u64());
+        #[cfg(feature = "histogram")]
+        if let Some(allocations) = &common.allocations {
+            for (range, _) in allocations.iter() {
+                common
+                    .stats
+                    .live_sizes
+                    .add((range.end - range.start).to_u64());
+            }
+        }
+        common
+    }
+
+    /// the parts of an allocator in this state with the given regions, region attributes and free
+    /// extents, each sorted by base
+    pub(crate) fn raw_parts(
+        &self,
+        regions: Vec<MapEntry<Tag, A>>,
+        region_attrs: Vec<(A, RegionAttrs<A>)>,
+        free: Vec<(Range<A>, u64)>,
+    ) -> RawParts<Tag, A>
+    where
+        Tag: Clone,
+    {
+        RawParts {
+            regions,
+            region_attrs,
+            free,
+            reserved: self.reserved.iter().collect(),
+            pinned: self.pinned.iter().collect(),
+            holds: self.reservation_deadlines().collect(),
+            granularity: self.granularity,
+            policy: self.policy,
+            direction: self.direction,
+            limits: self.limits,
+            guard: self.guard,
+            epoch: self.epoch,
+            total_space: self.total_space,
+            free_space: self.free_space,
+            allocations: self.allocations.as_ref().map(|allocations| {
+                allocations
+                    .iter()
+                    .map(|(range, tag)| (range, tag.clone()))
+                    .collect()
+            }),
+        }
+    }
+
+    /// the size an allocation of `size` takes up: rounded up to the granularity, plus the guard
+    pub(crate) fn whole_size(&self, size: A) -> Result<A> {
+        Size::new(size)?
+            .round_up(self.granularity)?
+            .get()
+            .checked_add(self.guard)
+            .ok_or_else(|| Error::new(ErrorKind::Overflow))
+    }
+
+    pub(crate) fn check_unpinned(&self, range: Range<A>) -> Result<()> {
+        if self.pinned.overlaps(range) {
+            return Err(Error::new(ErrorKind::Pinned));
+        }
+        Ok(())
+    }
+
+    /// takes `addr..addr + size` out of the free space, in total and in the region it lies in
+    pub(crate) fn take_space(&mut self, addr: A, size: A) {
+        self.free_space -= size;
+        *self.region_free_mut(addr) -= size;
+    }
+
+    /// gives `addr..addr + size` back to the free space, in total and in the region it lies in
+    pub(crate) fn give_space(&mut self, addr: A, size: A) {
+        self.free_space += size;
+        *self.region_free_mut(addr) += size;
+    }
+
+    pub(crate) fn region_free_mut(&mut self, addr: A) -> &mut A {
+        self.region_free
+            .range_mut(..=addr)
+            .next_back()
+            .map(|(_, free)| free)
+            .expect("free space is always inside a region")
+    }
+
+    pub(crate) fn set_guard(&mut self, guard: A) -> Result<()> {
+        if self.free_space + self.reserved.covered() != self.total_space {
+            return Err(Error::new(ErrorKind::NotFree));
+        }
+        self.guard = self.granularity.align_up(guard)?;
+        Ok(())
+    }
+
+    /// sets `limits` if the backend's `regions` and `free_extents` are within them
+    pub(crate) fn set_limits(
+        &mut self,
+        limits: Limits,
+        regions: usize,
+        free_extents: usize,
+    ) -> Result<()> {
+        limits.admit(regions, free_extents)?;
+        self.limits = limits;
+        Ok(())
+    }
+
+    /// what `place` picks for `request` under each policy
+    pub(crate) fn simulate(
+        &self,
+        request: Request<A>,
+        place: impl Fn(Request<A>, Policy) -> Option<Placement<A>>,
+    ) -> Result<Vec<(Policy, Option<Placement<A>>)>> {
+        let request = request.normalized(self.granularity)?;
+        Ok(Policy::ALL
+            .into_iter()
+            .map(|policy| (policy, place(request, policy)))
+            .collect())
+    }
+
+    pub(crate) fn reset_peak(&mut self) {
+        self.stats.peak_allocated = self.stats.allocated;
+    }
+
+    pub(crate) fn unpin(&mut self, base: A, size: A) -> Result<()> {
+        let end = base.saturating_add(size);
+        if base == end || !self.pinned.contains_range(base..end) {
+            return Err(Error::new(ErrorKind::NotPinned));
+        }
+        self.pinned.remove(base..end);
+        Ok(())
+    }
+
+    pub(crate) fn keep_reservation(&mut self, base: A) -> Result<()> {
+        match self.holds.get(base) {
+            Some((range, _)) if range.start == base => {
+                self.holds.remove(range);
+                Ok(())
+            }
+            _ => Err(Error::new(ErrorKind::NotReserved)),
+        }
+    }
+
+    pub(crate) fn reservation_deadlines(&self) -> impl Iterator<Item = (Range<A>, u64)> + '_ {
+        self.holds
+            .iter()
+            .map(|(range, &deadline)| (range, deadline))
+    }
+}
+
+impl<Tag: Clone, A: Address> Common<Tag, A> {
+    pub(crate) fn iter_allocated(&self) -> impl Iterator<Item = (Range<A>, &Tag)> + '_ {
+        self.allocations.iter().flat_map(Allocations::iter)
+    }
+
+    pub(crate) fn allocation_at(&self, addr: A) -> Option<(Range<A>, &Tag)> {
+        self.allocations.as_ref()?.get(addr)
+    }
+}
+
+/// a backend's regions and free extents, looked up and changed through the hooks each backend
+/// implements, and the operations that need nothing but those hooks and the [`Common`] state
+pub(crate) trait Bookkeeping<A: Address>: RangeAlloc<A, Tag: Clone> {
+    fn common(&self) -> &Common<Self::Tag, A>;
+
+    fn common_mut(&mut self) -> &mut Common<Self::Tag, A>;
+
+    fn region_count(&self) -> usize;
+
+    fn free_extent_count(&self) -> usize;
+
+    /// all regions, usable and reserved, in no particular order
+    fn regions(&self) -> impl Iterator<Item = RegionInfo<'_, Self::Tag, A>>;
+
+    /// the region, usable or reserved, that `addr` belongs to
+    fn region_containing(&self, addr: A) -> Option<MapEntry<Self::Tag, A>>;
+
+    /// a region, usable or reserved, that overlaps `range`
+    fn overlapping_region(&self, range: Range<A>) -> Option<RegionId<A>>;
+
+    /// the base and attributes of the region containing `addr`, if it has non-default ones
+    fn attrs_at(&self, addr: A) -> Option<(A, &RegionAttrs<A>)>;
+
+    /// whether all of `base..base + size` is free
+    fn is_free(&self, base: A, size: A) -> bool;
+
+    /// whether any free extent overlaps `range`
+    fn overlaps_free(&self, range: Range<A>) -> bool;
+
+    /// removes exactly `base..base + size` from the free extents
+    fn carve(&mut self, base: A, size: A) -> Result<()>;
+
+    /// returns `base..base + size` to the free space, together with a remainder after it that
+    /// was too small to stay free, and returns the size given back. With `strict`, the range has
+    /// to lie within tracked allocations
+    fn give_back(&mut self, base: A, size: A, strict: bool) -> Result<A>;
+
+    /// finds a place for the request in `window` and takes it, tracked but neither counted nor
+    /// observed. The range returned leaves out the guard
+    fn place_and_take(
+        &mut self,
+        min_size: A,
+        alignment: A,
+        window: Range<A>,
+    ) -> Result<(Self::Tag, Range<A>)>;
+
+    fn remove_range(&mut self, base: A) -> Result<()>;
+
+    /// merges the usable regions ending and starting at `boundary` into one if they have the
+    /// same tag and neither has attributes, along with the free extents on both sides of it
+    fn merge_at(&mut self, boundary: A)
+    where
+        Self::Tag: PartialEq;
+
+    fn raw_parts(&self) -> RawParts<Self::Tag, A>;
+
+    /// called once tracking was switched on or off
+    fn tracking_changed(&mut self) {}
+
+    fn room_for_region(&self) -> Result<()> {
+        Limits::room_for_one(self.common().limits.max_regions, || self.region_count())
+    }
+
+    fn room_for_free_extent(&self) -> Result<()> {
+        Limits::room_for_one(self.common().limits.max_free_extents, || {
+            self.free_extent_count()
+        })
+    }
+
+    fn iter_regions(&self) -> impl Iterator<Item = RegionInfo<'_, Self::Tag, A>> {
+        let mut regions: Vec<_> = self.regions().collect();
+        regions.sort_by_key(|region| region.base);
+        regions.into_iter()
+    }
+
+    fn tag_at(&self, addr: A) -> Option<(Self::Tag, AddrState)> {
+        let region = self.region_containing(addr)?;
+        let state = if region.kind == RegionKind::Reserved || self.common().reserved.contains(addr)
+        {
+            AddrState::Reserved
+        } else if self.is_free(addr, A::ONE) {
+            AddrState::Free
+        } else {
+            AddrState::Allocated
+        };
+        Some((region.tag, state))
+    }
+
+    fn add_range_with_holes(
+        &mut self,
+        base: A,
+        size: A,
+        range_tag: Self::Tag,
+        holes: &[Range<A>],
+    ) -> Result<()> {
+        let end = base
+            .checked_add(size)
+            .ok_or_else(|| Error::new(ErrorKind::Overflow))?;
+        check_holes(&(base..end), holes)?;
+        self.add_range(base, size, range_tag)?;
+        for hole in holes {
+            // punching a hole splits a free extent, which the limits may not allow
+            if let Err(e) = self.reserve(hole.start, hole.end - hole.start) {
+                self.remove_range(base)
+                    .expect("the new region is only free or reserved");
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn add_range_coalescing(&mut self, base: A, size: A, range_tag: Self::Tag) -> Result<()>
+    where
+        Self::Tag: PartialEq,
+    {
+        self.add_range(base, size, range_tag)?;
+        self.merge_at(base);
+        self.merge_at(base + size);
+        Ok(())
+    }
+
+    fn add_ranges<I>(&mut self, entries: I) -> Vec<AddRangeResult<A>>
+    where
+        I: IntoIterator<Item = (A, A, Self::Tag)>,
+    {
+        entries
+            .into_iter()
+            .map(|(base, size, tag)| {
+                if let Some(region) = self.overlapping_region(base..base.saturating_add(size)) {
+                    return Err(Rejected {
+                        error: Error::new(ErrorKind::OverlappingRange),
+                        overlaps: Some(region),
+                    });
+                }
+                self.add_range(base, size, tag)
+                    .map(|()| RegionId(base))
+                    .map_err(|error| Rejected {
+                        error,
+                        overlaps: None,
+                    })
+            })
+            .collect()
+    }
+
+    fn reserve(&mut self, base: A, size: A) -> Result<()> {
+        let end = base
+            .checked_add(size)
+            .ok_or_else(|| Error::new(ErrorKind::Overflow))?;
+        self.common().check_unpinned(base..end)?;
+        self.carve(base, size)?;
+        self.common_mut().reserved.insert(base..end);
+        Ok(())
+    }
+
+    fn unreserve(&mut self, base: A, size: A) -> Result<()> {
+        let end = base
+            .checked_add(size)
+            .ok_or_else(|| Error::new(ErrorKind::Overflow))?;
+        if !self.common().reserved.contains_range(base..end) {
+            return Err(Error::new(ErrorKind::NotReserved));
+        }
+        self.common().check_unpinned(base..end)?;
+        self.give_back(base, size, false)?;
+        let common = self.common_mut();
+        common.reserved.remove(base..end);
+        common.holds.remove(base..end);
+        Ok(())
+    }
+
+    fn reserve_until(&mut self, base: A, size: A, deadline: u64) -> Result<()> {
+        self.reserve(base, size)?;
+        // `reserve` succeeded, so the end does not overflow
+        self.common_mut().holds.insert(base..base + size, deadline);
+        Ok(())
+    }
+
+    /// the usable regions as `(base, size)`, in no particular order
+    fn usable_regions(&self) -> Vec<(A, A)> {
+        self.regions()
+            .filter(|region| region.kind == RegionKind::Usable)
+            .map(|region| (region.base, region.size))
+            .collect()
+    }
+
+    /// reserves all of `ranges`, or nothing if any of them is not free
+    fn reserve_all(&mut self, ranges: &[(A, A)]) -> Result<()> {
+        if !ranges.iter().all(|&(base, size)| self.is_free(base, size)) {
+            return Err(Error::new(ErrorKind::NotFree));
+        }
+        for &(base, size) in ranges {
+            self.reserve(base, size)?;
+        }
+        Ok(())
+    }
+
+    fn reserve_bottom(&mut self, size: A) -> Result<()> {
+        let ranges: Vec<_> = self
+            .usable_regions()
+            .into_iter()
+            .map(|(base, region_size)| (base, size.min(region_size)))
+            .collect();
+        self.reserve_all(&ranges)
+    }
+
+    fn reserve_top(&mut self, size: A) -> Result<()> {
+        let ranges: Vec<_> = self
+            .usable_regions()
+            .into_iter()
+            .map(|(base, region_size)| {
+                let size = size.min(region_size);
+                (base + region_size - size, size)
+            })
+            .collect();
+        self.reserve_all(&ranges)
+    }
+
+    /// the size of the usable region starting at `region_base`
+    fn usable_region_at(&self, region_base: A) -> Result<A> {
+        self.regions()
+            .find(|region| region.kind == RegionKind::Usable && region.base == region_base)
+            .map(|region| region.size)
+            .ok_or_else(|| Error::new(ErrorKind::NotOwned))
+    }
+
+    fn reserve_bottom_in(&mut self, region_base: A, size: A) -> Result<()> {
+        let region_size = self.usable_region_at(region_base)?;
+        self.reserve(region_base, size.min(region_size))
+    }
+
+    fn reserve_top_in(&mut self, region_base: A, size: A) -> Result<()> {
+        let region_size = self.usable_region_at(region_base)?;
+        let size = size.min(region_size);
+        self.reserve(region_base + region_size - size, size)
+    }
+
+    fn expire_reservations(&mut self, now: u64) -> Vec<Range<A>> {
+        let due: Vec<_> = self
+            .common()
+            .reservation_deadlines()
+            .filter(|&(_, deadline)| deadline <= now)
+            .map(|(range, _)| range)
+            .collect();
+        due.into_iter()
+            .filter(|range| self.unreserve(range.start, range.end - range.start).is_ok())
+            .collect()
+    }
+
+    /// frees whatever is neither free nor reserved in each of `regions`. Regions are handled one
+    /// at a time, because `free` never crosses a region boundary
+    fn free_allocated_in(&mut self, regions: Vec<Range<A>>, free: &RangeSet<A>) -> Result<A> {
+        for region in &regions {
+            self.common().check_unpinned(region.clone())?;
+        }
+        let mut freed = A::ZERO;
+        for region in regions {
+            let allocated = RangeSet::from_iter([region])
+                .subtract(free)
+                .subtract(&self.common().reserved);
+            for range in allocated.iter() {
+                self.free_whole(range.start, range.end - range.start)?;
+            }
+            freed += allocated.covered();
+        }
+        Ok(freed)
+    }
+
+    fn pin(&mut self, base: A, size: A) -> Result<()> {
+        let size = Size::new(size)?.get();
+        let end = base
+            .checked_add(size)
+            .ok_or_else(|| Error::new(ErrorKind::Overflow))?;
+        self.region_containing(base)
+            .filter(|region| region.kind == RegionKind::Usable && end <= region.end())
+            .ok_or_else(|| Error::new(ErrorKind::NotOwned))?;
+        self.common_mut().pinned.insert(base..end);
+        Ok(())
+    }
+
+    fn alloc_sized_within(
+        &mut self,
+        min_size: A,
+        alignment: A,
+        window: Range<A>,
+    ) -> Result<(Self::Tag, Range<A>)> {
+        let result = self.place_and_take(min_size, alignment, window);
+        let common = self.common_mut();
+        let guard = common.guard;
+        common.stats.count(&result, |(_, range)| {
+            (range.end - range.start + guard).to_u64()
+        });
+        if let Ok((tag, range)) = &result {
+            let range = range.start..range.end + guard;
+            notify(&mut common.observer, Event::Alloc { range, tag });
+        }
+        result
+    }
+
+    /// allocates `base..base + size` and counts it, see [`alloc_fixed`](RangeAlloc::alloc_fixed)
+    fn alloc_at(&mut self, base: A, size: A) -> Result<(Self::Tag, A)> {
+        let result = self.take_fixed(base, size);
+        let common = self.common_mut();
+        let whole = common.whole_size(size);
+        common
+            .stats
+            .count(&result, |_| whole.as_ref().map_or(0, |size| size.to_u64()));
+        if let (Ok((tag, base)), Ok(whole)) = (&result, whole) {
+            let range = *base..*base + whole;
+            notify(&mut common.observer, Event::Alloc { range, tag });
+        }
+        result
+    }
+
+    /// allocates `base..base + size` without counting it
+    fn take_fixed(&mut self, base: A, size: A) -> Result<(Self::Tag, A)> {
+        if !self.common().granularity.is_aligned(base) {
+            return Err(Error::new(ErrorKind::InvalidAlignment));
+        }
+        let size = match self.attrs_at(base) {
+            Some((_, attrs)) => attrs.apply_fixed(base, size)?,
+            None => size,
+        };
+        let size = self.common().whole_size(size)?;
+        if base.checked_add(size).is_none() {
+            return Err(Error::new(ErrorKind::Overflow));
+        }
+
+        self.carve(base, size)?;
+        let tag = self
+            .region_containing(base)
+            .expect("free space is always inside a region")
+            .tag;
+        if let Some(allocations) = &mut self.common_mut().allocations {
+            allocations.insert(base..base + size, tag.clone());
+        }
+
+        Ok((tag, base))
+    }
+
+    /// frees `base..base + size`, guard included, and counts the free
+    fn free_whole(&mut self, base: A, size: A) -> Result<A> {
+        let strict = self.common().strict;
+        let freed = self.give_back(base, size, strict)?;
+        let common = self.common_mut();
+        common.stats.freed(freed.to_u64());
+        let range = base..base + freed;
+        notify(&mut common.observer, Event::Free { range });
+        Ok(freed)
+    }
+
+    fn free_by_base(&mut self, base: A) -> Result<A> {
+        let size = self
+            .common()
+            .allocation_at(base)
+            .filter(|(range, _)| range.start == base)
+            .map(|(range, _)| range.end - range.start)
+            .ok_or_else(|| Error::new(ErrorKind::NotAllocated))?;
+        self.free_whole(base, size)?;
+        Ok(size - self.common().guard)
+    }
+
+    fn try_grow(&mut self, base: A, old_size: A, new_size: A) -> Result<()> {
+        let Common {
+            granularity,
+            guard,
+            strict,
+            ..
+        } = *self.common();
+        let old_size = Size::new(old_size)?.round_up(granularity)?.get();
+        let new_size = Size::new(new_size)?.round_up(granularity)?.get();
+        if new_size < old_size {
+            return Err(Error::new(ErrorKind::InvalidSize));
+        }
+        // the guard moves along with the end
+        let overflow = || Error::new(ErrorKind::Overflow);
+        let old_end = base
+            .checked_add(old_size)
+            .and_then(|end| end.checked_add(guard))
+            .ok_or_else(overflow)?;
+        let new_end = base
+            .checked_add(new_size)
+            .and_then(|end| end.checked_add(guard))
+            .ok_or_else(overflow)?;
+        if let Some(allocations) = self.common().allocations.as_ref().filter(|_| strict) {
+            allocations.check_allocated(base..old_end)?;
+        }
+        if new_end == old_end {
+            return Ok(());
+        }
+
+        let region = self
+            .region_containing(base)
+            .filter(|region| region.kind == RegionKind::Usable)
+            .ok_or_else(|| Error::new(ErrorKind::NotOwned))?;
+        if new_end > region.end() {
+            return Err(Error::new(ErrorKind::NotFree));
+        }
+        self.carve(old_end, new_end - old_end)?;
+        let common = self.common_mut();
+        if let Some(allocations) = &mut common.allocations {
+            allocations.resize(base, new_end);
+        }
+        common.stats.resized(old_size.to_u64(), new_size.to_u64());
+        let (range, tag) = (old_end..new_end, &region.tag);
+        notify(&mut common.observer, Event::Alloc { range, tag });
+        Ok(())
+    }
+
+    fn shrink(&mut self, base: A, old_size: A, new_size: A) -> Result<()> {
+        let Common {
+            granularity,
+            guard,
+            strict,
+            ..
+        } = *self.common();
+        let old_size = Size::new(old_size)?.round_up(granularity)?.get();
+        let new_size = Size::new(new_size)?.round_up(granularity)?.get();
+        if new_size > old_size {
+            return Err(Error::new(ErrorKind::InvalidSize));
+        }
+        if new_size == old_size {
+            return Ok(());
+        }
+        let tail = base
+            .checked_add(new_size)
+            .and_then(|end| end.checked_add(guard))
+            .ok_or_else(|| Error::new(ErrorKind::Overflow))?;
+        let freed = self.give_back(tail, old_size - new_size, strict)?;
+        let common = self.common_mut();
+        common
+            .stats
+            .resized((new_size + freed).to_u64(), new_size.to_u64());
+        let range = tail..tail + freed;
+        notify(&mut common.observer, Event::Free { range });
+        Ok(())
+    }
+
+    fn set_tracking(&mut self, enabled: bool) {
+        let common = self.common_mut();
+        if enabled != common.allocations.is_some() {
+            common.allocations = enabled.then(Allocations::default);
+            common.strict &= enabled;
+            self.tracking_changed();
+        }
+    }
+
+    fn set_strict(&mut self, strict: bool) {
+        if strict {
+            self.set_tracking(true);
+        }
+        self.common_mut().strict = strict;
+    }
+
+    fn apply_moves(&mut self, moves: &[Move<A>]) -> Result<()> {
+        moves.iter().try_for_each(|&mv| self.apply_move(mv))
+    }
+
+    /// moves the allocation at `from` to `to`, see `apply_moves`
+    fn apply_move(&mut self, Move { from, to, size }: Move<A>) -> Result<()> {
+        let overflow = || Error::new(ErrorKind::Overflow);
+        let source = from..from.checked_add(size).ok_or_else(overflow)?;
+        to.checked_add(size).ok_or_else(overflow)?;
+        self.common().check_unpinned(source.clone())?;
+        if source.is_empty()
+            || self.overlaps_free(source.clone())
+            || self.common().reserved.overlaps(source.clone())
+        {
+            return Err(Error::new(ErrorKind::NotAllocated));
+        }
+        let tracked = self
+            .common()
+            .allocations
+            .as_ref()
+            .is_some_and(|allocations| allocations.covers(source.clone()));
+
+        let size = self.give_back(from, size, false)?;
+        let moved = self.carve(to, size).map(|()| to);
+        if moved.is_err() {
+            self.carve(from, size)
+                .expect("the source was allocated just now");
+        }
+        // on failure, the tracking goes back to the source
+        let at = *moved.as_ref().unwrap_or(&from);
+        let tag = self
+            .region_containing(at)
+            .expect("allocations are always inside a region")
+            .tag;
+        let common = self.common_mut();
+        if let Some(allocations) = common.allocations.as_mut().filter(|_| tracked) {
+            allocations.insert(at..at + size, tag.clone());
+        }
+        moved?;
+        let range = from..from + size;
+        notify(&mut common.observer, Event::Free { range });
+        let range = to..to + size;
+        notify(&mut common.observer, Event::Alloc { range, tag: &tag });
+        Ok(())
+    }
+}
+
+/// the public methods both backends share, forwarding to [`Common`] and [`Bookkeeping`]. Each
+/// arm is expanded in an impl block of a backend with its tag type `Tag` and address type `A` in
+/// scope: `constructors` where the default allocator parameters are filled in, `state` where the
+/// tag is unconstrained, `bookkeeping` where the backend implements [`Bookkeeping`], and
+/// `range_alloc` in its [`RangeAlloc`] impl
+macro_rules! shared_methods {
+    (constructors) => {
+        /// an allocator that rounds allocations to multiples of `granularity` instead of pages,
+        /// e.g. `1` for byte-granular heaps or 2 MiB for huge pages
+        pub fn with_granularity(granularity: $crate::units::Alignment<A>) -> Self {
+            let mut a = Self::default();
+            a.common.granularity = granularity;
+            a
+        }
+
+        /// an allocator for plain numbers like IDs, ports or interrupt vectors, where pages mean
+        /// nothing: sizes are used exactly as requested, without any rounding
+        pub fn exact() -> Self {
+            Self::with_granularity($crate::units::Alignment::ONE)
+        }
+    };
+    (state) => {
+        pub fn granularity(&self) -> $crate::units::Alignment<A> {
+            self.common.granularity
+        }
+
+        pub fn policy(&self) -> $crate::Policy {
+            self.common.policy
+        }
+
+        pub fn set_policy(&mut self, policy: $crate::Policy) {
+            self.common.policy = policy;
+        }
+
+        pub fn direction(&self) -> $crate::Direction {
+            self.common.direction
+        }
+
+        /// whether allocations are taken from the start or the end of free blocks, and which
+        /// blocks are searched first
+        pub fn set_direction(&mut self, direction: $crate::Direction) {
+            self.common.direction = direction;
+        }
+
+        pub fn guard(&self) -> A {
+            self.common.guard
+        }
+
+        /// keeps `guard`, rounded up to the granularity, free after every allocation, e.g. to
+        /// leave an unmapped guard page that catches overruns. The guard belongs to the
+        /// allocation, so it counts as allocated and is freed along with it, and
+        /// [`alloc_fixed`](crate::RangeAlloc::alloc_fixed) fails if it does not fit. Fails with
+        /// [`ErrorKind::NotFree`](crate::ErrorKind::NotFree) while anything is allocated, whose frees would otherwise give
+        /// back the wrong size
+        pub fn set_guard(&mut self, guard: A) -> $crate::Result<()> {
+            self.common.set_guard(guard)
+        }
+
+        pub fn limits(&self) -> $crate::Limits {
+            self.common.limits
+        }
+
+        /// caps the number of regions and free extents from now on. Fails with
+        /// [`ErrorKind::CapacityExceeded`](crate::ErrorKind::CapacityExceeded) if the allocator already has more than `limits` allow
+        pub fn set_limits(&mut self, limits: $crate::Limits) -> $crate::Result<()> {
+            let (regions, free_extents) = (self.region_count(), self.free_extent_count());
+            self.common.set_limits(limits, regions, free_extents)
+        }
+
+        /// the current epoch. It advances whenever space becomes free, and every free block
+        /// remembers the epoch in which it (or the most recent part merged into it) was freed
+        pub fn epoch(&self) -> u64 {
+            self.common.epoch
+        }
+
+        /// what each policy would pick for `request` in the current state, without allocating
+        pub fn simulate(
+            &self,
+            request: $crate::Request<A>,
+        ) -> $crate::Result<alloc::vec::Vec<($crate::Policy, Option<$crate::Placement<A>>)>> {
+            self.common.simulate(request, |request, policy| {
+                self.place(request, policy, &(A::ZERO..A::MAX)).ok()
+            })
+        }
+
+        /// calls `observer` after every successful allocation, free and `add_range` from now on,
+        /// see [`Event`](crate::Event). `None` removes the observer
+        pub fn set_observer(&mut self, observer: Option<$crate::Observer<Tag, A>>) {
+            self.common.observer = observer;
+        }
+
+        /// what the allocator did so far, see [`Stats`](crate::Stats)
+        pub fn stats(&self) -> $crate::Stats {
+            self.common.stats
+        }
+
+        /// restarts the high-water mark from what is allocated right now, e.g. at the start of
+        /// each reporting period
+        pub fn reset_peak(&mut self) {
+            self.common.reset_peak()
+        }
+
+        pub fn is_tracking(&self) -> bool {
+            self.common.allocations.is_some()
+        }
+
+        pub fn is_strict(&self) -> bool {
+            self.common.strict
+        }
+
+        /// the ranges that are currently reserved
+        pub fn reserved(&self) -> &$crate::collections::RangeSet<A> {
+            &self.common.reserved
+        }
+
+        pub fn reserved_space(&self) -> A {
+            self.common.reserved.covered()
+        }
+
+        /// the ranges that are currently pinned
+        pub fn pinned(&self) -> &$crate::collections::RangeSet<A> {
+            &self.common.pinned
+        }
+
+        /// releases a pin. Fails with [`ErrorKind::NotPinned`](crate::ErrorKind::NotPinned) unless all of `base..base + size`
+        /// is pinned
+        pub fn unpin(&mut self, base: A, size: A) -> $crate::Result<()> {
+            self.common.unpin(base, size)
+        }
+
+        /// drops the deadline of the reservation made with
+        /// [`reserve_until`](Self::reserve_until) at `base`, which then stays reserved until it
+        /// is unreserved. Fails with [`ErrorKind::NotReserved`](crate::ErrorKind::NotReserved) if there is none
+        pub fn keep_reservation(&mut self, base: A) -> $crate::Result<()> {
+            self.common.keep_reservation(base)
+        }
+
+        /// the reservations that have a deadline, and their deadlines
+        pub fn reservation_deadlines(
+            &self,
+        ) -> impl Iterator<Item = (core::ops::Range<A>, u64)> + '_ {
+            self.common.reservation_deadlines()
+        }
+
+        /// the free space in the usable region `region`, like [`space`](crate::RangeAlloc::space) for
+        /// the whole allocator. `None` if no usable region starts there
+        pub fn space_in_region(&self, region: $crate::RegionId<A>) -> Option<A> {
+            self.common.region_free.get(&region.base()).copied()
+        }
+    };
+    (bookkeeping) => {
+        /// all regions, usable and reserved, sorted by base, without cloning their tags
+        pub fn iter_regions(
+            &self,
+        ) -> impl Iterator<Item = $crate::map::RegionInfo<'_, Tag, A>> + '_ {
+            $crate::common::Bookkeeping::iter_regions(self)
+        }
+
+        /// the tag of the region `addr` belongs to, and whether the address is free, allocated
+        /// or reserved. `None` if no region contains it
+        pub fn tag_at(&self, addr: A) -> Option<(Tag, $crate::map::AddrState)> {
+            $crate::common::Bookkeeping::tag_at(self, addr)
+        }
+
+        /// adds a usable region with `holes` taken out of it, e.g. low RAM without the EBDA and
+        /// MMIO holes, so the rest becomes several free extents under one tag. The holes stay
+        /// [reserved](Self::reserve) inside the region. Fails without adding anything if a hole
+        /// is empty, lies outside the region or overlaps another one
+        pub fn add_range_with_holes(
+            &mut self,
+            base: A,
+            size: A,
+            range_tag: Tag,
+            holes: &[core::ops::Range<A>],
+        ) -> $crate::Result<()> {
+            $crate::common::Bookkeeping::add_range_with_holes(self, base, size, range_tag, holes)
+        }
+
+        /// adds a usable region like [`add_range`](crate::RangeAlloc::add_range), but merges it with
+        /// the regions directly before and after it that have the same tag, so allocations can
+        /// span them, e.g. for memory map entries split at arbitrary points. Regions with
+        /// non-default [attributes](Self::add_range_with) are never merged
+        pub fn add_range_coalescing(
+            &mut self,
+            base: A,
+            size: A,
+            range_tag: Tag,
+        ) -> $crate::Result<()>
+        where
+            Tag: PartialEq,
+        {
+            $crate::common::Bookkeeping::add_range_coalescing(self, base, size, range_tag)
+        }
+
+        /// adds every entry of a memory map, reporting the outcome per entry instead of stopping
+        /// at the first bad one. Entries are added in order, so a later entry overlapping an
+        /// earlier one is rejected
+        pub fn add_ranges<I>(&mut self, entries: I) -> alloc::vec::Vec<$crate::AddRangeResult<A>>
+        where
+            I: IntoIterator<Item = (A, A, Tag)>,
+        {
+            $crate::common::Bookkeeping::add_ranges(self, entries)
+        }
+
+        /// takes `base..base + size` out of the free space without handing it out as an
+        /// allocation. The whole range has to be free and not pinned
+        pub fn reserve(&mut self, base: A, size: A) -> $crate::Result<()> {
+            $crate::common::Bookkeeping::reserve(self, base, size)
+        }
+
+        /// returns a previously reserved range to the free space
+        pub fn unreserve(&mut self, base: A, size: A) -> $crate::Result<()> {
+            $crate::common::Bookkeeping::unreserve(self, base, size)
+        }
+
+        /// reserves `base..base + size` like [`reserve`](Self::reserve), but only until the tick
+        /// `deadline`, e.g. while a range is promised to a peer that has yet to commit.
+        /// [`expire_reservations`](Self::expire_reservations) returns it to the free space once
+        /// the deadline has passed, unless it was [kept](Self::keep_reservation) or unreserved
+        /// before
+        pub fn reserve_until(&mut self, base: A, size: A, deadline: u64) -> $crate::Result<()> {
+            $crate::common::Bookkeeping::reserve_until(self, base, size, deadline)
+        }
+
+        /// reserves the lowest `size` bytes of every region (or the whole region if it is
+        /// smaller). Nothing is reserved if any of them is not entirely free
+        pub fn reserve_bottom(&mut self, size: A) -> $crate::Result<()> {
+            $crate::common::Bookkeeping::reserve_bottom(self, size)
+        }
+
+        /// reserves the highest `size` bytes of every region (or the whole region if it is
+        /// smaller). Nothing is reserved if any of them is not entirely free
+        pub fn reserve_top(&mut self, size: A) -> $crate::Result<()> {
+            $crate::common::Bookkeeping::reserve_top(self, size)
+        }
+
+        /// reserves the lowest `size` bytes of the region starting at `region_base`
+        pub fn reserve_bottom_in(&mut self, region_base: A, size: A) -> $crate::Result<()> {
+            $crate::common::Bookkeeping::reserve_bottom_in(self, region_base, size)
+        }
+
+        /// reserves the highest `size` bytes of the region starting at `region_base`
+        pub fn reserve_top_in(&mut self, region_base: A, size: A) -> $crate::Result<()> {
+            $crate::common::Bookkeeping::reserve_top_in(self, region_base, size)
+        }
+
+        /// unreserves every reservation whose deadline is at or before `now`, and returns them.
+        /// Reservations that overlap a pinned range stay until a sweep after they were unpinned
+        pub fn expire_reservations(&mut self, now: u64) -> alloc::vec::Vec<core::ops::Range<A>> {
+            $crate::common::Bookkeeping::expire_reservations(self, now)
+        }
+
+        /// pins `base..base + size`, e.g. a buffer with DMA in flight, so administrative
+        /// operations fail with [`ErrorKind::Pinned`](crate::ErrorKind::Pinned) instead of touching it: removing its
+        /// region, reserving or unreserving any of it and freeing everything of its region's
+        /// tag. Allocating and freeing are not affected. The range has to lie in a single usable
+        /// region
+        pub fn pin(&mut self, base: A, size: A) -> $crate::Result<()> {
+            $crate::common::Bookkeeping::pin(self, base, size)
+        }
+
+        /// allocates a range that lies entirely within `window`, e.g. below 4 GiB for 32-bit DMA
+        pub fn alloc_within(
+            &mut self,
+            min_size: A,
+            alignment: A,
+            window: core::ops::Range<A>,
+        ) -> $crate::Result<(Tag, A)> {
+            self.alloc_sized_within(min_size, alignment, window)
+                .map(|(tag, range)| (tag, range.start))
+        }
+
+        /// like [`alloc`](crate::RangeAlloc::alloc), but returns the whole range that was allocated:
+        /// the size rounded up to the granularity, plus remainders too small to stay free. The
+        /// [guard](Self::set_guard) follows the range. Freeing exactly this range gives
+        /// everything back
+        pub fn alloc_sized(
+            &mut self,
+            min_size: A,
+            alignment: A,
+        ) -> $crate::Result<(Tag, core::ops::Range<A>)> {
+            self.alloc_sized_within(min_size, alignment, A::ZERO..A::MAX)
+        }
+
+        /// [`alloc_sized`](Self::alloc_sized) within `window`, see
+        /// [`alloc_within`](Self::alloc_within)
+        pub fn alloc_sized_within(
+            &mut self,
+            min_size: A,
+            alignment: A,
+            window: core::ops::Range<A>,
+        ) -> $crate::Result<(Tag, core::ops::Range<A>)> {
+            $crate::common::Bookkeeping::alloc_sized_within(self, min_size, alignment, window)
+        }
+
+        /// extends the allocation `base..base + old_size` to `new_size` without moving it, by
+        /// taking the free space directly after it. Fails without changing anything if that
+        /// space is not free or lies outside the allocation's region. In
+        /// [strict](Self::set_strict) mode it also fails with
+        /// [`ErrorKind::NotAllocated`](crate::ErrorKind::NotAllocated) for a range that was
+        /// never handed out
+        pub fn try_grow(&mut self, base: A, old_size: A, new_size: A) -> $crate::Result<()> {
+            $crate::common::Bookkeeping::try_grow(self, base, old_size, new_size)
+        }
+
+        /// shrinks the allocation `base..base + old_size` to `new_size`, returning the tail to
+        /// the free space
+        pub fn shrink(&mut self, base: A, old_size: A, new_size: A) -> $crate::Result<()> {
+            $crate::common::Bookkeeping::shrink(self, base, old_size, new_size)
+        }
+
+        /// starts or stops remembering the live allocations, for
+        /// [`iter_allocated`](Self::iter_allocated) and [`allocation_at`](Self::allocation_at).
+        /// Only allocations made while tracking is enabled are known. How tracking changes the
+        /// cost of freeing is part of the backend's [`COMPLEXITY`](Self::COMPLEXITY)
+        pub fn set_tracking(&mut self, enabled: bool) {
+            $crate::common::Bookkeeping::set_tracking(self, enabled)
+        }
+
+        /// rejects frees of ranges that were never handed out with [`ErrorKind::NotAllocated`](crate::ErrorKind::NotAllocated)
+        /// instead of adding them to the free space. This needs [tracking](Self::set_tracking),
+        /// which is enabled along with it and disabling it ends strict mode too. Allocations
+        /// made before tracking was enabled are not known and can not be freed then, so this is
+        /// best set before the first allocation. Frees of ranges that are already free fail with
+        /// [`ErrorKind::DoubleFree`](crate::ErrorKind::DoubleFree) in any mode
+        pub fn set_strict(&mut self, strict: bool) {
+            $crate::common::Bookkeeping::set_strict(self, strict)
+        }
+
+        /// the tracked allocations in ascending order, with the tag of the region they belong to
+        pub fn iter_allocated(&self) -> impl Iterator<Item = (core::ops::Range<A>, &Tag)> + '_ {
+            self.common.iter_allocated()
+        }
+
+        /// the tracked allocation containing `addr`
+        pub fn allocation_at(&self, addr: A) -> Option<(core::ops::Range<A>, &Tag)> {
+            self.common.allocation_at(addr)
+        }
+
+        /// frees the tracked allocation starting at `base` with the size it was handed out with,
+        /// rounding included, and returns that size without the [guard](Self::set_guard). Fails
+        /// with [`ErrorKind::NotAllocated`](crate::ErrorKind::NotAllocated) if no tracked allocation starts there, e.g. because
+        /// [tracking](Self::set_tracking) is disabled
+        pub fn free_by_base(&mut self, base: A) -> $crate::Result<A> {
+            $crate::common::Bookkeeping::free_by_base(self, base)
+        }
+
+        /// takes the allocator apart into plain data, see [`raw`](crate::raw)
+        pub fn into_raw_parts(self) -> $crate::raw::RawParts<Tag, A> {
+            $crate::common::Bookkeeping::raw_parts(&self)
+        }
+
+        /// the state of the allocator as plain data, to persist in a format of your choosing
+        /// while the allocator carries on. [`restore`](Self::restore) rebuilds it
+        pub fn snapshot(&self) -> $crate::raw::Snapshot<Tag, A> {
+            $crate::common::Bookkeeping::raw_parts(self)
+        }
+
+        /// draws the regions, free extents and, if [tracked](Self::set_tracking), allocations as
+        /// a graphviz graph, e.g. to look at fragmentation with `dot -Tsvg`
+        pub fn dump_dot(&self, w: impl core::fmt::Write) -> core::fmt::Result
+        where
+            Tag: core::fmt::Debug,
+        {
+            $crate::common::Bookkeeping::raw_parts(self).write_dot(w)
+        }
+
+        /// the moves that would coalesce the free space without performing them, for callers
+        /// that move the data themselves, see [`compaction`](crate::compaction)
+        pub fn plan_compaction(&self) -> alloc::vec::Vec<$crate::compaction::Move<A>> {
+            $crate::compaction::plan(&$crate::common::Bookkeeping::raw_parts(self))
+        }
+
+        /// updates the bookkeeping once the data was moved as `moves` say, one move after the
+        /// other: what was allocated at `from` is allocated at `to` from then on, stays tracked
+        /// if it was and takes the tag of the region it ends up in. Stops at the first move that
+        /// fails, with [`ErrorKind::NotAllocated`](crate::ErrorKind::NotAllocated) if its source is not allocated,
+        /// [`ErrorKind::Pinned`](crate::ErrorKind::Pinned) if it is pinned or the error of taking its destination, and the
+        /// moves before it stay applied
+        pub fn apply_moves(&mut self, moves: &[$crate::compaction::Move<A>]) -> $crate::Result<()> {
+            $crate::common::Bookkeeping::apply_moves(self, moves)
+        }
+    };
+    (range_alloc) => {
+        /// allocates a range. The range will not be handed out again until it has been freed
+        fn alloc(&mut self, min_size: A, alignment: A) -> $crate::Result<(Tag, A)> {
+            self.alloc_within(min_size, alignment, A::ZERO..A::MAX)
+        }
+
+        fn alloc_within(
+            &mut self,
+            min_size: A,
+            alignment: A,
+            window: core::ops::Range<A>,
+        ) -> $crate::Result<(Tag, A)> {
+            self.alloc_within(min_size, alignment, window)
+        }
+
+        /// allocates the range at the given base address, which has to be aligned to the
+        /// granularity. The size is rounded up to a multiple of the granularity. Fails if any
+        /// part of it is not free
+        fn alloc_fixed(&mut self, base: A, size: A) -> $crate::Result<(Tag, A)> {
+            $crate::common::Bookkeeping::alloc_at(self, base, size)
+        }
+
+        /// frees a previously handed out range. `size` may be the size that was requested, it is
+        /// rounded up the same way the allocation was
+        fn free(&mut self, base: A, size: A) -> $crate::Result<()> {
+            let size = self.common.whole_size(size)?;
+            $crate::common::Bookkeeping::free_whole(self, base, size)?;
+            Ok(())
+        }
+
+        fn space(&self) -> A {
+            self.common.free_space
+        }
+
+        fn total_space(&self) -> A {
+            self.common.total_space
+        }
+
+        /// reserved ranges are not free, but do not count as allocations either
+        fn is_empty(&self) -> bool {
+            self.space() + self.reserved_space() == self.total_space()
+        }
+    };
+}
+pub(crate) use shared_methods;
@@ -0,0 +1,94 @@
+//! planning moves that coalesce the free space, for callers that can move their data themselves
+//!
+//! `plan_compaction` on the backends slides allocations towards the start of their region, into
+//! the free space before them, and returns the [`Move`]s that would do so without performing them.
+//! Whoever owns the data, e.g. a GPU engine, carries them out in order and then hands them to
+//! `apply_moves`, which updates the bookkeeping to match.
+//!
+//! with tracking enabled, every tracked allocation moves on its own and everything else that is
+//! allocated stays put. Without it, each run of allocated space between free extents moves as a
+//! whole. Reserved and pinned ranges never move, and nothing moves across them or into another
+//! region.
+//!
+//! the original alignment of an allocation is not known, so a move keeps it aligned to the
+//! largest power of two its base is aligned to, up to its size rounded up to a power of two.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::{address::Address, collections::RangeSet, map::RegionKind, raw::RawParts};
+
+/// moving `size` bytes from `from` down to `to`. The two ranges may overlap, so the data has to be
+/// moved like `memmove` does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Move<A = usize> {
+    pub from: A,
+    pub to: A,
+    pub size: A,
+}
+
+/// the alignment a unit at `base` keeps when it moves, see the module docs
+fn kept_alignment<A: Address>(base: A, size: A) -> u64 {
+    let natural = 1u64
+        .checked_shl(base.to_u64().trailing_zeros())
+        .unwrap_or(1 << 63);
+    natural.min(size.to_u64().next_power_of_two())
+}
+
+/// the moves that slide what can move in each usable region of `parts` towards its start
+pub(crate) fn plan<Tag, A: Address>(parts: &RawParts<Tag, A>) -> Vec<Move<A>> {
+    let free: RangeSet<A> = parts.free.iter().map(|(range, _)| range.clone()).collect();
+    let reserved: RangeSet<A> = parts.reserved.iter().cloned().collect();
+    let pinned: RangeSet<A> = parts.pinned.iter().cloned().collect();
+
+    let mut moves = Vec::new();
+    for region in &parts.regions {
+        if region.kind != RegionKind::Usable {
+            continue;
+        }
+        let range = region.base..region.end();
+        let whole = RangeSet::from_iter([range.clone()]);
+        let inside = |unit: &Range<A>| range.start <= unit.start && unit.end <= range.end;
+        let units: Vec<Range<A>> = match &parts.allocations {
+            Some(allocations) => allocations
+                .iter()
+                .map(|(unit, _)| unit.clone())
+                .filter(|unit| inside(unit))
+                .collect(),
+            None => whole.subtract(&free).subtract(&reserved).iter().collect(),
+        };
+        let units: Vec<Range<A>> = units
+            .into_iter()
+            .filter(|unit| !pinned.overlaps(unit.clone()))
+            .collect();
+        // everything else that is not free stays where it is
+        let movable: RangeSet<A> = units.iter().cloned().collect();
+        let fixed = whole.subtract(&free).subtract(&movable);
+        let mut fixed = fixed.iter().peekable();
+
+        // `at` is where the next unit can go, past everything before it that stays
+        let mut at = range.start;
+        for unit in units {
+            while let Some(before) = fixed.next_if(|fixed| fixed.start < unit.start) {
+                at = at.max(before.end);
+            }
+            let size = unit.end - unit.start;
+            let to = A::try_from(kept_alignment(unit.start, size))
+                .ok()
+                .and_then(|alignment| at.round_up(alignment))
+                .filter(|&to| to < unit.start);
+            match to {
+                Some(to) => {
+                    moves.push(Move {
+                        from: unit.start,
+                        to,
+                        size,
+                    });
+                    at = to + size;
+                }
+                None => at = unit.end,
+            }
+        }
+    }
+    moves
+}
@@ -0,0 +1,173 @@
+//! a drop-in for the gfx-rs `range-alloc` crate
+//!
+//! [`RangeAllocator`] has the API of `range_alloc::RangeAllocator`, so code written against it
+//! only has to change its imports. Underneath is the linear backend in
+//! [exact mode](crate::RangeAllocator::exact) with the best-fit policy, which is what the
+//! original does too. Like the original, misuse in [`free_range`](RangeAllocator::free_range)
+//! and [`grow_to`](RangeAllocator::grow_to) panics.
+
+use alloc::vec::Vec;
+use core::{fmt, ops::Range};
+
+use crate::{Policy, RangeAlloc, address::Address, linear};
+
+/// why [`allocate_range`](RangeAllocator::allocate_range) failed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeAllocationError<T> {
+    /// all the free space, which is not enough or split into pieces that are too small
+    pub fragmented_free_length: T,
+}
+
+impl<T: fmt::Debug> fmt::Display for RangeAllocationError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "no free range fits, {:?} free in total",
+            self.fragmented_free_length
+        )
+    }
+}
+
+/// hands out ranges of `T` from a single range, see the [module](self) docs
+pub struct RangeAllocator<T> {
+    inner: linear::RangeAllocator<(), T>,
+    initial_range: Range<T>,
+}
+
+impl<T: Address> RangeAllocator<T> {
+    /// an allocator with all of `range` free
+    pub fn new(range: Range<T>) -> Self {
+        let mut inner = linear::RangeAllocator::exact();
+        inner.set_policy(Policy::BestFit);
+        inner.set_tracking(true);
+        if range.start < range.end {
+            inner
+                .add_range(range.start, range.end - range.start, ())
+                .expect("a fresh allocator takes any range");
+        }
+        RangeAllocator {
+            inner,
+            initial_range: range,
+        }
+    }
+
+    /// the range everything is allocated from, including what [`grow_to`](Self::grow_to) added
+    pub fn initial_range(&self) -> &Range<T> {
+        &self.initial_range
+    }
+
+    /// extends the range to end at `new_end`, with the new part free
+    ///
+    /// # Panics
+    ///
+    /// if `new_end` lies before the current end
+    pub fn grow_to(&mut self, new_end: T) {
+        let Range { start, end } = self.initial_range;
+        assert!(new_end >= end, "can not shrink to {new_end:?}");
+        let grown = if start < end {
+            self.inner.grow_region(start, new_end - start)
+        } else if start < new_end {
+            self.inner.add_range(start, new_end - start, ())
+        } else {
+            Ok(())
+        };
+        grown.expect("nothing else lies after the range");
+        self.initial_range.end = new_end;
+    }
+
+    /// allocates `length` from the smallest free range it fits in
+    pub fn allocate_range(&mut self, length: T) -> Result<Range<T>, RangeAllocationError<T>> {
+        match self.inner.alloc(length, T::ONE) {
+            Ok((_, base)) => Ok(base..base + length),
+            Err(_) => Err(RangeAllocationError {
+                fragmented_free_length: self.total_available(),
+            }),
+        }
+    }
+
+    /// frees `range`, which may be any part of what was allocated
+    ///
+    /// # Panics
+    ///
+    /// if `range` is empty, lies outside [`initial_range`](Self::initial_range) or is already
+    /// partly free
+    pub fn free_range(&mut self, range: Range<T>) {
+        assert!(
+            self.initial_range.start <= range.start && range.end <= self.initial_range.end,
+            "{range:?} lies outside the allocator's range"
+        );
+        assert!(range.start < range.end, "can not free the empty range");
+        self.inner
+            .free(range.start, range.end - range.start)
+            .expect("only allocated ranges can be freed");
+    }
+
+    /// the allocated ranges in ascending order, with touching allocations merged
+    pub fn allocated_ranges(&self) -> impl Iterator<Item = Range<T>> + '_ {
+        let mut ranges: Vec<Range<T>> = Vec::new();
+        for (range, _) in self.inner.iter_allocated() {
+            match ranges.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => ranges.push(range),
+            }
+        }
+        ranges.into_iter()
+    }
+
+    /// frees everything
+    pub fn reset(&mut self) {
+        self.inner
+            .free_all_with_tag(&())
+            .expect("nothing is pinned");
+    }
+
+    /// whether nothing is allocated
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// the free space in total
+    pub fn total_available(&self) -> T {
+        self.inner.space()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{RangeAllocationError, RangeAllocator};
+
+    #[test]
+    fn gfx_api() {
+        let mut alloc = RangeAllocator::new(0..10u64);
+        assert_eq!(alloc.allocate_range(4), Ok(0..4));
+        assert_eq!(alloc.allocate_range(2), Ok(4..6));
+        assert_eq!(alloc.allocate_range(2), Ok(6..8));
+        alloc.free_range(4..6);
+        // best fit takes the hole, not the range at the end
+        assert_eq!(alloc.allocate_range(1), Ok(4..5));
+        let ranges: Vec<_> = alloc.allocated_ranges().collect();
+        assert_eq!(ranges, [0..5, 6..8]);
+        assert_eq!(
+            alloc.allocate_range(3),
+            Err(RangeAllocationError {
+                fragmented_free_length: 3
+            })
+        );
+
+        alloc.grow_to(12);
+        assert_eq!(alloc.allocate_range(3), Ok(8..11));
+        assert_eq!(alloc.initial_range(), &(0..12));
+        alloc.free_range(1..3);
+        assert_eq!(alloc.total_available(), 4);
+        alloc.reset();
+        assert!(alloc.is_empty());
+        assert_eq!(alloc.allocated_ranges().count(), 0);
+
+        let mut alloc = RangeAllocator::new(5..5u32);
+        assert!(alloc.allocate_range(1).is_err());
+        alloc.grow_to(8);
+        assert_eq!(alloc.allocate_range(3), Ok(5..8));
+    }
+}
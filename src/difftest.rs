@@ -0,0 +1,273 @@
+//! feeding the same operations to several allocators and checking each against a model
+//!
+//! [`DiffTest`] runs a stream of [`Op`]s, e.g. from a fuzzer, against every allocator added to
+//! it. Backends may place allocations differently, so each one is checked on its own against a
+//! model of the ranges it handed out: allocations lie inside the regions, are aligned and never
+//! overlap, fixed allocations succeed exactly when their range is free, frees succeed and
+//! `space` matches what is not allocated. [`DiffTest::with_backends`] starts with the crate's
+//! backends, so a custom one only has to be added.
+//!
+//! ```ignore
+//! let mut d = DiffTest::with_backends(&[(0x10_0000, 0x40_0000)])?;
+//! d.add("mine", MyAllocator::new())?;
+//! for op in ops {
+//!     d.apply(op)?;
+//! }
+//! ```
+
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::{fmt, ops::Range};
+
+use crate::{Error, RangeAlloc, address::Address, btree, linear, safe};
+
+/// an operation, in pages so any stream of numbers is a valid one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// `pages` pages, at least one, aligned to `1 << align_order` pages. Orders above 16 count
+    /// as 16
+    Alloc { pages: u32, align_order: u8 },
+    /// `pages` pages, at least one, starting `page` pages after the base of the first region
+    AllocFixed { page: u64, pages: u32 },
+    /// the `pick`-th live allocation of every allocator, modulo their number. Nothing if there
+    /// are none
+    Free { pick: usize },
+}
+
+/// how an allocator disagreed with its model
+#[derive(Debug)]
+pub enum MismatchKind<A = usize> {
+    /// adding the regions failed
+    AddFailed(Error),
+    /// the allocation overlaps a live one
+    Overlap(Range<A>),
+    /// the allocation is not inside a region
+    OutsideRegions(Range<A>),
+    Misaligned(Range<A>),
+    /// a fixed allocation of a free range failed
+    FixedRefused(Error),
+    /// a fixed allocation of a range that is not free succeeded
+    FixedAccepted,
+    /// a fixed allocation succeeded, but somewhere else
+    Moved(A),
+    FreeFailed(Error),
+    /// `space` is not what the model has left free
+    Space {
+        expected: A,
+        actual: A,
+    },
+}
+
+/// an allocator that disagreed with its model after `op`
+#[derive(Debug)]
+pub struct Mismatch<A = usize> {
+    /// the name it was added with
+    pub name: &'static str,
+    pub op: Option<Op>,
+    pub kind: MismatchKind<A>,
+}
+
+impl<A: Address> fmt::Display for Mismatch<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(op) = self.op {
+            write!(f, " after {op:?}")?;
+        }
+        match &self.kind {
+            MismatchKind::AddFailed(e) => write!(f, ": cannot add regions: {e}"),
+            MismatchKind::Overlap(r) => write!(f, ": {r:x?} overlaps a live allocation"),
+            MismatchKind::OutsideRegions(r) => write!(f, ": {r:x?} is outside the regions"),
+            MismatchKind::Misaligned(r) => write!(f, ": {r:x?} is misaligned"),
+            MismatchKind::FixedRefused(e) => write!(f, ": refused a free range: {e}"),
+            MismatchKind::FixedAccepted => write!(f, ": allocated a range that is not free"),
+            MismatchKind::Moved(base) => write!(f, ": fixed allocation moved to {base:#x}"),
+            MismatchKind::FreeFailed(e) => write!(f, ": cannot free: {e}"),
+            MismatchKind::Space { expected, actual } => {
+                write!(f, ": {actual:#x} free instead of {expected:#x}")
+            }
+        }
+    }
+}
+
+impl<A: Address> core::error::Error for Mismatch<A> {}
+
+/// an allocator under test and the model of what it handed out
+struct Subject<'a, Tag, A> {
+    name: &'static str,
+    alloc: Box<dyn RangeAlloc<A, Tag = Tag> + 'a>,
+    /// the live allocations in the order `Op::Free` picks from
+    live: Vec<Range<A>>,
+    /// the live allocations by base, as their end
+    used: BTreeMap<A, A>,
+    free: A,
+}
+
+impl<Tag, A: Address> Subject<'_, Tag, A> {
+    fn overlaps(&self, range: &Range<A>) -> bool {
+        self.used
+            .range(..range.end)
+            .next_back()
+            .is_some_and(|(_, &end)| end > range.start)
+    }
+
+    fn allocated(&mut self, range: Range<A>) {
+        self.free -= range.end - range.start;
+        self.used.insert(range.start, range.end);
+        self.live.push(range);
+    }
+}
+
+/// allocators receiving the same operations, each checked against its own model
+pub struct DiffTest<'a, Tag = (), A = usize> {
+    /// the regions every allocator gets, sorted by base
+    regions: Vec<Range<A>>,
+    subjects: Vec<Subject<'a, Tag, A>>,
+}
+
+impl<'a, Tag: Default + 'a, A: Address> DiffTest<'a, Tag, A> {
+    /// a test without allocators, which get the regions given as base and size. They must be
+    /// page-aligned and must not overlap
+    pub fn new(regions: &[(A, A)]) -> Self {
+        let mut regions: Vec<_> = regions
+            .iter()
+            .map(|&(base, size)| base..base + size)
+            .collect();
+        regions.sort_by_key(|region| region.start);
+        DiffTest {
+            regions,
+            subjects: Vec::new(),
+        }
+    }
+
+    /// adds `a`, which must not have any regions yet, under `name` and gives it the regions
+    pub fn add(
+        &mut self,
+        name: &'static str,
+        mut a: impl RangeAlloc<A, Tag = Tag> + 'a,
+    ) -> Result<(), Mismatch<A>> {
+        for region in &self.regions {
+            a.add_range(region.start, region.end - region.start, Tag::default())
+                .map_err(|e| Mismatch {
+                    name,
+                    op: None,
+                    kind: MismatchKind::AddFailed(e),
+                })?;
+        }
+        self.subjects.push(Subject {
+            name,
+            alloc: Box::new(a),
+            live: Vec::new(),
+            used: BTreeMap::new(),
+            free: self.regions.iter().map(|r| r.end - r.start).sum(),
+        });
+        Ok(())
+    }
+
+    /// the allocators added so far, by name
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.subjects.iter().map(|s| s.name)
+    }
+
+    /// runs `op` against every allocator and checks each against its model. Operations whose
+    /// addresses do not fit `A` do nothing
+    pub fn apply(&mut self, op: Op) -> Result<(), Mismatch<A>> {
+        let page = |n: u64| A::try_from(n.checked_mul(A::BASE_PAGE.to_u64())?).ok();
+        for s in &mut self.subjects {
+            let name = s.name;
+            let mismatch = |kind| Mismatch {
+                name,
+                op: Some(op),
+                kind,
+            };
+            match op {
+                Op::Alloc { pages, align_order } => {
+                    let (Some(size), Some(alignment)) =
+                        (page(pages.max(1).into()), page(1 << align_order.min(16)))
+                    else {
+                        return Ok(());
+                    };
+                    let Ok((_, base)) = s.alloc.alloc(size, alignment) else {
+                        continue;
+                    };
+                    let range = base..base + size;
+                    let inside = self
+                        .regions
+                        .iter()
+                        .any(|r| r.start <= base && range.end <= r.end);
+                    if !inside {
+                        return Err(mismatch(MismatchKind::OutsideRegions(range)));
+                    } else if base.round_down(alignment) != base {
+                        return Err(mismatch(MismatchKind::Misaligned(range)));
+                    } else if s.overlaps(&range) {
+                        return Err(mismatch(MismatchKind::Overlap(range)));
+                    }
+                    s.allocated(range);
+                }
+                Op::AllocFixed {
+                    page: offset,
+                    pages,
+                } => {
+                    let first = self.regions.first().map_or(A::ZERO, |r| r.start);
+                    let (Some(offset), Some(size)) = (page(offset), page(pages.max(1).into()))
+                    else {
+                        return Ok(());
+                    };
+                    let Some(range) = first
+                        .checked_add(offset)
+                        .and_then(|base| Some(base..base.checked_add(size)?))
+                    else {
+                        return Ok(());
+                    };
+                    let free = !s.overlaps(&range)
+                        && self
+                            .regions
+                            .iter()
+                            .any(|r| r.start <= range.start && range.end <= r.end);
+                    match s.alloc.alloc_fixed(range.start, size) {
+                        Err(e) if free => return Err(mismatch(MismatchKind::FixedRefused(e))),
+                        Err(_) => {}
+                        Ok(_) if !free => return Err(mismatch(MismatchKind::FixedAccepted)),
+                        Ok((_, base)) if base != range.start => {
+                            return Err(mismatch(MismatchKind::Moved(base)));
+                        }
+                        Ok(_) => s.allocated(range),
+                    }
+                }
+                Op::Free { pick } => {
+                    if s.live.is_empty() {
+                        continue;
+                    }
+                    let range = s.live.swap_remove(pick % s.live.len());
+                    s.used.remove(&range.start);
+                    s.free += range.end - range.start;
+                    s.alloc
+                        .free(range.start, range.end - range.start)
+                        .map_err(|e| mismatch(MismatchKind::FreeFailed(e)))?;
+                }
+            }
+            let actual = s.alloc.space();
+            if actual != s.free {
+                return Err(mismatch(MismatchKind::Space {
+                    expected: s.free,
+                    actual,
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// [`apply`](Self::apply)s every operation, stopping at the first mismatch
+    pub fn run(&mut self, ops: impl IntoIterator<Item = Op>) -> Result<(), Mismatch<A>> {
+        ops.into_iter().try_for_each(|op| self.apply(op))
+    }
+}
+
+impl<'a, Tag: Default + Clone + fmt::Debug + 'a, A: Address> DiffTest<'a, Tag, A> {
+    /// a test of the linear, btree and safe backends with default settings
+    pub fn with_backends(regions: &[(A, A)]) -> Result<Self, Mismatch<A>> {
+        let mut d = DiffTest::new(regions);
+        d.add("linear", linear::RangeAllocator::<Tag, A>::default())?;
+        d.add("btree", btree::RangeAllocator::<Tag, A>::default())?;
+        d.add("safe", safe::RangeAllocator::<Tag, A>::default())?;
+        Ok(d)
+    }
+}
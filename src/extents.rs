@@ -0,0 +1,199 @@
+//! writing a new backend from a few primitives
+//!
+//! a backend only has to keep the free extents, see [`ExtentStore`]: list them, split an
+//! allocation off one, and put a freed range back. [`Extents`] builds a complete [`RangeAlloc`]
+//! on top of that, with the regions and their tags, argument checks, alignment, windows, fixed
+//! allocations and the space accounting, so experiments like buddy, TLSF or bitmap stores start
+//! from a working allocator. Placement is first-fit in the order the store lists its extents,
+//! unless the store overrides [`place`](ExtentStore::place) with a faster or smarter search.
+
+#![forbid(unsafe_code)]
+
+use alloc::collections::BTreeMap;
+use core::ops::Range;
+
+use crate::{
+    Direction, Error, ErrorKind, Placement, RangeAlloc, Request, Result,
+    address::Address,
+    units::{Alignment, Size},
+};
+
+/// the free extents of a backend, the part [`Extents`] can not do for it
+pub trait ExtentStore<A: Address = usize> {
+    /// the free extents, in the order they are tried for an allocation
+    fn free_extents(&self) -> impl Iterator<Item = Range<A>> + '_;
+
+    /// takes `range` out of the free space. It lies within a single free extent, which is split
+    /// around it
+    fn split(&mut self, range: Range<A>);
+
+    /// returns `range` to the free space. It does not overlap any free extent, and lies within
+    /// `region`, so it may only be merged with free extents inside `region`
+    fn insert(&mut self, range: Range<A>, region: Range<A>);
+
+    /// the base of `size` free bytes aligned to `alignment` that lie within `window`, both powers
+    /// of two and multiples of the granularity. Tries the free extents in order by default
+    fn place(&self, size: A, alignment: A, window: &Range<A>) -> Option<A> {
+        self.free_extents().find_map(|extent| {
+            Placement::within_window(
+                extent,
+                window,
+                alignment,
+                size,
+                Alignment::ONE,
+                Direction::BottomUp,
+            )
+            .map(|placement| placement.base)
+        })
+    }
+}
+
+/// a [`RangeAlloc`] over an [`ExtentStore`]
+pub struct Extents<S, Tag, A = usize> {
+    store: S,
+    /// base -> end and tag of every region
+    regions: BTreeMap<A, (A, Tag)>,
+    granularity: Alignment<A>,
+    total_space: A,
+    free_space: A,
+}
+
+impl<S: ExtentStore<A>, Tag: Clone, A: Address> Extents<S, Tag, A> {
+    /// allocations are rounded to whole pages, like with the built-in backends
+    pub fn new(store: S) -> Self {
+        Self::with_granularity(store, Alignment::BASE_PAGE)
+    }
+
+    /// every allocation is rounded up to a multiple of `granularity`
+    pub fn with_granularity(store: S, granularity: Alignment<A>) -> Self {
+        Extents {
+            store,
+            regions: BTreeMap::new(),
+            granularity,
+            total_space: A::ZERO,
+            free_space: A::ZERO,
+        }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    /// the region containing all of `range`
+    fn region_of(&self, range: &Range<A>) -> Option<(Range<A>, &Tag)> {
+        self.regions
+            .range(..=range.start)
+            .next_back()
+            .filter(|&(_, &(end, _))| range.end <= end)
+            .map(|(&base, (end, tag))| (base..*end, tag))
+    }
+
+    /// rounds `size` up to the granularity and checks that `base..base + size` fits
+    fn range(&self, base: A, size: A) -> Result<Range<A>> {
+        let size = Size::new(size)?.round_up(self.granularity)?.get();
+        let end = base
+            .checked_add(size)
+            .ok_or(Error::new(ErrorKind::Overflow))?;
+        Ok(base..end)
+    }
+
+    /// hands out `range`, which lies within a free extent
+    fn take(&mut self, range: Range<A>) -> (Tag, A) {
+        let (_, tag) = self
+            .region_of(&range)
+            .expect("free extents are inside regions");
+        let tag = tag.clone();
+        self.free_space -= range.end - range.start;
+        let base = range.start;
+        self.store.split(range);
+        (tag, base)
+    }
+}
+
+impl<S: ExtentStore<A> + Default, Tag: Clone, A: Address> Default for Extents<S, Tag, A> {
+    fn default() -> Self {
+        Self::new(S::default())
+    }
+}
+
+impl<S: ExtentStore<A>, Tag: Clone, A: Address> RangeAlloc<A> for Extents<S, Tag, A> {
+    type Tag = Tag;
+
+    fn add_range(&mut self, base: A, size: A, range_tag: Tag) -> Result<()> {
+        let end = base
+            .checked_add(Size::new(size)?.get())
+            .ok_or(Error::new(ErrorKind::Overflow))?;
+        let range = base..end;
+        let overlaps = self
+            .regions
+            .range(..range.end)
+            .next_back()
+            .is_some_and(|(_, &(end, _))| end > range.start);
+        if overlaps {
+            return Err(Error::new(ErrorKind::OverlappingRange));
+        }
+        self.regions.insert(base, (range.end, range_tag));
+        self.store.insert(range.clone(), range);
+        self.total_space += size;
+        self.free_space += size;
+        Ok(())
+    }
+
+    fn alloc(&mut self, min_size: A, alignment: A) -> Result<(Tag, A)> {
+        self.alloc_within(min_size, alignment, A::ZERO..A::MAX)
+    }
+
+    fn alloc_within(&mut self, min_size: A, alignment: A, window: Range<A>) -> Result<(Tag, A)> {
+        let request = Request::new(min_size, alignment).normalized(self.granularity)?;
+        let alignment = request.alignment.max(self.granularity.get());
+        let base = self
+            .store
+            .place(request.size, alignment, &window)
+            .ok_or(Error::new(ErrorKind::OutOfSpace))?;
+        Ok(self.take(base..base + request.size))
+    }
+
+    fn alloc_fixed(&mut self, base: A, size: A) -> Result<(Tag, A)> {
+        if !self.granularity.is_aligned(base) {
+            return Err(Error::new(ErrorKind::InvalidAlignment));
+        }
+        let range = self.range(base, size)?;
+        if !self
+            .store
+            .free_extents()
+            .any(|extent| extent.start <= range.start && range.end <= extent.end)
+        {
+            return Err(Error::new(ErrorKind::NotFree));
+        }
+        Ok(self.take(range))
+    }
+
+    fn free(&mut self, base: A, size: A) -> Result<()> {
+        let range = self.range(base, size)?;
+        let Some((region, _)) = self.region_of(&range) else {
+            return Err(Error::new(ErrorKind::NotOwned));
+        };
+        if self
+            .store
+            .free_extents()
+            .any(|extent| extent.start < range.end && range.start < extent.end)
+        {
+            return Err(Error::new(ErrorKind::DoubleFree));
+        }
+        self.free_space += range.end - range.start;
+        self.store.insert(range, region);
+        Ok(())
+    }
+
+    fn total_space(&self) -> A {
+        self.total_space
+    }
+
+    fn space(&self) -> A {
+        self.free_space
+    }
+}
@@ -0,0 +1,72 @@
+//! a process-wide allocator, initialized once
+//!
+//! this is the plumbing kernels usually write for their frame allocator: a `static` instance of
+//! the default backend behind a [`SharedRangeAllocator`], filled in from the memory map during
+//! boot and used from everywhere afterwards.
+
+use crate::{Error, ErrorKind, RangeAlloc, RangeAllocator, Result, shared::SharedRangeAllocator};
+
+static GLOBAL: SharedRangeAllocator<Option<RangeAllocator<()>>> = SharedRangeAllocator::new(None);
+
+/// sets up the global allocator with the given `(base, size)` regions. Fails with
+/// [`ErrorKind::AlreadyInitialized`] if it was set up before, or with the error of the first region
+/// that could not be added, in which case the global allocator stays uninitialized
+pub fn init(regions: impl IntoIterator<Item = (usize, usize)>) -> Result<()> {
+    let mut global = GLOBAL.lock();
+    if global.is_some() {
+        return Err(Error::new(ErrorKind::AlreadyInitialized));
+    }
+    let mut allocator = RangeAllocator::new();
+    for (base, size) in regions {
+        allocator.add_range(base, size, ())?;
+    }
+    *global = Some(allocator);
+    Ok(())
+}
+
+pub fn is_initialized() -> bool {
+    GLOBAL.lock().is_some()
+}
+
+/// runs `f` with the global allocator locked. Panics if [`init`] has not been called
+pub fn with<T>(f: impl FnOnce(&mut RangeAllocator<()>) -> T) -> T {
+    let mut global = GLOBAL.lock();
+    f(global
+        .as_mut()
+        .expect("the global allocator has not been initialized"))
+}
+
+/// like [`with`], but returns `None` instead of waiting if the allocator is locked, and instead
+/// of panicking if it is not initialized. Safe to call from interrupt handlers
+pub fn try_with<T>(f: impl FnOnce(&mut RangeAllocator<()>) -> T) -> Option<T> {
+    let mut global = GLOBAL.try_lock()?;
+    global.as_mut().map(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ErrorKind, RangeAlloc};
+
+    // the global allocator is shared by the whole test binary, so everything is in one test
+    #[test]
+    fn init_once() {
+        assert!(!super::is_initialized());
+        assert!(super::try_with(|a| a.space()).is_none());
+        assert!(super::init([(0x1000, 0x4000), (0x2000, 0x1000)]).is_err());
+        assert!(!super::is_initialized());
+
+        super::init([(0x1000, 0x4000), (0x10000, 0x1000)]).expect("can initialize");
+        assert_eq!(
+            super::init([]).unwrap_err().kind(),
+            ErrorKind::AlreadyInitialized
+        );
+        let (_, x) = super::with(|a| a.alloc(0x1000, 0x1000)).expect("can allocate");
+        assert_eq!(super::try_with(|a| a.space()), Some(0x4000));
+        super::with(|a| {
+            // a nested access does not deadlock, it just fails
+            assert!(super::try_with(|_| ()).is_none());
+            a.free(x, 0x1000)
+        })
+        .expect("can free");
+    }
+}
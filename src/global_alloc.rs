@@ -0,0 +1,194 @@
+//! using a backend as the Rust heap
+//!
+//! [`LockedAlloc`] puts a backend behind a [`SharedRangeAllocator`] and implements
+//! [`GlobalAlloc`] on top of it, e.g. for an early-boot kernel heap:
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static HEAP: LockedAlloc<RangeAllocator<()>> = LockedAlloc::new();
+//!
+//! let mut heap = RangeAllocator::exact();
+//! heap.add_range(heap_start, heap_size, ())?;
+//! HEAP.init(heap)?;
+//! ```
+//!
+//! every layout is passed on as a size and an alignment, so the backend should not round
+//! allocations to pages, see [`RangeAllocator::exact`](crate::RangeAllocator::exact). The
+//! backend's regions must not contain address 0, which would read as a failed allocation.
+//!
+//! with the `allocator-api` feature, which needs a nightly compiler, [`LockedAlloc`] also
+//! implements the unstable `Allocator` trait, so collections can be placed in a managed window
+//! directly: `Vec::new_in(&window)`.
+
+#[cfg(feature = "allocator-api")]
+use core::{
+    alloc::{AllocError, Allocator},
+    ptr::NonNull,
+};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+};
+
+use crate::{
+    Error, ErrorKind, RangeAlloc, Result,
+    shared::{RawLock, SharedRangeAllocator, SpinLock},
+};
+
+/// a [`GlobalAlloc`] over a backend, which can be set up after the `static` holding it
+pub struct LockedAlloc<R, L = SpinLock> {
+    inner: SharedRangeAllocator<Option<R>, L>,
+}
+
+impl<R> LockedAlloc<R> {
+    /// an adapter without a backend, every allocation fails until [`init`](Self::init) is called
+    pub const fn new() -> Self {
+        LockedAlloc {
+            inner: SharedRangeAllocator::new(None),
+        }
+    }
+
+    /// an adapter that is ready to use
+    pub const fn with_allocator(inner: R) -> Self {
+        LockedAlloc {
+            inner: SharedRangeAllocator::new(Some(inner)),
+        }
+    }
+}
+
+impl<R, L: RawLock> LockedAlloc<R, L> {
+    /// like [`new`](LockedAlloc::new), protected by `lock` instead of a spinlock, e.g. one that
+    /// masks interrupts so handlers can allocate too
+    pub const fn with_lock(lock: L) -> Self {
+        LockedAlloc {
+            inner: SharedRangeAllocator::with_lock(lock, None),
+        }
+    }
+
+    /// hands the backend to the adapter. Fails with [`ErrorKind::AlreadyInitialized`] if it
+    /// already has one
+    pub fn init(&self, inner: R) -> Result<()> {
+        let mut slot = self.inner.lock();
+        if slot.is_some() {
+            return Err(Error::new(ErrorKind::AlreadyInitialized));
+        }
+        *slot = Some(inner);
+        Ok(())
+    }
+
+    /// runs `f` with the backend locked, e.g. to add more memory to the heap. `None` if there is
+    /// no backend yet
+    pub fn with<T>(&self, f: impl FnOnce(&mut R) -> T) -> Option<T> {
+        self.inner.lock().as_mut().map(f)
+    }
+}
+
+impl<R, L: RawLock> Default for LockedAlloc<R, L> {
+    fn default() -> Self {
+        Self::with_lock(L::new())
+    }
+}
+
+// SAFETY: blocks are only handed out by the backend, which never hands out a range twice before
+// it is freed. With a spinlock, allocating from an interrupt handler deadlocks if the interrupted
+// code holds the lock, like with any spinlocked heap
+unsafe impl<R: RangeAlloc + Send, L: RawLock> GlobalAlloc for LockedAlloc<R, L> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // zero-sized layouts are not allowed by `GlobalAlloc`, but cost nothing to handle
+        let size = layout.size().max(1);
+        self.with(|a| a.alloc(size, layout.align()))
+            .and_then(|res| res.ok())
+            .map_or(ptr::null_mut(), |(_, addr)| addr as *mut u8)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let size = layout.size().max(1);
+        let res = self.with(|a| a.free(ptr as usize, size));
+        debug_assert!(
+            matches!(res, Some(Ok(()))),
+            "freeing {ptr:p} failed: {res:?}"
+        );
+    }
+}
+
+// SAFETY: as for `GlobalAlloc`. Blocks stay valid until they are deallocated, also if the
+// adapter is moved, because the backend hands out addresses it does not own
+#[cfg(feature = "allocator-api")]
+unsafe impl<R: RangeAlloc + Send, L: RawLock> Allocator for LockedAlloc<R, L> {
+    fn allocate(&self, layout: Layout) -> core::result::Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            let dangling = NonNull::new(ptr::without_provenance_mut(layout.align()))
+                .expect("alignments are non-zero");
+            return Ok(NonNull::slice_from_raw_parts(dangling, 0));
+        }
+        let (_, addr) = self
+            .with(|a| a.alloc(layout.size(), layout.align()))
+            .ok_or(AllocError)?
+            .map_err(|_| AllocError)?;
+        let ptr = NonNull::new(addr as *mut u8).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            // SAFETY: forwarded from the caller
+            unsafe { GlobalAlloc::dealloc(self, ptr.as_ptr(), layout) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::alloc::{GlobalAlloc, Layout};
+
+    use super::LockedAlloc;
+    use crate::{ErrorKind, RangeAlloc, RangeAllocator};
+
+    #[test]
+    fn heap() {
+        static HEAP: LockedAlloc<RangeAllocator<()>> = LockedAlloc::new();
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        assert!(unsafe { HEAP.alloc(layout) }.is_null());
+
+        let mut heap = RangeAllocator::exact();
+        heap.add_range(0x1000, 0x1000, ()).expect("can add range");
+        HEAP.init(heap).expect("has no backend yet");
+        assert_eq!(
+            HEAP.init(RangeAllocator::exact()).unwrap_err().kind(),
+            ErrorKind::AlreadyInitialized
+        );
+
+        let x = unsafe { HEAP.alloc(layout) };
+        let y = unsafe { HEAP.alloc(Layout::from_size_align(0x100, 0x100).unwrap()) };
+        assert!(!x.is_null() && !y.is_null());
+        assert_eq!(y as usize % 0x100, 0);
+        assert_eq!(HEAP.with(|a| a.space()), Some(0x1000 - 24 - 0x100));
+        assert!(unsafe { HEAP.alloc(Layout::from_size_align(0x1000, 1).unwrap()) }.is_null());
+
+        unsafe {
+            HEAP.dealloc(x, layout);
+            HEAP.dealloc(y, Layout::from_size_align(0x100, 0x100).unwrap());
+        }
+        assert_eq!(HEAP.with(|a| a.space()), Some(0x1000));
+    }
+
+    #[cfg(feature = "allocator-api")]
+    #[test]
+    fn collections_in_a_window() {
+        use alloc::vec::Vec;
+
+        let mut window = RangeAllocator::exact();
+        window
+            .add_range(0x10_0000, 0x1000, ())
+            .expect("can add range");
+        let window = LockedAlloc::with_allocator(window);
+
+        // the window is not backed by memory, so the vector must not be touched
+        let v: Vec<u64, _> = Vec::with_capacity_in(16, &window);
+        assert!((0x10_0000..0x10_1000).contains(&(v.as_ptr() as usize)));
+        assert_eq!(window.with(|a| a.space()), Some(0x1000 - 16 * 8));
+        drop(v);
+        assert_eq!(window.with(|a| a.space()), Some(0x1000));
+        assert!(Vec::<u8, _>::try_with_capacity_in(0x2000, &window).is_err());
+    }
+}
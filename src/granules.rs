@@ -0,0 +1,126 @@
+//! handing out single granules without a lock
+//!
+//! [`Granules`] takes one range from a backend up front and tracks its granules, e.g. pages, in
+//! an [`AtomicBitmap`]. Taking and returning a granule only needs a shared reference and is a
+//! single compare-and-swap in the common case, so threads allocating single pages do not contend
+//! on the lock around the backend. Everything larger still goes to the backend.
+
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    Error, ErrorKind, RangeAlloc, Result, address::Address, collections::AtomicBitmap,
+    units::Alignment,
+};
+
+/// a range taken from a backend, handed out one granule at a time through a shared reference
+#[derive(Debug)]
+pub struct Granules<Tag, A: Address = usize> {
+    tag: Tag,
+    base: A,
+    granule: Alignment<A>,
+    /// a set bit is a granule in use
+    in_use: AtomicBitmap,
+    /// where the next search starts, so threads do not all race for the same bits
+    next: AtomicUsize,
+}
+
+impl<Tag: Clone, A: Address> Granules<Tag, A> {
+    /// takes `count` granules of `granule`, which has to be a power of two, from `backend`
+    pub fn take<R: RangeAlloc<A, Tag = Tag>>(
+        backend: &mut R,
+        granule: A,
+        count: usize,
+    ) -> Result<Self> {
+        let granule = Alignment::new(granule)?;
+        if count == 0 {
+            return Err(Error::new(ErrorKind::InvalidSize));
+        }
+        let size = granule
+            .get()
+            .to_u64()
+            .checked_mul(count as u64)
+            .and_then(|size| A::try_from(size).ok())
+            .ok_or(Error::new(ErrorKind::Overflow))?;
+        let (tag, base) = backend.alloc(size, granule.get())?;
+        Ok(Granules {
+            tag,
+            base,
+            granule,
+            in_use: AtomicBitmap::new(count),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// a free granule, or `None` if all of them are in use. Returns the tag of the region the
+    /// range was taken from and the base of the granule
+    pub fn alloc(&self) -> Option<(Tag, A)> {
+        let next = self.next.load(Ordering::Relaxed);
+        let bit = self
+            .in_use
+            .set_first_zero(next)
+            .or_else(|| self.in_use.set_first_zero(0))?;
+        self.next.store(bit + 1, Ordering::Relaxed);
+        Some((self.tag.clone(), self.address(bit)))
+    }
+
+    /// gives back the granule at `base`
+    pub fn free(&self, base: A) -> Result<()> {
+        if !self.range().contains(&base) {
+            return Err(Error::new(ErrorKind::NotOwned));
+        }
+        if !self.granule.is_aligned(base) {
+            return Err(Error::new(ErrorKind::InvalidAlignment));
+        }
+        let bit = ((base - self.base).to_u64() >> self.shift()) as usize;
+        if !self.in_use.clear_range(bit..bit + 1) {
+            return Err(Error::new(ErrorKind::DoubleFree));
+        }
+        Ok(())
+    }
+
+    /// returns the range to `backend`. Fails with [`ErrorKind::NotFree`] while a granule is still
+    /// in use, the range then stays allocated in the backend
+    pub fn give_back<R: RangeAlloc<A, Tag = Tag>>(self, backend: &mut R) -> Result<()> {
+        if !self.is_empty() {
+            return Err(Error::new(ErrorKind::NotFree));
+        }
+        let range = self.range();
+        backend.free(range.start, range.end - range.start)
+    }
+
+    pub fn range(&self) -> Range<A> {
+        self.base..self.address(self.in_use.len())
+    }
+
+    pub fn granule(&self) -> A {
+        self.granule.get()
+    }
+
+    /// number of granules in use. Only a snapshot while other threads allocate or free
+    pub fn len(&self) -> usize {
+        self.in_use.count_ones()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// number of granules, used or not
+    pub fn capacity(&self) -> usize {
+        self.in_use.len()
+    }
+
+    fn shift(&self) -> u32 {
+        self.granule.get().to_u64().trailing_zeros()
+    }
+
+    fn address(&self, bit: usize) -> A {
+        self.base
+            + A::try_from((bit as u64) << self.shift())
+                .ok()
+                .expect("the granules were taken from the backend")
+    }
+}
@@ -0,0 +1,174 @@
+//! freeing everything allocated during a request or a frame at once
+//!
+//! [`Grouped`] wraps a backend and files every allocation made between
+//! [`group_begin`](Grouped::group_begin) and [`group_end`](Grouped::group_end) under a
+//! [`GroupId`]. [`free_group`](Grouped::free_group) then gives all of them back in one call,
+//! without the caller keeping a list. Groups nest: allocations go into the innermost open group.
+//! Allocations made outside of any group are passed through untouched.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::ops::Range;
+
+use crate::{RangeAlloc, Result, address::Address};
+
+/// names a group of allocations of a [`Grouped`]. Ids are never reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GroupId(u64);
+
+/// a backend whose allocations can be grouped and freed together
+pub struct Grouped<R, A = usize> {
+    inner: R,
+    /// the groups that were begun but not ended, innermost last
+    open: Vec<GroupId>,
+    next: u64,
+    /// the allocations of every group that has some, or is open, as base -> size
+    groups: BTreeMap<GroupId, BTreeMap<A, A>>,
+    /// the group of every allocation that belongs to one, by base
+    members: BTreeMap<A, GroupId>,
+}
+
+impl<A: Address, R: RangeAlloc<A>> Grouped<R, A> {
+    pub fn new(inner: R) -> Self {
+        Grouped {
+            inner,
+            open: Vec::new(),
+            next: 0,
+            groups: BTreeMap::new(),
+            members: BTreeMap::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    /// the backend. Allocations still in groups stay allocated
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// starts a new group, which all following allocations go into until it is ended
+    pub fn group_begin(&mut self) -> GroupId {
+        let id = GroupId(self.next);
+        self.next += 1;
+        self.open.push(id);
+        self.groups.insert(id, BTreeMap::new());
+        id
+    }
+
+    /// ends the innermost open group and returns it. Later allocations go into the group that
+    /// was open before it, if any
+    pub fn group_end(&mut self) -> Option<GroupId> {
+        let id = self.open.pop()?;
+        if self.groups.get(&id).is_some_and(BTreeMap::is_empty) {
+            self.groups.remove(&id);
+        }
+        Some(id)
+    }
+
+    /// the innermost open group
+    pub fn current_group(&self) -> Option<GroupId> {
+        self.open.last().copied()
+    }
+
+    /// the group the allocation at `base` belongs to
+    pub fn group_of(&self, base: A) -> Option<GroupId> {
+        self.members.get(&base).copied()
+    }
+
+    /// number of live allocations in `group`
+    pub fn group_len(&self, group: GroupId) -> usize {
+        self.groups.get(&group).map_or(0, BTreeMap::len)
+    }
+
+    /// frees every live allocation of `group` and returns how many there were, so freeing a
+    /// group again frees nothing. An open group stays open. If the backend fails to free one, the
+    /// allocations not freed yet stay in the group and the error is returned
+    pub fn free_group(&mut self, group: GroupId) -> Result<usize> {
+        let Some(allocations) = self.groups.get_mut(&group) else {
+            return Ok(0);
+        };
+        let mut freed = 0;
+        while let Some((base, size)) = allocations.pop_first() {
+            if let Err(e) = self.inner.free(base, size) {
+                allocations.insert(base, size);
+                return Err(e);
+            }
+            self.members.remove(&base);
+            freed += 1;
+        }
+        if !self.open.contains(&group) {
+            self.groups.remove(&group);
+        }
+        Ok(freed)
+    }
+
+    fn record(&mut self, base: A, size: A) {
+        if let Some(&group) = self.open.last() {
+            self.groups
+                .get_mut(&group)
+                .expect("invariant: open groups are kept")
+                .insert(base, size);
+            self.members.insert(base, group);
+        }
+    }
+}
+
+impl<A: Address, R: RangeAlloc<A>> RangeAlloc<A> for Grouped<R, A> {
+    type Tag = R::Tag;
+
+    fn add_range(&mut self, base: A, size: A, range_tag: Self::Tag) -> Result<()> {
+        self.inner.add_range(base, size, range_tag)
+    }
+
+    fn alloc(&mut self, min_size: A, alignment: A) -> Result<(Self::Tag, A)> {
+        let (tag, base) = self.inner.alloc(min_size, alignment)?;
+        self.record(base, min_size);
+        Ok((tag, base))
+    }
+
+    fn alloc_within(
+        &mut self,
+        min_size: A,
+        alignment: A,
+        window: Range<A>,
+    ) -> Result<(Self::Tag, A)> {
+        let (tag, base) = self.inner.alloc_within(min_size, alignment, window)?;
+        self.record(base, min_size);
+        Ok((tag, base))
+    }
+
+    fn alloc_fixed(&mut self, base: A, size: A) -> Result<(Self::Tag, A)> {
+        let (tag, base) = self.inner.alloc_fixed(base, size)?;
+        self.record(base, size);
+        Ok((tag, base))
+    }
+
+    /// frees a single allocation, which also takes it out of its group
+    fn free(&mut self, base: A, size: A) -> Result<()> {
+        self.inner.free(base, size)?;
+        if let Some(group) = self.members.remove(&base) {
+            let allocations = self
+                .groups
+                .get_mut(&group)
+                .expect("invariant: groups with members are kept");
+            allocations.remove(&base);
+            if allocations.is_empty() && !self.open.contains(&group) {
+                self.groups.remove(&group);
+            }
+        }
+        Ok(())
+    }
+
+    fn total_space(&self) -> A {
+        self.inner.total_space()
+    }
+
+    fn space(&self) -> A {
+        self.inner.space()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
@@ -0,0 +1,101 @@
+//! handing out small integers, like interrupt vectors or device ids
+//!
+//! [`IdAllocator`] is the linear backend in [exact mode](crate::RangeAllocator::exact), with an
+//! API in terms of ids instead of sizes and tags.
+
+use core::ops::Range;
+
+use crate::{
+    Error, ErrorKind, RangeAlloc, Result, address::Address, common::Bookkeeping,
+    linear::RangeAllocator,
+};
+
+/// allocates ids from a contiguous range
+pub struct IdAllocator<A = usize> {
+    inner: RangeAllocator<(), A>,
+    ids: Range<A>,
+}
+
+impl<A: Address> IdAllocator<A> {
+    /// an allocator handing out the ids in `ids`, all of them free
+    pub fn new(ids: Range<A>) -> Result<Self> {
+        if ids.start >= ids.end {
+            return Err(Error::new(ErrorKind::InvalidSize));
+        }
+        let mut inner = RangeAllocator::exact();
+        inner.add_range(ids.start, ids.end - ids.start, ())?;
+        Ok(IdAllocator { inner, ids })
+    }
+
+    /// the ids this allocator hands out
+    pub fn ids(&self) -> Range<A> {
+        self.ids.clone()
+    }
+
+    /// number of ids that are free
+    pub fn available(&self) -> A {
+        self.inner.space()
+    }
+
+    /// any free id. Which one is up to the backend's first-fit policy, not necessarily the lowest
+    pub fn alloc(&mut self) -> Result<A> {
+        self.alloc_contiguous(A::ONE, A::ONE)
+    }
+
+    /// `count` consecutive ids, the first of which is a multiple of `alignment`. E.g. multi-message
+    /// MSI needs a power-of-two block of vectors aligned to its size
+    pub fn alloc_contiguous(&mut self, count: A, alignment: A) -> Result<A> {
+        self.inner.alloc(count, alignment).map(|(_, first)| first)
+    }
+
+    /// claims a specific id, e.g. one that is hard-wired
+    pub fn claim(&mut self, id: A) -> Result<()> {
+        self.inner.alloc_fixed(id, A::ONE).map(|_| ())
+    }
+
+    pub fn free(&mut self, id: A) -> Result<()> {
+        self.free_contiguous(id, A::ONE)
+    }
+
+    /// frees ids handed out by [`alloc_contiguous`](Self::alloc_contiguous)
+    pub fn free_contiguous(&mut self, first: A, count: A) -> Result<()> {
+        if !self.ids.contains(&first) {
+            return Err(Error::new(ErrorKind::NotOwned));
+        }
+        self.inner.free(first, count)
+    }
+
+    pub fn is_allocated(&self, id: A) -> bool {
+        self.ids.contains(&id) && !self.inner.is_free(id, A::ONE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdAllocator;
+    use crate::ErrorKind;
+
+    #[test]
+    fn interrupt_vectors() {
+        let mut vectors = IdAllocator::<u32>::new(32..256).expect("valid range");
+        assert_eq!(vectors.alloc().unwrap(), 32);
+        assert_eq!(vectors.alloc().unwrap(), 33);
+        let msi = vectors.alloc_contiguous(4, 4).expect("has space");
+        assert_eq!(msi, 36);
+        assert!(vectors.is_allocated(37) && !vectors.is_allocated(34));
+        vectors.claim(0x80).expect("is free");
+        assert_eq!(vectors.claim(0x80).unwrap_err().kind(), ErrorKind::NotFree);
+        assert_eq!(vectors.available(), 224 - 7);
+
+        vectors.free(32).expect("was allocated");
+        vectors.free_contiguous(msi, 4).expect("was allocated");
+        assert!(!vectors.is_allocated(32));
+        for _ in 0..224 - 2 {
+            let id = vectors.alloc().expect("has space");
+            assert!(vectors.ids().contains(&id) && id != 33 && id != 0x80);
+        }
+        assert_eq!(vectors.alloc().unwrap_err().kind(), ErrorKind::OutOfSpace);
+        assert_eq!(vectors.free(300).unwrap_err().kind(), ErrorKind::NotOwned);
+        assert!(IdAllocator::<u32>::new(5..5).is_err());
+    }
+}
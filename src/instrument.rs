@@ -0,0 +1,396 @@
+//! latency instrumentation for [`RangeAlloc`] backends
+//!
+//! [`Instrumented`] wraps any backend and records how long every operation took according to a
+//! user-supplied [`Clock`]. This is meant for validating worst-case latency under a real workload,
+//! the overhead of reading the clock and storing the sample is part of every measurement.
+//!
+//! with a [rate window](Instrumented::set_rate_window) set, it also keeps [`Rates`]: how much was
+//! allocated and freed, and how often allocations failed, during the latest window of time, e.g.
+//! for a controller that reacts to memory pressure building up rather than to the free space.
+
+use alloc::collections::VecDeque;
+use core::ops::Range;
+
+use crate::{RangeAlloc, Result, address::Address};
+
+/// a monotonic time source. The unit is up to the caller (cycles, ticks, nanoseconds, ...)
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+impl<F: Fn() -> u64> Clock for F {
+    fn now(&self) -> u64 {
+        self()
+    }
+}
+
+/// sub-buckets per power of two, which bounds the error of a percentile to an eighth
+const SUB_BUCKETS: usize = 8;
+/// latencies below `SUB_BUCKETS` get a bucket each, every larger power of two gets `SUB_BUCKETS`
+const BUCKETS: usize = SUB_BUCKETS + (64 - SUB_BUCKETS.ilog2() as usize) * SUB_BUCKETS;
+
+/// the recorded latencies of a single kind of operation, in a histogram of fixed size. Small
+/// latencies are kept exactly, larger ones to within an eighth of their value
+#[derive(Debug, Clone)]
+pub struct Latencies {
+    buckets: [u64; BUCKETS],
+    count: usize,
+    max: u64,
+}
+
+impl Default for Latencies {
+    fn default() -> Self {
+        Latencies {
+            buckets: [0; BUCKETS],
+            count: 0,
+            max: 0,
+        }
+    }
+}
+
+/// percentile summary of [`Latencies`], in the unit of the [`Clock`] that produced them
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub count: usize,
+    pub p50: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl Latencies {
+    fn bucket(latency: u64) -> usize {
+        if latency < SUB_BUCKETS as u64 {
+            return latency as usize;
+        }
+        let order = latency.ilog2();
+        let shift = order - SUB_BUCKETS.ilog2();
+        let sub = (latency >> shift) as usize - SUB_BUCKETS;
+        SUB_BUCKETS + shift as usize * SUB_BUCKETS + sub
+    }
+
+    /// the largest latency that falls into `bucket`
+    fn bucket_max(bucket: usize) -> u64 {
+        if bucket < SUB_BUCKETS {
+            return bucket as u64;
+        }
+        let shift = (bucket - SUB_BUCKETS) / SUB_BUCKETS;
+        let sub = (bucket % SUB_BUCKETS + SUB_BUCKETS) as u128;
+        (((sub + 1) << shift) - 1).min(u64::MAX.into()) as u64
+    }
+
+    fn record(&mut self, latency: u64) {
+        self.buckets[Self::bucket(latency)] += 1;
+        self.count += 1;
+        self.max = self.max.max(latency);
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// nearest-rank percentile, `p` is in `0..=100`, rounded up to the end of its bucket but at
+    /// most the [`max`](Self::max). Returns 0 if nothing has been recorded
+    pub fn percentile(&self, p: u8) -> u64 {
+        self.percentiles([p])[0]
+    }
+
+    /// the percentiles `ps`, in ascending order, in a single pass over the buckets
+    fn percentiles<const N: usize>(&self, ps: [u8; N]) -> [u64; N] {
+        assert!(ps.iter().all(|&p| p <= 100), "percentile out of range");
+        let mut out = [0; N];
+        if self.count == 0 {
+            return out;
+        }
+        let mut buckets = self.buckets.iter().enumerate();
+        let mut seen = 0;
+        let mut bucket = 0;
+        for (p, out) in ps.into_iter().zip(&mut out) {
+            let rank = (self.count * p as usize).div_ceil(100).max(1) as u64;
+            while seen < rank {
+                let (i, n) = buckets.next().expect("the buckets hold every sample");
+                seen += n;
+                bucket = i;
+            }
+            *out = Self::bucket_max(bucket).min(self.max);
+        }
+        out
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        let [p50, p99] = self.percentiles([50, 99]);
+        LatencySummary {
+            count: self.count,
+            p50,
+            p99,
+            max: self.max,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// an allocation or a free, at the time it finished
+#[derive(Debug, Clone, Copy)]
+struct Event {
+    time: u64,
+    bytes: u64,
+    kind: EventKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EventKind {
+    Alloc,
+    FailedAlloc,
+    Free,
+}
+
+/// the allocations and frees during the latest window of time, which ends with the latest one.
+/// Rates are per `unit` of the [`Clock`], e.g. `1_000_000_000` for per second with a clock
+/// counting nanoseconds
+#[derive(Debug, Default, Clone)]
+pub struct Rates {
+    /// 0 if rates are not kept
+    window: u64,
+    events: VecDeque<Event>,
+    allocs: u64,
+    failed_allocs: u64,
+    frees: u64,
+    bytes_allocated: u64,
+    bytes_freed: u64,
+}
+
+impl Rates {
+    fn new(window: u64) -> Self {
+        Rates {
+            window,
+            ..Rates::default()
+        }
+    }
+
+    fn record(&mut self, event: Event) {
+        if self.window == 0 {
+            return;
+        }
+        self.count(event, true);
+        self.events.push_back(event);
+        while let Some(&oldest) = self.events.front()
+            && event.time.saturating_sub(oldest.time) > self.window
+        {
+            self.count(oldest, false);
+            self.events.pop_front();
+        }
+    }
+
+    fn count(&mut self, event: Event, add: bool) {
+        let (n, bytes) = match event.kind {
+            EventKind::Alloc => (&mut self.allocs, Some(&mut self.bytes_allocated)),
+            EventKind::FailedAlloc => (&mut self.failed_allocs, None),
+            EventKind::Free => (&mut self.frees, Some(&mut self.bytes_freed)),
+        };
+        let step = |x: &mut u64, by| *x = if add { *x + by } else { *x - by };
+        step(n, 1);
+        if let Some(bytes) = bytes {
+            step(bytes, event.bytes);
+        }
+    }
+
+    /// the length of the window in clock units, 0 if rates are not kept
+    pub fn window(&self) -> u64 {
+        self.window
+    }
+
+    /// successful allocations in the window
+    pub fn allocs(&self) -> u64 {
+        self.allocs
+    }
+
+    pub fn failed_allocs(&self) -> u64 {
+        self.failed_allocs
+    }
+
+    pub fn frees(&self) -> u64 {
+        self.frees
+    }
+
+    /// requested bytes of the successful allocations in the window
+    pub fn bytes_allocated(&self) -> u64 {
+        self.bytes_allocated
+    }
+
+    pub fn bytes_freed(&self) -> u64 {
+        self.bytes_freed
+    }
+
+    /// successful allocations per `unit`
+    pub fn alloc_rate(&self, unit: u64) -> f64 {
+        self.per(self.allocs, unit)
+    }
+
+    pub fn free_rate(&self, unit: u64) -> f64 {
+        self.per(self.frees, unit)
+    }
+
+    /// bytes allocated minus bytes freed per `unit`, negative while memory is given back
+    pub fn net_byte_rate(&self, unit: u64) -> f64 {
+        self.per(self.bytes_allocated, unit) - self.per(self.bytes_freed, unit)
+    }
+
+    /// share of the allocations in the window that failed, 0 if there were none
+    pub fn failure_rate(&self) -> f64 {
+        let attempts = self.allocs + self.failed_allocs;
+        if attempts == 0 {
+            return 0.0;
+        }
+        self.failed_allocs as f64 / attempts as f64
+    }
+
+    fn per(&self, count: u64, unit: u64) -> f64 {
+        if self.window == 0 {
+            return 0.0;
+        }
+        count as f64 * unit as f64 / self.window as f64
+    }
+}
+
+/// latencies of all operations of an [`Instrumented`] allocator
+#[derive(Debug, Default, Clone)]
+pub struct LatencyStats {
+    pub add_range: Latencies,
+    pub alloc: Latencies,
+    pub free: Latencies,
+    pub rates: Rates,
+}
+
+/// wraps a [`RangeAlloc`] and measures every `add_range`, `alloc` and `free`, successful or not.
+/// `alloc_fixed` counts as an `alloc`
+pub struct Instrumented<A, C> {
+    inner: A,
+    clock: C,
+    stats: LatencyStats,
+}
+
+impl<A, C: Clock> Instrumented<A, C> {
+    pub fn new(inner: A, clock: C) -> Self {
+        Instrumented {
+            inner,
+            clock,
+            stats: LatencyStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &LatencyStats {
+        &self.stats
+    }
+
+    /// forgets everything recorded, but keeps the rate window
+    pub fn reset_stats(&mut self) {
+        self.stats = LatencyStats {
+            rates: Rates::new(self.stats.rates.window),
+            ..LatencyStats::default()
+        };
+    }
+
+    /// keeps [`Rates`] over the latest `window` clock units from now on, or stops keeping them if
+    /// `window` is 0. Resets the rates recorded so far
+    pub fn set_rate_window(&mut self, window: u64) {
+        self.stats.rates = Rates::new(window);
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+}
+
+/// evaluates to the result and the time it finished
+macro_rules! timed {
+    ($this:expr, $op:ident, $e:expr) => {{
+        let start = $this.clock.now();
+        let res = $e;
+        let end = $this.clock.now();
+        $this.stats.$op.record(end.saturating_sub(start));
+        (res, end)
+    }};
+}
+
+impl<A, C> Instrumented<A, C> {
+    fn record_alloc<Addr: Address, T>(&mut self, time: u64, size: Addr, res: &Result<T>) {
+        let kind = match res {
+            Ok(_) => EventKind::Alloc,
+            Err(_) => EventKind::FailedAlloc,
+        };
+        self.stats.rates.record(Event {
+            time,
+            bytes: size.to_u64(),
+            kind,
+        });
+    }
+}
+
+impl<Addr: Address, A: RangeAlloc<Addr>, C: Clock> RangeAlloc<Addr> for Instrumented<A, C> {
+    type Tag = A::Tag;
+
+    fn add_range(&mut self, base: Addr, size: Addr, range_tag: Self::Tag) -> Result<()> {
+        timed!(self, add_range, self.inner.add_range(base, size, range_tag)).0
+    }
+
+    fn alloc(&mut self, min_size: Addr, alignment: Addr) -> Result<(Self::Tag, Addr)> {
+        let (res, time) = timed!(self, alloc, self.inner.alloc(min_size, alignment));
+        self.record_alloc(time, min_size, &res);
+        res
+    }
+
+    fn alloc_within(
+        &mut self,
+        min_size: Addr,
+        alignment: Addr,
+        window: Range<Addr>,
+    ) -> Result<(Self::Tag, Addr)> {
+        let (res, time) = timed!(
+            self,
+            alloc,
+            self.inner.alloc_within(min_size, alignment, window)
+        );
+        self.record_alloc(time, min_size, &res);
+        res
+    }
+
+    fn alloc_fixed(&mut self, base: Addr, size: Addr) -> Result<(Self::Tag, Addr)> {
+        let (res, time) = timed!(self, alloc, self.inner.alloc_fixed(base, size));
+        self.record_alloc(time, size, &res);
+        res
+    }
+
+    fn free(&mut self, base: Addr, size: Addr) -> Result<()> {
+        let (res, time) = timed!(self, free, self.inner.free(base, size));
+        if res.is_ok() {
+            self.stats.rates.record(Event {
+                time,
+                bytes: size.to_u64(),
+                kind: EventKind::Free,
+            });
+        }
+        res
+    }
+
+    fn total_space(&self) -> Addr {
+        self.inner.total_space()
+    }
+
+    fn space(&self) -> Addr {
+        self.inner.space()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
@@ -1,303 +1,3429 @@
 #![allow(unused)]
-mod btree;
+#![no_std]
+#![cfg_attr(feature = "allocator-api", feature(allocator_api))]
+
+extern crate alloc;
+#[cfg(any(test, feature = "std"))]
+extern crate std;
+
+pub mod adaptive;
+pub mod address;
+pub mod allocator;
+pub mod btree;
+pub mod builder;
+#[cfg(feature = "bench")]
+pub mod coalescing;
 pub mod collections;
-mod linear;
+mod common;
+pub mod compaction;
+pub mod compat;
+pub mod difftest;
+pub mod extents;
+#[cfg(feature = "global")]
+pub mod global;
+#[cfg(feature = "global-alloc")]
+pub mod global_alloc;
+pub mod granules;
+pub mod groups;
+pub mod ids;
+pub mod instrument;
+pub mod linear;
+pub mod map;
+pub mod metrics;
+pub mod offset;
+pub mod raw;
+pub mod registry;
+pub mod replay;
+pub mod safe;
+pub mod sharded;
+pub mod shared;
+pub mod slab;
+#[cfg(feature = "svg")]
+pub mod svg;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+pub mod trace;
+pub mod txn;
+pub mod units;
+pub mod verify;
 
-use core::panic;
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::{
+    cmp::Reverse,
+    fmt,
+    ops::Range,
+    panic,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
+use address::Address;
 pub use linear::RangeAllocator;
+use units::{Alignment, Size};
 
-pub trait RangeAlloc {
+/// an allocator handing out ranges of the address space `A`
+///
+/// the trait is object safe, so backends can be picked at runtime as a
+/// `Box<dyn RangeAlloc<Tag = T>>`. Boxes and mutable references implement it too
+pub trait RangeAlloc<A: Address = usize> {
     type Tag;
-    fn add_range(&mut self, base: usize, size: usize, range_tag: Self::Tag) -> Result<()>;
+    fn add_range(&mut self, base: A, size: A, range_tag: Self::Tag) -> Result<()>;
+
+    fn alloc(&mut self, min_size: A, alignment: A) -> Result<(Self::Tag, A)>;
+
+    /// allocates the range at the given base address. Fails if any part of it is not free
+    fn alloc_fixed(&mut self, base: A, size: A) -> Result<(Self::Tag, A)>;
+
+    /// like [`alloc`](Self::alloc), but the range has to lie entirely within `window`. Backends
+    /// that cannot restrict the placement fail with [`ErrorKind::Unimplemented`]
+    fn alloc_within(
+        &mut self,
+        min_size: A,
+        alignment: A,
+        window: Range<A>,
+    ) -> Result<(Self::Tag, A)> {
+        Err(Error::new(ErrorKind::Unimplemented))
+    }
+
+    /// like [`alloc`](Self::alloc), with size and alignment already validated by the caller
+    fn alloc_checked(&mut self, size: Size<A>, alignment: Alignment<A>) -> Result<(Self::Tag, A)> {
+        self.alloc(size.get(), alignment.get())
+    }
 
-    fn alloc(&mut self, min_size: usize, alignment: usize) -> Result<(Self::Tag, usize)>;
+    fn free(&mut self, base: A, size: A) -> Result<()>;
 
-    fn free(&mut self, base: usize, size: usize) -> Result<()>;
+    fn total_space(&self) -> A;
 
-    fn total_space(&self) -> usize;
+    fn space(&self) -> A;
 
-    fn space(&self) -> usize;
+    /// whether nothing is allocated
+    fn is_empty(&self) -> bool {
+        self.space() == self.total_space()
+    }
+
+    /// whether no space is left at all
+    fn is_full(&self) -> bool {
+        self.space() == A::ZERO
+    }
+
+    /// the fraction of the total space that is not free, in `0.0..=1.0`. 0 without any regions
+    fn utilization(&self) -> f64 {
+        let total = self.total_space().to_u64();
+        if total == 0 {
+            return 0.0;
+        }
+        (total - self.space().to_u64()) as f64 / total as f64
+    }
+
+    /// starts a [transaction](txn), whose allocations and frees are undone unless it is
+    /// committed
+    fn begin(&mut self) -> txn::Txn<'_, Self, A>
+    where
+        Self: Sized,
+    {
+        txn::Txn::new(self)
+    }
 }
 
-// TODO: this should obviously not just be a string. For sketching this is enough
-#[derive(Debug)]
-pub struct Error(String);
+/// e.g. for the allocators built by [`RangeAllocatorBuilder::build`](builder::RangeAllocatorBuilder::build)
+impl<A: Address, R: RangeAlloc<A> + ?Sized> RangeAlloc<A> for Box<R> {
+    type Tag = R::Tag;
+
+    fn add_range(&mut self, base: A, size: A, range_tag: Self::Tag) -> Result<()> {
+        (**self).add_range(base, size, range_tag)
+    }
+
+    fn alloc(&mut self, min_size: A, alignment: A) -> Result<(Self::Tag, A)> {
+        (**self).alloc(min_size, alignment)
+    }
+
+    fn alloc_fixed(&mut self, base: A, size: A) -> Result<(Self::Tag, A)> {
+        (**self).alloc_fixed(base, size)
+    }
+
+    fn alloc_within(
+        &mut self,
+        min_size: A,
+        alignment: A,
+        window: Range<A>,
+    ) -> Result<(Self::Tag, A)> {
+        (**self).alloc_within(min_size, alignment, window)
+    }
+
+    fn alloc_checked(&mut self, size: Size<A>, alignment: Alignment<A>) -> Result<(Self::Tag, A)> {
+        (**self).alloc_checked(size, alignment)
+    }
+
+    fn free(&mut self, base: A, size: A) -> Result<()> {
+        (**self).free(base, size)
+    }
+
+    fn total_space(&self) -> A {
+        (**self).total_space()
+    }
+
+    fn space(&self) -> A {
+        (**self).space()
+    }
+
+    fn is_empty(&self) -> bool {
+        (**self).is_empty()
+    }
+
+    fn is_full(&self) -> bool {
+        (**self).is_full()
+    }
+
+    fn utilization(&self) -> f64 {
+        (**self).utilization()
+    }
+}
+
+/// e.g. to hand an allocator to a function taking `impl RangeAlloc` and keep using it afterwards
+impl<A: Address, R: RangeAlloc<A> + ?Sized> RangeAlloc<A> for &mut R {
+    type Tag = R::Tag;
+
+    fn add_range(&mut self, base: A, size: A, range_tag: Self::Tag) -> Result<()> {
+        (**self).add_range(base, size, range_tag)
+    }
+
+    fn alloc(&mut self, min_size: A, alignment: A) -> Result<(Self::Tag, A)> {
+        (**self).alloc(min_size, alignment)
+    }
+
+    fn alloc_fixed(&mut self, base: A, size: A) -> Result<(Self::Tag, A)> {
+        (**self).alloc_fixed(base, size)
+    }
+
+    fn alloc_within(
+        &mut self,
+        min_size: A,
+        alignment: A,
+        window: Range<A>,
+    ) -> Result<(Self::Tag, A)> {
+        (**self).alloc_within(min_size, alignment, window)
+    }
+
+    fn alloc_checked(&mut self, size: Size<A>, alignment: Alignment<A>) -> Result<(Self::Tag, A)> {
+        (**self).alloc_checked(size, alignment)
+    }
+
+    fn free(&mut self, base: A, size: A) -> Result<()> {
+        (**self).free(base, size)
+    }
+
+    fn total_space(&self) -> A {
+        (**self).total_space()
+    }
+
+    fn space(&self) -> A {
+        (**self).space()
+    }
+
+    fn is_empty(&self) -> bool {
+        (**self).is_empty()
+    }
+
+    fn is_full(&self) -> bool {
+        (**self).is_full()
+    }
+
+    fn utilization(&self) -> f64 {
+        (**self).utilization()
+    }
+}
+
+/// why an operation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// no free block is large enough for the request
+    OutOfSpace,
+    /// some free blocks are large enough, but none of them can satisfy the alignment or the
+    /// constraints of its region
+    Overconstrained {
+        size: u64,
+        alignment: u64,
+    },
+    /// the request is larger than every usable region, so it fails however the free space is
+    /// arranged. `bridging_regions` on the backends finds two touching regions that could hold
+    /// it together
+    RequestExceedsAnyRegion {
+        size: u64,
+        largest_region: u64,
+    },
+    /// the range overlaps a region that was already added
+    OverlappingRange,
+    /// an alignment or address that is not a power of two, or not aligned as required
+    InvalidAlignment,
+    /// a size of zero
+    InvalidSize,
+    /// the range does not fit into the address space
+    Overflow,
+    /// the range does not belong to any region of the allocator
+    NotOwned,
+    /// the range is already free, at least in part
+    DoubleFree,
+    /// the range was never handed out, as far as the tracked allocations tell
+    NotAllocated,
+    /// the range is allocated or reserved, so it can not be claimed
+    NotFree,
+    /// the range was not reserved
+    NotReserved,
+    /// the range is pinned, so administrative operations must not touch it
+    Pinned,
+    /// the range was not pinned
+    NotPinned,
+    /// the operation would exceed one of the allocator's [`Limits`]
+    CapacityExceeded,
+    /// the global allocator was already set up
+    AlreadyInitialized,
+    /// raw parts that do not describe a valid allocator
+    Inconsistent,
+    Unimplemented,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::OutOfSpace => write!(f, "no space"),
+            ErrorKind::Overconstrained { size, alignment } => {
+                write!(
+                    f,
+                    "has space but overconstrained: {size:#x} aligned to {alignment:#x}"
+                )
+            }
+            ErrorKind::RequestExceedsAnyRegion {
+                size,
+                largest_region,
+            } => write!(
+                f,
+                "request of {size:#x} exceeds the largest region of {largest_region:#x}"
+            ),
+            ErrorKind::OverlappingRange => write!(f, "overlapping range"),
+            ErrorKind::InvalidAlignment => write!(f, "invalid alignment"),
+            ErrorKind::InvalidSize => write!(f, "size is zero"),
+            ErrorKind::Overflow => write!(f, "range overflows"),
+            ErrorKind::NotOwned => write!(f, "not owned by this allocator"),
+            ErrorKind::DoubleFree => write!(f, "range is already free"),
+            ErrorKind::NotAllocated => write!(f, "range is not allocated"),
+            ErrorKind::NotFree => write!(f, "range is not free"),
+            ErrorKind::NotReserved => write!(f, "range is not reserved"),
+            ErrorKind::Pinned => write!(f, "range is pinned"),
+            ErrorKind::NotPinned => write!(f, "range is not pinned"),
+            ErrorKind::CapacityExceeded => write!(f, "capacity limit reached"),
+            ErrorKind::AlreadyInitialized => write!(f, "already initialized"),
+            ErrorKind::Inconsistent => write!(f, "inconsistent allocator state"),
+            ErrorKind::Unimplemented => write!(f, "unimplemented"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Error {
+    kind: ErrorKind,
+    /// where the error was returned from
+    location: &'static panic::Location<'static>,
+}
 
 impl Error {
     #[track_caller]
-    pub fn cause(msg: &str) -> Error {
-        Error(format!(
-            "{} returning error: {msg}",
-            panic::Location::caller()
-        ))
+    pub fn new(kind: ErrorKind) -> Error {
+        Error {
+            kind,
+            location: panic::Location::caller(),
+        }
     }
 
     #[track_caller]
     pub fn unimplemented() -> Error {
-        Error::cause("unimplemented")
+        Error::new(ErrorKind::Unimplemented)
+    }
+
+    /// an [`ErrorKind::Inconsistent`] whose location is the check that failed
+    #[track_caller]
+    pub(crate) fn inconsistent() -> Error {
+        Error::new(ErrorKind::Inconsistent)
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} returning error: {}", self.location, self.kind)
     }
 }
 
+impl core::error::Error for Error {}
+
 pub type Result<T> = core::result::Result<T, Error>;
 
-#[macro_export]
-macro_rules! round_up {
-    ($n:expr, $size:expr) => {{
-        let n = $n;
-        let size = $size;
-        (n + size - 1) & (!(size - 1))
-    }};
+/// identifies a region, usable or reserved, by its base address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RegionId<A = usize>(A);
+
+impl<A: Address> RegionId<A> {
+    pub fn base(self) -> A {
+        self.0
+    }
 }
 
-// #[cfg(test)]
-pub mod tests {
-    use std::{
-        collections::{HashMap, HashSet},
-        hint::black_box,
-        ops::Range,
-    };
+/// why an entry passed to `add_ranges` was not added
+#[derive(Debug)]
+pub struct Rejected<A = usize> {
+    pub error: Error,
+    /// the existing region the entry overlapped, if that is why it was rejected
+    pub overlaps: Option<RegionId<A>>,
+}
 
-    use super::*;
+/// per-entry outcome of `add_ranges`
+pub type AddRangeResult<A = usize> = core::result::Result<RegionId<A>, Rejected<A>>;
 
-    pub fn new_linear() -> linear::RangeAllocator<()> {
-        linear::RangeAllocator::new()
+/// how an allocator picks among the free blocks that can satisfy a request
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Policy {
+    /// the first suitable block in the allocator's search order
+    #[default]
+    FirstFit,
+    /// the block that has been free the longest, leaving recently used memory alone
+    OldestFree,
+    /// the block that was freed most recently, which is likely still cache and TLB hot
+    NewestFree,
+    /// the smallest suitable block, keeping large blocks intact for large requests
+    BestFit,
+    /// the largest suitable block, so the remainder is more likely to be large enough to be useful
+    WorstFit,
+    /// like first-fit, but the search resumes where the previous allocation was made instead of
+    /// at the start, and wraps around. Streaming workloads then rarely look at the same block
+    /// twice
+    NextFit,
+}
+
+impl Policy {
+    pub const ALL: [Policy; 6] = [
+        Policy::FirstFit,
+        Policy::OldestFree,
+        Policy::NewestFree,
+        Policy::BestFit,
+        Policy::WorstFit,
+        Policy::NextFit,
+    ];
+
+    /// picks one of the `candidates`, which are given in the allocator's search order together
+    /// with the epoch of their free block
+    fn select<A: Address>(
+        self,
+        mut candidates: impl Iterator<Item = (Placement<A>, u64)>,
+    ) -> Option<Placement<A>> {
+        let candidate = match self {
+            // next-fit is first-fit over candidates that start at the cursor
+            Policy::FirstFit | Policy::NextFit => candidates.next(),
+            Policy::OldestFree => candidates.min_by_key(|(_, epoch)| *epoch),
+            Policy::NewestFree => candidates.max_by_key(|(_, epoch)| *epoch),
+            Policy::BestFit => {
+                candidates.min_by_key(|(placement, _)| placement.block.end - placement.block.start)
+            }
+            // `max_by_key` would pick the last of equally large blocks
+            Policy::WorstFit => candidates
+                .min_by_key(|(placement, _)| Reverse(placement.block.end - placement.block.start)),
+        };
+        candidate.map(|(placement, _)| placement)
     }
 
-    pub fn new_btree() -> btree::RangeAllocator<()> {
-        btree::RangeAllocator::new()
+    /// like [`select`](Self::select), but regions with a policy of their own are not compared
+    /// with the rest. `region_policy` returns the base and policy of such a region for an address
+    /// in it. The region of the first candidate in search order is picked, and the candidates in
+    /// it are selected from under its policy, or under `self` if it has none
+    fn select_per_region<A: Address>(
+        self,
+        candidates: impl Iterator<Item = (Placement<A>, u64)>,
+        region_policy: impl Fn(A) -> Option<(A, Policy)>,
+    ) -> Option<Placement<A>> {
+        let mut candidates = candidates.peekable();
+        let (first, _) = candidates.peek()?;
+        let pool = region_policy(first.block.start);
+        let policy = pool.map_or(self, |(_, policy)| policy);
+        policy.select(candidates.filter(|(placement, _)| {
+            region_policy(placement.block.start).map(|(base, _)| base) == pool.map(|(base, _)| base)
+        }))
     }
+}
 
-    pub fn setup(a: &mut impl RangeAlloc<Tag = ()>) {
-        a.add_range(0x7ff000, 4096 * 4096, ())
-            .expect("can add range");
+/// how the work of an operation grows with the number `n` of free blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Complexity {
+    Constant,
+    /// `O(log n)`, e.g. a lookup in a sorted map
+    Logarithmic,
+    /// `O(n)`, e.g. a walk over all free blocks
+    Linear,
+}
 
-        a.add_range(0xfff0000, 4096 * 128, ())
-            .expect("can add range");
+/// the worst-case [`Complexity`] of a backend's operations. The tests hold the backends to it by
+/// counting the free blocks an operation examines as the free map grows, see e.g.
+/// [`linear::RangeAllocator::steps`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComplexityClass {
+    pub alloc: Complexity,
+    pub alloc_fixed: Complexity,
+    pub free: Complexity,
+}
+
+/// the number of free blocks a backend examined one by one. Atomic so searches through `&self`
+/// can count too
+#[derive(Debug, Default)]
+struct Steps(AtomicUsize);
+
+impl Steps {
+    fn add(&self, n: usize) {
+        // a statistic, nothing is ordered around it
+        self.0.fetch_add(n, Ordering::Relaxed);
     }
 
-    /// allocate n times from a, picking the sizes and alignments from `sizes` and `alignments`
-    /// both iterators are made to wrap around
-    pub fn allocate_n(
-        a: &mut impl RangeAlloc<Tag = ()>,
-        sizes: impl Iterator<Item = usize> + Clone,
-        alignments: impl Iterator<Item = usize> + Clone,
-        n: usize,
-    ) -> Vec<(usize, usize)> {
-        let mut sizes = sizes.cycle();
-        let mut alignments = alignments.cycle();
-
-        let mut positions = Vec::with_capacity(n);
-        for _ in 0..n {
-            let size = sizes.next().unwrap();
-            let Ok((_, x)) = a.alloc(size, alignments.next().unwrap()) else {
-                continue;
-            };
+    fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
 
-            positions.push((x, size));
-        }
+/// which end of the address space allocations are taken from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Direction {
+    /// from the start of free blocks, lowest addresses first
+    #[default]
+    BottomUp,
+    /// from the end of free blocks, highest addresses first, the way firmware and kernel loaders
+    /// conventionally place some structures
+    TopDown,
+}
+
+/// an allocation request, as passed to [`RangeAlloc::alloc`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request<A = usize> {
+    pub size: A,
+    pub alignment: A,
+}
 
-        positions
+impl<A: Address> Request<A> {
+    pub fn new(size: A, alignment: A) -> Self {
+        Request { size, alignment }
     }
 
-    pub fn alloc_aligned(a: &mut impl RangeAlloc<Tag = ()>) {
-        let (_, x) = a.alloc(black_box(4096), 4096 * 4096).expect("can allocate");
-        let (_, y) = a.alloc(black_box(4096), 4096 * 4096).expect("can allocate");
-        a.free(x, 4096).expect("can free again");
-        a.free(y, 4096).expect("can free again");
+    /// validates the request and rounds its size up to a multiple of `granularity`
+    fn normalized(self, granularity: Alignment<A>) -> Result<Request<A>> {
+        Ok(Request {
+            size: Size::new(self.size)?.round_up(granularity)?.get(),
+            alignment: Alignment::new(self.alignment)?.get(),
+        })
     }
+}
 
-    pub fn alloc_different_configurations(a: &mut impl RangeAlloc<Tag = ()>) {
-        const N: usize = 500;
+/// where a request is, or would be, placed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placement<A = usize> {
+    pub base: A,
+    pub size: A,
+    /// the free block the allocation is carved from
+    pub block: Range<A>,
+}
 
-        let sizes = [10, 3, 5, 6, 2, 9, 1, 4, 8, 7].map(|x| x * 4096);
-        let alignments = [8, 2, 9, 1, 3, 0, 6, 5, 7].map(|x| 4096 << x);
+impl<A: Address> Placement<A> {
+    /// places an allocation of `size` at the lowest `alignment` boundary in `block`, or the
+    /// highest one when going top-down, if it fits. The end of the allocation is rounded up to
+    /// `granularity`
+    fn within(
+        block: Range<A>,
+        alignment: A,
+        size: A,
+        granularity: Alignment<A>,
+        direction: Direction,
+    ) -> Option<Placement<A>> {
+        if direction == Direction::TopDown {
+            // aligned to the granularity as well, so the end is a multiple of it without rounding
+            let base = block
+                .end
+                .checked_sub(size)?
+                .round_down(alignment.max(granularity.get()));
+            return (base >= block.start).then_some(Placement { base, size, block });
+        }
+        let base = block.start.round_up(alignment)?;
+        if base > block.end || size > block.end - base {
+            return None;
+        }
+        let end = (base + size).round_up(granularity.get())?;
+        Some(Placement {
+            base,
+            size: end - base,
+            block,
+        })
+    }
 
-        let mut sizes = sizes.iter().copied().cycle();
-        let mut alignments = alignments.iter().copied().cycle();
+    /// like [`within`](Self::within), but only uses the part of `block` inside `window`
+    fn within_window(
+        block: Range<A>,
+        window: &Range<A>,
+        alignment: A,
+        size: A,
+        granularity: Alignment<A>,
+        direction: Direction,
+    ) -> Option<Placement<A>> {
+        let start = block.start.max(window.start);
+        let end = block.end.min(window.end);
+        if start >= end {
+            return None;
+        }
+        Placement::within(start..end, alignment, size, granularity, direction)
+            .filter(|p| p.base + p.size <= window.end)
+            .map(|p| Placement { block, ..p })
+    }
+}
 
-        let mut positions = [(0, 0); N];
+/// constraints of a region that apply to every allocation placed in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RegionAttrs<A = usize> {
+    /// allocations in the region are aligned to, and their size is rounded up to, a multiple of
+    /// the granule. Has to be a power of two
+    pub granule: A,
+    /// how to pick among the free blocks of the region. Uses the allocator's policy if unset
+    pub policy: Option<Policy>,
+}
 
-        for pos in positions.iter_mut() {
-            let size = sizes.next().unwrap();
-            let Ok((_, x)) = a.alloc(size, alignments.next().unwrap()) else {
-                continue;
-            };
-            // .expect("can allocate");
+impl<A: Address> Default for RegionAttrs<A> {
+    fn default() -> Self {
+        RegionAttrs {
+            granule: A::ONE,
+            policy: None,
+        }
+    }
+}
 
-            *pos = (x, size);
+impl<A: Address> RegionAttrs<A> {
+    pub fn with_granule(granule: A) -> Self {
+        RegionAttrs {
+            granule,
+            ..Self::default()
         }
+    }
 
-        for pos in positions {
-            if pos.0 == 0 {
-                continue;
-            }
-            a.free(pos.0, pos.1).expect("can free");
+    pub fn with_policy(policy: Policy) -> Self {
+        RegionAttrs {
+            policy: Some(policy),
+            ..Self::default()
         }
     }
 
-    #[test]
-    fn simple() {
-        let mut allocator: RangeAllocator<()> = RangeAllocator::new();
-        let n = 8;
+    fn validate(&self) -> Result<()> {
+        Alignment::new(self.granule)?;
+        Ok(())
+    }
 
-        allocator
-            .add_range(0xa00000, 4096 * n, ())
-            .expect("can add range");
+    /// raises `(alignment, size)` of a request to what the region requires. A size that does not
+    /// fit after rounding saturates, so it never fits into a block
+    fn apply(&self, alignment: A, size: A) -> (A, A) {
+        let size = size.round_up(self.granule).unwrap_or(A::MAX);
+        (alignment.max(self.granule), size)
+    }
 
-        let x: Vec<_> = (0..n)
-            .map(|_| allocator.alloc(4096, 4096).expect("can allocate").1)
-            .collect();
+    /// rounds the size of a fixed allocation at `base` up to the granule, failing if `base` is
+    /// not a multiple of it
+    fn apply_fixed(&self, base: A, size: A) -> Result<A> {
+        let granule = Alignment::new(self.granule)?;
+        if !granule.is_aligned(base) {
+            return Err(Error::new(ErrorKind::InvalidAlignment));
+        }
+        Ok(Size::new(size)?.round_up(granule)?.get())
+    }
+}
 
-        for (i, a) in x.iter().enumerate() {
-            for (j, b) in x.iter().enumerate() {
-                if i == j {
-                    continue;
-                }
+/// hard caps on an allocator's bookkeeping, for environments that have to bound its memory use
+/// and run time. Operations that would exceed them fail with [`ErrorKind::CapacityExceeded`]
+/// without changing anything. No limits are set by default
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Limits {
+    /// regions, usable and reserved
+    pub max_regions: Option<usize>,
+    /// separate free extents, which splitting a free block or freeing a range without free
+    /// neighbours adds
+    pub max_free_extents: Option<usize>,
+}
 
-                assert_ne!(a, b, "cannot allocate same region twice");
-                if a < b {
-                    assert!(b - a >= 4096, "cannot overlap");
-                } else {
-                    assert!(a - b >= 4096, "cannot overlap");
-                }
-            }
+impl Limits {
+    /// fails if one more than `count` exceeds `max`. `count` is only evaluated if there is a
+    /// limit
+    fn room_for_one(max: Option<usize>, count: impl FnOnce() -> usize) -> Result<()> {
+        match max {
+            Some(max) if count() >= max => Err(Error::new(ErrorKind::CapacityExceeded)),
+            _ => Ok(()),
         }
+    }
 
-        for base in x {
-            allocator.free(base, 4096).expect("can free");
+    /// fails if `regions` or `free_extents` already exceed the limits
+    fn admit(&self, regions: usize, free_extents: usize) -> Result<()> {
+        let within = |max: Option<usize>, count| max.is_none_or(|max| count <= max);
+        if within(self.max_regions, regions) && within(self.max_free_extents, free_extents) {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::CapacityExceeded))
         }
     }
+}
 
-    macro_rules! both_tests {
-        ($linear:ident, $btree:ident, $a:ident => $case:expr) => {
-            #[test]
-            fn $linear() {
-                let mut $a: linear::RangeAllocator<()> = new_linear();
-                $case
-            }
+/// what an [`Observer`] is told after a successful operation. Ranges are the ones the allocator
+/// keeps, so they include the rounding to the granularity and the guard
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event<'a, Tag, A = usize> {
+    /// a usable region was added
+    AddRange { range: Range<A>, tag: &'a Tag },
+    /// `range` was allocated from the region tagged `tag`, by any kind of allocation or by
+    /// growing an allocation into it
+    Alloc { range: Range<A>, tag: &'a Tag },
+    /// `range` was freed, by a free or by shrinking an allocation
+    Free { range: Range<A> },
+}
 
-            #[test]
-            fn $btree() {
-                let mut $a: btree::RangeAllocator<()> = new_btree();
-                $case
-            }
-        };
+/// a callback set with `set_observer` on the backends, e.g. to mirror the allocator's activity
+/// into tracing or shadow page tables without wrapping every call site
+pub type Observer<Tag, A = usize> = Box<dyn FnMut(Event<'_, Tag, A>) + Send + Sync>;
+
+/// tells `observer`, if there is one, about `event`
+fn notify<Tag, A>(observer: &mut Option<Observer<Tag, A>>, event: Event<'_, Tag, A>) {
+    if let Some(observer) = observer {
+        observer(event);
     }
+}
 
-    both_tests!(linear_alloc_2_aligned, btree_alloc_2_aligned, a => {
-        setup(&mut a);
-        tests::alloc_aligned(&mut a);
-    });
+/// counters the backends keep as they go, cheap enough to always be on. Sizes include the
+/// rounding to the granularity, reserved ranges do not count as allocated
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// successful allocations, fixed ones included
+    pub allocs: u64,
+    pub frees: u64,
+    /// size of everything allocated right now
+    pub allocated: u64,
+    /// the most that was allocated at once, since the allocator was created or
+    /// `reset_peak` was called
+    pub peak_allocated: u64,
+    pub failures: Failures,
+    /// the live allocations by size
+    #[cfg(feature = "histogram")]
+    pub live_sizes: SizeHistogram,
+}
 
-    both_tests!(linear_alloc_different_configurations, btree_alloc_different_configurations, a => {
-        setup(&mut a);
-        tests::alloc_different_configurations(&mut a);
-    });
+/// failed allocations by the reason they failed for
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Failures {
+    /// [`ErrorKind::OutOfSpace`]
+    pub out_of_space: u64,
+    /// [`ErrorKind::Overconstrained`]
+    pub overconstrained: u64,
+    /// [`ErrorKind::RequestExceedsAnyRegion`]
+    pub exceeds_region: u64,
+    /// fixed allocations of ranges that are not free
+    pub not_free: u64,
+    /// everything else, e.g. invalid sizes or exceeded [`Limits`]
+    pub other: u64,
+}
+
+/// number of live allocations in every power-of-two size class. Bin `i` counts the sizes from
+/// `1 << i` up to, but not including, `2 << i`. Resizing an allocation moves it to the bin of its
+/// new size, freeing only part of one takes it out of the bin of the part that was freed
+#[cfg(feature = "histogram")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeHistogram {
+    bins: [u64; 64],
+}
+
+#[cfg(feature = "histogram")]
+impl Default for SizeHistogram {
+    fn default() -> Self {
+        SizeHistogram { bins: [0; 64] }
+    }
+}
+
+#[cfg(feature = "histogram")]
+impl SizeHistogram {
+    /// the live allocations of at least `1 << order` and less than `2 << order`
+    pub fn count(&self, order: u32) -> u64 {
+        self.bins.get(order as usize).copied().unwrap_or(0)
+    }
+
+    /// the non-empty bins, smallest first, as the smallest size they hold and their count
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        (0..64)
+            .filter(|&order| self.bins[order] > 0)
+            .map(|order| (1 << order, self.bins[order]))
+    }
+
+    /// number of live allocations
+    pub fn total(&self) -> u64 {
+        self.bins.iter().sum()
+    }
+
+    fn bin(size: u64) -> usize {
+        size.max(1).ilog2() as usize
+    }
 
-    fn overlap(a: (usize, usize), b: (usize, usize)) -> bool {
-        if a.0 < b.0 {
-            a.0 + a.1 > b.0
+    fn add(&mut self, size: u64) {
+        self.bins[Self::bin(size)] += 1;
+    }
+
+    /// frees of ranges that were never allocated can not take a bin below zero
+    fn remove(&mut self, size: u64) {
+        let bin = &mut self.bins[Self::bin(size)];
+        *bin = bin.saturating_sub(1);
+    }
+}
+
+impl Stats {
+    fn allocated(&mut self, size: u64) {
+        self.allocs += 1;
+        self.grown(size);
+        #[cfg(feature = "histogram")]
+        self.live_sizes.add(size);
+    }
+
+    /// counts the allocation of `old` bytes growing or shrinking to `new` bytes
+    fn resized(&mut self, old: u64, new: u64) {
+        if new > old {
+            self.grown(new - old);
         } else {
-            b.0 + b.1 > a.0
+            self.shrunk(old - new);
+        }
+        #[cfg(feature = "histogram")]
+        {
+            self.live_sizes.remove(old);
+            self.live_sizes.add(new);
         }
     }
 
-    fn run_trace(mut a: impl RangeAlloc<Tag = u64>, trace: &str) {
-        // add <region-id> <start> <size>
-        // alloc <allocation-id> <size> <alignment> fail
-        // free <allocation-id>
+    fn grown(&mut self, size: u64) {
+        self.allocated += size;
+        self.peak_allocated = self.peak_allocated.max(self.allocated);
+    }
+
+    fn freed(&mut self, size: u64) {
+        self.frees += 1;
+        self.shrunk(size);
+        #[cfg(feature = "histogram")]
+        self.live_sizes.remove(size);
+    }
 
-        let mut regions = HashSet::new();
-        let mut allocations = HashMap::new();
+    /// frees of ranges that were never allocated can not take it below zero
+    fn shrunk(&mut self, size: u64) {
+        self.allocated = self.allocated.saturating_sub(size);
+    }
 
-        let mut errors = 0;
-        let mut error = |msg: &dyn std::fmt::Display| {
-            errors += 1;
-            eprintln!("{msg}")
+    fn failed(&mut self, e: &Error) {
+        let count = match e.kind() {
+            ErrorKind::OutOfSpace => &mut self.failures.out_of_space,
+            ErrorKind::Overconstrained { .. } => &mut self.failures.overconstrained,
+            ErrorKind::RequestExceedsAnyRegion { .. } => &mut self.failures.exceeds_region,
+            ErrorKind::NotFree => &mut self.failures.not_free,
+            _ => &mut self.failures.other,
         };
+        *count += 1;
+    }
 
-        for line in trace.lines() {
-            let mut l = line.split_whitespace();
-            let Some(cmd) = l.next() else { continue };
-
-            macro_rules! next_int {
-                (let $f:ident <- $l:expr) => {
-                    let $f = $l
-                        .next()
-                        .expect(concat!("has ", stringify!($f)))
-                        .parse::<u64>()
-                        .expect(concat!(stringify!($f), " is u64"));
-                };
-            }
+    /// counts the outcome of an allocation of `size` bytes
+    fn count<T>(&mut self, result: &Result<T>, size: impl FnOnce(&T) -> u64) {
+        match result {
+            Ok(x) => self.allocated(size(x)),
+            Err(e) => self.failed(e),
+        }
+    }
+}
 
-            match cmd {
-                "add" => {
-                    next_int!(let region_id <- l);
-                    next_int!(let start <- l);
-                    next_int!(let size <- l);
-                    assert!(regions.insert(region_id), "duplicate region id {region_id}");
-
-                    a.add_range(
-                        start.try_into().unwrap(),
-                        size.try_into().unwrap(),
-                        region_id,
-                    );
-                }
-                "alloc" => {
-                    next_int!(let allocation_id <- l);
-                    next_int!(let size <- l);
-                    next_int!(let alignment <- l);
-                    let fail = l.next().is_some_and(|x| x == "fail");
-
-                    let size = size.try_into().unwrap();
-                    // maybe instead of failing on error we should keep going, it can be caused by
-                    // a suboptimal allocator, which is not necessarily incorrect
-                    match a.alloc(size, alignment.try_into().unwrap()) {
-                        Err(_) if fail => {}
-                        Err(e) => error(&"unexpected error {e:?} {line}"),
-                        Ok(_) if fail => error(&"did not expect to succeed"),
-                        Ok((tag, base)) => {
-                            assert!(
-                                regions.contains(&tag),
-                                "tag {tag} was not added to allocator"
-                            );
-                            allocations.insert(allocation_id, (base, size));
-
-                            for (id, &other) in allocations.iter() {
-                                if *id == allocation_id {
-                                    continue;
-                                }
-                                assert!(!overlap((base, size), other));
-                            }
-                        }
-                    }
-                }
-                "free" => {
-                    next_int!(let allocation_id <- l);
+/// the first two touching regions that together span at least `size`, from regions sorted by base
+fn bridging_pair<A: Address>(regions: &[Range<A>], size: A) -> Option<Range<A>> {
+    regions.windows(2).find_map(|pair| {
+        let span = pair[0].start..pair[1].end;
+        (pair[0].end == pair[1].start && span.end - span.start >= size).then_some(span)
+    })
+}
+
+/// checks the holes of a region added with `add_range_with_holes`: every hole has to be non-empty,
+/// inside `region` and apart from the others, touching is fine
+fn check_holes<A: Address>(region: &Range<A>, holes: &[Range<A>]) -> Result<()> {
+    let mut holes = holes.to_vec();
+    holes.sort_by_key(|hole| hole.start);
+    for hole in &holes {
+        if hole.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidSize));
+        }
+        if hole.start < region.start || region.end < hole.end {
+            return Err(Error::new(ErrorKind::NotOwned));
+        }
+    }
+    if holes.windows(2).any(|pair| pair[0].end > pair[1].start) {
+        return Err(Error::new(ErrorKind::OverlappingRange));
+    }
+    Ok(())
+}
 
-                    let Some((base, size)) = allocations.remove(&allocation_id) else {
-                        continue;
-                    };
+/// the entries of a memory map sorted by base, with touching entries of equal tags merged. Fails
+/// if an entry is empty or overlaps another one or any of the `existing` regions, which have to
+/// be sorted
+fn merged_regions<Tag: PartialEq, A: Address>(
+    entries: impl IntoIterator<Item = (A, A, Tag)>,
+    existing: &[Range<A>],
+) -> Result<Vec<(Range<A>, Tag)>> {
+    let mut entries = entries
+        .into_iter()
+        .map(|(base, size, tag)| {
+            let size = Size::new(size)?.get();
+            let end = base
+                .checked_add(size)
+                .ok_or_else(|| Error::new(ErrorKind::Overflow))?;
+            Ok((base..end, tag))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort_by_key(|(range, _)| range.start);
 
-                    a.free(base, size).expect("can free");
-                }
-                _ => core::panic!("unknown command {cmd}"),
+    let overlapping = || Error::new(ErrorKind::OverlappingRange);
+    let mut merged: Vec<(Range<A>, Tag)> = Vec::with_capacity(entries.len());
+    for (range, tag) in entries {
+        match merged.last_mut() {
+            Some((last, _)) if last.end > range.start => return Err(overlapping()),
+            Some((last, last_tag)) if last.end == range.start && *last_tag == tag => {
+                last.end = range.end
             }
+            _ => merged.push((range, tag)),
+        }
+    }
+    // both are sorted, so a single walk finds every overlap between them
+    let mut existing = existing.iter().peekable();
+    for (range, _) in &merged {
+        while existing
+            .next_if(|region| region.end <= range.start)
+            .is_some()
+        {}
+        if existing
+            .peek()
+            .is_some_and(|region| region.start < range.end)
+        {
+            return Err(overlapping());
         }
     }
+    Ok(merged)
+}
 
-    macro_rules! trace_test {
-        ($trace:ident) => {
-            #[test]
-            fn $trace() {
-                let a = linear::RangeAllocator::new();
-                run_trace(a, include_str!(concat!("testdata/", stringify!($trace))))
-            }
-        };
+/// the live allocations of a backend that tracks them
+#[derive(Debug, Clone)]
+struct Allocations<Tag, A> {
+    /// base -> end and the tag of the region it was allocated from
+    map: BTreeMap<A, (A, Tag)>,
+}
+
+impl<Tag, A> Default for Allocations<Tag, A> {
+    fn default() -> Self {
+        Allocations {
+            map: BTreeMap::new(),
+        }
     }
+}
 
-    trace_test!(basic_trace);
-    trace_test!(gen1);
-    trace_test!(gen2);
+impl<Tag: Clone, A: Address> Allocations<Tag, A> {
+    fn insert(&mut self, range: Range<A>, tag: Tag) {
+        self.map.insert(range.start, (range.end, tag));
+    }
+
+    /// the allocation containing `addr`
+    fn get(&self, addr: A) -> Option<(Range<A>, &Tag)> {
+        self.map
+            .range(..=addr)
+            .next_back()
+            .filter(|&(_, &(end, _))| addr < end)
+            .map(|(&base, (end, tag))| (base..*end, tag))
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Range<A>, &Tag)> + '_ {
+        self.map.iter().map(|(&base, (end, tag))| (base..*end, tag))
+    }
+
+    /// whether every address of `range` is allocated, by one allocation or several touching ones
+    fn covers(&self, range: Range<A>) -> bool {
+        let mut start = range.start;
+        while start < range.end {
+            match self.get(start) {
+                Some((allocation, _)) => start = allocation.end,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// fails unless `range` is [covered](Self::covers), for frees of ranges never handed out
+    #[track_caller]
+    fn check_allocated(&self, range: Range<A>) -> Result<()> {
+        if self.covers(range) {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::NotAllocated))
+        }
+    }
+
+    /// moves the end of the allocation starting at `base`
+    fn resize(&mut self, base: A, end: A) {
+        if let Some((old_end, _)) = self.map.get_mut(&base) {
+            *old_end = end;
+        }
+    }
+
+    /// forgets `range`, which may cover just a part of an allocation, e.g. when it is shrunk, or
+    /// several of them
+    fn remove(&mut self, range: Range<A>) {
+        let overlapping: Vec<_> = self
+            .map
+            .range(..range.end)
+            .rev()
+            .take_while(|&(_, &(end, _))| end > range.start)
+            .map(|(&base, _)| base)
+            .collect();
+        for base in overlapping {
+            let (end, tag) = self.map.remove(&base).expect("allocation exists");
+            if base < range.start {
+                self.insert(base..range.start, tag.clone());
+            }
+            if range.end < end {
+                self.insert(range.end..end, tag);
+            }
+        }
+    }
+}
+
+/// rounds `n` up to a multiple of the power of two `size`. A `size` of 0 leaves `n` unchanged
+#[macro_export]
+macro_rules! round_up {
+    ($n:expr, $size:expr) => {{
+        let n = $n;
+        let size = $size.max(1);
+        (n + size - 1) & (!(size - 1))
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        eprintln, format,
+        hint::black_box,
+        ops::Range,
+        string::String,
+        vec,
+        vec::Vec,
+    };
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::testkit::*;
+
+    #[test]
+    fn simple() {
+        let mut allocator: RangeAllocator<()> = RangeAllocator::new();
+        let n = 8;
+
+        allocator
+            .add_range(0xa00000, 4096 * n, ())
+            .expect("can add range");
+
+        let x: Vec<_> = (0..n)
+            .map(|_| allocator.alloc(4096, 4096).expect("can allocate").1)
+            .collect();
+
+        for (i, a) in x.iter().enumerate() {
+            for (j, b) in x.iter().enumerate() {
+                if i == j {
+                    continue;
+                }
+
+                assert_ne!(a, b, "cannot allocate same region twice");
+                if a < b {
+                    assert!(b - a >= 4096, "cannot overlap");
+                } else {
+                    assert!(a - b >= 4096, "cannot overlap");
+                }
+            }
+        }
+
+        for base in x {
+            allocator.free(base, 4096).expect("can free");
+        }
+    }
+
+    macro_rules! both_tests {
+        ($linear:ident, $btree:ident, $a:ident => $case:expr) => {
+            #[test]
+            fn $linear() {
+                let mut $a: linear::RangeAllocator<()> = new_linear();
+                $case
+            }
+
+            #[test]
+            fn $btree() {
+                let mut $a: btree::RangeAllocator<()> = new_btree();
+                $case
+            }
+        };
+    }
+
+    both_tests!(linear_alloc_2_aligned, btree_alloc_2_aligned, a => {
+        setup(&mut a);
+        tests::alloc_aligned(&mut a);
+    });
+
+    both_tests!(linear_alloc_different_configurations, btree_alloc_different_configurations, a => {
+        setup(&mut a);
+        tests::alloc_different_configurations(&mut a);
+    });
+
+    /// replays `trace` against `a`. If `check_layout` is set, `expect` commands must match the
+    /// exact placement, otherwise they are ignored so the trace can run against any policy
+    fn run_trace(mut a: impl RangeAlloc<Tag = u64>, trace: &str, check_layout: bool) {
+        if let Err(e) = replay::replay_str(&mut a, trace, check_layout, ()) {
+            core::panic!("{e}");
+        }
+    }
+
+    macro_rules! trace_test {
+        ($trace:ident) => {
+            #[test]
+            fn $trace() {
+                let a = linear::RangeAllocator::new();
+                run_trace(
+                    a,
+                    include_str!(concat!("testdata/", stringify!($trace))),
+                    false,
+                )
+            }
+        };
+    }
+
+    /// golden-layout test: the trace's `expect` commands pin down the exact placement policy of
+    /// the backend
+    macro_rules! layout_test {
+        ($name:ident, $trace:ident, $alloc:expr) => {
+            #[test]
+            fn $name() {
+                run_trace(
+                    $alloc,
+                    include_str!(concat!("testdata/", stringify!($trace))),
+                    true,
+                )
+            }
+        };
+    }
+
+    trace_test!(basic_trace);
+    trace_test!(gen1);
+    trace_test!(gen2);
+
+    trace_test!(fixed_trace);
+    #[test]
+    fn btree_fixed_trace() {
+        run_trace(
+            btree::RangeAllocator::new(),
+            include_str!("testdata/fixed_trace"),
+            false,
+        )
+    }
+
+    layout_test!(
+        linear_layout_exact,
+        linear_layout,
+        linear::RangeAllocator::new()
+    );
+    layout_test!(
+        btree_layout_exact,
+        btree_layout,
+        btree::RangeAllocator::new()
+    );
+
+    #[test]
+    fn generated_traces() {
+        let config = trace::GenConfig {
+            ops: 2000,
+            alloc_percent: 60,
+            ..Default::default()
+        };
+        for seed in 1..20 {
+            let trace = trace::generate(seed, &config);
+            let mut a = linear::RangeAllocator::new();
+            replay::replay(&mut a, &trace, false, ()).unwrap_or_else(|e| core::panic!("{e}"));
+            let mut b = btree::RangeAllocator::new();
+            replay::replay(&mut b, &trace, false, ()).unwrap_or_else(|e| core::panic!("{e}"));
+        }
+    }
+
+    #[test]
+    fn replay_failures() {
+        use replay::{Failure, ReplayError, replay_str};
+
+        let failure = |trace: &str| {
+            let mut a = btree::RangeAllocator::<u64>::new();
+            match replay_str(&mut a, trace, true, ()) {
+                Err(ReplayError::Failed { index, failure, .. }) => (index, failure),
+                other => core::panic!("{other:?}"),
+            }
+        };
+        let add = "add 1 0x1000 0x4000\n";
+        assert!(matches!(
+            failure(&format!("{add}add 1 0x8000 0x1000")),
+            (1, Failure::DuplicateRegion)
+        ));
+        assert!(matches!(
+            failure(&format!("{add}alloc 1 0x1000 0x1000 fail")),
+            (1, Failure::UnexpectedSuccess)
+        ));
+        assert!(matches!(
+            failure(&format!("{add}alloc 1 0x8000 0x1000")),
+            (1, Failure::UnexpectedError(_))
+        ));
+        assert!(matches!(
+            failure(&format!("{add}alloc 1 0x1000 0x1000\nexpect 1 0x2000")),
+            (2, Failure::Misplaced { base: 0x1000 })
+        ));
+        assert!(matches!(
+            failure(&format!("{add}free 1\nexpect 1 0x1000")),
+            (2, Failure::NotLive)
+        ));
+        assert!(matches!(
+            replay_str(
+                &mut btree::RangeAllocator::<u64>::new(),
+                "alloc 1",
+                false,
+                ()
+            ),
+            Err(ReplayError::Parse(_))
+        ));
+
+        // validation runs after every operation and stops the replay
+        let mut a = btree::RangeAllocator::<u64>::new();
+        let mut seen = 0;
+        let result = replay_str(
+            &mut a,
+            &format!("{add}alloc 1 0x1000 0x1000\nalloc 2 0x1000 0x1000"),
+            false,
+            |a: &btree::RangeAllocator<u64>, _: &trace::TraceOp, live: &replay::Live| {
+                seen += 1;
+                if live.len() > 1 {
+                    return Err(format!("{:#x} free", a.space()));
+                }
+                Ok(())
+            },
+        );
+        let Err(ReplayError::Failed {
+            index: 2,
+            failure: Failure::Invalid(reason),
+            ..
+        }) = result
+        else {
+            core::panic!("{result:?}");
+        };
+        assert_eq!((reason.as_str(), seen), ("0x2000 free", 3));
+
+        let live = replay_str(&mut a, "", false, ()).expect("nothing to replay");
+        assert!(live.is_empty());
+    }
+
+    #[cfg(feature = "record")]
+    #[test]
+    fn record_trace() {
+        let mut r = trace::Recorder::new(new_btree(), Vec::new());
+        r.add_range(0x1000, 0x10_0000, ()).expect("can add range");
+        r.add_range(0x1000, 0x1000, ()).unwrap_err();
+        let (_, x) = r.alloc(0x3000, 0x1000).expect("can allocate");
+        r.alloc_fixed(0x8_0000, 0x2000).expect("can allocate");
+        r.alloc_fixed(0x8_1000, 0x1000).unwrap_err();
+        r.alloc_within(0x1000, 0x1000, 0x9_0000..0xa_0000)
+            .expect("can allocate");
+        r.free(x, 0x3000).expect("can free");
+        r.alloc(0x20_0000, 0x1000).unwrap_err();
+        r.alloc(0x1000, 0x4000).expect("can allocate");
+        let (_, out) = r.finish().expect("can write to a Vec");
+
+        let trace = std::string::String::from_utf8(out).expect("traces are text");
+        assert!(trace.contains("alloc_fixed 3 0x90000 0x1000\n"));
+        // the placements are pinned down by `expect`
+        run_trace(btree::RangeAllocator::new(), &trace, true);
+    }
+
+    both_tests!(linear_offset_allocator, btree_offset_allocator, a => {
+        use crate::offset::OffsetAllocator;
+
+        let base = 0x4000_0000;
+        let mut a = OffsetAllocator::with_range(a, base, 4096 * 16, ()).expect("can add range");
+
+        let (_, x) = a.alloc(4096, 4096).expect("can allocate");
+        assert_eq!(x, 0);
+        let (_, y) = a.alloc(4096, 4096 * 4).expect("can allocate");
+        assert_eq!(y, 4096 * 4);
+        // the aligned allocation splits a free block, and only the allocation leaves the free space
+        assert_eq!(a.space(), a.total_space() - 2 * 4096);
+        assert_eq!(a.to_absolute(y).unwrap(), base + 4096 * 4);
+
+        a.free(x, 4096).expect("can free");
+        a.free(y, 4096).expect("can free");
+        assert_eq!(a.space(), a.total_space());
+        assert!(a.to_offset(base - 1).is_err());
+    });
+
+    both_tests!(linear_reserve_bottom_and_top, btree_reserve_bottom_and_top, a => {
+        a.add_range(0x0, 0x40_0000, ()).expect("can add range");
+        a.add_range(0x100_0000, 0x20_0000, ()).expect("can add range");
+        let total = a.space();
+
+        a.reserve_bottom(0x10_0000).expect("can reserve bottom");
+        a.reserve_top_in(0x100_0000, 0x1000).expect("can reserve top");
+        assert_eq!(a.reserved_space(), 0x20_1000);
+        assert_eq!(a.space(), total - 0x20_1000);
+        assert_eq!(a.total_space(), total);
+
+        // neither the low megabyte of any region nor the top page is handed out
+        let allocations = allocate_n(&mut a, std::iter::once(4096), std::iter::once(4096), 10000);
+        for &(x, _) in &allocations {
+            assert!(!a.reserved().contains(x));
+        }
+        assert_eq!(a.space(), 0);
+
+        // already allocated, so nothing can be reserved any more
+        assert!(a.reserve_top(0x1000).is_err());
+        assert!(a.reserve(0x20_0000, 0x1000).is_err());
+
+        for (x, size) in allocations {
+            a.free(x, size).expect("can free");
+        }
+        a.unreserve(0x0, 0x10_0000).expect("can unreserve");
+        assert!(a.unreserve(0x0, 0x1000).is_err());
+        assert_eq!(a.space(), total - 0x10_1000);
+    });
+
+    both_tests!(linear_verify_against, btree_verify_against, a => {
+        use crate::verify::Discrepancy;
+
+        a.add_range(0x10_0000, 0x10_0000, ()).expect("can add range");
+        a.reserve_bottom(0x1000).expect("can reserve");
+        let allocations = allocate_n(&mut a, [0x1000, 0x3000].into_iter(), std::iter::once(0x1000), 4);
+        let mapped = || allocations.iter().map(|&(x, size)| x..x + size);
+
+        assert_eq!(a.verify_against(mapped()), []);
+
+        let (x, size) = allocations[1];
+        let discrepancies = a.verify_against(mapped().filter(|r| r.start != x).chain([0x1f_0000..0x1f_1000, 0x30_0000..0x30_1000]));
+        assert_eq!(
+            discrepancies,
+            [
+                Discrepancy::AllocatedButUnmapped(x..x + size),
+                Discrepancy::MappedButFree(0x1f_0000..0x1f_1000),
+                Discrepancy::MappedOutsideRegions(0x30_0000..0x30_1000),
+            ]
+        );
+    });
+
+    both_tests!(linear_reserved_regions, btree_reserved_regions, a => {
+        use crate::map::{MapEntry, RegionKind};
+
+        a.add_range(0x10_0000, 0x10_0000, ()).expect("can add range");
+        a.add_range_reserved(0xa_0000, 0x6_0000, ()).expect("can add reserved range");
+        assert!(a.add_range(0xf_0000, 0x2_0000, ()).is_err());
+        assert!(a.add_range_reserved(0x1f_f000, 0x2000, ()).is_err());
+
+        assert_eq!(a.total_space(), 0x10_0000);
+        assert_eq!(a.space(), 0x10_0000);
+
+        let map = a.export_map();
+        assert_eq!(
+            map,
+            [
+                MapEntry { base: 0xa_0000, size: 0x6_0000, tag: (), kind: RegionKind::Reserved },
+                MapEntry { base: 0x10_0000, size: 0x10_0000, tag: (), kind: RegionKind::Usable },
+            ]
+        );
+        assert!(map.is_sorted());
+        let unique: std::collections::HashSet<_> = map.iter().cloned().chain(a.export_map()).collect();
+        assert_eq!(unique.len(), 2);
+        assert_eq!(a.region_containing(0xb_8000).map(|r| r.kind), Some(RegionKind::Reserved));
+        assert_eq!(a.region_containing(0x10_0000).map(|r| r.kind), Some(RegionKind::Usable));
+        assert_eq!(a.region_containing(0x20_0000), None);
+
+        let allocations = allocate_n(&mut a, std::iter::once(0x1000), std::iter::once(0x1000), 1000);
+        assert!(allocations.iter().all(|&(x, _)| x >= 0x10_0000));
+    });
+
+    both_tests!(linear_region_granule, btree_region_granule, a => {
+        a.add_range(0x1000, 0x4000, ()).expect("can add range");
+        a.add_range_with(0x10_0000, 0x10_0000, (), RegionAttrs::with_granule(0x1_0000))
+            .expect("can add range");
+        assert!(a.add_range_with(0x30_0000, 0x1000, (), RegionAttrs::with_granule(0x3000)).is_err());
+
+        let allocations = allocate_n(&mut a, std::iter::once(0x1000), std::iter::once(0x1000), 8);
+        let (small, big): (Vec<_>, Vec<_>) = allocations.iter().partition(|(x, _)| *x < 0x10_0000);
+        assert_eq!(small.len() + big.len(), 8);
+        assert!(!big.is_empty());
+        for &&(x, _) in &big {
+            assert_eq!(x % 0x1_0000, 0);
+        }
+        // allocations in the big region are rounded up to the granule
+        assert_eq!(a.space(), a.total_space() - small.len() * 0x1000 - big.len() * 0x1_0000);
+
+        for &&(x, _) in &small {
+            a.free(x, 0x1000).expect("can free");
+        }
+        for &&(x, _) in &big {
+            a.free(x, 0x1_0000).expect("can free");
+        }
+        assert_eq!(a.space(), a.total_space());
+
+        // fixed allocations are aligned and rounded up to the granule as well
+        assert_eq!(kind(a.alloc_fixed(0x10_1000, 0x1000)), ErrorKind::InvalidAlignment);
+        a.alloc_fixed(0x11_0000, 0x1000).expect("can allocate");
+        assert_eq!(a.space(), a.total_space() - 0x1_0000);
+        assert_eq!(kind(a.alloc_fixed(0x11_8000, 0x1000)), ErrorKind::InvalidAlignment);
+        a.free(0x11_0000, 0x1_0000).expect("can free");
+        assert_eq!(a.space(), a.total_space());
+    });
+
+    both_tests!(linear_free_block_aging, btree_free_block_aging, a => {
+        a.add_range(0x10_0000, 0x10_0000, ()).expect("can add range");
+        let allocations = allocate_n(&mut a, std::iter::once(0x1000), std::iter::once(0x1000), 256);
+        assert_eq!(a.space(), 0);
+
+        // free every other page so nothing coalesces, in a known order
+        let freed: Vec<_> = allocations.iter().step_by(2).copied().collect();
+        let start = a.epoch();
+        for &(x, size) in &freed {
+            a.free(x, size).expect("can free");
+        }
+        let oldest = freed[0].0;
+        let newest = freed[freed.len() - 1].0;
+
+        assert_eq!(a.cold_free_ranges(start + 2), vec![oldest..oldest + 0x1000]);
+
+        a.set_policy(Policy::OldestFree);
+        assert_eq!(a.alloc(0x1000, 0x1000).expect("can allocate").1, oldest);
+
+        a.set_policy(Policy::NewestFree);
+        assert_eq!(a.alloc(0x1000, 0x1000).expect("can allocate").1, newest);
+    });
+
+    both_tests!(linear_rejects_invalid_units, btree_rejects_invalid_units, a => {
+        assert_eq!(kind(a.add_range(0x1000, 0, ())), ErrorKind::InvalidSize);
+        assert_eq!(kind(a.add_range(usize::MAX - 0xfff, 0x2000, ())), ErrorKind::Overflow);
+        assert_eq!(
+            kind(a.add_range_reserved(usize::MAX - 0xfff, 0x2000, ())),
+            ErrorKind::Overflow
+        );
+        a.add_range(0x1000, 0x4000, ()).expect("can add range");
+
+        assert_eq!(kind(a.alloc(0, 0x1000)), ErrorKind::InvalidSize);
+        assert_eq!(kind(a.alloc(0x1000, 0x3000)), ErrorKind::InvalidAlignment);
+        assert_eq!(kind(a.alloc(usize::MAX, 0x1000)), ErrorKind::Overflow);
+        assert_eq!(kind(a.reserve(0x2000, usize::MAX)), ErrorKind::Overflow);
+        assert_eq!(kind(a.unreserve(0x2000, usize::MAX)), ErrorKind::Overflow);
+        assert_eq!(kind(a.reserve_until(0x2000, usize::MAX, 10)), ErrorKind::Overflow);
+        assert_eq!(a.space(), 0x4000);
+
+        let (_, x) = a
+            .alloc_checked(Size::new(0x1000).unwrap(), Alignment::new(0x2000).unwrap())
+            .expect("can allocate");
+        assert_eq!(x, 0x2000);
+    });
+
+    both_tests!(linear_zero_alignment, btree_zero_alignment, a => {
+        a.add_range(0x1000, 0x4000, ()).expect("can add range");
+        // an alignment of 0 places like an alignment of 1
+        let (_, x) = a.alloc(0x1000, 0).expect("can allocate");
+        assert_eq!(x, 0x1000);
+        let (_, y) = a.alloc_within(0x1000, 0, 0x3000..0x5000).expect("can allocate");
+        assert_eq!(y, 0x3000);
+        assert_eq!(Request::new(0x1000usize, 0).normalized(Alignment::BASE_PAGE).unwrap().alignment, 1);
+        assert_eq!(round_up!(0x1234usize, 0), 0x1234);
+        assert_eq!(0x1234usize.round_up(0), Some(0x1234));
+    });
+
+    both_tests!(linear_add_ranges_partial, btree_add_ranges_partial, a => {
+        a.add_range_reserved(0x8000, 0x1000, ()).expect("can add reserved range");
+
+        let results = a.add_ranges([
+            (0x0, 0x4000, ()),
+            (0x3000, 0x2000, ()),
+            (0x7000, 0x2000, ()),
+            (0xa000, 0, ()),
+            (0xb000, 0x1000, ()),
+        ]);
+        assert_eq!(results.len(), 5);
+        assert_eq!(results[0].as_ref().unwrap().base(), 0x0);
+        assert_eq!(results[1].as_ref().unwrap_err().overlaps.map(RegionId::base), Some(0x0));
+        assert_eq!(results[2].as_ref().unwrap_err().overlaps.map(RegionId::base), Some(0x8000));
+        assert!(results[3].as_ref().unwrap_err().overlaps.is_none());
+        assert_eq!(results[4].as_ref().unwrap().base(), 0xb000);
+
+        assert_eq!(a.total_space(), 0x5000);
+        assert_eq!(a.space(), 0x5000);
+    });
+
+    both_tests!(linear_simulate_policies, btree_simulate_policies, a => {
+        a.add_range(0x0, 0x10000, ()).expect("can add range");
+        let mut allocs: Vec<_> = (0..4)
+            .map(|_| a.alloc(0x1000, 0x1000).expect("can allocate").1)
+            .collect();
+        allocs.sort();
+        a.free(allocs[0], 0x1000).expect("can free");
+        a.free(allocs[2], 0x1000).expect("can free");
+
+        let space = a.space();
+        let results = a.simulate(Request::new(0x1000, 0x1000)).expect("valid request");
+        assert_eq!(a.space(), space);
+        assert_eq!(results.len(), Policy::ALL.len());
+        let pick = |policy| {
+            results
+                .iter()
+                .find(|(p, _)| *p == policy)
+                .and_then(|(_, placement)| placement.clone())
+                .expect("every policy finds a block")
+        };
+        assert_eq!(pick(Policy::NewestFree).base, allocs[2]);
+        let oldest = pick(Policy::OldestFree);
+        assert!(oldest.base != allocs[0] && oldest.base != allocs[2]);
+        assert_eq!(oldest.size, 0x1000);
+
+        a.set_policy(Policy::NewestFree);
+        assert_eq!(a.alloc(0x1000, 0x1000).expect("can allocate").1, allocs[2]);
+
+        let results = a.simulate(Request::new(0x100000, 0x1000)).expect("valid request");
+        assert!(results.iter().all(|(_, placement)| placement.is_none()));
+        assert!(a.simulate(Request::new(0x1000, 3)).is_err());
+    });
+
+    both_tests!(linear_region_policies, btree_region_policies, a => {
+        a.add_range(0x100_0000, 0x10_0000, ()).expect("can add range");
+        a.add_range_with(0x1_0000, 0x8000, (), RegionAttrs::with_policy(Policy::BestFit))
+            .expect("can add range");
+        let small = a.region_containing(0x1_0000).expect("region exists").id();
+        assert_eq!(a.region_policy(small), Some(Policy::BestFit));
+
+        // leave a 1 page hole after a 3 page hole in the small region, and nothing in the big one
+        a.alloc_fixed(0x100_0000, 0x10_0000).expect("can allocate");
+        for page in 0..8 {
+            a.alloc_fixed(0x1_0000 + page * 0x1000, 0x1000).expect("can allocate");
+        }
+        for page in [5, 0, 1, 2] {
+            a.free(0x1_0000 + page * 0x1000, 0x1000).expect("can free");
+        }
+
+        let (_, x) = a.alloc(0x1000, 0x1000).expect("can allocate");
+        assert_eq!(x, 0x1_5000);
+        a.free(x, 0x1000).expect("can free");
+
+        // without the override, the allocator's policy applies to the small region again
+        a.set_policy(Policy::OldestFree);
+        a.set_region_policy(small, None).expect("region exists");
+        assert_eq!(a.region_policy(small), None);
+        let (_, x) = a.alloc(0x1000, 0x1000).expect("can allocate");
+        assert_eq!(x, 0x1_0000);
+
+        let big = a.region_containing(0x100_0000).expect("region exists").id();
+        a.set_region_policy(big, Some(Policy::NewestFree)).expect("region exists");
+        assert_eq!(a.region_policy(big), Some(Policy::NewestFree));
+        assert!(a.set_region_policy(RegionId(0x200_0000), Some(Policy::BestFit)).is_err());
+    });
+
+    fn kind<T>(res: Result<T>) -> ErrorKind {
+        res.map(|_| ()).unwrap_err().kind()
+    }
+
+    both_tests!(linear_grow_shrink, btree_grow_shrink, a => {
+        a.add_range(0x1000, 0x8000, ()).expect("can add range");
+        a.add_range(0x9000, 0x4000, ()).expect("can add range");
+        a.alloc_fixed(0x1000, 0x1000).expect("can allocate");
+        a.alloc_fixed(0x4000, 0x1000).expect("can allocate");
+
+        a.try_grow(0x1000, 0x1000, 0x3000).expect("can grow into the free space");
+        assert_eq!(kind(a.try_grow(0x1000, 0x3000, 0x4000)), ErrorKind::NotFree);
+        assert_eq!(kind(a.try_grow(0x1000, 0x3000, 0x2000)), ErrorKind::InvalidSize);
+        assert_eq!(a.space(), 0x8000);
+
+        // growing stops at the end of the region, even if the next one is free
+        a.try_grow(0x4000, 0x1000, 0x5000).expect("can grow to the end of the region");
+        assert_eq!(kind(a.try_grow(0x4000, 0x5000, 0x6000)), ErrorKind::NotFree);
+
+        a.shrink(0x4000, 0x5000, 0x800).expect("can shrink");
+        assert_eq!(a.space(), 0x8000);
+        assert_eq!(kind(a.shrink(0x1000, 0x3000, 0x4000)), ErrorKind::InvalidSize);
+        a.shrink(0x1000, 0x3000, 0x1000).expect("can shrink");
+        a.free(0x1000, 0x1000).expect("can free");
+        a.free(0x4000, 0x1000).expect("can free");
+        assert_eq!(a.space(), a.total_space());
+        assert_eq!(kind(a.shrink(usize::MAX - 0xfff, 0x2000, 0x1000)), ErrorKind::Overflow);
+
+        // in strict mode only tracked allocations can be resized
+        a.set_strict(true);
+        a.reserve(0x6000, 0x1000).expect("can reserve");
+        assert_eq!(kind(a.try_grow(0x6000, 0x1000, 0x2000)), ErrorKind::NotAllocated);
+        assert_eq!(kind(a.try_grow(0x6000, 0x1000, 0x1000)), ErrorKind::NotAllocated);
+        a.alloc_fixed(0x1000, 0x1000).expect("can allocate");
+        a.try_grow(0x1000, 0x1000, 0x2000).expect("can grow");
+        a.shrink(0x1000, 0x2000, 0x1000).expect("can shrink");
+        assert_eq!(a.space(), a.total_space() - 0x2000);
+    });
+
+    both_tests!(linear_largest_free_in, btree_largest_free_in, a => {
+        a.add_range(0x1000, 0x8000, ()).expect("can add range");
+        a.add_range(0x10_0000, 0x1_0000, ()).expect("can add range");
+        a.reserve(0x10_0000, 0x8000).expect("can reserve");
+        a.alloc_fixed(0x2000, 0x1000).expect("can allocate");
+        a.alloc_fixed(0x6000, 0x1000).expect("can allocate");
+        // free: 0x1000..0x2000, 0x3000..0x6000, 0x7000..0x9000 and 0x10_8000..0x11_0000
+
+        assert_eq!(a.largest_free_in(0..0x10_0000), 0x3000);
+        assert_eq!(a.largest_free_in(0..usize::MAX), 0x8000);
+        // the extents at either end count only with the part inside the range
+        assert_eq!(a.largest_free_in(0x4800..0x8000), 0x1800);
+        assert_eq!(a.largest_free_in(0x5800..0x7800), 0x800);
+        assert_eq!(a.largest_free_in(0x1000..0x3000), 0x1000);
+        assert_eq!(a.largest_free_in(0x2000..0x3000), 0);
+        assert_eq!(a.largest_free_in(0x10_0000..0x10_8000), 0);
+        assert_eq!(a.largest_free_in(0x3000..0x3000), 0);
+    });
+
+    both_tests!(linear_remove_range, btree_remove_range, a => {
+        a.add_range(0x1000, 0x4000, ()).expect("can add range");
+        a.add_range(0x5000, 0x4000, ()).expect("can add range");
+        a.add_range_with(0x10000, 0x4000, (), RegionAttrs::with_policy(Policy::BestFit))
+            .expect("can add range");
+        a.alloc_fixed(0x2000, 0x1000).expect("can allocate");
+        a.reserve(0x6000, 0x1000).expect("can reserve");
+        // the blocks of adjacent regions may have been merged
+        a.free(0x2000, 0x1000).expect("can free");
+        a.alloc_fixed(0x3000, 0x1000).expect("can allocate");
+
+        assert_eq!(kind(a.remove_range(0x1000)), ErrorKind::NotFree);
+        assert_eq!(kind(a.remove_range(0x2000)), ErrorKind::NotOwned);
+        a.remove_range(0x5000).expect("region is unallocated");
+        assert_eq!(a.total_space(), 0x8000);
+        assert_eq!(a.space(), 0x7000);
+        assert!(a.reserved().is_empty());
+        assert!(a.region_containing(0x5000).is_none());
+        assert_eq!(kind(a.alloc_fixed(0x5000, 0x1000)), ErrorKind::NotFree);
+
+        let big = a.region_containing(0x10000).expect("region exists").id();
+        a.remove_range(0x10000).expect("region is unallocated");
+        assert_eq!(kind(a.set_region_policy(big, None)), ErrorKind::NotOwned);
+        assert_eq!(kind(a.alloc(0x4000, 0x1000)), ErrorKind::OutOfSpace);
+
+        // the address range can be added again
+        a.add_range(0x5000, 0x4000, ()).expect("can add range");
+        a.free(0x3000, 0x1000).expect("can free");
+        a.remove_range(0x1000).expect("region is unallocated");
+        assert_eq!(a.space(), a.total_space());
+    });
+
+    both_tests!(linear_free_past_region_end, btree_free_past_region_end, a => {
+        a.add_range(0x1000, 0x2000, ()).expect("can add range");
+        a.add_range(0x3000, 0x2000, ()).expect("can add range");
+        a.alloc_fixed(0x1000, 0x2000).expect("can allocate");
+
+        // the range runs into the next region
+        assert_eq!(kind(a.free(0x2000, 0x2000)), ErrorKind::NotOwned);
+        assert_eq!(a.space(), 0x2000);
+        a.free(0x2000, 0x1000).expect("can free");
+        assert_eq!(a.space(), 0x3000);
+
+        // past the end of the last region as well
+        a.alloc_fixed(0x3000, 0x2000).expect("can allocate");
+        assert_eq!(kind(a.free(0x4000, 0x2000)), ErrorKind::NotOwned);
+        a.free(0x4000, 0x1000).expect("can free");
+        assert_eq!(a.space(), 0x2000);
+        assert_eq!(a.verify_against([0x1000..0x2000, 0x3000..0x4000]), []);
+    });
+
+    both_tests!(linear_free_rounded_size, btree_free_rounded_size, a => {
+        a.add_range(0x1000, 0x2000, ()).expect("can add range");
+        a.add_range(0x3000, 0x2000, ()).expect("can add range");
+
+        // freeing with the requested size releases everything that was allocated
+        let (_, x) = a.alloc(0x10, 0x1000).expect("can allocate");
+        a.free(x, 0x10).expect("can free");
+        assert_eq!(a.space(), a.total_space());
+        assert_eq!(a.verify_against([]), []);
+
+        // the remainder at the end of the region is part of the allocation and freed with it
+        a.add_range(0x10000, 0x1800, ()).expect("can add range");
+        let (_, x) = a.alloc_within(0x10, 0x1000, 0x10000..0x20000).expect("can allocate");
+        assert_eq!(a.space(), a.total_space() - 0x1800);
+        a.free(x, 0x10).expect("can free");
+        a.alloc_fixed(0x10000, 0x1000).expect("can allocate");
+        a.free(0x10000, 0x1000).expect("can free");
+        assert_eq!(a.space(), a.total_space());
+        assert_eq!(a.verify_against([]), []);
+
+        // a block ending at a region boundary is not merged with the next region
+        a.alloc_fixed(0x1000, 0x2000).expect("can allocate");
+        a.alloc_fixed(0x3000, 0x2000).expect("can allocate");
+        a.free(0x1000, 0x2000).expect("can free");
+        a.free(0x3000, 0x1800).expect("can free");
+        assert_eq!(a.space(), a.total_space());
+        assert_eq!(
+            kind(a.alloc(0x4000, 0x1000)),
+            ErrorKind::RequestExceedsAnyRegion {
+                size: 0x4000,
+                largest_region: 0x2000
+            }
+        );
+        assert_eq!(a.bridging_regions(0x4000), Some(0x1000..0x5000));
+    });
+
+    both_tests!(linear_alloc_sized, btree_alloc_sized, a => {
+        a.add_range(0x1000, 0x4000, ()).expect("can add range");
+        let (_, x) = a.alloc_sized(0x10, 0x1000).expect("can allocate");
+        assert_eq!(x, 0x1000..0x2000);
+
+        a.add_range(0x10000, 0x1800, ()).expect("can add range");
+        // the remainder at the end of the region comes with the allocation
+        let (_, y) = a
+            .alloc_sized_within(0x1000, 0x1000, 0x10000..0x20000)
+            .expect("can allocate");
+        assert_eq!(y, 0x10000..0x11800);
+        assert_eq!(a.space(), 0x3000);
+
+        for range in [x, y] {
+            a.free(range.start, range.end - range.start).expect("can free");
+        }
+        assert!(a.is_empty());
+    });
+
+    both_tests!(linear_utilization, btree_utilization, a => {
+        assert!(a.is_empty() && a.is_full());
+        assert_eq!(a.utilization(), 0.0);
+
+        a.add_range(0x1000, 0x4000, ()).expect("can add range");
+        a.reserve(0x1000, 0x1000).expect("can reserve");
+        assert!(a.is_empty() && !a.is_full());
+        assert_eq!(a.utilization(), 0.25);
+
+        let (_, x) = a.alloc(0x1000, 0x1000).expect("can allocate");
+        assert!(!a.is_empty());
+        a.alloc_fixed(0x3000, 0x2000).expect("can allocate");
+        assert!(a.is_full());
+        assert_eq!(a.utilization(), 1.0);
+
+        a.free(x, 0x1000).expect("can free");
+        a.free(0x3000, 0x2000).expect("can free");
+        assert!(a.is_empty());
+        assert_eq!(a.space() + a.reserved_space(), a.total_space());
+    });
+
+    both_tests!(linear_track_allocations, btree_track_allocations, a => {
+        a.add_range(0x1000, 0x8000, ()).expect("can add range");
+        let (_, untracked) = a.alloc(0x1000, 0x1000).expect("can allocate");
+        a.set_tracking(true);
+        assert!(a.is_tracking());
+
+        let (_, x) = a.alloc(0x800, 0x1000).expect("can allocate");
+        a.alloc_fixed(0x6000, 0x2000).expect("can allocate");
+        assert_eq!(a.allocation_at(x + 0x10).map(|(r, _)| r), Some(x..x + 0x1000));
+        assert!(a.allocation_at(untracked).is_none());
+
+        a.try_grow(0x6000, 0x2000, 0x3000).expect("can grow");
+        a.shrink(0x6000, 0x3000, 0x1000).expect("can shrink");
+        a.free(x, 0x800).expect("can free");
+        let allocated: Vec<_> = a.iter_allocated().map(|(r, _)| (r.start, r.end)).collect();
+        assert_eq!(allocated, [(0x6000, 0x7000)]);
+        a.free(untracked, 0x1000).expect("can free");
+        assert_eq!(a.iter_allocated().count(), 1);
+
+        a.set_tracking(false);
+        assert_eq!(a.iter_allocated().count(), 0);
+        assert!(a.allocation_at(0x6000).is_none());
+    });
+
+    both_tests!(linear_double_free, btree_double_free, a => {
+        a.add_range(0x1000, 0x8000, ()).expect("can add range");
+        let (_, x) = a.alloc(0x2000, 0x1000).expect("can allocate");
+        let (_, y) = a.alloc(0x1000, 0x1000).expect("can allocate");
+        a.free(x, 0x2000).expect("can free");
+        let space = a.space();
+        assert_eq!(kind(a.free(x, 0x2000)), ErrorKind::DoubleFree);
+        // partly free, partly allocated
+        assert_eq!(kind(a.free(x + 0x1000, 0x2000)), ErrorKind::DoubleFree);
+        assert_eq!(a.space(), space);
+
+        // a range that was never allocated is only caught in strict mode
+        a.set_strict(true);
+        assert!(a.is_tracking());
+        let (_, z) = a.alloc(0x2000, 0x1000).expect("can allocate");
+        assert_eq!(kind(a.free(y, 0x1000)), ErrorKind::NotAllocated);
+        assert_eq!(kind(a.free(z, 0x3000)), ErrorKind::NotAllocated);
+        a.free(z + 0x1000, 0x1000).expect("a part of an allocation can be freed");
+        a.free(z, 0x1000).expect("can free");
+        assert_eq!(kind(a.free(z, 0x1000)), ErrorKind::DoubleFree);
+
+        a.set_tracking(false);
+        assert!(!a.is_strict());
+        a.free(y, 0x1000).expect("can free");
+        assert!(a.is_empty());
+    });
+
+    both_tests!(linear_free_by_base, btree_free_by_base, a => {
+        a.add_range(0x1000, 0x8000, ()).expect("can add range");
+        let (_, untracked) = a.alloc(0x1000, 0x1000).expect("can allocate");
+        assert_eq!(kind(a.free_by_base(untracked)), ErrorKind::NotAllocated);
+
+        a.set_tracking(true);
+        let (_, x) = a.alloc(0x1800, 0x1000).expect("can allocate");
+        let (_, y) = a.alloc(0x3000, 0x1000).expect("can allocate");
+        assert_eq!(kind(a.free_by_base(x + 0x1000)), ErrorKind::NotAllocated);
+        // the size is rounded like `alloc` rounded it
+        assert_eq!(a.free_by_base(x).ok(), Some(0x2000));
+        assert_eq!(kind(a.free_by_base(x)), ErrorKind::NotAllocated);
+
+        // what is left of a shrunk allocation is freed as it is now
+        a.shrink(y, 0x3000, 0x1000).expect("can shrink");
+        assert_eq!(a.free_by_base(y).ok(), Some(0x1000));
+        a.free(untracked, 0x1000).expect("can free");
+        assert!(a.is_empty());
+    });
+
+    both_tests!(linear_stats, btree_stats, a => {
+        a.add_range(0x1000, 0x8000, ()).expect("can add range");
+        let (_, x) = a.alloc(0x1800, 0x1000).expect("can allocate");
+        a.alloc_fixed(0x7000, 0x1000).expect("can allocate");
+        assert!(a.alloc(0x10000, 0x1000).is_err());
+        assert!(a.alloc_fixed(0x7000, 0x1000).is_err());
+        assert!(a.alloc(0, 0x1000).is_err());
+        let stats = a.stats();
+        assert_eq!((stats.allocs, stats.frees), (2, 0));
+        assert_eq!((stats.allocated, stats.peak_allocated), (0x3000, 0x3000));
+        let failures = crate::Failures {
+            exceeds_region: 1,
+            not_free: 1,
+            other: 1,
+            ..Default::default()
+        };
+        assert_eq!(stats.failures, failures);
+
+        a.try_grow(x, 0x2000, 0x3000).expect("can grow");
+        a.shrink(x, 0x3000, 0x1000).expect("can shrink");
+        a.free(x, 0x1000).expect("can free");
+        let stats = a.stats();
+        assert_eq!((stats.allocs, stats.frees), (2, 1));
+        assert_eq!((stats.allocated, stats.peak_allocated), (0x1000, 0x4000));
+        a.reset_peak();
+        assert_eq!(a.stats().peak_allocated, 0x1000);
+
+        // reservations are not allocations, even in strict mode
+        a.set_strict(true);
+        a.reserve(0x1000, 0x2000).expect("can reserve");
+        a.unreserve(0x1000, 0x2000).expect("can unreserve");
+        assert_eq!(a.stats().allocated, 0x1000);
+
+        // a rebuilt allocator carries on with what is allocated
+        let a = RangeAllocator::from_raw_parts(a.into_raw_parts()).expect("parts are consistent");
+        assert_eq!(a.stats().allocated, 0x1000);
+    });
+
+    both_tests!(linear_add_range_with_holes, btree_add_range_with_holes, a => {
+        // low RAM without the EBDA at its top and a hole in the middle
+        let holes = [0x9_f000..0xa_0000, 0x5_0000..0x5_2000];
+        a.add_range_with_holes(0x1000, 0x9_f000, (), &holes)
+            .expect("can add range");
+        assert_eq!(a.region_count(), 1);
+        assert_eq!(a.total_space(), 0x9_f000);
+        assert_eq!(a.reserved_space(), 0x3000);
+        assert_eq!(a.space(), 0x9_c000);
+        assert_eq!(kind(a.alloc_fixed(0x5_1000, 0x1000)), ErrorKind::NotFree);
+        assert_eq!(kind(a.alloc(0x5_0000, 0x1000)), ErrorKind::OutOfSpace);
+        a.alloc_fixed(0x5_2000, 0x1000).expect("can allocate");
+
+        let (empty, outside) = (0x20_1000..0x20_1000, 0x1f_f000..0x20_2000);
+        let overlapping = [0x20_2000..0x20_4000, 0x20_1000..0x20_3000];
+        for (holes, expected) in [
+            (&[empty][..], ErrorKind::InvalidSize),
+            (&[outside], ErrorKind::NotOwned),
+            (&overlapping, ErrorKind::OverlappingRange),
+        ] {
+            let added = a.add_range_with_holes(0x20_0000, 0x10_0000, (), holes);
+            assert_eq!(kind(added), expected);
+        }
+        // touching holes are fine
+        a.add_range_with_holes(0x20_0000, 0x1_0000, (), &[0x20_1000..0x20_2000, 0x20_2000..0x20_3000])
+            .expect("can add range");
+
+        // nothing is left behind when the limits do not allow the extents: there are four, the
+        // region adds one and its first hole another
+        let limits = Limits {
+            max_free_extents: Some(5),
+            ..Limits::default()
+        };
+        a.set_limits(limits).expect("limits are not exceeded yet");
+        let holes = [0x40_1000..0x40_2000, 0x40_3000..0x40_4000];
+        assert_eq!(
+            kind(a.add_range_with_holes(0x40_0000, 0x1_0000, (), &holes)),
+            ErrorKind::CapacityExceeded
+        );
+        assert_eq!(a.region_count(), 2);
+        assert_eq!(a.reserved_space(), 0x5000);
+        a.check_invariants().expect("consistent");
+    });
+
+    both_tests!(linear_guard, btree_guard, a => {
+        a.add_range(0x1000, 0x8000, ()).expect("can add range");
+        a.set_guard(0x800).expect("nothing is allocated");
+        assert_eq!(a.guard(), 0x1000);
+        a.set_tracking(true);
+
+        // every allocation is followed by a page nobody gets
+        let (_, x) = a.alloc(0x1000, 0x1000).expect("can allocate");
+        let (_, y) = a.alloc(0x1000, 0x1000).expect("can allocate");
+        assert_eq!((x, y), (0x1000, 0x3000));
+        assert_eq!(a.space(), 0x4000);
+        assert_eq!(a.allocation_at(0x2000).map(|(range, _)| range), Some(0x1000..0x3000));
+        assert_eq!(kind(a.alloc_fixed(0x2000, 0x1000)), ErrorKind::NotFree);
+        assert_eq!(kind(a.set_guard(0)), ErrorKind::NotFree);
+
+        // fixed allocations need room for their guard too
+        assert_eq!(kind(a.alloc_fixed(0x8000, 0x1000)), ErrorKind::NotFree);
+        a.alloc_fixed(0x7000, 0x1000).expect("can allocate");
+        let (_, range) = a.alloc_sized(0x1000, 0x1000).expect("can allocate");
+        assert_eq!(range, 0x5000..0x6000);
+        assert_eq!(kind(a.alloc(0x1000, 0x1000)), ErrorKind::OutOfSpace);
+
+        // the guard moves along when the allocation grows or shrinks
+        a.free(x, 0x1000).expect("can free");
+        assert_eq!(kind(a.try_grow(0x5000, 0x1000, 0x2000)), ErrorKind::NotFree);
+        a.shrink(0x7000, 0x1000, 0x1000).expect("nothing to do");
+        a.free(0x7000, 0x1000).expect("can free");
+        a.try_grow(0x5000, 0x1000, 0x3000).expect("can grow");
+        assert_eq!(a.allocation_at(0x5000).map(|(range, _)| range), Some(0x5000..0x9000));
+        a.shrink(0x5000, 0x3000, 0x1000).expect("can shrink");
+        assert_eq!(a.free_by_base(0x5000).ok(), Some(0x1000));
+        a.free(y, 0x1000).expect("can free");
+        assert!(a.is_empty());
+        assert_eq!(a.stats().allocated, 0);
+        a.check_invariants().expect("consistent");
+    });
+
+    both_tests!(linear_regions, btree_regions, a => {
+        a.add_range(0x10000, 0x8000, ()).expect("can add range");
+        a.add_range_reserved(0x8000, 0x1000, ()).expect("can add range");
+        a.add_range(0x1000, 0x4000, ()).expect("can add range");
+        let regions: Vec<_> = a
+            .iter_regions()
+            .map(|region| (region.base, region.size, region.kind))
+            .collect();
+        assert_eq!(
+            regions,
+            [
+                (0x1000, 0x4000, map::RegionKind::Usable),
+                (0x8000, 0x1000, map::RegionKind::Reserved),
+                (0x10000, 0x8000, map::RegionKind::Usable),
+            ]
+        );
+        let rom = a.region(0x8000).expect("is a region");
+        assert_eq!((rom.end(), rom.kind), (0x9000, map::RegionKind::Reserved));
+        assert_eq!(a.region(0x10000).map(|region| region.size), Some(0x8000));
+        assert!(a.region(0x2000).is_none());
+    });
+
+    both_tests!(linear_observer, btree_observer, a => {
+        let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = events.clone();
+        a.set_observer(Some(Box::new(move |event: Event<'_, ()>| {
+            let event = match event {
+                Event::AddRange { range, .. } => ("add", range),
+                Event::Alloc { range, .. } => ("alloc", range),
+                Event::Free { range } => ("free", range),
+            };
+            seen.lock().unwrap().push(event);
+        })));
+        a.add_range(0x1000, 0x8000, ()).expect("can add range");
+        let (_, x) = a.alloc(0x1800, 0x1000).expect("can allocate");
+        a.alloc_fixed(0x6000, 0x1000).expect("can allocate");
+        assert!(a.alloc_fixed(0x6000, 0x1000).is_err());
+        a.try_grow(x, 0x2000, 0x3000).expect("can grow");
+        a.shrink(x, 0x3000, 0x1000).expect("can shrink");
+        a.free(x, 0x1000).expect("can free");
+        a.set_observer(None);
+        a.free(0x6000, 0x1000).expect("can free");
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                ("add", 0x1000..0x9000),
+                ("alloc", 0x1000..0x3000),
+                ("alloc", 0x6000..0x7000),
+                ("alloc", 0x3000..0x4000),
+                ("free", 0x2000..0x4000),
+                ("free", 0x1000..0x2000),
+            ]
+        );
+    });
+
+    both_tests!(linear_add_range_coalescing, btree_add_range_coalescing, a => {
+        a.add_range_coalescing(0x1000, 0x1000, ()).expect("can add range");
+        a.add_range_coalescing(0x3000, 0x2000, ()).expect("can add range");
+        a.alloc_fixed(0x1000, 0x1000).expect("can allocate");
+        a.alloc_fixed(0x4000, 0x1000).expect("can allocate");
+        a.add_range_coalescing(0x2000, 0x1000, ()).expect("can add range");
+        assert!(a.add_range_coalescing(0x2000, 0x1000, ()).is_err());
+        a.check_invariants().expect("invariants hold");
+
+        let regions: Vec<_> = a.iter_regions().map(|region| (region.base, region.size)).collect();
+        assert_eq!(regions, [(0x1000, 0x4000)]);
+        let free: Vec<_> = a.snapshot().free.into_iter().map(|(range, _)| range).collect();
+        let expected = 0x2000..0x4000;
+        assert_eq!(free, [expected]);
+        assert_eq!(a.alloc(0x2000, 0x1000).ok(), Some(((), 0x2000)));
+    });
+
+    both_tests!(linear_grow_region, btree_grow_region, a => {
+        a.add_range(0x1000, 0x2000, ()).expect("can add range");
+        a.add_range(0x8000, 0x1000, ()).expect("can add range");
+        a.alloc_fixed(0x1000, 0x1000).expect("can allocate");
+        a.grow_region(0x1000, 0x4000).expect("can grow");
+        a.check_invariants().expect("invariants hold");
+        assert_eq!(a.total_space(), 0x5000);
+        let free: Vec<_> = a.snapshot().free.into_iter().map(|(range, _)| range).collect();
+        assert_eq!(free, [0x2000..0x5000, 0x8000..0x9000]);
+        assert_eq!(a.alloc_fixed(0x2000, 0x3000).ok(), Some(((), 0x2000)));
+
+        a.grow_region(0x1000, 0x7000).expect("can grow");
+        assert_eq!(a.grow_region(0x1000, 0x8000).ok(), None);
+        assert_eq!(a.grow_region(0x1000, 0x1000).ok(), None);
+        assert_eq!(a.grow_region(0x2000, 0x8000).ok(), None);
+        a.check_invariants().expect("invariants hold");
+        let free: Vec<_> = a.snapshot().free.into_iter().map(|(range, _)| range).collect();
+        assert_eq!(free, [0x5000..0x8000, 0x8000..0x9000]);
+    });
+
+    #[test]
+    fn trait_objects() {
+        fn fill(mut a: impl RangeAlloc<Tag = u32>) -> usize {
+            core::iter::from_fn(|| a.alloc(0x1000, 0x1000).ok()).count()
+        }
+
+        let backends: [Box<dyn RangeAlloc<Tag = u32>>; 2] = [
+            Box::new(linear::RangeAllocator::new()),
+            Box::new(btree::RangeAllocator::new()),
+        ];
+        for mut a in backends {
+            a.add_range(0x1000, 0x4000, 7).expect("can add range");
+            assert_eq!(a.alloc(0x1000, 0x1000).ok(), Some((7, 0x1000)));
+            assert_eq!(fill(&mut a), 3);
+            assert!(a.is_full());
+            a.free(0x1000, 0x1000).expect("can free");
+            assert_eq!(fill(&mut *a), 1);
+        }
+    }
+
+    both_tests!(linear_add_regions, btree_add_regions, a => {
+        a.add_range(0x10000, 0x1000, ()).expect("can add range");
+        let overlapping = [(0x1000, 0x1000, ()), (0x8000, 0x9000, ())];
+        assert_eq!(a.add_regions(overlapping).ok(), None);
+        let overlapping = [(0x1000, 0x2000, ()), (0x2000, 0x1000, ())];
+        assert_eq!(a.add_regions(overlapping).ok(), None);
+        assert_eq!(a.add_regions([(0x1000, 0, ())]).ok(), None);
+        assert_eq!(a.region_count(), 1);
+
+        let entries = [(0x3000, 0x1000, ()), (0x1000, 0x2000, ()), (0x6000, 0x1000, ())];
+        a.add_regions(entries).expect("can add regions");
+        a.check_invariants().expect("invariants hold");
+        let regions: Vec<_> = a.iter_regions().map(|region| (region.base, region.size)).collect();
+        assert_eq!(regions, [(0x1000, 0x3000), (0x6000, 0x1000), (0x10000, 0x1000)]);
+        assert_eq!(a.alloc(0x3000, 0x1000).ok(), Some(((), 0x1000)));
+    });
+
+    #[test]
+    fn regions_from_iter() {
+        let map = [
+            (0x3000, 0x1000, 1),
+            (0x1000, 0x1000, 1),
+            (0x2000, 0x1000, 2),
+        ];
+        let a: linear::RangeAllocator<u32> = map.into_iter().collect();
+        assert_eq!(a.region_count(), 3);
+        let mut b: btree::RangeAllocator<u32> = map.into_iter().collect();
+        b.extend([(0x4000, 0x1000, 1)]);
+        let regions: Vec<_> = b
+            .iter_regions()
+            .map(|region| (region.base, region.size))
+            .collect();
+        assert_eq!(
+            regions,
+            [
+                (0x1000, 0x1000),
+                (0x2000, 0x1000),
+                (0x3000, 0x1000),
+                (0x4000, 0x1000)
+            ]
+        );
+    }
+
+    #[test]
+    fn btree_fanout() {
+        let mut a = btree::RangeAllocator::<()>::exact().with_fanout::<3>();
+        a.add_range(0, 1000, ()).expect("can add range");
+        for base in (0..1000).step_by(2) {
+            a.alloc_fixed(base, 1).expect("can allocate");
+        }
+        assert_eq!(a.free_extent_count(), 500);
+        assert_eq!(a.alloc(2, 1).ok(), None);
+        for base in (0..1000).step_by(4) {
+            a.free(base, 1).expect("can free");
+        }
+        assert_eq!(a.free_extent_count(), 251);
+        assert_eq!(a.alloc(3, 1).ok(), Some(((), 3)));
+
+        let a = a.with_fanout::<64>();
+        assert_eq!(a.free_extent_count(), 250);
+        assert_eq!(a.space(), 747);
+    }
+
+    both_tests!(linear_compaction, btree_compaction, a => {
+        use compaction::Move;
+
+        a.add_range(0x1000, 0x10000, ()).expect("can add range");
+        a.set_tracking(true);
+        for (base, size) in [(0x1000, 0x1000), (0x2000, 0x2000), (0x4000, 0x1000), (0x5000, 0x1000)] {
+            a.alloc_fixed(base, size).expect("can allocate");
+        }
+        a.alloc_fixed(0xa000, 0x1000).expect("can allocate");
+        a.reserve(0x8000, 0x1000).expect("can reserve");
+        a.free(0x2000, 0x2000).expect("can free");
+
+        // nothing moves across the reservation
+        let moves = a.plan_compaction();
+        let mv = |from, to| Move { from, to, size: 0x1000 };
+        assert_eq!(moves, [mv(0x4000, 0x2000), mv(0x5000, 0x3000), mv(0xa000, 0x9000)]);
+        a.apply_moves(&moves).expect("can apply moves");
+        a.check_invariants().expect("invariants hold");
+        let allocated: Vec<_> = a.iter_allocated().map(|(range, _)| range).collect();
+        assert_eq!(allocated, [0x1000..0x2000, 0x2000..0x3000, 0x3000..0x4000, 0x9000..0xa000]);
+        let free: Vec<_> = a.snapshot().free.into_iter().map(|(range, _)| range).collect();
+        assert_eq!(free, [0x4000..0x8000, 0xa000..0x11000]);
+        assert_eq!(a.plan_compaction(), []);
+
+        // a failing move leaves its source allocated
+        assert_eq!(a.apply_moves(&[mv(0x4000, 0x5000)]).ok(), None);
+        assert_eq!(a.apply_moves(&[mv(0x3000, 0x8000)]).ok(), None);
+        assert!(a.allocation_at(0x3000).is_some());
+        a.pin(0x3000, 0x1000).expect("can pin");
+        assert_eq!(a.apply_moves(&[mv(0x3000, 0x4000)]).ok(), None);
+        a.check_invariants().expect("invariants hold");
+    });
+
+    #[cfg(feature = "histogram")]
+    both_tests!(linear_live_sizes, btree_live_sizes, a => {
+        a.add_range(0x1000, 0x10000, ()).expect("can add range");
+        a.set_tracking(true);
+        let (_, x) = a.alloc(0x1000, 0x1000).expect("can allocate");
+        a.alloc(0x1000, 0x1000).expect("can allocate");
+        let (_, y) = a.alloc(0x3000, 0x1000).expect("can allocate");
+        let bins: Vec<_> = a.stats().live_sizes.iter().collect();
+        assert_eq!(bins, [(0x1000, 2), (0x2000, 1)]);
+
+        a.try_grow(y, 0x3000, 0x4000).expect("can grow");
+        a.free(x, 0x1000).expect("can free");
+        let sizes = a.stats().live_sizes;
+        assert_eq!((sizes.count(12), sizes.count(13), sizes.count(14)), (1, 0, 1));
+        assert_eq!(sizes.total(), 2);
+
+        let a = RangeAllocator::from_raw_parts(a.into_raw_parts()).expect("parts are consistent");
+        assert_eq!(a.stats().live_sizes, sizes);
+    });
+
+    #[test]
+    fn exact_numbers() {
+        fn check(mut a: impl RangeAlloc<u32, Tag = ()>) {
+            // interrupt vectors 32..256
+            a.add_range(32, 224, ()).expect("can add range");
+            let (_, first) = a.alloc(1, 1).expect("can allocate");
+            assert_eq!(first, 32);
+            let (_, second) = a.alloc(1, 0).expect("can allocate");
+            assert_eq!(second, 33);
+            let (_, block) = a.alloc(3, 8).expect("can allocate");
+            assert_eq!(block, 40);
+            assert_eq!(a.space(), 224 - 5);
+
+            a.alloc_fixed(34, 1).expect("can allocate");
+            a.free(first, 1).expect("can free");
+            a.free(block, 3).expect("can free");
+            assert_eq!(a.space(), 224 - 2);
+
+            // every remaining number can be handed out on its own
+            let ids: Vec<_> = (0..222)
+                .map(|_| a.alloc(1, 1).expect("has space").1)
+                .collect();
+            assert_eq!(ids.len(), 222);
+            assert_eq!(kind(a.alloc(1, 1)), ErrorKind::OutOfSpace);
+        }
+        check(linear::RangeAllocator::<(), u32>::exact());
+        check(btree::RangeAllocator::<(), u32>::exact());
+    }
+
+    both_tests!(linear_tag_at, btree_tag_at, a => {
+        use crate::map::AddrState;
+
+        a.add_range(0x1000, 0x4000, ()).expect("can add range");
+        a.add_range_reserved(0x8000, 0x1000, ()).expect("can add range");
+        a.alloc_fixed(0x2000, 0x1000).expect("can allocate");
+        a.reserve(0x4000, 0x1000).expect("can reserve");
+
+        assert_eq!(a.tag_at(0x1fff), Some(((), AddrState::Free)));
+        assert_eq!(a.tag_at(0x2000), Some(((), AddrState::Allocated)));
+        assert_eq!(a.tag_at(0x4800), Some(((), AddrState::Reserved)));
+        assert_eq!(a.tag_at(0x8000), Some(((), AddrState::Reserved)));
+        assert_eq!(a.tag_at(0x5000), None);
+    });
+
+    both_tests!(linear_next_fit, btree_next_fit, a => {
+        a.add_range(0x1000, 0x8000, ()).expect("can add range");
+        a.set_policy(Policy::NextFit);
+        let (_, x) = a.alloc(0x1000, 0x1000).expect("can allocate");
+        let (_, y) = a.alloc(0x1000, 0x1000).expect("can allocate");
+        a.free(x, 0x1000).expect("can free");
+
+        // the search continues after `y` instead of going back to `x`
+        let (_, z) = a.alloc(0x1000, 0x1000).expect("can allocate");
+        assert_ne!(z, x);
+        assert_eq!(z, y + 0x1000);
+
+        // and wraps around once it reaches the end
+        let rest: Vec<_> = (0..6)
+            .map(|_| a.alloc(0x1000, 0x1000).expect("can allocate").1)
+            .collect();
+        assert!(rest.contains(&x));
+        assert!(a.is_full());
+        assert_eq!(kind(a.alloc(0x1000, 0x1000)), ErrorKind::OutOfSpace);
+    });
+
+    both_tests!(linear_next_fit_cursor_merged_away, btree_next_fit_cursor_merged_away, a => {
+        a.add_range(0x1000, 0x8000, ()).expect("can add range");
+        a.set_policy(Policy::NextFit);
+        let (_, x) = a.alloc(0x1000, 0x1000).expect("can allocate");
+        let (_, y) = a.alloc(0x1000, 0x1000).expect("can allocate");
+        a.free(x, 0x1000).expect("can free");
+
+        // the cursor is in the block after `y`, which merges into the one at `x`
+        a.free(y, 0x1000).expect("can free");
+        a.check_invariants().expect("the cursor is still in the free list");
+        let (_, z) = a.alloc(0x1000, 0x1000).expect("can allocate");
+        assert!((0x1000..0x9000).contains(&z));
+        a.free(z, 0x1000).expect("can free");
+        assert_eq!(a.space(), a.total_space());
+    });
+
+    both_tests!(linear_top_down, btree_top_down, a => {
+        a.add_range(0x1000, 0x8000, ()).expect("can add range");
+        a.set_direction(Direction::TopDown);
+        assert_eq!(a.alloc(0x1000, 0x1000).expect("can allocate").1, 0x8000);
+        assert_eq!(a.alloc(0x2000, 0x2000).expect("can allocate").1, 0x6000);
+
+        // the highest block is searched first, wherever it is
+        a.add_range(0x10_0000, 0x4000, ()).expect("can add range");
+        assert_eq!(a.alloc(0x1000, 0x1000).expect("can allocate").1, 0x10_3000);
+        a.free(0x8000, 0x1000).expect("can free");
+        assert_eq!(a.alloc(0x1000, 0x1000).expect("can allocate").1, 0x10_2000);
+        let (_, x) = a
+            .alloc_within(0x1000, 0x1000, 0..0x5000)
+            .expect("can allocate");
+        assert_eq!(x, 0x4000);
+
+        // a remainder below the granularity at the region end stays part of the allocation
+        a.add_range(0x20_0000, 0x1800, ()).expect("can add range");
+        let space = a.space();
+        assert_eq!(a.alloc(0x1000, 0x1000).expect("can allocate").1, 0x20_0000);
+        assert_eq!(a.space(), space - 0x1800);
+        a.free(0x20_0000, 0x1000).expect("can free");
+        assert_eq!(a.space(), space);
+        a.check_invariants().expect("stays consistent");
+    });
+
+    both_tests!(linear_pinning, btree_pinning, a => {
+        a.add_range(0x1000, 0x8000, ()).expect("can add range");
+        a.add_range(0x10_0000, 0x4000, ()).expect("can add range");
+        let (_, dma) = a.alloc_within(0x2000, 0x1000, 0x10_0000..0x10_4000).expect("can allocate");
+        a.pin(dma, 0x2000).expect("can pin");
+        a.pin(0x2000, 0x1000).expect("can pin free space");
+        assert_eq!(kind(a.pin(0x9000, 0x1000)), ErrorKind::NotOwned);
+        assert_eq!(kind(a.pin(0x8000, 0x2000)), ErrorKind::NotOwned);
+
+        assert_eq!(kind(a.reserve(0x1000, 0x2000)), ErrorKind::Pinned);
+        a.free(dma, 0x2000).expect("freeing is not administrative");
+        assert_eq!(kind(a.remove_range(0x10_0000)), ErrorKind::Pinned);
+        assert_eq!(kind(a.free_all_with_tag(&())), ErrorKind::Pinned);
+
+        assert_eq!(kind(a.unpin(dma, 0x3000)), ErrorKind::NotPinned);
+        a.unpin(dma, 0x2000).expect("was pinned");
+        a.remove_range(0x10_0000).expect("nothing is pinned in there anymore");
+        a.reserve(0x1000, 0x1000).expect("can reserve next to the pin");
+        a.unpin(0x2000, 0x1000).expect("was pinned");
+        assert!(a.pinned().is_empty());
+        a.check_invariants().expect("stays consistent");
+    });
+
+    both_tests!(linear_reservation_expiry, btree_reservation_expiry, a => {
+        let one = |range: Range<usize>| vec![range];
+        a.add_range(0x1000, 0x8000, ()).expect("can add range");
+        a.reserve_until(0x1000, 0x2000, 10).expect("can reserve");
+        a.reserve_until(0x4000, 0x2000, 20).expect("can reserve");
+        a.reserve_until(0x7000, 0x1000, 10).expect("can reserve");
+        a.reserve(0x8000, 0x1000).expect("can reserve");
+        assert_eq!(a.space(), 0x2000);
+
+        // the peer committed to the last one in time
+        a.keep_reservation(0x7000).expect("has a deadline");
+        assert_eq!(kind(a.keep_reservation(0x8000)), ErrorKind::NotReserved);
+        a.pin(0x5000, 0x1000).expect("can pin");
+
+        assert!(a.expire_reservations(9).is_empty());
+        assert_eq!(a.expire_reservations(10), one(0x1000..0x3000));
+        // a pinned reservation outlives its deadline
+        assert!(a.expire_reservations(30).is_empty());
+        a.unpin(0x5000, 0x1000).expect("was pinned");
+        assert_eq!(a.reservation_deadlines().collect::<Vec<_>>(), [(0x4000..0x6000, 20)]);
+        assert_eq!(a.expire_reservations(30), one(0x4000..0x6000));
+        assert_eq!(a.reserved().iter().collect::<Vec<_>>(), one(0x7000..0x9000));
+
+        // unreserving part of a reservation keeps the deadline of the rest
+        a.reserve_until(0x1000, 0x3000, 40).expect("can reserve");
+        a.unreserve(0x1000, 0x1000).expect("was reserved");
+        assert_eq!(a.reservation_deadlines().collect::<Vec<_>>(), [(0x2000..0x4000, 40)]);
+        a.check_invariants().expect("stays consistent");
+        assert_eq!(a.expire_reservations(40), one(0x2000..0x4000));
+        assert_eq!(a.space(), 0x6000);
+    });
+
+    both_tests!(linear_limits, btree_limits, a => {
+        a.add_range(0x1000, 0x8000, ()).expect("can add range");
+        a.add_range(0x10_0000, 0x4000, ()).expect("can add range");
+        let limits = Limits {
+            max_regions: Some(2),
+            max_free_extents: Some(2),
+        };
+        a.set_limits(limits).expect("the allocator is within the limits");
+        assert_eq!(a.limits(), limits);
+        assert_eq!((a.region_count(), a.free_extent_count()), (2, 2));
+
+        let exceeded = ErrorKind::CapacityExceeded;
+        assert_eq!(kind(a.add_range(0x20_0000, 0x1000, ())), exceeded);
+        assert_eq!(kind(a.add_range_reserved(0x20_0000, 0x1000, ())), exceeded);
+        // splitting a free block in two needs another extent, taking its start does not
+        assert_eq!(kind(a.alloc_fixed(0x4000, 0x1000)), exceeded);
+        assert_eq!(kind(a.reserve(0x4000, 0x1000)), exceeded);
+        let (_, x) = a.alloc_within(0x1000, 0x1000, 0..0x9000).expect("can allocate");
+        let (_, y) = a.alloc_within(0x1000, 0x1000, 0..0x9000).expect("can allocate");
+        assert_eq!((x, y), (0x1000, 0x2000));
+
+        // freeing `x` first would leave it apart from the rest of the free space
+        let space = a.space();
+        assert_eq!(kind(a.free(x, 0x1000)), exceeded);
+        assert_eq!(a.space(), space);
+        a.free(y, 0x1000).expect("merges with the free space after it");
+        a.free(x, 0x1000).expect("merges with the free space after it");
+
+        assert_eq!(
+            kind(a.set_limits(Limits {
+                max_free_extents: Some(1),
+                ..limits
+            })),
+            exceeded
+        );
+        assert_eq!(a.limits(), limits);
+        a.set_limits(Limits::default()).expect("no limits");
+        a.alloc_fixed(0x4000, 0x1000).expect("can split now");
+        a.check_invariants().expect("stays consistent");
+    });
+
+    both_tests!(linear_invariants, btree_invariants, a => {
+        a.add_range(0x1000, 0x1_0000, ()).expect("can add range");
+        a.add_range(0x1_1000, 0x1_0000, ()).expect("can add range");
+        a.set_tracking(true);
+        a.set_policy(Policy::NextFit);
+        a.reserve(0x4000, 0x1000).expect("can reserve");
+        a.check_invariants().expect("a fresh allocator is consistent");
+
+        let mut live = Vec::new();
+        for i in 0..40_u64 {
+            match a.alloc(0x1000 << (i % 3), 0x1000) {
+                Ok((_, x)) => live.push((x, 0x1000 << (i % 3))),
+                Err(_) => {
+                    let (x, size) = live.remove(i as usize % live.len());
+                    a.free(x, size).expect("can free");
+                }
+            }
+            if i % 4 == 0 && !live.is_empty() {
+                let (x, size) = live.swap_remove(0);
+                a.free(x, size).expect("can free");
+            }
+            a.check_invariants().expect("stays consistent");
+        }
+    });
+
+    both_tests!(linear_space_in_region, btree_space_in_region, a => {
+        a.add_range(0x1000, 0x4000, ()).expect("can add range");
+        a.add_range(0x5000, 0x4000, ()).expect("can add range");
+        a.add_range(0x10000, 0x10000, ()).expect("can add range");
+        let regions = [0x1000, 0x5000, 0x10000].map(RegionId);
+
+        a.alloc_fixed(0x2000, 0x1000).expect("can allocate");
+        a.alloc_fixed(0x5000, 0x3000).expect("can allocate");
+        a.reserve(0x8000, 0x1000).expect("can reserve");
+        a.alloc_within(0x2000, 0x1000, 0x10000..0x20000).expect("can allocate");
+        a.free(0x6000, 0x1000).expect("can free");
+        let space = regions.map(|r| a.space_in_region(r).unwrap());
+        assert_eq!(space, [0x3000, 0x1000, 0xe000]);
+        assert_eq!(space.iter().sum::<usize>(), a.space());
+        assert_eq!(a.total_space_in_region(RegionId(0x5000)), Some(0x4000));
+
+        a.free(0x5000, 0x1000).expect("can free");
+        a.free(0x7000, 0x1000).expect("can free");
+        a.unreserve(0x8000, 0x1000).expect("can unreserve");
+        a.remove_range(0x5000).expect("region is free");
+        assert_eq!(a.space_in_region(RegionId(0x5000)), None);
+        assert_eq!(a.total_space_in_region(RegionId(0x5000)), None);
+        assert_eq!(a.space_in_region(RegionId(0x2000)), None);
+        assert_eq!(a.space_in_region(RegionId(0x1000)), Some(0x3000));
+    });
+
+    #[test]
+    fn raw_parts() {
+        let mut a = new_linear();
+        a.add_range(0x1000, 0x4000, ()).expect("can add range");
+        a.add_range(0x5000, 0x4000, ()).expect("can add range");
+        a.add_range_reserved(0xa_0000, 0x6_0000, ())
+            .expect("can add reserved range");
+        a.add_range_with(
+            0x10_0000,
+            0x10_0000,
+            (),
+            RegionAttrs::with_granule(0x1_0000),
+        )
+        .expect("can add range");
+        a.alloc_fixed(0x4000, 0x1000).expect("can allocate");
+        a.alloc_fixed(0x5000, 0x2000).expect("can allocate");
+        a.reserve(0x2000, 0x1000).expect("can reserve");
+        let (_, x) = a
+            .alloc_within(0x1000, 0x1000, 0x10_0000..0x20_0000)
+            .expect("can allocate");
+        a.free(0x6000, 0x1000).expect("can free");
+        let (space, total) = (a.space(), a.total_space());
+
+        // linear -> btree -> linear ends up where it started
+        let parts = a.into_raw_parts();
+        let b = btree::RangeAllocator::from_raw_parts(parts.clone()).expect("parts are valid");
+        assert_eq!((b.space(), b.total_space()), (space, total));
+        assert_eq!(b.space_in_region(RegionId(0x5000)), Some(0x3000));
+        let back = b.into_raw_parts();
+        assert_eq!(back, parts);
+
+        let mut c = linear::RangeAllocator::from_raw_parts(back).expect("parts are valid");
+        assert_eq!(kind(c.alloc_fixed(0x5000, 0x1000)), ErrorKind::NotFree);
+        assert_eq!(kind(c.alloc_fixed(0x2000, 0x1000)), ErrorKind::NotFree);
+        c.free(x, 0x1_0000).expect("was allocated");
+        c.alloc_fixed(0x6000, 0x1000).expect("is free");
+        assert_eq!(c.verify_against(std::iter::once(0x4000..0x7000)), []);
+
+        let invalid = |f: fn(&mut raw::RawParts<(), usize>)| {
+            let mut parts = parts.clone();
+            f(&mut parts);
+            kind(btree::RangeAllocator::from_raw_parts(parts))
+        };
+        assert_eq!(invalid(|p| p.free_space += 0x1000), ErrorKind::Inconsistent);
+        // the error points at the invariant that does not hold
+        let located = |f: fn(&mut raw::RawParts<(), usize>)| {
+            let mut parts = parts.clone();
+            f(&mut parts);
+            let e = btree::RangeAllocator::from_raw_parts(parts).map(|_| ());
+            format!("{}", e.unwrap_err())
+        };
+        let (space, order) = (
+            located(|p| p.free_space += 0x1000),
+            located(|p| p.regions.reverse()),
+        );
+        assert!(space.starts_with("src/raw.rs"));
+        assert_ne!(space, order);
+        assert_eq!(
+            invalid(|p| p.reserved.push(0x1000..0x2000)),
+            ErrorKind::Inconsistent
+        );
+        assert_eq!(
+            invalid(|p| p.free.push((0x30_0000..0x30_1000, 0))),
+            ErrorKind::Inconsistent
+        );
+        assert_eq!(invalid(|p| p.regions.reverse()), ErrorKind::Inconsistent);
+        assert_eq!(
+            invalid(|p| p.allocations = Some(Vec::from([(0x6000..0x7000, ())]))),
+            ErrorKind::Inconsistent
+        );
+        assert_eq!(
+            invalid(|p| {
+                // a free extent split in two
+                let (range, epoch) = p.free.remove(0);
+                p.free.insert(0, (range.start + 0x1000..range.end, epoch));
+                p.free.insert(0, (range.start..range.start + 0x1000, epoch));
+            }),
+            ErrorKind::Inconsistent
+        );
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let mut a = new_btree();
+        setup(&mut a);
+        let (_, x) = a.alloc(0x3000, 0x1000).expect("can allocate");
+        a.reserve(0x90_0000, 0x1000).expect("can reserve");
+        let snapshot = a.snapshot();
+        let space = a.space();
+
+        // the allocator carries on, the snapshot stays as it was
+        a.free(x, 0x3000).expect("was allocated");
+        assert_eq!(a.snapshot().free_space, snapshot.free_space + 0x3000);
+
+        // persisted as the extents only, and read back into the other backend
+        let free: Vec<_> = snapshot
+            .free
+            .iter()
+            .map(|(range, epoch)| (range.start, range.end, *epoch))
+            .collect();
+        let restored = raw::Snapshot {
+            free: free
+                .into_iter()
+                .map(|(start, end, epoch)| (start..end, epoch))
+                .collect(),
+            ..snapshot.clone()
+        };
+        let mut b = linear::RangeAllocator::restore(restored).expect("snapshot is valid");
+        assert_eq!(b.space(), space);
+        assert_eq!(kind(b.alloc_fixed(x, 0x1000)), ErrorKind::NotFree);
+        assert_eq!(kind(b.alloc_fixed(0x90_0000, 0x1000)), ErrorKind::NotFree);
+        assert_eq!(b.snapshot(), snapshot);
+
+        let mut broken = snapshot;
+        broken.free.clear();
+        assert_eq!(
+            kind(btree::RangeAllocator::restore(broken)),
+            ErrorKind::Inconsistent
+        );
+    }
+
+    #[test]
+    fn dump_dot() {
+        let mut a = linear::RangeAllocator::<&str>::new();
+        a.add_range(0x1000, 0x8000, "low").expect("can add range");
+        a.add_range_reserved(0x9000, 0x1000, "{rom}")
+            .expect("can add range");
+        let (_, untracked) = a.alloc(0x1000, 0x1000).expect("can allocate");
+        a.set_tracking(true);
+        a.alloc(0x2000, 0x1000).expect("can allocate");
+        a.reserve(0x6000, 0x1000).expect("can reserve");
+        let mut dot = String::new();
+        a.dump_dot(&mut dot).expect("can write");
+        assert_eq!(
+            dot,
+            "digraph {\n\
+             node[shape=record,fontname=monospace];\n\
+             region0 [label=\"{0x1000..0x9000 \\\"low\\\"|{untracked\\n0x1000..0x2000|\
+             allocated\\n0x2000..0x4000|free\\n0x4000..0x6000|reserved\\n0x6000..0x7000|\
+             free\\n0x7000..0x9000}}\"];\n\
+             region1 [label=\"{0x9000..0xa000 \\\"\\{rom\\}\\\"|reserved region}\",style=dashed];\n\
+             }\n"
+        );
+
+        // the other backend draws the same
+        a.free(untracked, 0x1000).expect("can free");
+        let b = btree::RangeAllocator::from(a);
+        let mut other = String::new();
+        b.dump_dot(&mut other).expect("can write");
+        assert!(other.contains("|{free\\n0x1000..0x2000|allocated\\n0x2000..0x4000|"));
+    }
+
+    #[test]
+    fn debug_and_display() {
+        let mut a = linear::RangeAllocator::<&str>::new();
+        a.add_range(0x1000, 0x8000, "low").expect("can add range");
+        a.add_range_reserved(0x9000, 0x1000, "rom")
+            .expect("can add range");
+        a.add_range(0x10000, 0x1000, "high").expect("can add range");
+        a.alloc_fixed(0x3000, 0x1000).expect("can allocate");
+        a.alloc_fixed(0x10000, 0x1000).expect("can allocate");
+        let debug = "linear::RangeAllocator {
+    regions:
+        0x1000..0x9000 (0x8000) \"low\"
+        0x9000..0xa000 (0x1000) \"rom\" reserved
+        0x10000..0x11000 (0x1000) \"high\"
+    free:
+        0x1000..0x3000 (0x2000)
+        0x4000..0x9000 (0x5000)
+}";
+        let display = "0x1000..0x9000 usable \"low\": 0x7000 free in 2 extents
+0x9000..0xa000 reserved \"rom\"
+0x10000..0x11000 usable \"high\": 0x0 free in 0 extents";
+        assert_eq!(format!("{a:?}"), debug);
+        assert_eq!(format!("{a}"), display);
+
+        let b = btree::RangeAllocator::from(a);
+        assert_eq!(format!("{b:?}"), debug.replace("linear", "btree"));
+        assert_eq!(format!("{b}"), display);
+    }
+
+    /// the free blocks examined by an allocation, a fixed allocation and a free, with `n` single
+    /// free pages in front of a large free tail
+    fn steps_with<R: RangeAlloc<Tag = ()>>(
+        mut a: R,
+        steps: impl Fn(&R) -> usize,
+        n: usize,
+    ) -> [usize; 3] {
+        a.add_range(0, (2 * n + 256) * 4096, ())
+            .expect("can add range");
+        for i in 0..n {
+            a.alloc_fixed((2 * i + 1) * 4096, 4096)
+                .expect("can allocate");
+        }
+        let tail = 2 * n * 4096;
+
+        let mut measure = |op: &mut dyn FnMut(&mut R)| {
+            let before = steps(&a);
+            op(&mut a);
+            steps(&a) - before
+        };
+        [
+            measure(&mut |a| {
+                a.alloc(2 * 4096, 4096).expect("can allocate");
+            }),
+            measure(&mut |a| {
+                a.alloc_fixed(tail + 128 * 4096, 4096)
+                    .expect("can allocate");
+            }),
+            measure(&mut |a| a.free((n | 1) * 4096, 4096).expect("can free")),
+        ]
+    }
+
+    /// checks the growth of the measured steps from `n` to `16 * n` free blocks against `class`
+    fn check_growth<R: RangeAlloc<Tag = ()>>(
+        new: impl Fn() -> R,
+        steps: impl Fn(&R) -> usize + Copy,
+        class: ComplexityClass,
+    ) {
+        let (small, large) = (256, 16 * 256);
+        let before = steps_with(new(), steps, small);
+        let after = steps_with(new(), steps, large);
+        let classes = [class.alloc, class.alloc_fixed, class.free];
+        for ((op, claimed), (before, after)) in ["alloc", "alloc_fixed", "free"]
+            .into_iter()
+            .zip(classes)
+            .zip(before.into_iter().zip(after))
+        {
+            // no walk over the free blocks may grow faster than they do, and lookups not at all
+            let allowed = match claimed {
+                Complexity::Linear => 2 * large / small,
+                Complexity::Constant | Complexity::Logarithmic => 2,
+            };
+            assert!(
+                after < allowed * (before + 1),
+                "{op} is {claimed:?}, but examined {before} blocks for {small} free blocks and \
+                 {after} for {large}"
+            );
+        }
+    }
+
+    #[test]
+    fn complexity() {
+        check_growth(
+            new_linear,
+            linear::RangeAllocator::steps,
+            linear::RangeAllocator::<()>::COMPLEXITY,
+        );
+        check_growth(
+            new_btree,
+            btree::RangeAllocator::steps,
+            btree::RangeAllocator::<()>::COMPLEXITY,
+        );
+
+        // a first-fit search in the btree skips the blocks too small for the request in the tree
+        let [small, ..] = steps_with(new_btree(), btree::RangeAllocator::steps, 256);
+        let [large, ..] = steps_with(new_btree(), btree::RangeAllocator::steps, 16 * 256);
+        assert_eq!((small, large), (1, 1));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn snapshot() {
+        let mut a = linear::RangeAllocator::<u32>::new();
+        a.set_policy(Policy::BestFit);
+        a.add_range(0x1000, 0x8000, 1).expect("can add range");
+        a.add_range_reserved(0x10_0000, 0x1000, 2)
+            .expect("can add reserved range");
+        a.add_range(0x20_0000, 0x4000, 3).expect("can add range");
+        a.alloc_fixed(0x2000, 0x2000).expect("can allocate");
+        a.reserve_until(0x6000, 0x1000, 40).expect("can reserve");
+        a.pin(0x20_1000, 0x1000).expect("can pin");
+
+        let json = serde_json::to_string(&a).expect("can serialize");
+        let b: btree::RangeAllocator<u32> = serde_json::from_str(&json).expect("can deserialize");
+        let mut c: linear::RangeAllocator<u32> =
+            serde_json::from_str(&json).expect("can deserialize");
+        assert_eq!(b.into_raw_parts(), a.into_raw_parts());
+        assert_eq!(c.policy(), Policy::BestFit);
+        assert!(c.reservation_deadlines().eq([(0x6000..0x7000, 40)]));
+        assert_eq!(c.alloc(0x2000, 0x1000).expect("has space"), (1, 0x4000));
+        assert_eq!(kind(c.remove_range(0x20_0000)), ErrorKind::Pinned);
+        assert_eq!(kind(c.alloc_fixed(0x3000, 0x1000)), ErrorKind::NotFree);
+
+        // snapshots are checked like raw parts
+        let tampered = json.replace("\"granularity\":4096", "\"granularity\":3072");
+        assert_ne!(tampered, json);
+        assert!(serde_json::from_str::<btree::RangeAllocator<u32>>(&tampered).is_err());
+        let tampered = json.replace("\"free_space\":", "\"free_space\":1");
+        assert!(serde_json::from_str::<linear::RangeAllocator<u32>>(&tampered).is_err());
+    }
+
+    #[test]
+    fn extent_store() {
+        let mut a = safe::RangeAllocator::default();
+        setup(&mut a);
+        alloc_different_configurations(&mut a);
+        alloc_aligned(&mut a);
+        assert!(a.is_empty());
+
+        let allocations = allocate_n(
+            &mut a,
+            [0x1000, 0x3000].into_iter(),
+            std::iter::once(0x1000),
+            8,
+        );
+        assert_eq!(allocations[..2], [(0x7ff000, 0x1000), (0x800000, 0x3000)]);
+        assert_eq!(
+            kind(a.add_range(0x800000, 0x1000, ())),
+            ErrorKind::OverlappingRange
+        );
+        assert_eq!(kind(a.alloc_fixed(0x800000, 0x1000)), ErrorKind::NotFree);
+        assert_eq!(kind(a.free(0x1000, 0x1000)), ErrorKind::NotOwned);
+        let (_, x) = a
+            .alloc_within(0x1000, 0x1000, 0xfff0000..0x10000000)
+            .expect("can allocate");
+        assert_eq!(x, 0xfff0000);
+        a.free(x, 0x1000).expect("can free");
+        assert_eq!(kind(a.free(x, 0x1000)), ErrorKind::DoubleFree);
+        for (x, size) in allocations {
+            a.free(x, size).expect("can free");
+        }
+
+        // the regions touch, but their extents stay apart
+        a.add_range(0x7ff000 + 4096 * 4096, 0x1000, ())
+            .expect("can add range");
+        assert_eq!(a.store().extents().len(), 3);
+        assert_eq!(a.space(), a.total_space());
+    }
+
+    #[test]
+    fn linear_size_classes() {
+        // single free pages between the allocations, and two larger blocks
+        let mut a = new_linear();
+        a.add_range(0x10_0000, 0x10_0000, ())
+            .expect("can add range");
+        for i in 0..64 {
+            a.alloc_fixed(0x10_0000 + i * 0x2000, 0x1000)
+                .expect("can allocate");
+        }
+        for i in 20..28 {
+            a.free(0x10_0000 + i * 0x2000, 0x1000).expect("can free");
+        }
+        a.check_invariants().expect("is consistent");
+
+        // only the two larger blocks are looked at, but the choice is the same as when walking
+        // the whole list
+        let picks: Vec<_> = a
+            .simulate(Request::new(0x4000, 0x1000))
+            .expect("valid request")
+            .into_iter()
+            .map(|(policy, placement)| (policy, placement.map(|p| p.base)))
+            .collect();
+        let (merged, tail) = (Some(0x12_7000), Some(0x17_f000));
+        assert_eq!(
+            picks,
+            [
+                (Policy::FirstFit, tail),
+                (Policy::OldestFree, tail),
+                (Policy::NewestFree, merged),
+                (Policy::BestFit, merged),
+                (Policy::WorstFit, tail),
+                (Policy::NextFit, tail),
+            ]
+        );
+        let (_, x) = a.alloc(0x4000, 0x1000).expect("can allocate");
+        a.check_invariants().expect("is consistent");
+        a.free(x, 0x4000).expect("can free");
+        a.check_invariants().expect("is consistent");
+    }
+
+    #[test]
+    fn linear_free_index() {
+        // the same operations with and without tracking, which indexes the free blocks
+        let mut plain = new_linear();
+        let mut tracked = new_linear();
+        tracked.set_tracking(true);
+        for a in [&mut plain, &mut tracked] {
+            a.add_range(0x1000, 0x4_0000, ()).expect("can add range");
+            a.add_range(0x4_1000, 0x4_0000, ()).expect("can add range");
+        }
+
+        let mut live = Vec::new();
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        for _ in 0..2000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            if !seed.is_multiple_of(3) || live.is_empty() {
+                let size = 0x1000 << (seed % 4);
+                let x = plain.alloc(size, 0x1000).map(|(_, x)| x);
+                let y = tracked.alloc(size, 0x1000).map(|(_, y)| y);
+                assert_eq!(x.is_ok(), y.is_ok());
+                if let (Ok(x), Ok(y)) = (x, y) {
+                    live.push((x, y, size));
+                }
+            } else {
+                let (x, y, size) = live.swap_remove(seed as usize % live.len());
+                plain.free(x, size).expect("can free");
+                tracked.free(y, size).expect("can free");
+            }
+            assert_eq!(plain.space(), tracked.space());
+        }
+
+        let tracked_ranges: Vec<_> = live.iter().map(|&(_, y, size)| y..y + size).collect();
+        assert_eq!(tracked.verify_against(tracked_ranges), []);
+        for (_, y, size) in live {
+            tracked.free(y, size).expect("can free");
+        }
+        let free: Vec<_> = tracked
+            .into_raw_parts()
+            .free
+            .into_iter()
+            .map(|(r, _)| r)
+            .collect();
+        assert_eq!(free, [0x1000..0x4_1000, 0x4_1000..0x8_1000]);
+    }
+
+    #[test]
+    fn builder() {
+        use builder::{Backend, RangeAllocatorBuilder};
+
+        let config = RangeAllocatorBuilder::new()
+            .policy(Policy::WorstFit)
+            .direction(Direction::TopDown)
+            .exact();
+        for backend in [Backend::Linear, Backend::Btree] {
+            let mut a = config.backend(backend).build::<()>();
+            a.add_range(0x1000, 0x100, ()).expect("can add range");
+            a.add_range(0x2000, 0x40, ()).expect("can add range");
+            // the larger block wins over the higher one, and is used from its end
+            assert_eq!(a.alloc(0x10, 1).expect("can allocate").1, 0x10f0);
+            assert_eq!(a.space(), 0x130);
+        }
+
+        let mut a = RangeAllocatorBuilder::new()
+            .granularity(Alignment::new(0x20_0000).expect("is a power of two"))
+            .tracking(true)
+            .build_linear::<()>();
+        a.add_range(0x20_0000, 0x40_0000, ())
+            .expect("can add range");
+        let (_, x) = a.alloc(0x1000, 0x1000).expect("can allocate");
+        assert_eq!(a.space(), 0x20_0000);
+        assert_eq!(
+            a.allocation_at(x).map(|(range, _)| range),
+            Some(x..x + 0x20_0000)
+        );
+    }
+
+    #[test]
+    fn backend_conversion() {
+        let mut a = new_linear();
+        a.add_range(0x1000, 0x1_0000, ()).expect("can add range");
+        a.set_tracking(true);
+        a.alloc_fixed(0x2000, 0x2000).expect("can allocate");
+        let (_, x) = a
+            .alloc_within(0x1000, 0x1000, 0x8000..0x1_0000)
+            .expect("can allocate");
+        a.reserve(0x5000, 0x1000).expect("can reserve");
+        let allocated = |ranges: Vec<(Range<usize>, &())>| -> Vec<_> {
+            ranges
+                .into_iter()
+                .map(|(range, _)| (range.start, range.end))
+                .collect()
+        };
+
+        a.set_direction(Direction::TopDown);
+
+        let mut b: btree::RangeAllocator<()> = a.into();
+        assert!(b.is_tracking());
+        assert_eq!(b.direction(), Direction::TopDown);
+        assert_eq!(
+            allocated(b.iter_allocated().collect()),
+            [(0x2000, 0x4000), (x, x + 0x1000)]
+        );
+        b.free(0x2000, 0x2000).expect("was allocated");
+        assert_eq!(kind(b.alloc_fixed(x, 0x1000)), ErrorKind::NotFree);
+
+        let c: linear::RangeAllocator<()> = b.into();
+        assert_eq!(allocated(c.iter_allocated().collect()), [(x, x + 0x1000)]);
+        assert_eq!(c.space(), 0x1_0000 - 0x2000);
+        assert_eq!(c.reserved_space(), 0x1000);
+    }
+
+    #[test]
+    fn free_all_with_tag() {
+        // tags are VM ids, with two adjacent regions belonging to VM 1
+        macro_rules! check {
+            ($a:expr) => {{
+                let mut a = $a;
+                a.add_range(0x1000, 0x4000, 1).expect("can add range");
+                a.add_range(0x5000, 0x4000, 1).expect("can add range");
+                a.add_range(0x10000, 0x4000, 2).expect("can add range");
+                a.set_tracking(true);
+                a.alloc_fixed(0x3000, 0x2000).expect("can allocate");
+                a.alloc_fixed(0x7000, 0x1000).expect("can allocate");
+                a.alloc_fixed(0x11000, 0x1000).expect("can allocate");
+                a.reserve(0x1000, 0x1000).expect("can reserve");
+
+                assert_eq!(a.free_all_with_tag(&1).expect("can free"), 0x3000);
+                assert_eq!(a.space(), a.total_space() - 0x2000);
+                assert_eq!(a.free_all_with_tag(&1).expect("can free"), 0);
+                assert_eq!(a.free_all_with_tag(&3).expect("can free"), 0);
+                let allocated: Vec<_> =
+                    a.iter_allocated().map(|(r, &tag)| (r.start, tag)).collect();
+                assert_eq!(allocated, [(0x11000, 2)]);
+                assert_eq!(a.verify_against([0x11000..0x12000]), []);
+            }};
+        }
+        check!(linear::RangeAllocator::<u32>::default());
+        check!(btree::RangeAllocator::<u32>::default());
+    }
+
+    both_tests!(linear_registry, btree_registry, a => {
+        a.add_range(0x1000, 0x4000, ()).expect("can add range");
+        let mut r = registry::Registry::new(a);
+        let x = r.alloc(0x800, 0x1000).expect("can allocate");
+        let y = r.alloc_fixed(0x4000, 0x1000).expect("can allocate");
+        assert_eq!(r.get(x).expect("is live").size, 0x1000);
+        assert_eq!(r.len(), 2);
+
+        let freed = r.free(x).expect("can free");
+        assert_eq!(kind(r.free(x)), ErrorKind::DoubleFree);
+        assert_eq!(r.inner().space(), 0x3000);
+
+        // the slot of `x` is reused, but `x` stays invalid
+        let z = r.alloc(0x1000, 0x1000).expect("can allocate");
+        assert_eq!(z.index(), x.index());
+        assert_eq!(r.get(z).expect("is live").base, freed.base);
+        assert!(r.get(x).is_none());
+        assert!(r.get(y).is_some());
+    });
+
+    both_tests!(linear_groups, btree_groups, a => {
+        a.add_range(0x1000, 0x10_0000, ()).expect("can add range");
+        let mut g = groups::Grouped::new(a);
+        let (_, outside) = g.alloc(0x1000, 0x1000).expect("can allocate");
+
+        let frame = g.group_begin();
+        let (_, x) = g.alloc(0x1000, 0x1000).expect("can allocate");
+        let (_, y) = g.alloc_fixed(0x8_0000, 0x3000).expect("can allocate");
+        let request = g.group_begin();
+        let (_, z) = g.alloc(0x2000, 0x1000).expect("can allocate");
+        assert_eq!(g.group_end(), Some(request));
+        let (_, w) = g.alloc_within(0x1000, 0x1000, 0x9_0000..0xa_0000).expect("can allocate");
+        assert_eq!(g.group_end(), Some(frame));
+        assert_eq!(g.group_end(), None);
+
+        assert_eq!(g.group_of(outside), None);
+        assert_eq!(g.group_of(z), Some(request));
+        assert_eq!(g.group_len(frame), 3);
+
+        // a single free takes the allocation out of its group
+        g.free(x, 0x1000).expect("can free");
+        assert_eq!(g.free_group(frame).expect("can free group"), 2);
+        assert_eq!(g.free_group(frame).expect("can free group"), 0);
+        assert_eq!(g.inner().space(), 0x10_0000 - 0x3000);
+        assert_eq!(g.free_group(request).expect("can free group"), 1);
+        g.free(outside, 0x1000).expect("can free");
+        assert!(g.is_empty());
+    });
+
+    both_tests!(linear_transactions, btree_transactions, a => {
+        a.add_range(0x1000, 0x10_0000, ()).expect("can add range");
+        let (_, x) = a.alloc(0x2000, 0x1000).expect("can allocate");
+        let space = a.space();
+
+        let mut txn = a.begin();
+        txn.alloc(0x1000, 0x1000).expect("can allocate");
+        txn.alloc_fixed(0x8_0000, 0x3000).expect("can allocate");
+        txn.free(x, 0x2000).expect("can free");
+        assert_eq!(kind(txn.alloc_fixed(0x8_1000, 0x1000)), ErrorKind::NotFree);
+        assert_eq!(txn.len(), 3);
+        txn.rollback().expect("can roll back");
+        assert_eq!(a.space(), space);
+        assert_eq!(kind(a.alloc_fixed(x, 0x1000)), ErrorKind::NotFree);
+        a.alloc_fixed(0x8_0000, 0x3000).expect("is free again");
+        a.free(0x8_0000, 0x3000).expect("can free");
+
+        // dropping without committing rolls back too
+        let mut txn = a.begin();
+        txn.alloc(0x4000, 0x1000).expect("can allocate");
+        drop(txn);
+        assert_eq!(a.space(), space);
+
+        let mut txn = a.begin();
+        let (_, y) = txn.alloc(0x4000, 0x1000).expect("can allocate");
+        txn.free(x, 0x2000).expect("can free");
+        txn.commit();
+        assert_eq!(a.space(), space - 0x2000);
+        a.free(y, 0x4000).expect("was kept");
+        assert!(a.is_empty());
+    });
+
+    both_tests!(linear_slab, btree_slab, a => {
+        a.add_range(0x1000, 0x4000, ()).expect("can add range");
+        let mut s = slab::Slab::new(a, 0x400, 0x100, 4).expect("valid slab");
+        let slots: Vec<_> = (0..6).map(|_| s.alloc().expect("can allocate").1).collect();
+        assert_eq!(slots[..4], [0x1000, 0x1400, 0x1800, 0x1c00]);
+        assert_eq!((s.len(), s.capacity()), (6, 8));
+        assert_eq!(s.inner().space(), 0x2000);
+
+        assert_eq!(kind(s.free(0x1200)), ErrorKind::NotOwned);
+        assert_eq!(kind(s.free(0x4000)), ErrorKind::NotOwned);
+        s.free(slots[1]).expect("can free");
+        assert_eq!(kind(s.free(slots[1])), ErrorKind::DoubleFree);
+        // the slot freed last is handed out first
+        assert_eq!(s.alloc().expect("can allocate").1, slots[1]);
+
+        for &slot in &slots[4..] {
+            s.free(slot).expect("can free");
+        }
+        assert_eq!(s.shrink().expect("can shrink"), 1);
+        assert_eq!((s.len(), s.capacity()), (4, 4));
+        assert_eq!(s.inner().space(), 0x3000);
+        assert_eq!(kind(s.free(slots[4])), ErrorKind::NotOwned);
+
+        assert_eq!(kind(slab::Slab::new(new_linear(), 0x300, 0x200, 4)), ErrorKind::InvalidAlignment);
+        assert_eq!(kind(slab::Slab::new(new_linear(), 0x400, 0x100, 0)), ErrorKind::InvalidSize);
+        let s = slab::Slab::of::<[u64; 3]>(new_btree(), 8).expect("valid slab");
+        assert_eq!(s.slot_size(), 24);
+    });
+
+    both_tests!(linear_granules, btree_granules, a => {
+        use granules::Granules;
+
+        a.add_range(0x1000, 0x10_0000, ()).expect("can add range");
+        let g = Granules::take(&mut a, 0x1000, 256).expect("can take granules");
+        assert_eq!(g.range(), 0x1000..0x10_1000);
+        assert_eq!(a.space(), 0);
+
+        // every granule is handed out once, whichever thread asks
+        let mut taken: Vec<_> = std::thread::scope(|s| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| s.spawn(|| (0..64).map(|_| g.alloc().expect("has space").1).collect::<Vec<_>>()))
+                .collect();
+            threads.into_iter().flat_map(|t| t.join().expect("thread does not panic")).collect()
+        });
+        taken.sort();
+        assert_eq!(taken, (0..256).map(|i| 0x1000 + i * 0x1000).collect::<Vec<_>>());
+        assert!(g.alloc().is_none());
+
+        assert_eq!(kind(g.free(0x10_1000)), ErrorKind::NotOwned);
+        assert_eq!(kind(g.free(0x1800)), ErrorKind::InvalidAlignment);
+        g.free(0x2000).expect("can free");
+        assert_eq!(kind(g.free(0x2000)), ErrorKind::DoubleFree);
+        assert_eq!(g.alloc().expect("has space").1, 0x2000);
+
+        assert_eq!(kind(Granules::take(&mut a, 0x1000, 1)), ErrorKind::OutOfSpace);
+        assert_eq!(kind(Granules::take(&mut a, 0x1800, 1)), ErrorKind::InvalidAlignment);
+        assert_eq!(kind(Granules::take(&mut a, 0x1000, 0)), ErrorKind::InvalidSize);
+        for base in taken {
+            g.free(base).expect("can free");
+        }
+        assert!(g.is_empty());
+        g.give_back(&mut a).expect("can give back");
+        assert_eq!(a.space(), a.total_space());
+    });
+
+    both_tests!(linear_registry_move, btree_registry_move, a => {
+        use registry::Constraints;
+
+        a.add_range(0x1000, 0x4000, ()).expect("can add range");
+        a.add_range(0x10_0000, 0x10_0000, ()).expect("can add range");
+        let mut r = registry::Registry::new(a);
+        let x = r.alloc_fixed(0x2000, 0x2000).expect("can allocate");
+
+        let moved = r
+            .move_allocation(x, Constraints::within(0x1_0000, 0x10_0000..0x20_0000))
+            .expect("can move");
+        assert_eq!(moved.from, 0x2000..0x4000);
+        assert_eq!(moved.to.end - moved.to.start, 0x2000);
+        assert!(moved.to.start >= 0x10_0000 && moved.to.start % 0x1_0000 == 0);
+        assert_eq!(r.get(x).expect("is live").base, moved.to.start);
+        assert_eq!(r.inner().space(), 0x10_4000 - 0x2000);
+
+        // the old range is free again, and a failed move changes nothing
+        r.alloc_fixed(0x1000, 0x4000).expect("can allocate");
+        assert_eq!(
+            kind(r.move_allocation(x, Constraints::within(0x1000, 0..0x10_0000))),
+            ErrorKind::OutOfSpace
+        );
+        assert_eq!(r.get(x).expect("is live").base, moved.to.start);
+        assert_eq!(r.inner().space(), 0x10_0000 - 0x2000);
+
+        let back = r.move_allocation(x, Constraints::aligned(0x1000)).expect("can move");
+        assert_eq!(back.from, moved.to);
+        r.free(x).expect("can free");
+        assert_eq!(kind(r.move_allocation(x, Constraints::aligned(0x1000))), ErrorKind::NotOwned);
+    });
+
+    both_tests!(linear_alloc_within, btree_alloc_within, a => {
+        a.add_range(0x1000, 0x4000, ()).expect("can add range");
+        a.add_range(0x1_0000, 0x4000, ()).expect("can add range");
+
+        // the window starts in the middle of the first region and ends in the middle of the second
+        let window = 0x3000..0x1_2000;
+        let mut bases: Vec<_> = (0..2)
+            .map(|_| a.alloc_within(0x2000, 0x1000, window.clone()).expect("can allocate").1)
+            .collect();
+        bases.sort();
+        assert_eq!(bases, [0x3000, 0x1_0000]);
+        assert_eq!(
+            kind(a.alloc_within(0x1000, 0x1000, window.clone())),
+            ErrorKind::OutOfSpace
+        );
+
+        // the space outside the window is still there
+        let (_, x) = a.alloc_within(0x2000, 0x1000, 0..0x3000).expect("can allocate");
+        assert_eq!(x, 0x1000);
+        let (_, x) = a.alloc(0x2000, 0x1000).expect("can allocate");
+        assert_eq!(x, 0x1_2000);
+    });
+
+    both_tests!(linear_error_kinds, btree_error_kinds, a => {
+        a.add_range(0x1000, 0x4000, ()).expect("can add range");
+        assert_eq!(kind(a.add_range(0x2000, 0x4000, ())), ErrorKind::OverlappingRange);
+        assert_eq!(
+            kind(a.alloc(0x8000, 0x1000)),
+            ErrorKind::RequestExceedsAnyRegion {
+                size: 0x8000,
+                largest_region: 0x4000
+            }
+        );
+        assert_eq!(a.bridging_regions(0x8000), None);
+        a.alloc_fixed(0x1000, 0x4000).expect("can allocate");
+        assert_eq!(kind(a.alloc(0x1000, 0x1000)), ErrorKind::OutOfSpace);
+        a.free(0x1000, 0x4000).expect("can free");
+        assert_eq!(
+            kind(a.alloc(0x2000, 0x4000)),
+            ErrorKind::Overconstrained {
+                size: 0x2000,
+                alignment: 0x4000
+            }
+        );
+        assert_eq!(kind(a.free(0x0, 0x1000)), ErrorKind::NotOwned);
+
+        a.alloc_fixed(0x2000, 0x1000).expect("can allocate");
+        assert_eq!(kind(a.alloc_fixed(0x2000, 0x1000)), ErrorKind::NotFree);
+        assert_eq!(kind(a.alloc_fixed(0x3800, 0x1000)), ErrorKind::InvalidAlignment);
+    });
+
+    both_tests!(linear_adaptive_policy, btree_adaptive_policy, a => {
+        use crate::adaptive::{Adaptive, Switch, Thresholds};
+        use std::cell::RefCell;
+
+        let switches = RefCell::new(Vec::new());
+        let thresholds = Thresholds { window: 4, ..Thresholds::default() };
+        let mut a = Adaptive::new(a, thresholds, |s: &Switch| switches.borrow_mut().push(*s));
+        a.add_range(0x0, 0x10_0000, ()).expect("can add range");
+
+        let allocations = allocate_n(&mut a, std::iter::once(0x1000), std::iter::once(0x1000), 256);
+        assert_eq!(a.policy(), Policy::FirstFit);
+        assert!(switches.borrow().is_empty());
+
+        // every other page is free, so the free space is as fragmented as it gets
+        for &(x, size) in allocations.iter().step_by(2) {
+            a.free(x, size).expect("can free");
+        }
+        let refilled = allocate_n(&mut a, std::iter::once(0x1000), std::iter::once(0x1000), 4);
+        assert_eq!(a.policy(), Policy::BestFit);
+        assert_eq!(switches.borrow().len(), 1);
+        assert_eq!((switches.borrow()[0].from, switches.borrow()[0].to), (Policy::FirstFit, Policy::BestFit));
+
+        // a single free block is not fragmented, but the window still sees failures
+        for &(x, size) in allocations.iter().skip(1).step_by(2).chain(&refilled) {
+            a.free(x, size).expect("can free");
+        }
+        assert_eq!(a.space(), a.total_space());
+        for _ in 0..4 {
+            assert!(a.alloc(0x20_0000, 0x1000).is_err());
+        }
+        assert_eq!(a.policy(), Policy::BestFit);
+
+        allocate_n(&mut a, std::iter::once(0x1000), std::iter::once(0x1000), 4);
+        assert_eq!(a.policy(), Policy::FirstFit);
+        assert_eq!(switches.borrow().len(), 2);
+        assert_eq!(switches.borrow()[1].failure_rate, 0.0);
+    });
+
+    /// a 64-bit address space beyond what a 32-bit `usize` could address, and a 32-bit one with
+    /// a region close to the top of the address space
+    fn wide_and_narrow_addresses(
+        mut wide: impl RangeAlloc<u64, Tag = ()>,
+        mut narrow: impl RangeAlloc<u32, Tag = ()>,
+    ) {
+        let base = 0x10_0000_0000u64;
+        wide.add_range(base, 0x10_0000, ()).expect("can add range");
+        let (_, x) = wide.alloc(0x1000, 0x10_000).expect("can allocate");
+        assert_eq!(x, base);
+        assert_eq!(wide.space(), 0xf_f000);
+        wide.free(x, 0x1000).expect("can free");
+        assert_eq!(wide.space(), wide.total_space());
+
+        narrow
+            .add_range(0xfffe_0000, 0x1_0000, ())
+            .expect("can add range");
+        assert_eq!(
+            kind(narrow.alloc(0x1000, 0x8000_0000)),
+            ErrorKind::Overconstrained {
+                size: 0x1000,
+                alignment: 0x8000_0000
+            }
+        );
+        assert_eq!(kind(narrow.alloc(u32::MAX, 0x1000)), ErrorKind::Overflow);
+        let (_, x) = narrow
+            .alloc_fixed(0xfffe_f000, 0x1000)
+            .expect("can allocate last page");
+        assert_eq!(x, 0xfffe_f000);
+        assert_eq!(narrow.space(), 0xf000);
+    }
+
+    #[test]
+    fn linear_generic_address() {
+        wide_and_narrow_addresses(
+            linear::RangeAllocator::<(), u64>::default(),
+            linear::RangeAllocator::<(), u32>::default(),
+        );
+    }
+
+    #[test]
+    fn btree_generic_address() {
+        wide_and_narrow_addresses(
+            btree::RangeAllocator::<(), u64>::default(),
+            btree::RangeAllocator::<(), u32>::default(),
+        );
+    }
+
+    /// a byte-granular heap and an allocator handing out 2 MiB huge pages
+    fn custom_granularity(
+        mut bytes: impl RangeAlloc<Tag = ()>,
+        mut huge: impl RangeAlloc<Tag = ()>,
+    ) {
+        bytes.add_range(0x1000, 0x100, ()).expect("can add range");
+        let (_, x) = bytes.alloc(3, 1).expect("can allocate");
+        let (_, y) = bytes.alloc(5, 8).expect("can allocate");
+        assert_eq!((x, y), (0x1000, 0x1008));
+        // the gap left by aligning `y` is still free
+        assert_eq!(bytes.space(), 0x100 - 3 - 5);
+        bytes.free(y, 5).expect("can free");
+        bytes.free(x, 3).expect("can free");
+        assert_eq!(bytes.space(), bytes.total_space());
+
+        huge.add_range(0x20_0000, 0x80_0000, ())
+            .expect("can add range");
+        let (_, x) = huge.alloc(0x1000, 0x1000).expect("can allocate");
+        assert_eq!(x, 0x20_0000);
+        assert_eq!(huge.space(), 0x60_0000);
+        assert_eq!(
+            kind(huge.alloc_fixed(0x50_0000, 0x1000)),
+            ErrorKind::InvalidAlignment
+        );
+        huge.alloc_fixed(0x60_0000, 1).expect("can allocate");
+        assert_eq!(huge.space(), 0x40_0000);
+    }
+
+    #[test]
+    fn linear_custom_granularity() {
+        custom_granularity(
+            linear::RangeAllocator::with_granularity(Alignment::ONE),
+            linear::RangeAllocator::with_granularity(Alignment::new(0x20_0000).unwrap()),
+        );
+    }
+
+    #[test]
+    fn btree_custom_granularity() {
+        custom_granularity(
+            btree::RangeAllocator::with_granularity(Alignment::ONE),
+            btree::RangeAllocator::with_granularity(Alignment::new(0x20_0000).unwrap()),
+        );
+    }
+
+    #[test]
+    fn instrumented_records_latencies() {
+        use crate::instrument::Instrumented;
+        use std::cell::Cell;
+
+        // every call to the clock advances it by one tick
+        let ticks = Cell::new(0);
+        let clock = || {
+            ticks.set(ticks.get() + 1);
+            ticks.get()
+        };
+
+        let mut a = Instrumented::new(new_linear(), clock);
+        setup(&mut a);
+        alloc_aligned(&mut a);
+
+        let stats = a.stats();
+        assert_eq!(stats.add_range.count(), 2);
+        assert_eq!(stats.alloc.count(), 2);
+        assert_eq!(stats.free.count(), 2);
+
+        let summary = stats.alloc.summary();
+        assert_eq!(summary.p50, 1);
+        assert_eq!(summary.p99, 1);
+        assert_eq!(summary.max, 1);
+        assert_eq!(stats.rates.window(), 0);
+        assert_eq!(stats.rates.allocs(), 0);
+    }
+
+    #[test]
+    fn instrumented_latency_histogram() {
+        use crate::instrument::Instrumented;
+        use std::cell::Cell;
+
+        // the clock is read before and after every operation, and the n-th one takes n ticks
+        let (now, reads) = (Cell::new(0), Cell::new(0));
+        let clock = || {
+            reads.set(reads.get() + 1);
+            if reads.get() % 2 == 0 {
+                now.set(now.get() + reads.get() / 2);
+            }
+            now.get()
+        };
+        let mut a = Instrumented::new(new_linear(), clock);
+        for _ in 0..1000 {
+            assert!(a.alloc(0x1000, 0x1000).is_err());
+        }
+
+        let summary = a.stats().alloc.summary();
+        assert_eq!(summary.count, 1000);
+        assert_eq!(summary.max, 1000);
+        // within the eighth of the histogram's resolution
+        assert!((500..=500 + 500 / 8).contains(&summary.p50));
+        assert!((990..=1000).contains(&summary.p99));
+        assert_eq!(a.stats().alloc.percentile(0), 1);
+        assert_eq!(a.stats().alloc.percentile(100), 1000);
+    }
+
+    #[test]
+    fn instrumented_rates() {
+        use crate::instrument::Instrumented;
+        use std::cell::Cell;
+
+        // time only moves when the test says so
+        let now = Cell::new(0);
+        let mut a = Instrumented::new(new_linear(), || now.get());
+        a.set_rate_window(100);
+        a.add_range(0x1000, 0x4000, ()).expect("can add range");
+
+        let (_, x) = a.alloc(0x2000, 0x1000).expect("can allocate");
+        now.set(50);
+        a.alloc(0x2000, 0x1000).expect("can allocate");
+        assert!(a.alloc(0x1000, 0x1000).is_err());
+        let rates = &a.stats().rates;
+        assert_eq!((rates.allocs(), rates.failed_allocs()), (2, 1));
+        assert_eq!(rates.bytes_allocated(), 0x4000);
+        assert_eq!(rates.alloc_rate(1000), 20.0);
+        assert!((rates.failure_rate() - 1.0 / 3.0).abs() < 1e-9);
+
+        // the first allocation drops out of the window
+        now.set(120);
+        a.free(x, 0x2000).expect("can free");
+        let rates = &a.stats().rates;
+        assert_eq!(
+            (rates.allocs(), rates.failed_allocs(), rates.frees()),
+            (1, 1, 1)
+        );
+        assert_eq!(rates.net_byte_rate(100), 0.0);
+
+        a.reset_stats();
+        assert_eq!(a.stats().rates.window(), 100);
+        assert_eq!(a.stats().rates.frees(), 0);
+    }
+
+    fn diff_ops() -> impl Strategy<Value = Vec<difftest::Op>> {
+        use difftest::Op;
+
+        let op = prop_oneof![
+            (0..24u32, 0..4u8).prop_map(|(pages, align_order)| Op::Alloc { pages, align_order }),
+            (0..112u64, 0..8u32).prop_map(|(page, pages)| Op::AllocFixed { page, pages }),
+            any::<usize>().prop_map(|pick| Op::Free { pick }),
+        ];
+        proptest::collection::vec(op, 0..200)
+    }
+
+    #[test]
+    fn difftest_catches_mismatches() {
+        use difftest::{DiffTest, MismatchKind, Op};
+
+        /// a backend that loses track of every second free
+        struct Leaky(linear::RangeAllocator<()>, bool);
+        impl RangeAlloc for Leaky {
+            type Tag = ();
+            fn add_range(&mut self, base: usize, size: usize, tag: ()) -> Result<()> {
+                self.0.add_range(base, size, tag)
+            }
+            fn alloc(&mut self, size: usize, alignment: usize) -> Result<((), usize)> {
+                self.0.alloc(size, alignment)
+            }
+            fn alloc_fixed(&mut self, base: usize, size: usize) -> Result<((), usize)> {
+                self.0.alloc_fixed(base, size)
+            }
+            fn free(&mut self, base: usize, size: usize) -> Result<()> {
+                self.1 = !self.1;
+                if self.1 {
+                    self.0.free(base, size)
+                } else {
+                    Ok(())
+                }
+            }
+            fn total_space(&self) -> usize {
+                self.0.total_space()
+            }
+            fn space(&self) -> usize {
+                self.0.space()
+            }
+        }
+
+        let mut d = DiffTest::with_backends(&[(0x1000, 0x8000)]).expect("can add regions");
+        d.add("leaky", Leaky(new_linear(), false))
+            .expect("can add regions");
+        let alloc = Op::Alloc {
+            pages: 1,
+            align_order: 0,
+        };
+        d.run([alloc, alloc, Op::Free { pick: 0 }])
+            .expect("nobody leaked yet");
+        let mismatch = d.apply(Op::Free { pick: 0 }).unwrap_err();
+        assert_eq!(mismatch.name, "leaky");
+        assert!(matches!(mismatch.kind, MismatchKind::Space { .. }));
+    }
+
+    proptest! {
+        #[cfg_attr(miri, ignore)]
+        #[test]
+        fn backends_match_model(ops in diff_ops()) {
+            let mut d = difftest::DiffTest::<()>::with_backends(&[(0x10_0000, 0x40_000), (0x14_0000, 0x20_000)])
+                .expect("can add regions");
+            if let Err(mismatch) = d.run(ops) {
+                prop_assert!(false, "{}", mismatch);
+            }
+        }
+    }
 }
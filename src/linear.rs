@@ -1,29 +1,81 @@
-use std::{marker::PhantomData, ops::Range, ptr::NonNull};
+//! the linear backend, a doubly linked list of free blocks that needs no allocations beyond its
+//! nodes, and the crate's default [`RangeAllocator`]. The blocks are linked a second time by size
+//! class, so searches for large ranges skip the many small fragments
+//!
+//! the nodes live in a pool owned by the allocator, which keeps the slots of released nodes for
+//! the next ones. Once it has grown to the peak number of blocks, splitting and merging them no
+//! longer allocates
+
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::{cmp::Reverse, fmt, marker::PhantomData, ops::Range, ptr::NonNull};
 
 use log::trace;
 
-use crate::{Error, RangeAlloc, Result, round_up};
+use crate::{
+    Complexity, ComplexityClass, Direction, Error, ErrorKind, Event, Limits, Placement, Policy,
+    RangeAlloc, RegionAttrs, RegionId, Request, Result, Steps,
+    address::Address,
+    allocator::{self, Allocator, Global},
+    btree,
+    collections::{RangeSet, pool::Pool},
+    common::{self, Bookkeeping, Common},
+    map::{self, MapEntry, RegionInfo, RegionKind},
+    merged_regions, notify,
+    raw::{RawParts, Snapshot, ensure},
+    round_up,
+    units::{Alignment, Size},
+    verify::{self, Discrepancy},
+};
 
 pub const BASE_PAGE_SIZE: usize = 4096;
 
+/// free blocks are kept in a list per power of two of their size, enough for 64-bit addresses
+const SIZE_CLASSES: usize = 64;
+
+/// the size class of a block of `size`, which has to be non-zero: blocks of class `c` are at
+/// least `2^c` and less than `2^(c + 1)` long
+fn size_class<A: Address>(size: A) -> usize {
+    (u64::BITS - 1 - size.to_u64().leading_zeros()) as usize
+}
+
 #[derive(Debug)]
-struct Node<Tag> {
+struct Node<Tag, A> {
     tag: Tag,
-    base: usize,
-    size: usize,
-    next: Option<NonNull<Node<Tag>>>,
-    prev: Option<NonNull<Node<Tag>>>,
+    base: A,
+    size: A,
+    /// when the block became free, unused for regions
+    epoch: u64,
+    /// when the block was added to the free list, so the list is ordered by descending `seq`.
+    /// Unused for regions
+    seq: u64,
+    next: Option<NonNull<Node<Tag, A>>>,
+    prev: Option<NonNull<Node<Tag, A>>>,
+    /// the neighbours in the list of the block's size class, unused for regions
+    class_next: Option<NonNull<Node<Tag, A>>>,
+    class_prev: Option<NonNull<Node<Tag, A>>>,
 }
 
 fn overlaps<I: PartialOrd>(a: Range<I>, b: Range<I>) -> bool {
     a.contains(&b.start) || b.contains(&a.start)
 }
 
-impl<T> Node<T> {
-    fn range(&self) -> Range<usize> {
+impl<T, A: Address> Node<T, A> {
+    /// the region this node describes, with its tag by reference
+    fn entry(&self, kind: RegionKind) -> MapEntry<&T, A> {
+        MapEntry {
+            base: self.base,
+            size: self.size,
+            tag: &self.tag,
+            kind,
+        }
+    }
+
+    fn range(&self) -> Range<A> {
         self.base..self.base + self.size
     }
+}
 
+impl<T, A> Node<T, A> {
     fn unlink(&mut self) -> Option<Option<NonNull<Self>>> {
         let mut head = None;
         unsafe {
@@ -44,15 +96,22 @@ impl<T> Node<T> {
     }
 }
 
-struct NodeIterMut<'a, T> {
-    node: Option<&'a mut Node<T>>,
+/// a free block next to a range, if there is one
+type Neighbour<'a, T, A> = Option<&'a mut Node<T, A>>;
+
+struct NodeIterMut<'a, T, A> {
+    node: Option<&'a mut Node<T, A>>,
+    /// where the nodes handed out are counted, for walks over the free list
+    steps: Option<&'a Steps>,
+    seen: usize,
 }
 
-impl<'a, T> Iterator for NodeIterMut<'a, T> {
-    type Item = &'a mut Node<T>;
+impl<'a, T, A> Iterator for NodeIterMut<'a, T, A> {
+    type Item = &'a mut Node<T, A>;
     fn next(&mut self) -> Option<Self::Item> {
         if let Some(node) = self.node.take() {
             self.node = node.next.map(|mut x| unsafe { x.as_mut() });
+            self.seen += 1;
             Some(node)
         } else {
             None
@@ -60,15 +119,27 @@ impl<'a, T> Iterator for NodeIterMut<'a, T> {
     }
 }
 
-struct NodeIter<'a, T> {
-    node: Option<&'a Node<T>>,
+impl<T, A> Drop for NodeIterMut<'_, T, A> {
+    fn drop(&mut self) {
+        if let Some(steps) = self.steps {
+            steps.add(self.seen);
+        }
+    }
+}
+
+struct NodeIter<'a, T, A> {
+    node: Option<&'a Node<T, A>>,
+    /// where the nodes handed out are counted, for walks over the free list
+    steps: Option<&'a Steps>,
+    seen: usize,
 }
 
-impl<'a, T> Iterator for NodeIter<'a, T> {
-    type Item = &'a Node<T>;
+impl<'a, T, A> Iterator for NodeIter<'a, T, A> {
+    type Item = &'a Node<T, A>;
     fn next(&mut self) -> Option<Self::Item> {
         if let Some(node) = self.node.take() {
             self.node = node.next.map(|x| unsafe { x.as_ref() });
+            self.seen += 1;
             Some(node)
         } else {
             None
@@ -76,34 +147,180 @@ impl<'a, T> Iterator for NodeIter<'a, T> {
     }
 }
 
-pub struct RangeAllocator<Tag> {
-    head: Option<NonNull<Node<Tag>>>,
-    mem_regions: Option<NonNull<Node<Tag>>>,
+impl<T, A> Drop for NodeIter<'_, T, A> {
+    fn drop(&mut self) {
+        if let Some(steps) = self.steps {
+            steps.add(self.seen);
+        }
+    }
+}
+
+pub struct RangeAllocator<Tag, A = usize, M: Allocator = Global> {
+    /// where all the nodes below live
+    nodes: Pool<Node<Tag, A>, M>,
+    head: Option<NonNull<Node<Tag, A>>>,
+    mem_regions: Option<NonNull<Node<Tag, A>>>,
+    /// regions that are part of the memory map but never allocatable
+    reserved_regions: Option<NonNull<Node<Tag, A>>>,
+    /// regions that were added with non-default attributes
+    region_attrs: Vec<(Range<A>, RegionAttrs<A>)>,
+    /// the free blocks by base, kept along with the allocations so `free` finds the blocks it
+    /// merges with in O(log n) instead of walking the list
+    free_index: Option<BTreeMap<A, NonNull<Node<Tag, A>>>>,
+    /// the free blocks again, in one list per size class, so a search can skip the blocks that
+    /// are too small
+    classes: [Option<NonNull<Node<Tag, A>>>; SIZE_CLASSES],
+    /// number of free blocks in every size class
+    class_len: [usize; SIZE_CLASSES],
+    /// number of blocks ever added to the free list, the `seq` of the latest one
+    insertions: u64,
+    /// the free block the latest allocation was made from, where [`Policy::NextFit`] resumes
+    /// searching. Moves on to the following block when that one is removed
+    cursor: Option<NonNull<Node<Tag, A>>>,
+    common: Common<Tag, A>,
     _data: PhantomData<Tag>,
 }
 
 impl<T> RangeAllocator<T> {
     pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<T, A: Address> Default for RangeAllocator<T, A> {
+    fn default() -> Self {
+        Self::empty_in(Global)
+    }
+}
+
+impl<Tag, A: Address> RangeAllocator<Tag, A> {
+    common::shared_methods!(constructors);
+}
+
+impl<Tag: Clone, A: Address> RangeAllocator<Tag, A> {
+    /// rebuilds an allocator from a [`snapshot`](Self::snapshot) of either backend, checked like
+    /// [`from_raw_parts`](Self::from_raw_parts)
+    pub fn restore(snapshot: Snapshot<Tag, A>) -> Result<Self> {
+        Self::from_raw_parts(snapshot)
+    }
+
+    /// rebuilds an allocator from `parts`, which may come from either backend. Fails with
+    /// [`ErrorKind::Inconsistent`] if they do not describe a valid allocator
+    pub fn from_raw_parts(parts: RawParts<Tag, A>) -> Result<Self> {
+        Self::from_raw_parts_in(parts, Global)
+    }
+}
+
+#[cfg(feature = "allocator-api")]
+impl<Tag, A: Address, M: Allocator> RangeAllocator<Tag, A, M> {
+    /// an allocator that keeps the nodes of its free list in `alloc`, and the rest of its
+    /// bookkeeping on the global heap, see the [`allocator`](crate::allocator) module
+    pub fn new_in(alloc: M) -> Self {
+        Self::empty_in(alloc)
+    }
+
+    /// [`with_granularity`](RangeAllocator::with_granularity) with the nodes in `alloc`
+    pub fn with_granularity_in(granularity: Alignment<A>, alloc: M) -> Self {
+        let mut a = Self::empty_in(alloc);
+        a.common.granularity = granularity;
+        a
+    }
+
+    pub fn allocator(&self) -> &M {
+        self.nodes.allocator()
+    }
+}
+
+impl<Tag, A: Address, M: Allocator> RangeAllocator<Tag, A, M> {
+    fn empty_in(alloc: M) -> Self {
         RangeAllocator {
+            nodes: Pool::new_in(alloc),
             head: None,
             mem_regions: None,
+            reserved_regions: None,
+            region_attrs: Vec::new(),
+            free_index: None,
+            classes: [None; SIZE_CLASSES],
+            class_len: [0; SIZE_CLASSES],
+            insertions: 0,
+            cursor: None,
+            common: Common::new(),
             _data: PhantomData,
         }
     }
 }
 
+/// moves everything over, including the allocations and, if enabled, their tracking
+impl<Tag: Default + Clone + fmt::Debug, A: Address> From<btree::RangeAllocator<Tag, A>>
+    for RangeAllocator<Tag, A>
+{
+    fn from(a: btree::RangeAllocator<Tag, A>) -> Self {
+        Self::from_raw_parts(a.into_raw_parts()).expect("the parts of an allocator are consistent")
+    }
+}
+
+/// the usable regions of a memory map, see [`add_regions`](RangeAllocator::add_regions). Panics
+/// if they are not valid, use `add_regions` to handle that
+impl<Tag: Clone + PartialEq, A: Address, M: Allocator> Extend<(A, A, Tag)>
+    for RangeAllocator<Tag, A, M>
+{
+    fn extend<I: IntoIterator<Item = (A, A, Tag)>>(&mut self, entries: I) {
+        self.add_regions(entries)
+            .expect("regions are not empty and do not overlap");
+    }
+}
+
+/// an allocator with the usable regions of a memory map, see [`Extend`]
+impl<Tag: Clone + PartialEq, A: Address> FromIterator<(A, A, Tag)> for RangeAllocator<Tag, A> {
+    fn from_iter<I: IntoIterator<Item = (A, A, Tag)>>(entries: I) -> Self {
+        let mut a = Self::default();
+        a.extend(entries);
+        a
+    }
+}
+
+/// as its [`RawParts`]. Deserializing checks the parts like
+/// [`from_raw_parts`](RangeAllocator::from_raw_parts), so a snapshot can also be restored
+/// into the other backend
+#[cfg(feature = "serde")]
+impl<Tag: Clone + serde::Serialize, A: Address + serde::Serialize, M: Allocator> serde::Serialize
+    for RangeAllocator<Tag, A, M>
+{
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> core::result::Result<S::Ok, S::Error> {
+        self.raw_parts().serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, Tag: Clone + serde::Deserialize<'de>, A: Address + serde::Deserialize<'de>>
+    serde::Deserialize<'de> for RangeAllocator<Tag, A>
+{
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> core::result::Result<Self, D::Error> {
+        Self::from_raw_parts(RawParts::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
 macro_rules! insert_to_list {
     ($this:expr, $list:ident,
             $base:expr, $size:expr,
-            $tag: expr) => {{
+            $tag: expr, $epoch:expr) => {{
         let new_first = pin!(
             $this,
             Node {
                 tag: $tag,
                 base: $base,
                 size: $size,
+                epoch: $epoch,
+                seq: 0,
                 next: $this.$list,
                 prev: None,
+                class_next: None,
+                class_prev: None,
             }
         );
         if let Some(mut old_first) = $this.$list {
@@ -116,8 +333,12 @@ macro_rules! insert_to_list {
 macro_rules! remove_from_list {
     ($this:expr, $list:ident, $node:expr) => {{
         let node = $node;
+        let next = node.next;
         let new_head = node.unlink();
         let node = NonNull::from(node);
+        if $this.cursor == Some(node) {
+            $this.cursor = next;
+        }
         release!($this, node);
         if let Some(head) = new_head {
             $this.$list = head;
@@ -125,233 +346,1181 @@ macro_rules! remove_from_list {
     }};
 }
 
-fn pin<Tag>(n: Node<Tag>) -> NonNull<Node<Tag>> {
-    unsafe { NonNull::new_unchecked(Box::into_raw(Box::new(n))) }
-}
-
-unsafe fn release<Tag>(n: NonNull<Node<Tag>>) {
-    unsafe { drop(Box::from_raw(n.as_ptr())) };
-}
-
 macro_rules! pin {
     ($this:expr, $n:expr) => {
-        pin($n)
+        $this.nodes.alloc($n)
     };
 }
 
 macro_rules! release {
+    // SAFETY: every node comes from the pool and is released once, when it leaves its list
     ($this:expr, $n:expr) => {
-        unsafe { release($n) }
+        unsafe { $this.nodes.release($n) }
     };
 }
 
-impl<Tag> RangeAllocator<Tag> {
-    fn iter_mut(&mut self) -> NodeIterMut<'_, Tag> {
+impl<Tag, A: Address, M: Allocator> RangeAllocator<Tag, A, M> {
+    /// allocating walks the free list, or the lists of the size classes that can hold the
+    /// request. Fixed allocations and frees walk the free list too. While allocations are
+    /// tracked, frees look the blocks up in O(log n) instead, after a walk over the regions.
+    /// Allocations do not remember their neighbouring free blocks for an O(1) free: such a hint
+    /// goes stale whenever the block is merged, split or handed out, and the tracked allocations
+    /// are a sorted map that takes O(log n) to update anyway
+    pub const COMPLEXITY: ComplexityClass = ComplexityClass {
+        alloc: Complexity::Linear,
+        alloc_fixed: Complexity::Linear,
+        free: Complexity::Linear,
+    };
+
+    /// the number of free blocks examined so far, one at a time. Blocks found through a lookup
+    /// in a sorted map are not counted
+    pub fn steps(&self) -> usize {
+        self.common.steps.get()
+    }
+
+    fn iter_mut(&mut self) -> NodeIterMut<'_, Tag, A> {
         NodeIterMut {
             node: self.head.map(|mut x| unsafe { x.as_mut() }),
+            steps: Some(&self.common.steps),
+            seen: 0,
         }
     }
 
-    fn iter(&self) -> NodeIter<'_, Tag> {
+    fn iter(&self) -> NodeIter<'_, Tag, A> {
         NodeIter {
             node: self.head.map(|x| unsafe { x.as_ref() }),
+            steps: Some(&self.common.steps),
+            seen: 0,
         }
     }
 
-    fn parent_iter(&self) -> NodeIter<'_, Tag> {
+    fn parent_iter(&self) -> NodeIter<'_, Tag, A> {
         NodeIter {
             node: self.mem_regions.map(|x| unsafe { x.as_ref() }),
+            steps: None,
+            seen: 0,
+        }
+    }
+
+    fn parent_iter_mut(&mut self) -> NodeIterMut<'_, Tag, A> {
+        NodeIterMut {
+            node: self.mem_regions.map(|mut x| unsafe { x.as_mut() }),
+            steps: None,
+            seen: 0,
+        }
+    }
+
+    fn reserved_region_iter(&self) -> NodeIter<'_, Tag, A> {
+        NodeIter {
+            node: self.reserved_regions.map(|x| unsafe { x.as_ref() }),
+            steps: None,
+            seen: 0,
+        }
+    }
+
+    /// the regions and the free blocks for printing, which does not count as steps
+    fn shown(
+        &self,
+    ) -> (
+        impl Iterator<Item = MapEntry<&Tag, A>>,
+        impl Iterator<Item = Range<A>>,
+    ) {
+        let regions = self
+            .parent_iter()
+            .map(|node| node.entry(RegionKind::Usable))
+            .chain(
+                self.reserved_region_iter()
+                    .map(|node| node.entry(RegionKind::Reserved)),
+            );
+        let free = NodeIter {
+            node: self.head.map(|x| unsafe { x.as_ref() }),
+            steps: None,
+            seen: 0,
+        };
+        (regions, free.map(Node::range))
+    }
+
+    /// whether the next-fit cursor is unset or a block of the free list, which does not count as
+    /// steps
+    fn cursor_in_list(&self) -> bool {
+        let mut free = NodeIter {
+            node: self.head.map(|x| unsafe { x.as_ref() }),
+            steps: None,
+            seen: 0,
+        };
+        self.cursor
+            .is_none_or(|cursor| free.any(|node| NonNull::from(node) == cursor))
+    }
+
+    /// where `request`, which has to be normalized, would be placed under `policy`
+    fn place(
+        &self,
+        request: Request<A>,
+        policy: Policy,
+        window: &Range<A>,
+    ) -> Result<Placement<A>> {
+        let constraints = |base: A| {
+            self.attrs_at(base)
+                .map_or((request.alignment, request.size), |(_, attrs)| {
+                    attrs.apply(request.alignment, request.size)
+                })
+        };
+        let region_policy = |base: A| {
+            self.attrs_at(base)
+                .and_then(|(region_base, attrs)| attrs.policy.map(|p| (region_base, p)))
+        };
+
+        // next-fit starts at the cursor and wraps around to the blocks before it
+        let start = match policy {
+            Policy::NextFit => {
+                debug_assert!(self.cursor_in_list(), "the cursor left the free list");
+                self.cursor
+            }
+            _ => None,
+        };
+        let from_cursor = NodeIter {
+            // SAFETY: the cursor is always a live block of the free list
+            node: start.map(|node| unsafe { node.as_ref() }),
+            steps: Some(&self.common.steps),
+            seen: 0,
+        };
+        let before_cursor = self
+            .iter()
+            .take_while(|&node| Some(NonNull::from(node)) != start);
+        // shorter blocks can not hold the request, region attributes only make it larger
+        let (fitting, all) = match self.blocks_fitting(request.size) {
+            Some(blocks) if policy != Policy::NextFit => (Some(blocks), None),
+            _ => (None, Some(from_cursor.chain(before_cursor))),
+        };
+        let blocks = fitting
+            .into_iter()
+            .flatten()
+            .chain(all.into_iter().flatten());
+        let placements = blocks.filter_map(|node| {
+            let (alignment, size) = constraints(node.base);
+            Placement::within_window(
+                node.range(),
+                window,
+                alignment,
+                size,
+                self.common.granularity,
+                self.common.direction,
+            )
+            .map(|p| (p, node.epoch))
+        });
+        let placement = if self.common.direction == Direction::TopDown && policy != Policy::NextFit
+        {
+            // the list is not sorted, so the highest blocks have to be searched for
+            let mut sorted = allocator::vec_in::<(Placement<A>, u64), _>(self.nodes.allocator());
+            sorted.extend(placements);
+            sorted.sort_unstable_by_key(|(p, _)| Reverse(p.block.start));
+            policy.select_per_region(sorted.into_iter(), region_policy)
+        } else {
+            policy.select_per_region(placements, region_policy)
+        };
+        if let Some(placement) = placement {
+            return Ok(placement);
+        }
+
+        // some block has enough space for the request, but does not satisfy the constraints
+        let in_window = |node: &Node<Tag, A>| {
+            node.base.max(window.start)..(node.base + node.size).min(window.end)
+        };
+        if self.iter().any(|node| {
+            let free = in_window(node);
+            free.start < free.end && constraints(node.base).1 <= free.end - free.start
+        }) {
+            Err(Error::new(ErrorKind::Overconstrained {
+                size: request.size.to_u64(),
+                alignment: request.alignment.to_u64(),
+            }))
+        } else {
+            let largest_region = self.largest_region();
+            if largest_region != A::ZERO && request.size > largest_region {
+                Err(Error::new(ErrorKind::RequestExceedsAnyRegion {
+                    size: request.size.to_u64(),
+                    largest_region: largest_region.to_u64(),
+                }))
+            } else {
+                Err(Error::new(ErrorKind::OutOfSpace))
+            }
         }
     }
+
+    /// the base and attributes of the region containing `addr`, if it has non-default ones
+    fn attrs_at(&self, addr: A) -> Option<(A, &RegionAttrs<A>)> {
+        self.region_attrs
+            .iter()
+            .find(|(region, _)| region.contains(&addr))
+            .map(|(region, attrs)| (region.start, attrs))
+    }
+
+    fn rebuild_free_index(&mut self) {
+        self.free_index = self.common.allocations.is_some().then(|| {
+            self.iter_mut()
+                .map(|node| (node.base, NonNull::from(node)))
+                .collect()
+        });
+    }
+
+    /// keeps the free block index in step with a block that moved from base `old` to `node`.
+    /// `old` is `None` for new blocks, `node` for removed ones
+    fn reindex(&mut self, old: Option<A>, node: Option<NonNull<Node<Tag, A>>>) {
+        if let Some(index) = &mut self.free_index {
+            if let Some(old) = old {
+                index.remove(&old);
+            }
+            if let Some(node) = node {
+                // SAFETY: `node` is a live block of the free list
+                index.insert(unsafe { node.as_ref() }.base, node);
+            }
+        }
+    }
+
+    /// adds a block to the free list and its size class
+    fn push_free(&mut self, base: A, size: A, tag: Tag, epoch: u64) -> NonNull<Node<Tag, A>> {
+        insert_to_list!(self, head, base, size, tag, epoch);
+        let node = self.head.expect("was just inserted");
+        self.insertions += 1;
+        // SAFETY: `node` is a live block of the free list
+        unsafe { (*node.as_ptr()).seq = self.insertions };
+        self.link_class(node);
+        node
+    }
+
+    /// removes a block from the free list and its size class, and releases it
+    fn drop_free(&mut self, node: NonNull<Node<Tag, A>>) {
+        self.unlink_class(node);
+        // SAFETY: `node` is a live block of the free list, which owns it
+        let node = unsafe { &mut *node.as_ptr() };
+        remove_from_list!(self, head, node);
+    }
+
+    /// moves a free block to `base..base + size`, and to the list of its new size class
+    fn resize(&mut self, node: NonNull<Node<Tag, A>>, base: A, size: A) {
+        // SAFETY: `node` is a live block of the free list
+        let class = size_class(unsafe { node.as_ref() }.size);
+        if class != size_class(size) {
+            self.unlink_class(node);
+        }
+        // SAFETY: as above, and no other reference to it is used meanwhile
+        unsafe {
+            (*node.as_ptr()).base = base;
+            (*node.as_ptr()).size = size;
+        }
+        if class != size_class(size) {
+            self.link_class(node);
+        }
+    }
+
+    fn link_class(&mut self, node: NonNull<Node<Tag, A>>) {
+        // SAFETY: `node` and the blocks of its class are live blocks of the free list
+        unsafe {
+            let class = size_class(node.as_ref().size);
+            let first = self.classes[class];
+            (*node.as_ptr()).class_next = first;
+            (*node.as_ptr()).class_prev = None;
+            if let Some(first) = first {
+                (*first.as_ptr()).class_prev = Some(node);
+            }
+            self.classes[class] = Some(node);
+            self.class_len[class] += 1;
+        }
+    }
+
+    fn unlink_class(&mut self, node: NonNull<Node<Tag, A>>) {
+        // SAFETY: `node` and its class neighbours are live blocks of the free list
+        unsafe {
+            let Node {
+                size,
+                class_next,
+                class_prev,
+                ..
+            } = *node.as_ptr();
+            let class = size_class(size);
+            if let Some(next) = class_next {
+                (*next.as_ptr()).class_prev = class_prev;
+            }
+            match class_prev {
+                Some(prev) => (*prev.as_ptr()).class_next = class_next,
+                None => self.classes[class] = class_next,
+            }
+            self.class_len[class] -= 1;
+        }
+    }
+
+    /// the free blocks of size class `class`
+    fn class_iter(&self, class: usize) -> impl Iterator<Item = &Node<Tag, A>> {
+        // SAFETY: the class lists only hold live blocks of the free list
+        core::iter::successors(self.classes[class].map(|x| unsafe { x.as_ref() }), |node| {
+            node.class_next.map(|x| unsafe { x.as_ref() })
+        })
+    }
+
+    /// the free blocks that are at least `size` long, and some that are a little shorter, in
+    /// free list order. `None` if they are most of the free list, which is then faster to walk
+    fn blocks_fitting(&self, size: A) -> Option<allocator::Vec<&Node<Tag, A>, &M>> {
+        let first = size_class(size);
+        let fitting: usize = self.class_len[first..].iter().sum();
+        if fitting * 2 > self.class_len.iter().sum() {
+            return None;
+        }
+        let mut blocks = allocator::vec_in::<&Node<Tag, A>, _>(self.nodes.allocator());
+        blocks.extend((first..SIZE_CLASSES).flat_map(|class| self.class_iter(class)));
+        blocks.sort_unstable_by_key(|node| Reverse(node.seq));
+        self.common.steps.add(blocks.len());
+        Some(blocks)
+    }
+
+    fn is_free_block_at(&self, base: A) -> bool {
+        match &self.free_index {
+            Some(index) => index.contains_key(&base),
+            None => self.iter().any(|node| node.base == base),
+        }
+    }
+
+    /// the free blocks of `region` directly before and after `range`
+    fn adjacent_free(
+        &mut self,
+        range: Range<A>,
+        region: &Range<A>,
+    ) -> (Neighbour<'_, Tag, A>, Neighbour<'_, Tag, A>) {
+        let is_before = |node: &Node<Tag, A>| node.base + node.size == range.start;
+        let is_after = |node: &Node<Tag, A>| node.base == range.end;
+        let (mut before, mut after) = (None, None);
+        match &self.free_index {
+            Some(index) => {
+                // SAFETY: the index only holds live blocks of the free list, and `before` and
+                // `after` are distinct blocks since `range` lies between them
+                before = index
+                    .range(..range.start)
+                    .next_back()
+                    .map(|(_, &node)| unsafe { &mut *node.as_ptr() })
+                    .filter(|node| is_before(node));
+                after = index
+                    .get(&range.end)
+                    .map(|&node| unsafe { &mut *node.as_ptr() });
+            }
+            None => {
+                for node in self.iter_mut() {
+                    if is_before(node) {
+                        before = Some(node)
+                    } else if is_after(node) {
+                        after = Some(node)
+                    }
+                }
+            }
+        }
+        (
+            before.filter(|node| node.base >= region.start),
+            after.filter(|node| node.base < region.end),
+        )
+    }
 }
 
-impl<Tag> RangeAllocator<Tag>
-where
-    Tag: Default + Clone,
-{
-    /// allocates a range at the given base address. Fails if that address is already allocated.
-    pub fn alloc_fixed(&mut self, base: usize, size: usize) -> Result<(Tag, usize)> {
-        Err(Error::unimplemented())
+impl<Tag, A: Address, M: Allocator> RangeAllocator<Tag, A, M> {
+    common::shared_methods!(state);
+
+    /// number of regions, usable and reserved
+    pub fn region_count(&self) -> usize {
+        self.parent_iter().count() + self.reserved_region_iter().count()
+    }
+
+    /// number of separate free extents. Walks the free list, like the limit checks do
+    /// when [`Limits::max_free_extents`] is set
+    pub fn free_extent_count(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// size of the largest usable region, 0 without any
+    pub fn largest_region(&self) -> A {
+        self.parent_iter()
+            .map(|node| node.size)
+            .max()
+            .unwrap_or(A::ZERO)
+    }
+
+    /// size of the largest free extent within `range`, counting only the part inside it, 0
+    /// without any. Walks the free list
+    pub fn largest_free_in(&self, range: Range<A>) -> A {
+        self.iter()
+            .map(|node| {
+                let start = node.base.max(range.start);
+                let end = node.range().end.min(range.end);
+                if start < end { end - start } else { A::ZERO }
+            })
+            .max()
+            .unwrap_or(A::ZERO)
+    }
+
+    /// two touching usable regions that together span at least `size`, for a request that
+    /// failed with [`ErrorKind::RequestExceedsAnyRegion`]. Blocks of different regions are never
+    /// merged, so the caller has to remove both regions and add their span as one
+    pub fn bridging_regions(&self, size: A) -> Option<Range<A>> {
+        let mut regions: Vec<Range<A>> = self.parent_iter().map(Node::range).collect();
+        regions.sort_by_key(|region| region.start);
+        crate::bridging_pair(&regions, size)
+    }
+
+    /// [`metrics::fragmentation_score`](crate::metrics::fragmentation_score) of the free space
+    pub fn fragmentation(&self) -> f64 {
+        crate::metrics::fragmentation_score(self.iter().map(|node| node.size.to_u64()))
+    }
+
+    /// estimated bytes used for bookkeeping, including the unused nodes kept for reuse
+    pub fn metadata_bytes(&self) -> usize {
+        size_of::<Self>()
+            + self.nodes.capacity() * size_of::<Node<Tag, A>>()
+            + self.region_attrs.capacity() * size_of::<(Range<A>, RegionAttrs<A>)>()
+            + self.common.reserved.len() * 2 * size_of::<A>()
+    }
+
+    /// the free blocks that have not changed since before `epoch`, i.e. have been idle the longest
+    pub fn cold_free_ranges(&self, epoch: u64) -> Vec<Range<A>> {
+        let mut ranges: Vec<_> = self
+            .iter()
+            .filter(|node| node.epoch < epoch)
+            .map(Node::range)
+            .collect();
+        ranges.sort_by_key(|range| range.start);
+        ranges
     }
 }
 
-impl<Tag: Clone> RangeAlloc for RangeAllocator<Tag> {
-    type Tag = Tag;
+impl<Tag: Clone, A: Address, M: Allocator> RangeAllocator<Tag, A, M> {
+    common::shared_methods!(bookkeeping);
 
-    /// adds a range to the allocator from which the allocator may pick
-    fn add_range(&mut self, base: usize, size: usize, range_tag: Tag) -> Result<()> {
-        assert!(size > 0);
-        trace!("add_range {base}:{size}");
-        if self
-            .iter_mut()
-            .any(|x| overlaps(x.range(), base..base + size))
-        {
-            return Err(Error::cause("overlapping range"));
+    /// adds a range whose allocations have to satisfy `attrs`
+    pub fn add_range_with(
+        &mut self,
+        base: A,
+        size: A,
+        range_tag: Tag,
+        attrs: RegionAttrs<A>,
+    ) -> Result<()> {
+        attrs.validate()?;
+        self.add_range(base, size, range_tag)?;
+        if attrs != RegionAttrs::default() {
+            self.region_attrs.push((base..base + size, attrs));
         }
+        Ok(())
+    }
 
-        insert_to_list!(self, head, base, size, range_tag.clone());
-        insert_to_list!(self, mem_regions, base, size, range_tag);
+    /// overrides the policy used within `region`, or reverts it to the allocator's policy if
+    /// `policy` is `None`
+    pub fn set_region_policy(&mut self, region: RegionId<A>, policy: Option<Policy>) -> Result<()> {
+        let base = region.base();
+        let size = self.usable_region_at(base)?;
+        match self.region_attrs.iter().position(|(r, _)| r.start == base) {
+            Some(i) => {
+                self.region_attrs[i].1.policy = policy;
+                if self.region_attrs[i].1 == RegionAttrs::default() {
+                    self.region_attrs.swap_remove(i);
+                }
+            }
+            None => {
+                if let Some(policy) = policy {
+                    self.region_attrs
+                        .push((base..base + size, RegionAttrs::with_policy(policy)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// the policy `region` uses instead of the allocator's, if any
+    pub fn region_policy(&self, region: RegionId<A>) -> Option<Policy> {
+        self.attrs_at(region.base())
+            .filter(|&(base, _)| base == region.base())
+            .and_then(|(_, attrs)| attrs.policy)
+    }
+
+    /// the size of the usable region `region`, like [`total_space`](RangeAlloc::total_space) for
+    /// the whole allocator. `None` if no usable region starts there
+    pub fn total_space_in_region(&self, region: RegionId<A>) -> Option<A> {
+        self.parent_iter()
+            .find(|node| node.base == region.base())
+            .map(|node| node.size)
+    }
+
+    /// adds a region that is part of the memory map but is never handed out, e.g. an MMIO hole.
+    /// It does not count towards `total_space`
+    pub fn add_range_reserved(&mut self, base: A, size: A, range_tag: Tag) -> Result<()> {
+        Size::new(size)?;
+        let end = base
+            .checked_add(size)
+            .ok_or_else(|| Error::new(ErrorKind::Overflow))?;
+        if self.overlapping_region(base..end).is_some() {
+            return Err(Error::new(ErrorKind::OverlappingRange));
+        }
+        self.room_for_region()?;
+
+        insert_to_list!(self, reserved_regions, base, size, range_tag, 0);
 
         Ok(())
     }
 
-    /// allocates a range. The range will not be handed out again until it has been freed
-    fn alloc(&mut self, min_size: usize, alignment: usize) -> Result<(Tag, usize)> {
-        trace!(
-            "allocate: {min_size} {alignment} currently have space: {}",
-            self.space()
+    /// extends the usable region starting at `base` to `new_size` at its end, e.g. when the heap
+    /// behind it was grown in place, and adds the new space to the free space. Unlike removing and
+    /// re-adding the region, this works while it has allocations. Fails with
+    /// [`ErrorKind::NotOwned`] if no usable region starts at `base`, [`ErrorKind::InvalidSize`] if
+    /// `new_size` is smaller than the region and [`ErrorKind::OverlappingRange`] if the new space
+    /// overlaps another region
+    pub fn grow_region(&mut self, base: A, new_size: A) -> Result<()> {
+        let new_end = base
+            .checked_add(new_size)
+            .ok_or_else(|| Error::new(ErrorKind::Overflow))?;
+        let old_end = self
+            .region(base)
+            .filter(|region| region.kind == RegionKind::Usable)
+            .map(|region| region.end())
+            .ok_or_else(|| Error::new(ErrorKind::NotOwned))?;
+        if new_end < old_end {
+            return Err(Error::new(ErrorKind::InvalidSize));
+        }
+        if new_end == old_end {
+            return Ok(());
+        }
+        if self.overlapping_region(old_end..new_end).is_some() {
+            return Err(Error::new(ErrorKind::OverlappingRange));
+        }
+        // the new space is freed like an allocation, which merges it with a free block at the old
+        // end or adds a free extent
+        if !self.overlaps_free(old_end - A::ONE..old_end) {
+            self.room_for_free_extent()?;
+        }
+
+        let node = self
+            .parent_iter_mut()
+            .find(|region| region.base == base)
+            .expect("region exists");
+        let tag = node.tag.clone();
+        node.size = new_size;
+        if let Some((range, _)) = self.region_attrs.iter_mut().find(|(r, _)| r.start == base) {
+            range.end = new_end;
+        }
+        self.give_back(old_end, new_end - old_end, false)
+            .expect("there is room for the new space");
+        self.common.total_space += new_end - old_end;
+        let range = old_end..new_end;
+        notify(
+            &mut self.common.observer,
+            Event::AddRange { range, tag: &tag },
         );
-        if !alignment.is_power_of_two() {
-            return Err(Error::cause("not power of two"));
+        Ok(())
+    }
+
+    /// adds the usable regions of a memory map at once, e.g. from UEFI or multiboot, with a single
+    /// overlap check instead of one per [`add_range`](RangeAlloc::add_range). The entries are
+    /// sorted and touching ones with equal tags merged. Nothing is added if any of them is empty
+    /// or overlaps another one or an existing region, or if they exceed the limits
+    pub fn add_regions<I>(&mut self, entries: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (A, A, Tag)>,
+        Tag: PartialEq,
+    {
+        let mut existing: Vec<_> = self
+            .parent_iter()
+            .chain(self.reserved_region_iter())
+            .map(Node::range)
+            .collect();
+        existing.sort_by_key(|region| region.start);
+        let regions = merged_regions(entries, &existing)?;
+        self.common.limits.admit(
+            existing.len() + regions.len(),
+            self.free_extent_count() + regions.len(),
+        )?;
+        for (range, tag) in regions {
+            self.push_region(range.start, range.end - range.start, tag);
         }
-        let min_size = round_up!(min_size, BASE_PAGE_SIZE);
+        Ok(())
+    }
+
+    /// all regions, usable and reserved, sorted by base. The order is guaranteed, regions never
+    /// overlap, so two allocators with the same regions export equal maps
+    pub fn export_map(&self) -> Vec<MapEntry<Tag, A>> {
+        let entry = |kind| {
+            move |node: &Node<Tag, A>| MapEntry {
+                base: node.base,
+                size: node.size,
+                tag: node.tag.clone(),
+                kind,
+            }
+        };
+        let mut map: Vec<_> = self
+            .parent_iter()
+            .map(entry(RegionKind::Usable))
+            .chain(self.reserved_region_iter().map(entry(RegionKind::Reserved)))
+            .collect();
+        map.sort_by_key(|entry| entry.base);
+        map
+    }
 
-        let mut any_can_fit = false;
+    /// [`from_raw_parts`](RangeAllocator::from_raw_parts) with the nodes in `alloc`
+    fn from_raw_parts_in(mut parts: RawParts<Tag, A>, alloc: M) -> Result<Self> {
+        parts.validate()?;
+        let mut a = Self::empty_in(alloc);
+        a.common = Common::from_parts(&mut parts);
 
-        let candidate = self.iter_mut().find(|node| {
-            if min_size > node.size {
-                return false;
+        // the lists are built back to front, so they end up sorted by base
+        for region in parts.regions.into_iter().rev() {
+            match region.kind {
+                RegionKind::Usable => {
+                    a.common.region_free.insert(region.base, A::ZERO);
+                    insert_to_list!(a, mem_regions, region.base, region.size, region.tag, 0);
+                }
+                RegionKind::Reserved => {
+                    insert_to_list!(a, reserved_regions, region.base, region.size, region.tag, 0);
+                }
             }
-            // this node has enough space for the request, but does it satisfy the constraints?
-            any_can_fit = true;
+        }
+        for (range, epoch) in parts.free.into_iter().rev() {
+            let size = range.end - range.start;
+            let tag = a
+                .parent_iter()
+                .find(|region| region.range().contains(&range.start))
+                .map(|region| region.tag.clone())
+                .expect("validated: free extents lie in usable regions");
+            a.push_free(range.start, size, tag, epoch);
+            *a.common.region_free_mut(range.start) += size;
+        }
+        for (base, attrs) in parts.region_attrs {
+            let size = a
+                .total_space_in_region(RegionId(base))
+                .expect("validated: attributes belong to usable regions");
+            a.region_attrs.push((base..base + size, attrs));
+        }
+        a.rebuild_free_index();
+        Ok(a)
+    }
 
-            let aligned = round_up!(node.base, alignment);
-            let spill = aligned - node.base;
+    /// checks the bookkeeping for consistency, which is slow, e.g. every so often in long-running
+    /// tests. Fails with [`ErrorKind::Inconsistent`] if anything does not add up, located at the
+    /// check that failed: free blocks sorted, apart and coalesced within their regions, every
+    /// list and size class linked both ways, and the space counters matching the blocks
+    pub fn check_invariants(&self) -> Result<()> {
+        self.raw_parts().validate()?;
 
-            if spill > node.size {
-                // aligned base is outside of allocation
-                return false;
+        // every list has to link back to where it came from
+        for list in [self.iter(), self.parent_iter(), self.reserved_region_iter()] {
+            let mut prev = None;
+            for node in list {
+                ensure(node.prev == prev)?;
+                prev = Some(NonNull::from(node));
             }
+        }
 
-            if min_size > node.size - spill {
-                // not enough space in this allocation
-                return false;
+        // every free block has to be in the list of its size class, and nothing else
+        for class in 0..SIZE_CLASSES {
+            let mut prev = None;
+            let mut len = 0;
+            for node in self.class_iter(class) {
+                ensure(node.class_prev == prev)?;
+                ensure(size_class(node.size) == class)?;
+                prev = Some(NonNull::from(node));
+                len += 1;
             }
+            ensure(len == self.class_len[class])?;
+        }
+        ensure(self.class_len.iter().sum::<usize>() == self.iter().count())?;
+        // no node leaks out of the lists
+        let nodes =
+            self.iter().count() + self.parent_iter().count() + self.reserved_region_iter().count();
+        ensure(nodes == self.nodes.len())?;
 
-            true
+        let mut region_free: BTreeMap<_, _> = self
+            .parent_iter()
+            .map(|region| (region.base, A::ZERO))
+            .collect();
+        for node in self.iter() {
+            let (_, space) = region_free
+                .range_mut(..=node.base)
+                .next_back()
+                .ok_or_else(|| Error::inconsistent())?;
+            *space += node.size;
+        }
+        let indexed = self.free_index.as_ref().is_none_or(|index| {
+            index.len() == self.iter().count()
+                && self
+                    .iter()
+                    .all(|node| index.get(&node.base) == Some(&NonNull::from(node)))
         });
+        ensure(region_free == self.common.region_free)?;
+        ensure(self.cursor_in_list())?;
+        ensure(indexed)
+    }
 
-        let Some(candidate) = candidate else {
-            if any_can_fit {
-                return Err(Error::cause("has space but overconstrained"));
-            } else {
-                return Err(Error::cause("no space"));
-            }
+    /// the region, usable or reserved, that starts at `base`
+    pub fn region(&self, base: A) -> Option<RegionInfo<'_, Tag, A>> {
+        self.parent_iter()
+            .find(|node| node.base == base)
+            .map(|node| node.entry(RegionKind::Usable))
+            .or_else(|| {
+                self.reserved_region_iter()
+                    .find(|node| node.base == base)
+                    .map(|node| node.entry(RegionKind::Reserved))
+            })
+    }
+
+    /// the region, usable or reserved, that `addr` belongs to
+    pub fn region_containing(&self, addr: A) -> Option<MapEntry<Tag, A>> {
+        let contains = |node: &&Node<Tag, A>| node.range().contains(&addr);
+        let (node, kind) = self
+            .parent_iter()
+            .find(contains)
+            .map(|node| (node, RegionKind::Usable))
+            .or_else(|| {
+                self.reserved_region_iter()
+                    .find(contains)
+                    .map(|node| (node, RegionKind::Reserved))
+            })?;
+        Some(MapEntry {
+            base: node.base,
+            size: node.size,
+            tag: node.tag.clone(),
+            kind,
+        })
+    }
+
+    /// takes the usable region starting at `base` away, e.g. when its memory is unplugged. Fails
+    /// with [`ErrorKind::NotFree`] while any part of it is allocated, and with
+    /// [`ErrorKind::Pinned`] while any part of it is pinned. Reservations inside the region are
+    /// dropped along with it
+    pub fn remove_range(&mut self, base: A) -> Result<()> {
+        let Some(region) = self.parent_iter().find(|region| region.base == base) else {
+            return Err(Error::new(ErrorKind::NotOwned));
         };
+        let range = region.range();
+        self.common.check_unpinned(range.clone())?;
+        let free: Vec<_> = self
+            .iter()
+            .map(|node| node.base.max(range.start)..(node.base + node.size).min(range.end))
+            .filter(|free| free.start < free.end)
+            .collect();
+        let reserved = self
+            .common
+            .reserved
+            .intersect(&RangeSet::from_iter([range.clone()]));
+        let free_space: A = free.iter().map(|free| free.end - free.start).sum();
+        if free_space + reserved.covered() != region.size {
+            return Err(Error::new(ErrorKind::NotFree));
+        }
+
+        for free in free {
+            self.carve(free.start, free.end - free.start)?;
+        }
+        self.common.reserved.remove(range.clone());
+        self.common.holds.remove(range.clone());
+        self.region_attrs.retain(|(region, _)| region.start != base);
+        let node = self
+            .parent_iter_mut()
+            .find(|region| region.base == base)
+            .expect("region exists");
+        remove_from_list!(self, mem_regions, node);
+        self.common.region_free.remove(&base);
+        self.common.total_space -= range.end - base;
+        trace!("remove_range {base}:{}", range.end - base);
+        Ok(())
+    }
 
-        let free_start = candidate.base;
-        let after_free = candidate.base + candidate.size;
+    /// frees everything allocated from the regions tagged `tag`, e.g. when the VM they belong to
+    /// is torn down, and returns how much that was. Reservations stay in place. Nothing is freed
+    /// if any of the regions contains a pinned range
+    pub fn free_all_with_tag(&mut self, tag: &Tag) -> Result<A>
+    where
+        Tag: PartialEq,
+    {
+        let regions: Vec<_> = self
+            .parent_iter()
+            .filter(|region| region.tag == *tag)
+            .map(Node::range)
+            .collect();
+        let free: RangeSet<A> = self.iter().map(Node::range).collect();
+        self.free_allocated_in(regions, &free)
+    }
 
-        let allocated_start = round_up!(free_start, alignment);
-        let after_allocated = round_up!(allocated_start + min_size, BASE_PAGE_SIZE);
+    /// compares the allocator's view with the ranges that are `allocated` according to an
+    /// external source of truth, e.g. the page tables
+    pub fn verify_against(
+        &self,
+        allocated: impl IntoIterator<Item = Range<A>>,
+    ) -> Vec<Discrepancy<A>> {
+        let regions = self.parent_iter().map(Node::range).collect();
+        let free = self.iter().map(Node::range).collect();
+        verify::diff(&regions, &free, &self.common.reserved, allocated)
+    }
 
-        fn chunk_between(start: usize, end: usize) -> Option<(usize, usize)> {
-            if end - start >= BASE_PAGE_SIZE {
+    /// adds the usable region `base..base + size`, which is known not to overlap any other
+    fn push_region(&mut self, base: A, size: A, range_tag: Tag) {
+        self.common.epoch += 1;
+        let node = self.push_free(base, size, range_tag.clone(), self.common.epoch);
+        self.reindex(None, Some(node));
+        let range = base..base + size;
+        notify(
+            &mut self.common.observer,
+            Event::AddRange {
+                range,
+                tag: &range_tag,
+            },
+        );
+        insert_to_list!(self, mem_regions, base, size, range_tag, 0);
+        self.common.total_space += size;
+        self.common.free_space += size;
+        self.common.region_free.insert(base, size);
+    }
+}
+
+impl<Tag: Clone, A: Address, M: Allocator> RangeAlloc<A> for RangeAllocator<Tag, A, M> {
+    type Tag = Tag;
+
+    /// adds a range to the allocator from which the allocator may pick
+    fn add_range(&mut self, base: A, size: A, range_tag: Tag) -> Result<()> {
+        Size::new(size)?;
+        trace!("add_range {base}:{size}");
+        let end = base
+            .checked_add(size)
+            .ok_or_else(|| Error::new(ErrorKind::Overflow))?;
+        if self.overlapping_region(base..end).is_some() {
+            return Err(Error::new(ErrorKind::OverlappingRange));
+        }
+        self.room_for_region()?;
+        self.room_for_free_extent()?;
+
+        self.push_region(base, size, range_tag);
+        Ok(())
+    }
+
+    common::shared_methods!(range_alloc);
+}
+
+impl<Tag: Clone, A: Address, M: Allocator> Bookkeeping<A> for RangeAllocator<Tag, A, M> {
+    fn common(&self) -> &Common<Tag, A> {
+        &self.common
+    }
+
+    fn common_mut(&mut self) -> &mut Common<Tag, A> {
+        &mut self.common
+    }
+
+    fn region_count(&self) -> usize {
+        RangeAllocator::region_count(self)
+    }
+
+    fn free_extent_count(&self) -> usize {
+        RangeAllocator::free_extent_count(self)
+    }
+
+    fn regions(&self) -> impl Iterator<Item = RegionInfo<'_, Tag, A>> {
+        self.shown().0
+    }
+
+    fn region_containing(&self, addr: A) -> Option<MapEntry<Tag, A>> {
+        RangeAllocator::region_containing(self, addr)
+    }
+
+    fn attrs_at(&self, addr: A) -> Option<(A, &RegionAttrs<A>)> {
+        RangeAllocator::attrs_at(self, addr)
+    }
+
+    fn remove_range(&mut self, base: A) -> Result<()> {
+        RangeAllocator::remove_range(self, base)
+    }
+
+    /// tracking also indexes the free blocks by address
+    fn tracking_changed(&mut self) {
+        self.rebuild_free_index();
+    }
+
+    fn overlaps_free(&self, range: Range<A>) -> bool {
+        match &self.free_index {
+            Some(index) => index
+                .range(..range.end)
+                .next_back()
+                // SAFETY: the index only holds live blocks of the free list
+                .is_some_and(|(_, node)| unsafe { node.as_ref() }.range().end > range.start),
+            None => self
+                .iter()
+                .any(|node| overlaps(node.range(), range.clone())),
+        }
+    }
+
+    fn merge_at(&mut self, boundary: A)
+    where
+        Tag: PartialEq,
+    {
+        let first = self
+            .parent_iter()
+            .find(|region| region.range().end == boundary);
+        let second = self.parent_iter().find(|region| region.base == boundary);
+        let (Some(first), Some(second)) = (first, second) else {
+            return;
+        };
+        let (first, size, same_tag) = (first.base, second.size, first.tag == second.tag);
+        if !same_tag || self.attrs_at(first).is_some() || self.attrs_at(boundary).is_some() {
+            return;
+        }
+
+        let node = self
+            .parent_iter_mut()
+            .find(|region| region.base == boundary)
+            .expect("region exists");
+        remove_from_list!(self, mem_regions, node);
+        self.parent_iter_mut()
+            .find(|region| region.base == first)
+            .expect("region exists")
+            .size += size;
+        let free = self
+            .common
+            .region_free
+            .remove(&boundary)
+            .expect("region exists");
+        *self.common.region_free_mut(first) += free;
+
+        let region = first..boundary + size;
+        if let (Some(before), Some(after)) = self.adjacent_free(boundary..boundary, &region) {
+            let (start, total_size) = (before.base, before.size + after.size);
+            let epoch = before.epoch.max(after.epoch);
+            before.epoch = epoch;
+            let (before, after) = (NonNull::from(before), NonNull::from(after));
+            self.resize(before, start, total_size);
+            self.drop_free(after);
+            self.reindex(Some(boundary), None);
+        }
+    }
+
+    fn overlapping_region(&self, range: Range<A>) -> Option<RegionId<A>> {
+        self.parent_iter()
+            .chain(self.reserved_region_iter())
+            .find(|x| overlaps(x.range(), range.clone()))
+            .map(|x| RegionId(x.base))
+    }
+
+    fn raw_parts(&self) -> RawParts<Tag, A> {
+        let mut free: Vec<_> = self.iter().map(|node| (node.range(), node.epoch)).collect();
+        free.sort_by_key(|(range, _)| range.start);
+        let mut region_attrs: Vec<_> = self
+            .region_attrs
+            .iter()
+            .map(|(region, attrs)| (region.start, *attrs))
+            .collect();
+        region_attrs.sort_by_key(|&(base, _)| base);
+        self.common.raw_parts(self.export_map(), region_attrs, free)
+    }
+
+    fn is_free(&self, base: A, size: A) -> bool {
+        self.iter()
+            .any(|node| node.base <= base && base + size <= node.base + node.size)
+    }
+
+    fn carve(&mut self, base: A, size: A) -> Result<()> {
+        let splits = |node: &Node<Tag, A>| node.base < base && base + size < node.base + node.size;
+        if self.iter().any(splits) {
+            self.room_for_free_extent()?;
+        }
+        let Some(node) = self
+            .iter_mut()
+            .find(|node| node.base <= base && base + size <= node.base + node.size)
+        else {
+            return Err(Error::new(ErrorKind::NotFree));
+        };
+
+        let before = (node.base, base - node.base);
+        let after = (base + size, node.base + node.size - (base + size));
+        let (tag, epoch) = (node.tag.clone(), node.epoch);
+        let node = NonNull::from(node);
+
+        match (before.1 > A::ZERO, after.1 > A::ZERO) {
+            (false, false) => {
+                self.drop_free(node);
+                self.reindex(Some(base), None);
+            }
+            (false, true) => {
+                self.resize(node, after.0, after.1);
+                self.reindex(Some(base), Some(node));
+            }
+            (true, false) => {
+                self.resize(node, before.0, before.1);
+            }
+            (true, true) => {
+                self.resize(node, before.0, before.1);
+                let after = self.push_free(after.0, after.1, tag, epoch);
+                self.reindex(None, Some(after));
+            }
+        }
+        self.common.take_space(base, size);
+
+        Ok(())
+    }
+
+    fn place_and_take(
+        &mut self,
+        min_size: A,
+        alignment: A,
+        window: Range<A>,
+    ) -> Result<(Tag, Range<A>)> {
+        trace!(
+            "allocate: {min_size} {alignment} currently have space: {}",
+            self.space()
+        );
+        let min_size = self.common.whole_size(min_size)?;
+        let request = Request::new(min_size, alignment).normalized(self.common.granularity)?;
+        let placement = self.place(request, self.common.policy, &window)?;
+        let granularity = self.common.granularity.get();
+
+        let free_start = placement.block.start;
+        let after_free = placement.block.end;
+
+        let allocated_start = placement.base;
+        let after_allocated = placement.base + placement.size;
+
+        // remainders smaller than the granularity stay part of the allocation
+        fn chunk_between<A: Address>(start: A, end: A, granularity: A) -> Option<(A, A)> {
+            if end - start >= granularity {
                 Some((start, end))
             } else {
                 None
             }
         }
 
-        let free_chunk_before = chunk_between(free_start, allocated_start);
-        let free_chunk_after = chunk_between(after_allocated, after_free);
+        let free_chunk_before = chunk_between(free_start, allocated_start, granularity);
+        let free_chunk_after = chunk_between(after_allocated, after_free, granularity);
+        if free_chunk_before.is_some() && free_chunk_after.is_some() {
+            self.room_for_free_extent()?;
+        }
 
-        let tag = candidate.tag.clone();
-        let (addr, _size) = match (free_chunk_before, free_chunk_after) {
+        let candidate = self
+            .iter_mut()
+            .find(|node| node.base == free_start)
+            .expect("placements are made in free blocks");
+        let (tag, epoch, next) = (candidate.tag.clone(), candidate.epoch, candidate.next);
+        let candidate = NonNull::from(candidate);
+        let (addr, size, cursor) = match (free_chunk_before, free_chunk_after) {
             (None, None) => {
-                remove_from_list!(self, head, candidate);
+                self.drop_free(candidate);
+                self.reindex(Some(free_start), None);
 
-                (free_start, after_free - free_start)
+                (free_start, after_free - free_start, next)
             }
             (None, Some(after)) => {
-                candidate.base = after.0;
-                candidate.size = after.1 - after.0;
-                (free_start, after_allocated - free_start)
+                self.resize(candidate, after.0, after.1 - after.0);
+                self.reindex(Some(free_start), Some(candidate));
+                (free_start, after_allocated - free_start, Some(candidate))
             }
             (Some(before), None) => {
-                candidate.base = before.0;
-                candidate.size = before.1 - before.0;
-                (allocated_start, after_free - allocated_start)
+                self.resize(candidate, before.0, before.1 - before.0);
+                (
+                    allocated_start,
+                    after_free - allocated_start,
+                    Some(candidate),
+                )
             }
             (Some(before), Some(after)) => {
-                candidate.base = before.0;
-                candidate.size = before.1 - before.0;
+                self.resize(candidate, before.0, before.1 - before.0);
 
                 // TODO: insert before `candidate`
                 // I haven't been able to do so without breaking stacked borrows
-                insert_to_list!(
-                    self,
-                    head,
-                    after.0,
-                    after.1 - after.0,
-                    candidate.tag.clone()
-                );
-
-                (allocated_start, after_allocated - allocated_start)
+                let after = self.push_free(after.0, after.1 - after.0, tag.clone(), epoch);
+                self.reindex(None, Some(after));
+
+                // the search resumes with the remainder after the allocation
+                (
+                    allocated_start,
+                    after_allocated - allocated_start,
+                    Some(after),
+                )
             }
         };
+        self.cursor = cursor;
+        self.common.take_space(addr, size);
+        if let Some(allocations) = &mut self.common.allocations {
+            allocations.insert(addr..addr + size, tag.clone());
+        }
 
-        Ok((tag, addr))
+        // the guard is not part of what the caller gets to use
+        Ok((tag, addr..addr + size - self.common.guard))
     }
 
-    /// frees a previously handed out range
-    fn free(&mut self, base: usize, size: usize) -> Result<()> {
+    fn give_back(&mut self, base: A, size: A, strict: bool) -> Result<A> {
         let parent_region = self
             .parent_iter()
             .find(|parent| parent.range().contains(&base));
 
         let Some(parent_region) = parent_region else {
-            return Err(Error::cause("not allocated by this allocator"));
+            return Err(Error::new(ErrorKind::NotOwned));
         };
 
         fn to_non_null<T>(x: Option<&mut T>) -> Option<NonNull<T>> {
             x.map(NonNull::from)
         }
 
+        let region = parent_region.range();
+        let mut size = Size::new(size)?.round_up(self.common.granularity)?.get();
+        // `alloc` hands out remainders too small to be allocated on their own as part of the
+        // allocation, which happens at a region end that is not a multiple of the granularity
+        let end = base.saturating_add(size);
+        // only the rounding may run past the end of the region
+        if end > region.end && end - region.end >= self.common.granularity.get() {
+            return Err(Error::new(ErrorKind::NotOwned));
+        }
+        if end >= region.end
+            || (region.end - end < self.common.granularity.get() && !self.is_free_block_at(end))
+        {
+            size = region.end - base;
+        }
+        let range = base..base.saturating_add(size);
+        if self.overlaps_free(range.clone()) {
+            return Err(Error::new(ErrorKind::DoubleFree));
+        }
+        if let Some(allocations) = self.common.allocations.as_ref().filter(|_| strict) {
+            allocations.check_allocated(range)?;
+        }
         let parent_tag = parent_region.tag.clone();
+        let epoch = self.common.epoch + 1;
 
-        let mut adjacent_before = None;
-        let mut adjacent_after = None;
-        for node in self.iter_mut() {
-            if node.base + node.size == base {
-                adjacent_before = Some(node)
-            } else if base + size == node.base {
-                adjacent_after = Some(node)
-            }
-        }
+        // blocks of neighbouring regions are never merged, so no allocation can span regions
+        let (adjacent_before, adjacent_after) = self.adjacent_free(base..base + size, &region);
 
         match (adjacent_before, adjacent_after) {
             (None, None) => {
-                insert_to_list!(self, head, base, size, parent_tag)
+                self.room_for_free_extent()?;
+                let node = self.push_free(base, size, parent_tag, epoch);
+                self.reindex(None, Some(node));
             }
             (Some(before), None) => {
-                before.size += size;
+                before.epoch = epoch;
+                let (start, total_size) = (before.base, before.size + size);
+                let before = NonNull::from(before);
+                self.resize(before, start, total_size);
             }
             (None, Some(after)) => {
-                after.size += size;
-                after.base -= size;
+                after.epoch = epoch;
+                let total_size = after.size + size;
+                let after = NonNull::from(after);
+                self.resize(after, base, total_size);
+                self.reindex(Some(base + size), Some(after));
             }
             (Some(before), Some(after)) => {
-                let total_size = before.size + size + after.size;
-                before.size = total_size;
+                before.epoch = epoch;
+                let (start, total_size) = (before.base, before.size + size + after.size);
+                let (before, after) = (NonNull::from(before), NonNull::from(after));
+                self.resize(before, start, total_size);
 
-                remove_from_list!(self, head, after);
+                self.drop_free(after);
+                self.reindex(Some(base + size), None);
             }
         }
+        self.common.epoch = epoch;
+        self.common.give_space(base, size);
+        if let Some(allocations) = &mut self.common.allocations {
+            allocations.remove(base..base + size);
+        }
 
-        Ok(())
-    }
-
-    fn space(&self) -> usize {
-        self.iter().map(|x| x.size).sum()
-    }
-
-    fn total_space(&self) -> usize {
-        self.parent_iter().map(|x| x.size).sum()
+        Ok(size)
     }
 }
 
-impl<Tag> RangeAllocator<Tag> {
+#[cfg(feature = "std")]
+impl<Tag, A: Address, M: Allocator> RangeAllocator<Tag, A, M> {
     pub fn print_nodes(&self) {
         for node @ Node {
             tag,
@@ -359,9 +1528,10 @@ impl<Tag> RangeAllocator<Tag> {
             size,
             next,
             prev,
+            ..
         } in self.iter()
         {
-            eprintln!(
+            std::eprintln!(
                 "Node@{addr:?}: {base:x}:{size} prev:{prev:?} next:{next:?}",
                 addr = node as *const _
             );
@@ -375,9 +1545,10 @@ impl<Tag> RangeAllocator<Tag> {
             size,
             next,
             prev,
+            ..
         } in self.parent_iter()
         {
-            eprintln!(
+            std::eprintln!(
                 "Parent@{addr:?}: {base:x}:{size} prev:{prev:?} next:{next:?}",
                 addr = node as *const _
             );
@@ -385,7 +1556,30 @@ impl<Tag> RangeAllocator<Tag> {
     }
 }
 
-impl<Tag> Drop for RangeAllocator<Tag> {
+// SAFETY: the nodes are owned by the allocator alone and only reachable through it
+unsafe impl<Tag: Send, A: Send, M: Allocator + Send> Send for RangeAllocator<Tag, A, M> {}
+
+// SAFETY: shared references only ever read the nodes. The only mutation through `&self` is of
+// the step counter, which is atomic
+unsafe impl<Tag: Sync, A: Sync, M: Allocator + Sync> Sync for RangeAllocator<Tag, A, M> {}
+
+/// the regions and free blocks as `base..end (size)` lines
+impl<Tag: fmt::Debug, A: Address, M: Allocator> fmt::Debug for RangeAllocator<Tag, A, M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (regions, free) = self.shown();
+        map::debug_map(f, "linear::RangeAllocator", regions, free)
+    }
+}
+
+/// the memory map, a line per region
+impl<Tag: fmt::Debug, A: Address, M: Allocator> fmt::Display for RangeAllocator<Tag, A, M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (regions, free) = self.shown();
+        map::display_map(f, regions, free)
+    }
+}
+
+impl<Tag, A, M: Allocator> Drop for RangeAllocator<Tag, A, M> {
     fn drop(&mut self) {
         while let Some(mut node) = self.head {
             let node = unsafe { node.as_mut() };
@@ -396,11 +1590,10 @@ impl<Tag> Drop for RangeAllocator<Tag> {
             let node = unsafe { node.as_mut() };
             remove_from_list!(self, mem_regions, node);
         }
-    }
-}
 
-impl<Tag: Default> Default for RangeAllocator<Tag> {
-    fn default() -> Self {
-        Self::new()
+        while let Some(mut node) = self.reserved_regions {
+            let node = unsafe { node.as_mut() };
+            remove_from_list!(self, reserved_regions, node);
+        }
     }
 }
@@ -0,0 +1,132 @@
+//! a description of the address space an allocator manages, for documentation and debugging
+//!
+//! the map types are `Ord` and `Hash`, and with the `serde` feature serializable, so memory maps
+//! can be diffed, deduplicated and hashed, e.g. for attestation or golden-file tests.
+
+use alloc::vec::Vec;
+use core::{fmt, ops::Range};
+
+use crate::{RegionId, address::Address};
+
+/// what a region of the memory map is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RegionKind {
+    /// added with `add_range`, the allocator may hand it out
+    Usable,
+    /// added with `add_range_reserved`, never allocatable and not part of `total_space`
+    Reserved,
+}
+
+/// what a single address is currently used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AddrState {
+    Free,
+    Allocated,
+    /// in a reserved region, or reserved within a usable one
+    Reserved,
+}
+
+/// a region of the memory map as it was added to the allocator. Entries order by base first
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MapEntry<Tag, A = usize> {
+    pub base: A,
+    pub size: A,
+    pub tag: Tag,
+    pub kind: RegionKind,
+}
+
+/// a region as the allocator keeps it, with the tag borrowed from the allocator
+pub type RegionInfo<'a, Tag, A = usize> = MapEntry<&'a Tag, A>;
+
+impl<Tag, A: Address> MapEntry<Tag, A> {
+    pub fn id(&self) -> RegionId<A> {
+        RegionId(self.base)
+    }
+
+    pub fn end(&self) -> A {
+        self.base + self.size
+    }
+
+    pub fn contains(&self, addr: A) -> bool {
+        (self.base..self.end()).contains(&addr)
+    }
+}
+
+/// the regions sorted by base, which both backends print from
+fn sorted<Tag, A: Address>(
+    regions: impl IntoIterator<Item = MapEntry<Tag, A>>,
+) -> Vec<MapEntry<Tag, A>> {
+    let mut regions: Vec<_> = regions.into_iter().collect();
+    regions.sort_by_key(|region| region.base);
+    regions
+}
+
+/// the `Debug` output of both backends: every region and free extent as a `base..end (size)` line,
+/// sorted by base
+pub(crate) fn debug_map<Tag: fmt::Debug, A: Address>(
+    f: &mut fmt::Formatter<'_>,
+    name: &str,
+    regions: impl IntoIterator<Item = MapEntry<Tag, A>>,
+    free: impl IntoIterator<Item = Range<A>>,
+) -> fmt::Result {
+    writeln!(f, "{name} {{")?;
+    writeln!(f, "    regions:")?;
+    for region in sorted(regions) {
+        let (base, end, size) = (region.base, region.end(), region.size);
+        write!(
+            f,
+            "        {base:#x}..{end:#x} ({size:#x}) {:?}",
+            region.tag
+        )?;
+        if region.kind == RegionKind::Reserved {
+            write!(f, " reserved")?;
+        }
+        writeln!(f)?;
+    }
+    writeln!(f, "    free:")?;
+    let mut free: Vec<_> = free.into_iter().collect();
+    free.sort_by_key(|range| range.start);
+    for range in free {
+        let size = range.end - range.start;
+        writeln!(
+            f,
+            "        {:#x}..{:#x} ({size:#x})",
+            range.start, range.end
+        )?;
+    }
+    write!(f, "}}")
+}
+
+/// the `Display` output of both backends: a line per region, with how much of the usable ones is
+/// free and in how many extents
+pub(crate) fn display_map<Tag: fmt::Debug, A: Address>(
+    f: &mut fmt::Formatter<'_>,
+    regions: impl IntoIterator<Item = MapEntry<Tag, A>>,
+    free: impl IntoIterator<Item = Range<A>>,
+) -> fmt::Result {
+    let free: Vec<_> = free.into_iter().collect();
+    for (i, region) in sorted(regions).into_iter().enumerate() {
+        if i > 0 {
+            writeln!(f)?;
+        }
+        let (base, end) = (region.base, region.end());
+        match region.kind {
+            RegionKind::Reserved => write!(f, "{base:#x}..{end:#x} reserved {:?}", region.tag)?,
+            RegionKind::Usable => {
+                let inside = free.iter().filter(|range| region.contains(range.start));
+                let (space, extents) = inside.fold((A::ZERO, 0), |(space, n), range| {
+                    (space + (range.end - range.start), n + 1)
+                });
+                write!(
+                    f,
+                    "{base:#x}..{end:#x} usable {:?}: {space:#x} free in {extents} extents",
+                    region.tag
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
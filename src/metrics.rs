@@ -0,0 +1,123 @@
+//! machine-readable per-scenario allocator metrics, so performance of this crate can be tracked
+//! across versions and against downstream traces
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+
+/// how fragmented the free space is: 0 if it is a single block (or there is none), approaching 1
+/// the more the free space is split into small blocks
+pub fn fragmentation_score(free_blocks: impl IntoIterator<Item = u64>) -> f64 {
+    let (total, largest) = free_blocks
+        .into_iter()
+        .fold((0, 0), |(total, largest), size| {
+            (total + size, largest.max(size))
+        });
+    if total == 0 {
+        return 0.0;
+    }
+    1.0 - largest as f64 / total as f64
+}
+
+/// the outcome of running one workload against one backend
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScenarioMetrics {
+    pub scenario: String,
+    pub backend: String,
+    /// number of operations performed
+    pub ops: u64,
+    pub elapsed_ns: u64,
+    /// [`fragmentation_score`] after the workload ran
+    pub fragmentation: f64,
+    /// estimated bytes of bookkeeping the allocator uses after the workload ran
+    pub metadata_bytes: usize,
+    /// slowest single operation
+    pub max_latency_ns: u64,
+}
+
+impl ScenarioMetrics {
+    pub fn ops_per_sec(&self) -> f64 {
+        if self.elapsed_ns == 0 {
+            return 0.0;
+        }
+        self.ops as f64 * 1e9 / self.elapsed_ns as f64
+    }
+
+    /// writes the metrics as a single JSON object
+    pub fn write_json(&self, w: &mut impl Write) -> fmt::Result {
+        write!(w, "{{\"scenario\":")?;
+        write_json_str(w, &self.scenario)?;
+        write!(w, ",\"backend\":")?;
+        write_json_str(w, &self.backend)?;
+        write!(
+            w,
+            ",\"ops\":{},\"elapsed_ns\":{},\"ops_per_sec\":{},\"fragmentation\":{},\"metadata_bytes\":{},\"max_latency_ns\":{}}}",
+            self.ops,
+            self.elapsed_ns,
+            json_f64(self.ops_per_sec()),
+            json_f64(self.fragmentation),
+            self.metadata_bytes,
+            self.max_latency_ns,
+        )
+    }
+}
+
+/// serializes all `metrics` as a JSON array
+pub fn to_json(metrics: &[ScenarioMetrics]) -> String {
+    let mut out = String::from("[");
+    for (i, m) in metrics.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        m.write_json(&mut out)
+            .expect("writing to a string cannot fail");
+    }
+    out.push(']');
+    out
+}
+
+/// JSON has no representation for NaN and infinities
+fn json_f64(x: f64) -> f64 {
+    if x.is_finite() { x } else { 0.0 }
+}
+
+fn write_json_str(w: &mut impl Write, s: &str) -> fmt::Result {
+    w.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => w.write_str("\\\"")?,
+            '\\' => w.write_str("\\\\")?,
+            c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+            c => w.write_char(c)?,
+        }
+    }
+    w.write_char('"')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fragmentation() {
+        assert_eq!(fragmentation_score([]), 0.0);
+        assert_eq!(fragmentation_score([4096]), 0.0);
+        assert_eq!(fragmentation_score([4096, 4096]), 0.5);
+    }
+
+    #[test]
+    fn json() {
+        let m = ScenarioMetrics {
+            scenario: "a \"quoted\" name".into(),
+            backend: "linear".into(),
+            ops: 10,
+            elapsed_ns: 1_000_000_000,
+            fragmentation: 0.25,
+            metadata_bytes: 128,
+            max_latency_ns: 7,
+        };
+        assert_eq!(
+            to_json(&[m]),
+            r#"[{"scenario":"a \"quoted\" name","backend":"linear","ops":10,"elapsed_ns":1000000000,"ops_per_sec":10,"fragmentation":0.25,"metadata_bytes":128,"max_latency_ns":7}]"#
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! zero-based views onto a sub-heap
+//!
+//! [`OffsetAllocator`] translates between offsets relative to a fixed base address and the
+//! absolute addresses the wrapped allocator works with, so code suballocating a buffer can stay in
+//! buffer-relative offsets.
+
+use core::ops::Range;
+
+use crate::{Error, ErrorKind, RangeAlloc, Result, address::Address};
+
+/// exposes offsets relative to `base` while the wrapped allocator works with absolute addresses
+///
+/// alignment is applied to the absolute address, so offsets are only aligned relative to the
+/// start of the sub-heap if `base` itself is aligned at least as strictly as the largest alignment
+/// requested.
+pub struct OffsetAllocator<A, Addr = usize> {
+    inner: A,
+    base: Addr,
+}
+
+impl<Addr: Address, A: RangeAlloc<Addr>> OffsetAllocator<A, Addr> {
+    pub fn new(inner: A, base: Addr) -> Self {
+        OffsetAllocator { inner, base }
+    }
+
+    /// creates an allocator managing the sub-heap `base..base + size`, with offset 0 at `base`
+    pub fn with_range(mut inner: A, base: Addr, size: Addr, tag: A::Tag) -> Result<Self> {
+        inner.add_range(base, size, tag)?;
+        Ok(Self::new(inner, base))
+    }
+
+    pub fn base(&self) -> Addr {
+        self.base
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+
+    /// the absolute address of `offset`
+    pub fn to_absolute(&self, offset: Addr) -> Result<Addr> {
+        self.base
+            .checked_add(offset)
+            .ok_or_else(|| Error::new(ErrorKind::Overflow))
+    }
+
+    /// the offset of the absolute address `addr`
+    pub fn to_offset(&self, addr: Addr) -> Result<Addr> {
+        addr.checked_sub(self.base)
+            .ok_or_else(|| Error::new(ErrorKind::NotOwned))
+    }
+}
+
+impl<Addr: Address, A: RangeAlloc<Addr>> RangeAlloc<Addr> for OffsetAllocator<A, Addr> {
+    type Tag = A::Tag;
+
+    /// adds the range starting at offset `base`
+    fn add_range(&mut self, base: Addr, size: Addr, range_tag: Self::Tag) -> Result<()> {
+        let base = self.to_absolute(base)?;
+        self.inner.add_range(base, size, range_tag)
+    }
+
+    /// allocates a range, returning its offset
+    fn alloc(&mut self, min_size: Addr, alignment: Addr) -> Result<(Self::Tag, Addr)> {
+        let (tag, addr) = self.inner.alloc(min_size, alignment)?;
+        Ok((tag, self.to_offset(addr)?))
+    }
+
+    /// allocates a range within the offsets `window`, returning its offset
+    fn alloc_within(
+        &mut self,
+        min_size: Addr,
+        alignment: Addr,
+        window: Range<Addr>,
+    ) -> Result<(Self::Tag, Addr)> {
+        let window = self.to_absolute(window.start)?..self.base.saturating_add(window.end);
+        let (tag, addr) = self.inner.alloc_within(min_size, alignment, window)?;
+        Ok((tag, self.to_offset(addr)?))
+    }
+
+    /// allocates the range at offset `base`, returning its offset
+    fn alloc_fixed(&mut self, base: Addr, size: Addr) -> Result<(Self::Tag, Addr)> {
+        let base = self.to_absolute(base)?;
+        let (tag, addr) = self.inner.alloc_fixed(base, size)?;
+        Ok((tag, self.to_offset(addr)?))
+    }
+
+    /// frees the range at offset `base`
+    fn free(&mut self, base: Addr, size: Addr) -> Result<()> {
+        let base = self.to_absolute(base)?;
+        self.inner.free(base, size)
+    }
+
+    fn total_space(&self) -> Addr {
+        self.inner.total_space()
+    }
+
+    fn space(&self) -> Addr {
+        self.inner.space()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
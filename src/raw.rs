@@ -0,0 +1,239 @@
+//! taking an allocator apart into plain data and putting it back together
+//!
+//! [`RawParts`] is everything a backend needs to carry on where it left off. Embedders can keep
+//! it in memory of their choosing, e.g. a control page shared with another process, or hand the
+//! parts of one backend to the other to switch backends without replaying how they got there.
+//!
+//! `snapshot` hands out the same parts without taking the allocator apart, as a [`Snapshot`] to
+//! persist in a format of your choosing, and `restore` rebuilds an allocator from it.
+//!
+//! with the `serde` feature, both backends serialize as their [`RawParts`], e.g. to keep the
+//! allocator state in a VM snapshot. Deserializing validates the parts like `from_raw_parts`.
+//!
+//! `dump_dot` on the backends draws the parts as a graphviz graph, one record per region split
+//! into its free, allocated and reserved extents, to see fragmentation at a glance.
+
+use alloc::vec::Vec;
+use core::{fmt, ops::Range};
+
+use crate::{
+    Direction, Error, ErrorKind, Limits, Policy, RegionAttrs, Result,
+    address::Address,
+    collections::RangeSet,
+    map::{MapEntry, RegionKind},
+    units::Alignment,
+};
+
+/// the state of an allocator as plain data
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        deserialize = "Tag: serde::Deserialize<'de>, A: Address + serde::Deserialize<'de>"
+    ))
+)]
+pub struct RawParts<Tag, A = usize> {
+    /// all regions, usable and reserved, sorted by base
+    pub regions: Vec<MapEntry<Tag, A>>,
+    /// the attributes of the regions that have non-default ones, by region base
+    pub region_attrs: Vec<(A, RegionAttrs<A>)>,
+    /// the free extents sorted by base, each with the epoch it became free in
+    pub free: Vec<(Range<A>, u64)>,
+    /// the ranges reserved inside usable regions
+    pub reserved: Vec<Range<A>>,
+    /// the pinned ranges, all inside usable regions
+    pub pinned: Vec<Range<A>>,
+    /// the reservations that expire, sorted by base, each with its deadline
+    pub holds: Vec<(Range<A>, u64)>,
+    pub granularity: Alignment<A>,
+    pub policy: Policy,
+    pub direction: Direction,
+    pub limits: Limits,
+    /// the space kept free after every allocation, part of the allocation
+    pub guard: A,
+    pub epoch: u64,
+    pub total_space: A,
+    pub free_space: A,
+    /// the tracked allocations sorted by base, `None` if tracking is disabled
+    pub allocations: Option<Vec<(Range<A>, Tag)>>,
+}
+
+/// the state of a running allocator, see [`RawParts`]
+pub type Snapshot<Tag, A = usize> = RawParts<Tag, A>;
+
+/// fails with [`ErrorKind::Inconsistent`] unless the invariant `holds`. The location of the error
+/// is the check that failed
+#[track_caller]
+pub(crate) fn ensure(holds: bool) -> Result<()> {
+    if holds {
+        Ok(())
+    } else {
+        Err(Error::inconsistent())
+    }
+}
+
+impl<Tag, A: Address> RawParts<Tag, A> {
+    /// checks that the parts describe an allocator the backends could have ended up with. Fails
+    /// with [`ErrorKind::Inconsistent`] otherwise
+    pub(crate) fn validate(&self) -> Result<()> {
+        let mut regions = Vec::with_capacity(self.regions.len());
+        for region in &self.regions {
+            let end = region
+                .base
+                .checked_add(region.size)
+                .ok_or_else(|| Error::inconsistent())?;
+            regions.push((region.base..end, region.kind));
+        }
+        let ordered = regions
+            .windows(2)
+            .all(|pair| pair[0].0.end <= pair[1].0.start);
+        ensure(ordered)?;
+        ensure(regions.iter().all(|(range, _)| !range.is_empty()))?;
+        let usable: Vec<_> = regions
+            .into_iter()
+            .filter(|(_, kind)| *kind == RegionKind::Usable)
+            .map(|(range, _)| range)
+            .collect();
+        let region_of = |range: &Range<A>| {
+            let i = usable.partition_point(|region| region.end <= range.start);
+            usable
+                .get(i)
+                .filter(|region| region.start <= range.start && range.end <= region.end)
+        };
+
+        // free extents in the same region would have been merged if they touched
+        for pair in self.free.windows(2) {
+            let (before, after) = (&pair[0].0, &pair[1].0);
+            let same_region = region_of(before) == region_of(after);
+            ensure(before.end < after.start || (before.end == after.start && !same_region))?;
+        }
+        let free: RangeSet<A> = self.free.iter().map(|(range, _)| range.clone()).collect();
+        let reserved: RangeSet<A> = self.reserved.iter().cloned().collect();
+        let all_inside = self
+            .free
+            .iter()
+            .map(|(range, _)| range)
+            .chain(&self.reserved)
+            .chain(&self.pinned)
+            .all(|range| !range.is_empty() && region_of(range).is_some());
+        let attrs_of_usable = self
+            .region_attrs
+            .iter()
+            .all(|(base, _)| usable.iter().any(|region| region.start == *base));
+        let holds_reserved = self
+            .holds
+            .iter()
+            .all(|(range, _)| !range.is_empty() && reserved.contains_range(range.clone()));
+        ensure(all_inside)?;
+        ensure(attrs_of_usable)?;
+        ensure(holds_reserved)?;
+        ensure(free.intersect(&reserved).is_empty())?;
+
+        if let Some(allocations) = &self.allocations {
+            let ordered = allocations
+                .windows(2)
+                .all(|pair| pair[0].0.end <= pair[1].0.start);
+            let inside = allocations
+                .iter()
+                .all(|(range, _)| !range.is_empty() && region_of(range).is_some());
+            let allocated: RangeSet<A> =
+                allocations.iter().map(|(range, _)| range.clone()).collect();
+            ensure(ordered)?;
+            ensure(inside)?;
+            ensure(allocated.intersect(&free.union(&reserved)).is_empty())?;
+        }
+
+        ensure(self.granularity.is_aligned(self.guard))?;
+        ensure(
+            self.limits
+                .admit(self.regions.len(), self.free.len())
+                .is_ok(),
+        )?;
+
+        let total_space: A = usable.iter().map(|region| region.end - region.start).sum();
+        ensure(total_space == self.total_space)?;
+        ensure(free.covered() == self.free_space)?;
+        Ok(())
+    }
+}
+
+/// writes `value` with the characters that are special in record labels escaped
+fn escaped(w: &mut impl fmt::Write, value: impl fmt::Display) -> fmt::Result {
+    struct Escape<'a, W>(&'a mut W);
+    impl<W: fmt::Write> fmt::Write for Escape<'_, W> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            for c in s.chars() {
+                if matches!(c, '{' | '}' | '|' | '<' | '>' | '"' | '\\') {
+                    self.0.write_char('\\')?;
+                }
+                self.0.write_char(c)?;
+            }
+            Ok(())
+        }
+    }
+    fmt::Write::write_fmt(&mut Escape(w), format_args!("{value}"))
+}
+
+impl<Tag: fmt::Debug, A: Address> RawParts<Tag, A> {
+    /// writes the parts as a graphviz graph: a record per region with its extents from left to
+    /// right. Without tracking, everything that is neither free nor reserved shows as allocated.
+    /// With it, the tracked allocations do and the rest shows as untracked
+    pub(crate) fn write_dot(&self, mut w: impl fmt::Write) -> fmt::Result {
+        writeln!(w, "digraph {{")?;
+        writeln!(w, "node[shape=record,fontname=monospace];")?;
+        let gap = match self.allocations {
+            Some(_) => "untracked",
+            None => "allocated",
+        };
+        for (i, region) in self.regions.iter().enumerate() {
+            let range = region.base..region.end();
+            write!(w, "region{i} [label=\"{{")?;
+            escaped(
+                &mut w,
+                format_args!("{:#x}..{:#x} {:?}", range.start, range.end, region.tag),
+            )?;
+            if region.kind == RegionKind::Reserved {
+                writeln!(w, "|reserved region}}\",style=dashed];")?;
+                continue;
+            }
+
+            let inside = |r: &&Range<A>| range.start <= r.start && r.end <= range.end;
+            let mut extents: Vec<(&Range<A>, &str)> = self
+                .free
+                .iter()
+                .map(|(r, _)| (r, "free"))
+                .chain(self.reserved.iter().map(|r| (r, "reserved")))
+                .chain(
+                    self.allocations
+                        .iter()
+                        .flatten()
+                        .map(|(r, _)| (r, "allocated")),
+                )
+                .filter(|(r, _)| inside(r))
+                .collect();
+            extents.sort_by_key(|(r, _)| r.start);
+
+            // the gaps between the extents are allocations
+            let mut fields = Vec::new();
+            let mut at = range.start;
+            for (r, kind) in extents {
+                if at < r.start {
+                    fields.push((at..r.start, gap));
+                }
+                fields.push((r.clone(), kind));
+                at = r.end;
+            }
+            if at < range.end {
+                fields.push((at..range.end, gap));
+            }
+            write!(w, "|{{")?;
+            for (j, (r, kind)) in fields.into_iter().enumerate() {
+                let sep = if j == 0 { "" } else { "|" };
+                write!(w, "{sep}{kind}\\n{:#x}..{:#x}", r.start, r.end)?;
+            }
+            writeln!(w, "}}}}\"];")?;
+        }
+        writeln!(w, "}}")
+    }
+}
@@ -0,0 +1,173 @@
+//! allocations identified by handles instead of addresses
+//!
+//! [`Registry`] wraps a backend and remembers every allocation it hands out, so callers only keep
+//! a [`Handle`] around and can free without repeating the size. Handles are generational: once an
+//! allocation is freed its handle stays invalid, even after the slot is reused.
+
+use core::ops::Range;
+
+use crate::{
+    Error, ErrorKind, RangeAlloc, Result,
+    address::Address,
+    collections::{Handle, HandleMap},
+    units::{Alignment, Size},
+};
+
+/// a live allocation of a [`Registry`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Allocation<Tag, A = usize> {
+    pub tag: Tag,
+    pub base: A,
+    /// the size as handed to the backend, rounded up to the registry's granularity
+    pub size: A,
+}
+
+/// where [`Registry::move_allocation`] may put an allocation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Constraints<A = usize> {
+    pub alignment: A,
+    /// the new placement has to lie entirely in here, e.g. inside another region
+    pub window: Option<Range<A>>,
+}
+
+impl<A: Address> Constraints<A> {
+    /// anywhere, aligned to `alignment`
+    pub fn aligned(alignment: A) -> Self {
+        Constraints {
+            alignment,
+            window: None,
+        }
+    }
+
+    /// inside `window`, aligned to `alignment`
+    pub fn within(alignment: A, window: Range<A>) -> Self {
+        Constraints {
+            alignment,
+            window: Some(window),
+        }
+    }
+}
+
+/// where a moved allocation was and where it is now, so the caller can copy its contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Moved<A = usize> {
+    pub from: Range<A>,
+    pub to: Range<A>,
+}
+
+/// tracks the allocations of a backend by [`Handle`]
+pub struct Registry<R: RangeAlloc<A>, A: Address = usize> {
+    inner: R,
+    allocations: HandleMap<Allocation<R::Tag, A>>,
+    granularity: Alignment<A>,
+}
+
+impl<R: RangeAlloc<A>, A: Address> Registry<R, A> {
+    /// for backends using the default granularity of [`Alignment::BASE_PAGE`]
+    pub fn new(inner: R) -> Self {
+        Self::with_granularity(inner, Alignment::BASE_PAGE)
+    }
+
+    /// `granularity` has to match the one `inner` rounds allocations to, so they are freed with
+    /// the size that was actually allocated
+    pub fn with_granularity(inner: R, granularity: Alignment<A>) -> Self {
+        Registry {
+            inner,
+            allocations: HandleMap::new(),
+            granularity,
+        }
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    /// the backend, leaking the allocations that are still live
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    pub fn len(&self) -> usize {
+        self.allocations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allocations.is_empty()
+    }
+
+    pub fn get(&self, handle: Handle) -> Option<&Allocation<R::Tag, A>> {
+        self.allocations.get(handle)
+    }
+
+    /// the live allocations, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (Handle, &Allocation<R::Tag, A>)> + '_ {
+        self.allocations.iter()
+    }
+
+    fn register(&mut self, tag: R::Tag, base: A, size: A) -> Result<Handle> {
+        let size = Size::new(size)?.round_up(self.granularity)?.get();
+        Ok(self.allocations.insert(Allocation { tag, base, size }))
+    }
+
+    pub fn alloc(&mut self, min_size: A, alignment: A) -> Result<Handle> {
+        let (tag, base) = self.inner.alloc(min_size, alignment)?;
+        self.register(tag, base, min_size)
+    }
+
+    pub fn alloc_fixed(&mut self, base: A, size: A) -> Result<Handle> {
+        let (tag, base) = self.inner.alloc_fixed(base, size)?;
+        self.register(tag, base, size)
+    }
+
+    /// frees the allocation and returns it. Fails with [`ErrorKind::DoubleFree`] for handles that
+    /// were already freed
+    pub fn free(&mut self, handle: Handle) -> Result<Allocation<R::Tag, A>> {
+        let allocation = self
+            .allocations
+            .get(handle)
+            .ok_or_else(|| Error::new(ErrorKind::DoubleFree))?;
+        self.inner.free(allocation.base, allocation.size)?;
+        Ok(self
+            .allocations
+            .remove(handle)
+            .expect("invariant: the handle was live"))
+    }
+
+    /// moves the allocation to a new placement satisfying `constraints`, keeping its handle. The
+    /// old range is freed, so its contents have to be copied over before anything else is
+    /// allocated. If no new placement is found the allocation stays where it was
+    pub fn move_allocation(
+        &mut self,
+        handle: Handle,
+        constraints: Constraints<A>,
+    ) -> Result<Moved<A>> {
+        let old = self
+            .allocations
+            .get(handle)
+            .ok_or_else(|| Error::new(ErrorKind::NotOwned))?;
+        let (base, size) = (old.base, old.size);
+        let (tag, new_base) = match constraints.window {
+            Some(window) => self
+                .inner
+                .alloc_within(size, constraints.alignment, window)?,
+            None => self.inner.alloc(size, constraints.alignment)?,
+        };
+        if let Err(e) = self.inner.free(base, size) {
+            self.inner
+                .free(new_base, size)
+                .expect("the new placement was just allocated");
+            return Err(e);
+        }
+
+        let allocation = self
+            .allocations
+            .get_mut(handle)
+            .expect("invariant: the handle was live");
+        allocation.tag = tag;
+        allocation.base = new_base;
+        Ok(Moved {
+            from: base..base + size,
+            to: new_base..new_base + size,
+        })
+    }
+}
@@ -0,0 +1,278 @@
+//! replaying traces against any allocator
+//!
+//! [`replay`] runs a [`Trace`] against a [`RangeAlloc`] whose tags are the region ids of the
+//! trace, e.g. one captured with [`Recorder`](crate::trace::Recorder), and checks what every
+//! allocator has to get right: fixed allocations fail exactly where the trace says, allocations
+//! come from added regions, never overlap and can be freed again. With `check_layout`, `expect`
+//! lines must match the exact placement and other allocations fail exactly where the trace says.
+//! Otherwise both are up to the policy, so the trace runs against any. Further checks plug in as a
+//! [`Validate`], which sees the allocator after every operation.
+//!
+//! ```ignore
+//! let trace = Trace::parse(&captured)?;
+//! replay(&mut MyAllocator::new(), &trace, false, |a: &MyAllocator, _: &TraceOp, _: &Live| {
+//!     a.check_invariants().map_err(|e| e.to_string())
+//! })?;
+//! ```
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+};
+use core::{fmt, ops::Range};
+
+use crate::{
+    Error, RangeAlloc,
+    address::Address,
+    trace::{ParseError, Trace, TraceOp},
+};
+
+/// the live allocations of a replay, by allocation id
+pub type Live<A = usize> = BTreeMap<u64, Range<A>>;
+
+/// a check run after every operation of a replay, with the allocator, the operation and the
+/// allocations live after it. `()` checks nothing
+pub trait Validate<R: ?Sized, A = usize> {
+    fn validate(&mut self, a: &R, op: &TraceOp, live: &Live<A>) -> Result<(), String>;
+}
+
+impl<R: ?Sized, A> Validate<R, A> for () {
+    fn validate(&mut self, _: &R, _: &TraceOp, _: &Live<A>) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl<R: ?Sized, A, F> Validate<R, A> for F
+where
+    F: FnMut(&R, &TraceOp, &Live<A>) -> Result<(), String>,
+{
+    fn validate(&mut self, a: &R, op: &TraceOp, live: &Live<A>) -> Result<(), String> {
+        self(a, op, live)
+    }
+}
+
+/// what went wrong while replaying an operation
+#[derive(Debug)]
+pub enum Failure {
+    /// a region id was added twice
+    DuplicateRegion,
+    /// a number does not fit the allocator's address type
+    AddressOutOfRange,
+    /// the allocator rejected a region
+    AddFailed(Error),
+    UnexpectedError(Error),
+    /// an operation marked `fail` succeeded
+    UnexpectedSuccess,
+    /// the allocation is tagged with a region that was never added
+    UnknownTag(u64),
+    /// a fixed allocation was placed at `base` instead of where it was asked for
+    Moved {
+        base: u64,
+    },
+    /// the allocation overlaps the live allocation `other`
+    Overlap {
+        other: u64,
+    },
+    FreeFailed(Error),
+    /// an `expect` named an allocation that is not live
+    NotLive,
+    /// an `expect` did not match: the allocation was placed at `base`
+    Misplaced {
+        base: u64,
+    },
+    /// a [`Validate`] failed with this message
+    Invalid(String),
+}
+
+/// why a replay stopped
+#[derive(Debug)]
+pub enum ReplayError {
+    /// the text of the trace could not be parsed
+    Parse(ParseError),
+    /// the operation at (0-based) `index` failed
+    Failed {
+        index: usize,
+        op: TraceOp,
+        failure: Failure,
+    },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Parse(e) => write!(f, "invalid trace: {e}"),
+            ReplayError::Failed { index, op, failure } => {
+                write!(f, "operation {index} `{op}`: ")?;
+                match failure {
+                    Failure::DuplicateRegion => write!(f, "duplicate region id"),
+                    Failure::AddressOutOfRange => write!(f, "address out of range"),
+                    Failure::AddFailed(e) => write!(f, "cannot add region: {e}"),
+                    Failure::UnexpectedError(e) => write!(f, "unexpected error: {e}"),
+                    Failure::UnexpectedSuccess => write!(f, "did not expect to succeed"),
+                    Failure::UnknownTag(tag) => write!(f, "tag {tag} was not added"),
+                    Failure::Moved { base } => write!(f, "fixed allocation moved to {base:#x}"),
+                    Failure::Overlap { other } => write!(f, "overlaps allocation {other}"),
+                    Failure::FreeFailed(e) => write!(f, "cannot free: {e}"),
+                    Failure::NotLive => write!(f, "allocation is not live"),
+                    Failure::Misplaced { base } => write!(f, "placed at {base:#x}"),
+                    Failure::Invalid(reason) => write!(f, "{reason}"),
+                }
+            }
+        }
+    }
+}
+
+impl core::error::Error for ReplayError {}
+
+impl From<ParseError> for ReplayError {
+    fn from(e: ParseError) -> Self {
+        ReplayError::Parse(e)
+    }
+}
+
+/// the state of a replay besides the allocator
+struct Replay<A> {
+    regions: BTreeSet<u64>,
+    live: Live<A>,
+    /// the live allocations by base, as end and id
+    by_base: BTreeMap<A, (A, u64)>,
+}
+
+fn addr<A: Address>(n: u64) -> Result<A, Failure> {
+    A::try_from(n).map_err(|_| Failure::AddressOutOfRange)
+}
+
+impl<A: Address> Replay<A> {
+    /// records an allocation the allocator made, checking it against the live ones
+    fn allocated(&mut self, id: u64, tag: u64, range: Range<A>) -> Result<(), Failure> {
+        if !self.regions.contains(&tag) {
+            return Err(Failure::UnknownTag(tag));
+        }
+        // a previous allocation with the same id is gone from the trace's point of view
+        if let Some(old) = self.live.remove(&id) {
+            self.by_base.remove(&old.start);
+        }
+        let before = self.by_base.range(..range.end).next_back();
+        if let Some((_, &(_, other))) = before.filter(|(_, (end, _))| *end > range.start) {
+            return Err(Failure::Overlap { other });
+        }
+        self.live.insert(id, range.clone());
+        self.by_base.insert(range.start, (range.end, id));
+        Ok(())
+    }
+
+    fn step<R>(&mut self, a: &mut R, op: TraceOp, check_layout: bool) -> Result<(), Failure>
+    where
+        R: RangeAlloc<A, Tag = u64> + ?Sized,
+    {
+        match op {
+            TraceOp::Add { region, base, size } => {
+                if !self.regions.insert(region) {
+                    return Err(Failure::DuplicateRegion);
+                }
+                a.add_range(addr(base)?, addr(size)?, region)
+                    .map_err(Failure::AddFailed)
+            }
+            TraceOp::Alloc {
+                id,
+                size,
+                alignment,
+                fail,
+            } => {
+                let size = addr(size)?;
+                // whether an allocation fits depends on where the earlier ones went, so only the
+                // layout the trace was made with has to fail exactly where it says
+                match a.alloc(size, addr(alignment)?) {
+                    Err(_) if fail || !check_layout => Ok(()),
+                    Err(e) => Err(Failure::UnexpectedError(e)),
+                    Ok(_) if fail && check_layout => Err(Failure::UnexpectedSuccess),
+                    Ok((tag, base)) => self.allocated(id, tag, base..base + size),
+                }
+            }
+            TraceOp::AllocFixed {
+                id,
+                base,
+                size,
+                fail,
+            } => {
+                let (base, size) = (addr(base)?, addr(size)?);
+                match a.alloc_fixed(base, size) {
+                    Err(_) if fail => Ok(()),
+                    Err(e) => Err(Failure::UnexpectedError(e)),
+                    Ok(_) if fail => Err(Failure::UnexpectedSuccess),
+                    Ok((_, x)) if x != base => Err(Failure::Moved { base: x.to_u64() }),
+                    Ok((tag, _)) => self.allocated(id, tag, base..base + size),
+                }
+            }
+            TraceOp::Free { id } => {
+                // allocations that failed as expected are freed too, which leaves nothing to do
+                let Some(range) = self.live.remove(&id) else {
+                    return Ok(());
+                };
+                self.by_base.remove(&range.start);
+                a.free(range.start, range.end - range.start)
+                    .map_err(Failure::FreeFailed)
+            }
+            TraceOp::Expect { id, base } => {
+                if !check_layout {
+                    return Ok(());
+                }
+                match self.live.get(&id) {
+                    None => Err(Failure::NotLive),
+                    Some(range) if range.start.to_u64() != base => Err(Failure::Misplaced {
+                        base: range.start.to_u64(),
+                    }),
+                    Some(_) => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// replays `trace` against `a`, whose tags are the region ids of the trace. If `check_layout` is
+/// set, `expect` lines must match the exact placement and allocations must succeed or fail like
+/// in the trace. `validate` runs after every operation.
+/// Returns the allocations still live at the end
+pub fn replay<A, R, V>(
+    a: &mut R,
+    trace: &Trace,
+    check_layout: bool,
+    mut validate: V,
+) -> Result<Live<A>, ReplayError>
+where
+    A: Address,
+    R: RangeAlloc<A, Tag = u64> + ?Sized,
+    V: Validate<R, A>,
+{
+    let mut replay = Replay {
+        regions: BTreeSet::new(),
+        live: Live::new(),
+        by_base: BTreeMap::new(),
+    };
+    for (index, &op) in trace.ops.iter().enumerate() {
+        replay
+            .step(a, op, check_layout)
+            .and_then(|()| {
+                validate
+                    .validate(a, &op, &replay.live)
+                    .map_err(Failure::Invalid)
+            })
+            .map_err(|failure| ReplayError::Failed { index, op, failure })?;
+    }
+    Ok(replay.live)
+}
+
+/// parses `trace` and [`replay`]s it
+pub fn replay_str<A, R, V>(
+    a: &mut R,
+    trace: &str,
+    check_layout: bool,
+    validate: V,
+) -> Result<Live<A>, ReplayError>
+where
+    A: Address,
+    R: RangeAlloc<A, Tag = u64> + ?Sized,
+    V: Validate<R, A>,
+{
+    replay(a, &Trace::parse(trace)?, check_layout, validate)
+}
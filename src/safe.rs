@@ -0,0 +1,83 @@
+//! a backend without any unsafe code
+//!
+//! [`RangeAllocator`] keeps the free extents in a sorted `Vec` and leaves everything else to
+//! [`Extents`]. Neither module contains `unsafe`, which the compiler enforces. Every operation
+//! takes time linear in the number of free extents, so this is the backend for when auditability
+//! matters more than speed, and the model the other backends are tested against.
+
+#![forbid(unsafe_code)]
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::{
+    address::Address,
+    extents::{ExtentStore, Extents},
+};
+
+/// a first-fit backend over [`FreeList`], placing allocations at the lowest address that fits
+pub type RangeAllocator<Tag, A = usize> = Extents<FreeList<A>, Tag, A>;
+
+/// free extents sorted by base
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FreeList<A = usize> {
+    extents: Vec<Range<A>>,
+}
+
+impl<A> Default for FreeList<A> {
+    fn default() -> Self {
+        FreeList {
+            extents: Vec::new(),
+        }
+    }
+}
+
+impl<A: Address> FreeList<A> {
+    pub fn extents(&self) -> &[Range<A>] {
+        &self.extents
+    }
+}
+
+impl<A: Address> ExtentStore<A> for FreeList<A> {
+    fn free_extents(&self) -> impl Iterator<Item = Range<A>> + '_ {
+        self.extents.iter().cloned()
+    }
+
+    fn split(&mut self, range: Range<A>) {
+        let i = self
+            .extents
+            .partition_point(|extent| extent.end <= range.start);
+        let extent = self.extents.remove(i);
+        assert!(
+            extent.start <= range.start && range.end <= extent.end,
+            "splits lie within a free extent"
+        );
+        if range.end < extent.end {
+            self.extents.insert(i, range.end..extent.end);
+        }
+        if extent.start < range.start {
+            self.extents.insert(i, extent.start..range.start);
+        }
+    }
+
+    fn insert(&mut self, mut range: Range<A>, region: Range<A>) {
+        let mut i = self
+            .extents
+            .partition_point(|extent| extent.start < range.start);
+        if self
+            .extents
+            .get(i)
+            .is_some_and(|next| next.start == range.end && next.end <= region.end)
+        {
+            range.end = self.extents.remove(i).end;
+        }
+        if i > 0
+            && self.extents[i - 1].end == range.start
+            && self.extents[i - 1].start >= region.start
+        {
+            i -= 1;
+            range.start = self.extents.remove(i).start;
+        }
+        self.extents.insert(i, range);
+    }
+}
@@ -0,0 +1,649 @@
+//! allocating on many cores without one lock for all of them
+//!
+//! [`ShardedRangeAllocator`] splits the managed ranges between a number of shards, usually one
+//! per CPU, each a backend behind its own [`SharedRangeAllocator`]. Allocations are served by the
+//! caller's shard, so cores only contend when they share a shard or free each other's ranges.
+//!
+//! A shard that runs dry steals: it borrows a chunk of at least the steal size from another shard
+//! and allocates from that, so the next allocations are local again. If no shard can lend a whole
+//! chunk, the request is served by another shard directly. Borrowed chunks stay with the borrower
+//! until [`rebalance`](ShardedRangeAllocator::rebalance) finds them unused and returns them.
+//!
+//! A small directory maps every address to the shard managing it, so frees find their shard.
+//! It has its own lock, which is only taken for frees, fixed allocations and when memory moves
+//! between shards. All locks are spinlocks unless another [`RawLock`] is chosen.
+
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::{fmt, ops::Range};
+
+use crate::{
+    Error, ErrorKind, RangeAlloc, Result,
+    address::Address,
+    btree, linear,
+    shared::{RawLock, SharedRangeAllocator, SpinLock},
+    units::{Alignment, Size},
+};
+
+/// a backend a [`ShardedRangeAllocator`] can take borrowed chunks back from
+pub trait Shard<A: Address = usize>: RangeAlloc<A> {
+    /// takes the region starting at `base` away. Fails while any part of it is allocated
+    fn remove_range(&mut self, base: A) -> Result<()>;
+}
+
+impl<Tag: Clone, A: Address> Shard<A> for linear::RangeAllocator<Tag, A> {
+    fn remove_range(&mut self, base: A) -> Result<()> {
+        linear::RangeAllocator::remove_range(self, base)
+    }
+}
+
+impl<Tag: Default + Clone + fmt::Debug, A: Address> Shard<A> for btree::RangeAllocator<Tag, A> {
+    fn remove_range(&mut self, base: A) -> Result<()> {
+        btree::RangeAllocator::remove_range(self, base)
+    }
+}
+
+/// a chunk one shard borrowed from another. It stays allocated in the lender, and is a region of
+/// the borrower
+#[derive(Clone, PartialEq, Eq)]
+struct Loan<A> {
+    range: Range<A>,
+    lender: usize,
+    borrower: usize,
+}
+
+/// which shard manages which addresses
+struct Directory<A> {
+    /// base -> end and shard, without overlaps. A borrowed chunk is split off the lender's entry
+    owners: BTreeMap<A, (A, usize)>,
+    /// oldest first
+    loans: Vec<Loan<A>>,
+}
+
+impl<A: Address> Directory<A> {
+    /// the shard managing all of `range`
+    fn owner(&self, range: &Range<A>) -> Option<usize> {
+        self.owners
+            .range(..=range.start)
+            .next_back()
+            .filter(|&(_, &(end, _))| range.end <= end)
+            .map(|(_, &(_, shard))| shard)
+    }
+
+    fn overlaps(&self, range: &Range<A>) -> bool {
+        self.owners
+            .range(..range.end)
+            .next_back()
+            .is_some_and(|(_, &(end, _))| end > range.start)
+    }
+
+    /// hands `range`, which lies within a single entry, to `shard`
+    fn assign(&mut self, range: Range<A>, shard: usize) {
+        let (&base, &(end, owner)) = self
+            .owners
+            .range(..=range.start)
+            .next_back()
+            .expect("invariant: moved ranges are managed by a shard");
+        self.owners.remove(&base);
+        if base < range.start {
+            self.owners.insert(base, (range.start, owner));
+        }
+        if range.end < end {
+            self.owners.insert(range.end, (end, owner));
+        }
+
+        // merged with its neighbours of the same shard, so returned chunks leave no trace
+        let (mut start, mut end) = (range.start, range.end);
+        if let Some((&before, &(before_end, before_shard))) = self.owners.range(..start).next_back()
+            && before_end == start
+            && before_shard == shard
+        {
+            self.owners.remove(&before);
+            start = before;
+        }
+        if let Some(&(after_end, after_shard)) = self.owners.get(&end)
+            && after_shard == shard
+        {
+            self.owners.remove(&end);
+            end = after_end;
+        }
+        self.owners.insert(start, (end, shard));
+    }
+}
+
+/// whether `error` means the shard has no room, so another shard may help
+fn is_dry(error: &Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::OutOfSpace | ErrorKind::RequestExceedsAnyRegion { .. }
+    )
+}
+
+/// a backend split into shards that allocate independently, see the [module](self) docs
+pub struct ShardedRangeAllocator<R, A = usize, L = SpinLock> {
+    shards: Box<[SharedRangeAllocator<R, L>]>,
+    directory: SharedRangeAllocator<Directory<A>, L>,
+    steal_size: A,
+}
+
+impl<A: Address, R: Shard<A>> ShardedRangeAllocator<R, A> {
+    /// one shard per backend. A shard that runs dry borrows at least `steal_size` from another,
+    /// rounded up to whole pages. Fails with [`ErrorKind::InvalidSize`] without any shard or
+    /// with a steal size of 0
+    pub fn new(shards: impl IntoIterator<Item = R>, steal_size: A) -> Result<Self> {
+        Self::with_locks(shards, steal_size)
+    }
+}
+
+impl<A: Address, R: Shard<A>, L: RawLock> ShardedRangeAllocator<R, A, L> {
+    /// like [`new`](ShardedRangeAllocator::new), with locks of type `L` instead of spinlocks
+    pub fn with_locks(shards: impl IntoIterator<Item = R>, steal_size: A) -> Result<Self> {
+        let shards: Box<[_]> = shards
+            .into_iter()
+            .map(|shard| SharedRangeAllocator::with_lock(L::new(), shard))
+            .collect();
+        if shards.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidSize));
+        }
+        let steal_size = Size::new(steal_size)?.round_up(Alignment::BASE_PAGE)?.get();
+        Ok(ShardedRangeAllocator {
+            shards,
+            directory: SharedRangeAllocator::with_lock(
+                L::new(),
+                Directory {
+                    owners: BTreeMap::new(),
+                    loans: Vec::new(),
+                },
+            ),
+            steal_size,
+        })
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// the shard serving `cpu`
+    pub fn shard_of(&self, cpu: usize) -> usize {
+        cpu % self.shards.len()
+    }
+
+    /// runs `f` with the backend of `shard` locked
+    pub fn with_shard<T>(&self, shard: usize, f: impl FnOnce(&mut R) -> T) -> T {
+        self.shards[shard].with(f)
+    }
+
+    /// splits the region into one part per shard, in whole pages. Shards get nothing if there are
+    /// fewer pages than shards
+    pub fn add_range(&self, base: A, size: A, range_tag: R::Tag) -> Result<()>
+    where
+        R::Tag: Clone,
+    {
+        let end = base
+            .checked_add(Size::new(size)?.get())
+            .ok_or(Error::new(ErrorKind::Overflow))?;
+        if self.directory.with(|d| d.overlaps(&(base..end))) {
+            return Err(Error::new(ErrorKind::OverlappingRange));
+        }
+        let part = A::try_from(size.to_u64() / self.shards.len() as u64)
+            .ok()
+            .expect("invariant: a part is smaller than the region")
+            .round_down(A::BASE_PAGE);
+        let mut start = base;
+        for shard in 0..self.shards.len() {
+            let part_end = if shard + 1 == self.shards.len() {
+                end
+            } else {
+                start + part
+            };
+            if start < part_end {
+                self.add_range_to(shard, start, part_end - start, range_tag.clone())?;
+            }
+            start = part_end;
+        }
+        Ok(())
+    }
+
+    /// adds the region to `shard` alone
+    pub fn add_range_to(&self, shard: usize, base: A, size: A, range_tag: R::Tag) -> Result<()> {
+        let end = base
+            .checked_add(Size::new(size)?.get())
+            .ok_or(Error::new(ErrorKind::Overflow))?;
+        // the backend only knows its own regions, the directory knows those of all shards
+        self.shards[shard].with(|s| {
+            self.directory.with(|d| {
+                if d.overlaps(&(base..end)) {
+                    return Err(Error::new(ErrorKind::OverlappingRange));
+                }
+                s.add_range(base, size, range_tag)?;
+                d.owners.insert(base, (end, shard));
+                Ok(())
+            })
+        })
+    }
+
+    /// allocates from the shard of `cpu`, stealing from the others if it is dry
+    pub fn alloc(&self, cpu: usize, min_size: A, alignment: A) -> Result<(R::Tag, A)> {
+        self.alloc_with(cpu, min_size, alignment, |s, size| s.alloc(size, alignment))
+    }
+
+    /// like [`alloc`](Self::alloc), within `window`
+    pub fn alloc_within(
+        &self,
+        cpu: usize,
+        min_size: A,
+        alignment: A,
+        window: Range<A>,
+    ) -> Result<(R::Tag, A)> {
+        self.alloc_with(cpu, min_size, alignment, |s, size| {
+            s.alloc_within(size, alignment, window.clone())
+        })
+    }
+
+    /// allocates the range from whichever shard manages it
+    pub fn alloc_fixed(&self, base: A, size: A) -> Result<(R::Tag, A)> {
+        let shard = self
+            .owner(base, size)?
+            .ok_or(Error::new(ErrorKind::NotFree))?;
+        self.shards[shard].with(|s| s.alloc_fixed(base, size))
+    }
+
+    /// returns the range to the shard managing it, from any CPU
+    pub fn free(&self, base: A, size: A) -> Result<()> {
+        let shard = self
+            .owner(base, size)?
+            .ok_or(Error::new(ErrorKind::NotOwned))?;
+        self.shards[shard].with(|s| s.free(base, size))
+    }
+
+    /// returns every borrowed chunk that is entirely free to its lender, and how many there were.
+    /// Call it now and then, e.g. from the idle loop, so memory flows back to where it came from
+    pub fn rebalance(&self) -> usize {
+        let loans = self.directory.with(|d| d.loans.clone());
+        let mut returned = 0;
+        // newest first, chunks lent on from a borrowed chunk have to go back before it
+        for loan in loans.into_iter().rev() {
+            let removed = self.shards[loan.borrower].with(|s| {
+                s.remove_range(loan.range.start).is_ok() && {
+                    self.directory.with(|d| {
+                        d.assign(loan.range.clone(), loan.lender);
+                        d.loans.retain(|l| *l != loan);
+                    });
+                    true
+                }
+            });
+            if removed {
+                self.shards[loan.lender]
+                    .with(|s| s.free(loan.range.start, loan.range.end - loan.range.start))
+                    .expect("invariant: loans stay allocated in the lender");
+                returned += 1;
+            }
+        }
+        returned
+    }
+
+    /// bytes currently borrowed by one shard from another
+    pub fn lent(&self) -> A {
+        self.directory.with(|d| {
+            d.loans
+                .iter()
+                .map(|loan| loan.range.end - loan.range.start)
+                .sum()
+        })
+    }
+
+    /// borrowed chunks count for the borrower only
+    pub fn total_space(&self) -> A {
+        let total: A = self
+            .shards
+            .iter()
+            .map(|s| s.with(|s| s.total_space()))
+            .sum();
+        total - self.lent()
+    }
+
+    pub fn space(&self) -> A {
+        self.shards.iter().map(|s| s.with(|s| s.space())).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.space() == self.total_space()
+    }
+
+    /// the allocator as seen from `cpu`, as a [`RangeAlloc`] for code that is generic over
+    /// backends
+    pub fn local(&self, cpu: usize) -> Local<'_, R, A, L> {
+        Local {
+            sharded: self,
+            shard: self.shard_of(cpu),
+        }
+    }
+
+    fn owner(&self, base: A, size: A) -> Result<Option<usize>> {
+        let end = base
+            .checked_add(Size::new(size)?.get())
+            .ok_or(Error::new(ErrorKind::Overflow))?;
+        Ok(self.directory.with(|d| d.owner(&(base..end))))
+    }
+
+    /// allocates with `place` from the shard of `cpu`, then from a chunk borrowed from another
+    /// shard, then from another shard directly
+    fn alloc_with(
+        &self,
+        cpu: usize,
+        min_size: A,
+        alignment: A,
+        place: impl Fn(&mut R, A) -> Result<(R::Tag, A)>,
+    ) -> Result<(R::Tag, A)> {
+        let home = self.shard_of(cpu);
+        match self.shards[home].with(|s| place(s, min_size)) {
+            Err(error) if is_dry(&error) => {}
+            res => return res,
+        }
+
+        let size = Size::new(min_size)?.round_up(Alignment::BASE_PAGE)?.get();
+        let chunk = size.max(self.steal_size);
+        let mut error = Error::new(ErrorKind::OutOfSpace);
+        for victim in (1..self.shards.len()).map(|i| (home + i) % self.shards.len()) {
+            if chunk > size
+                && let Some(res) = self.borrow(home, victim, chunk, &place, min_size)
+            {
+                return res;
+            }
+            match self.shards[victim].with(|s| place(s, min_size)) {
+                Err(e) if is_dry(&e) => error = e,
+                res => return res,
+            }
+        }
+        Err(error)
+    }
+
+    /// moves a chunk of `chunk` bytes from `victim` to `home` and allocates from it there, or
+    /// `None` if `victim` has no such chunk
+    fn borrow(
+        &self,
+        home: usize,
+        victim: usize,
+        chunk: A,
+        place: &impl Fn(&mut R, A) -> Result<(R::Tag, A)>,
+        min_size: A,
+    ) -> Option<Result<(R::Tag, A)>> {
+        let (tag, base) = self.shards[victim].with(|s| place(s, chunk)).ok()?;
+        let range = base..base + chunk;
+        // the directory is updated before the lock is dropped, so nothing allocated from the chunk
+        // can be freed to the lender
+        let res = self.shards[home].with(|s| {
+            s.add_range(base, chunk, tag).ok()?;
+            self.directory.with(|d| {
+                d.assign(range.clone(), home);
+                d.loans.push(Loan {
+                    range: range.clone(),
+                    lender: victim,
+                    borrower: home,
+                });
+            });
+            Some(place(s, min_size))
+        });
+        if res.is_none() {
+            // e.g. the chunk lies in a region `home` lent to `victim` before
+            self.shards[victim]
+                .with(|s| s.free(base, chunk))
+                .expect("invariant: was just allocated");
+        }
+        res
+    }
+}
+
+impl<R, A, L> fmt::Debug for ShardedRangeAllocator<R, A, L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardedRangeAllocator")
+            .field("shards", &self.shards.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// a [`ShardedRangeAllocator`] used from one CPU, see [`local`](ShardedRangeAllocator::local).
+/// Regions added through it go to that CPU's shard
+pub struct Local<'a, R, A = usize, L = SpinLock> {
+    sharded: &'a ShardedRangeAllocator<R, A, L>,
+    shard: usize,
+}
+
+impl<A: Address, R: Shard<A>, L: RawLock> RangeAlloc<A> for Local<'_, R, A, L> {
+    type Tag = R::Tag;
+
+    fn add_range(&mut self, base: A, size: A, range_tag: Self::Tag) -> Result<()> {
+        self.sharded.add_range_to(self.shard, base, size, range_tag)
+    }
+
+    fn alloc(&mut self, min_size: A, alignment: A) -> Result<(Self::Tag, A)> {
+        self.sharded.alloc(self.shard, min_size, alignment)
+    }
+
+    fn alloc_within(
+        &mut self,
+        min_size: A,
+        alignment: A,
+        window: Range<A>,
+    ) -> Result<(Self::Tag, A)> {
+        self.sharded
+            .alloc_within(self.shard, min_size, alignment, window)
+    }
+
+    fn alloc_fixed(&mut self, base: A, size: A) -> Result<(Self::Tag, A)> {
+        self.sharded.alloc_fixed(base, size)
+    }
+
+    fn free(&mut self, base: A, size: A) -> Result<()> {
+        self.sharded.free(base, size)
+    }
+
+    fn total_space(&self) -> A {
+        self.sharded.total_space()
+    }
+
+    fn space(&self) -> A {
+        self.sharded.space()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use alloc::vec::Vec;
+    use std::thread;
+
+    use super::ShardedRangeAllocator;
+    use crate::{ErrorKind, RangeAlloc, RangeAllocator, btree, testkit};
+
+    fn kind<T>(res: crate::Result<T>) -> ErrorKind {
+        res.map(|_| ()).unwrap_err().kind()
+    }
+
+    #[test]
+    fn steals_and_rebalances() {
+        let a = ShardedRangeAllocator::new(
+            [RangeAllocator::<()>::new(), RangeAllocator::new()],
+            0x2000,
+        )
+        .expect("valid configuration");
+        a.add_range(0x10_0000, 0x10_000, ()).expect("can add range");
+        assert_eq!(
+            kind(a.add_range_to(1, 0x10_f000, 0x2000, ())),
+            ErrorKind::OverlappingRange
+        );
+        for i in 0..8 {
+            assert_eq!(
+                a.alloc(0, 0x1000, 0x1000).expect("has space").1,
+                0x10_0000 + i * 0x1000
+            );
+        }
+
+        // shard 0 is dry and borrows two pages from shard 1
+        let (_, x) = a.alloc(0, 0x1000, 0x1000).expect("can steal");
+        assert_eq!(x, 0x10_8000);
+        assert_eq!(a.lent(), 0x2000);
+        assert_eq!(a.with_shard(0, |s| s.space()), 0x1000);
+        assert_eq!(a.total_space(), 0x10_000);
+        assert_eq!(a.space(), 0x7000);
+        // freed from another CPU, the page still goes back to the borrower
+        a.free(x, 0x1000).expect("can free");
+        assert_eq!(a.with_shard(0, |s| s.space()), 0x2000);
+
+        // shard 1 can not take the chunk back as a loan, so it allocates in shard 0 directly
+        for _ in 0..6 {
+            a.alloc(1, 0x1000, 0x1000).expect("has space");
+        }
+        let (_, y) = a.alloc(1, 0x1000, 0x1000).expect("can steal");
+        assert_eq!(y, 0x10_8000);
+        assert_eq!(a.with_shard(0, |s| s.space()), 0x1000);
+        assert_eq!(a.rebalance(), 0);
+
+        a.free(y, 0x1000).expect("can free");
+        assert_eq!(a.rebalance(), 1);
+        assert_eq!(a.lent(), 0);
+        assert_eq!(a.with_shard(1, |s| s.space()), 0x2000);
+        assert_eq!(kind(a.free(0x20_0000, 0x1000)), ErrorKind::NotOwned);
+        assert_eq!(kind(a.alloc(0, 0x3000, 0x1000)), ErrorKind::OutOfSpace);
+        assert_eq!(
+            kind(ShardedRangeAllocator::new(
+                Vec::<RangeAllocator<()>>::new(),
+                0x1000
+            )),
+            ErrorKind::InvalidSize
+        );
+    }
+
+    #[test]
+    fn concurrent_stealing() {
+        let a =
+            ShardedRangeAllocator::new((0..4).map(|_| btree::RangeAllocator::<()>::new()), 0x4000)
+                .expect("valid configuration");
+        testkit::setup(&mut a.local(0));
+        testkit::alloc_different_configurations(&mut a.local(3));
+
+        // all the memory starts out in shard 0, so the other threads have to steal
+        let allocated: Vec<usize> = thread::scope(|s| {
+            let threads: Vec<_> = (0..4)
+                .map(|cpu| {
+                    let a = &a;
+                    s.spawn(move || {
+                        (0..1000)
+                            .map(|_| a.alloc(cpu, 0x1000, 0x1000).expect("has space").1)
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .flat_map(|t| t.join().expect("thread does not panic"))
+                .collect()
+        });
+        let mut unique = allocated.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), 4000);
+        assert!(a.lent() > 0);
+        assert_eq!(a.space(), a.total_space() - 4000 * 0x1000);
+
+        for (i, x) in allocated.into_iter().enumerate() {
+            a.free(x, 0x1000).expect("can free");
+            if i % 500 == 0 {
+                a.rebalance();
+            }
+        }
+        while a.rebalance() > 0 {}
+        assert_eq!(a.lent(), 0);
+        assert!(a.is_empty());
+        assert_eq!(a.total_space(), 4096 * 4096 + 4096 * 128);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::{sync::Arc, thread};
+
+    use super::ShardedRangeAllocator;
+    use crate::{RangeAlloc, RangeAllocator};
+
+    /// runs `f` on a loom thread with a bigger stack than loom's default of 64 KiB, which the
+    /// backends outgrow in a debug build
+    fn spawn<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> thread::JoinHandle<T> {
+        thread::Builder::new()
+            .stack_size(1 << 20)
+            .spawn(f)
+            .expect("can spawn")
+    }
+
+    fn check(f: impl Fn() + Sync + Send + 'static) {
+        let mut model = loom::model::Builder::new();
+        model.preemption_bound = Some(3);
+        // a loom `Arc` can not be created outside the model
+        let f = alloc::sync::Arc::new(f);
+        model.check(move || {
+            let f = f.clone();
+            spawn(move || f()).join().unwrap();
+        });
+    }
+
+    #[test]
+    fn stealing_races_with_local_allocations() {
+        check(|| {
+            let a = Arc::new(
+                ShardedRangeAllocator::new(
+                    [RangeAllocator::<()>::new(), RangeAllocator::new()],
+                    0x2000,
+                )
+                .expect("valid configuration"),
+            );
+            a.add_range_to(0, 0x1000, 0x1000, ())
+                .expect("can add range");
+            a.add_range_to(1, 0x2000, 0x3000, ())
+                .expect("can add range");
+
+            let other = a.clone();
+            let t = spawn(move || other.alloc(1, 0x1000, 0x1000).expect("has space").1);
+            let first = a.alloc(0, 0x1000, 0x1000).expect("has space").1;
+            // shard 0 is dry and steals, while shard 1 may be handing out its pages
+            let second = a.alloc(0, 0x1000, 0x1000).expect("has space").1;
+            let theirs = t.join().unwrap();
+
+            assert!(first != second && second != theirs && theirs != first);
+            assert_eq!(a.space(), 0x1000);
+            for x in [first, second, theirs] {
+                a.free(x, 0x1000).expect("can free");
+            }
+            a.rebalance();
+            assert_eq!(a.lent(), 0);
+            assert!(a.is_empty());
+        });
+    }
+
+    #[test]
+    fn concurrent_frees_and_rebalance() {
+        check(|| {
+            let a = Arc::new(
+                ShardedRangeAllocator::new(
+                    [RangeAllocator::<()>::new(), RangeAllocator::new()],
+                    0x2000,
+                )
+                .expect("valid configuration"),
+            );
+            a.add_range_to(1, 0x1000, 0x3000, ())
+                .expect("can add range");
+            let (_, x) = a.alloc(0, 0x1000, 0x1000).expect("can steal");
+            assert_eq!(a.lent(), 0x2000);
+
+            let other = a.clone();
+            let t = spawn(move || {
+                other.free(x, 0x1000).expect("can free");
+            });
+            // returns the chunk only if the free came first
+            let returned = a.rebalance();
+            t.join().unwrap();
+            if returned == 0 {
+                assert_eq!(a.rebalance(), 1);
+            }
+            assert_eq!(a.lent(), 0);
+            assert_eq!(a.with_shard(1, |s| s.space()), 0x3000);
+        });
+    }
+}
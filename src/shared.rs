@@ -0,0 +1,548 @@
+//! sharing one allocator between threads or cores
+//!
+//! [`SharedRangeAllocator`] puts a backend behind a lock, so it can be used through a shared
+//! reference, e.g. from a `static`. It does not need `std`. The lock is a [`SpinLock`] by
+//! default. Platforms where spinning is not good enough, like an RTOS that needs a
+//! priority-inheritance mutex or a kernel that masks interrupts around the allocator, plug in
+//! their own by implementing [`RawLock`].
+//!
+//! [`SyncRangeAllocator`] offers the [`RangeAlloc`] methods themselves on a shared reference,
+//! taking the lock for each call. The lock is a [`SharedRangeAllocator`] by default, with `std`
+//! it can be a [`std::sync::Mutex`] instead, or anything else implementing [`Lock`].
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut, Range},
+};
+
+#[cfg(loom)]
+use loom::{
+    hint,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+#[cfg(not(loom))]
+use core::{
+    hint,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{RangeAlloc, Result, address::Address};
+
+/// the platform part of a lock, which only has to exclude others, without holding any data
+///
+/// # Safety
+///
+/// after [`lock`](Self::lock) or a successful [`try_lock`](Self::try_lock) returned, neither
+/// returns anywhere else until [`unlock`](Self::unlock) is called
+pub unsafe trait RawLock {
+    /// what is needed to unlock again, e.g. the interrupt state to restore
+    type State;
+
+    /// an unlocked lock
+    fn new() -> Self;
+
+    /// waits until the lock is available and takes it
+    fn lock(&self) -> Self::State;
+
+    /// takes the lock if nobody else holds it
+    fn try_lock(&self) -> Option<Self::State>;
+
+    /// # Safety
+    ///
+    /// the lock is held, and `state` is what taking it returned
+    unsafe fn unlock(&self, state: Self::State);
+}
+
+/// a lock that spins until it is available. Waiting does not give the holder a chance to run, so
+/// it is only fair where holders can not be preempted by those waiting
+pub struct SpinLock {
+    locked: AtomicBool,
+}
+
+impl SpinLock {
+    #[cfg(not(loom))]
+    pub const fn new() -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+        }
+    }
+
+    /// loom's atomics can not be created in a constant
+    #[cfg(loom)]
+    pub fn new() -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Default for SpinLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for SpinLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpinLock")
+            .field("locked", &self.locked.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+// SAFETY: the flag is only set by a successful compare-exchange from unlocked, and cleared by
+// `unlock`
+unsafe impl RawLock for SpinLock {
+    type State = ();
+
+    fn new() -> Self {
+        SpinLock::new()
+    }
+
+    fn lock(&self) {
+        loop {
+            if self.try_lock().is_some() {
+                return;
+            }
+            while self.locked.load(Ordering::Relaxed) {
+                hint::spin_loop();
+            }
+        }
+    }
+
+    fn try_lock(&self) -> Option<()> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| ())
+    }
+
+    unsafe fn unlock(&self, (): ()) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+/// an allocator protected by a lock
+pub struct SharedRangeAllocator<R, L = SpinLock> {
+    lock: L,
+    inner: UnsafeCell<R>,
+}
+
+// SAFETY: `inner` is only accessed through a `Guard`, and at most one guard exists at a time
+unsafe impl<R: Send, L: RawLock + Sync> Sync for SharedRangeAllocator<R, L> {}
+
+impl<R> SharedRangeAllocator<R> {
+    #[cfg(not(loom))]
+    pub const fn new(inner: R) -> Self {
+        Self::with_lock(SpinLock::new(), inner)
+    }
+
+    /// loom's atomics can not be created in a constant
+    #[cfg(loom)]
+    pub fn new(inner: R) -> Self {
+        Self::with_lock(SpinLock::new(), inner)
+    }
+}
+
+impl<R, L: RawLock> SharedRangeAllocator<R, L> {
+    /// an allocator protected by `lock` instead of a spinlock
+    pub const fn with_lock(lock: L, inner: R) -> Self {
+        SharedRangeAllocator {
+            lock,
+            inner: UnsafeCell::new(inner),
+        }
+    }
+
+    /// waits until the allocator is available and locks it
+    pub fn lock(&self) -> Guard<'_, R, L> {
+        Guard {
+            state: ManuallyDrop::new(self.lock.lock()),
+            shared: self,
+        }
+    }
+
+    /// locks the allocator if nobody else holds it. Code that may interrupt a lock holder on the
+    /// same core, like an interrupt handler, has to use this instead of [`lock`](Self::lock)
+    pub fn try_lock(&self) -> Option<Guard<'_, R, L>> {
+        self.lock.try_lock().map(|state| Guard {
+            state: ManuallyDrop::new(state),
+            shared: self,
+        })
+    }
+
+    /// runs `f` with the allocator locked
+    pub fn with<T>(&self, f: impl FnOnce(&mut R) -> T) -> T {
+        f(&mut self.lock())
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        self.inner.get_mut()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
+}
+
+impl<R: Default, L: RawLock> Default for SharedRangeAllocator<R, L> {
+    fn default() -> Self {
+        Self::with_lock(L::new(), R::default())
+    }
+}
+
+impl<R, L: fmt::Debug> fmt::Debug for SharedRangeAllocator<R, L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedRangeAllocator")
+            .field("lock", &self.lock)
+            .finish_non_exhaustive()
+    }
+}
+
+/// exclusive access to the allocator of a [`SharedRangeAllocator`], unlocking it when dropped
+pub struct Guard<'a, R, L: RawLock = SpinLock> {
+    shared: &'a SharedRangeAllocator<R, L>,
+    state: ManuallyDrop<L::State>,
+}
+
+impl<R, L: RawLock> Deref for Guard<'_, R, L> {
+    type Target = R;
+
+    fn deref(&self) -> &R {
+        // SAFETY: the guard holds the lock
+        unsafe { &*self.shared.inner.get() }
+    }
+}
+
+impl<R, L: RawLock> DerefMut for Guard<'_, R, L> {
+    fn deref_mut(&mut self) -> &mut R {
+        // SAFETY: the guard holds the lock
+        unsafe { &mut *self.shared.inner.get() }
+    }
+}
+
+impl<R, L: RawLock> Drop for Guard<'_, R, L> {
+    fn drop(&mut self) {
+        // SAFETY: the guard holds the lock, and the state is not used again
+        unsafe {
+            let state = ManuallyDrop::take(&mut self.state);
+            self.shared.lock.unlock(state);
+        }
+    }
+}
+
+/// a lock around an allocator, for [`SyncRangeAllocator`]
+pub trait Lock<R> {
+    fn new(inner: R) -> Self;
+
+    /// runs `f` with the lock held
+    fn with<T>(&self, f: impl FnOnce(&mut R) -> T) -> T;
+
+    fn into_inner(self) -> R;
+}
+
+impl<R, L: RawLock> Lock<R> for SharedRangeAllocator<R, L> {
+    fn new(inner: R) -> Self {
+        SharedRangeAllocator::with_lock(L::new(), inner)
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut R) -> T) -> T {
+        SharedRangeAllocator::with(self, f)
+    }
+
+    fn into_inner(self) -> R {
+        SharedRangeAllocator::into_inner(self)
+    }
+}
+
+/// a panic while the lock was held does not leave the allocator inconsistent, every operation
+/// checks its arguments before changing anything, so poisoning is ignored
+#[cfg(feature = "std")]
+impl<R> Lock<R> for std::sync::Mutex<R> {
+    fn new(inner: R) -> Self {
+        std::sync::Mutex::new(inner)
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut R) -> T) -> T {
+        f(&mut self
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner))
+    }
+
+    fn into_inner(self) -> R {
+        std::sync::Mutex::into_inner(self).unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// a backend whose [`RangeAlloc`] methods can be called through a shared reference, from any
+/// number of threads. `&SyncRangeAllocator` implements [`RangeAlloc`] too, so it can be handed to
+/// code that is generic over backends
+pub struct SyncRangeAllocator<R, A = usize, L = SharedRangeAllocator<R>> {
+    lock: L,
+    _backend: PhantomData<fn() -> (R, A)>,
+}
+
+impl<R, A, L: Lock<R>> SyncRangeAllocator<R, A, L> {
+    pub fn new(inner: R) -> Self {
+        SyncRangeAllocator {
+            lock: L::new(inner),
+            _backend: PhantomData,
+        }
+    }
+
+    /// runs `f` with the backend locked, for everything beyond the [`RangeAlloc`] methods, or to
+    /// make several calls without other threads getting in between
+    pub fn with<T>(&self, f: impl FnOnce(&mut R) -> T) -> T {
+        self.lock.with(f)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.lock.into_inner()
+    }
+}
+
+impl<A: Address, R: RangeAlloc<A>, L: Lock<R>> SyncRangeAllocator<R, A, L> {
+    pub fn add_range(&self, base: A, size: A, range_tag: R::Tag) -> Result<()> {
+        self.with(|a| a.add_range(base, size, range_tag))
+    }
+
+    pub fn alloc(&self, min_size: A, alignment: A) -> Result<(R::Tag, A)> {
+        self.with(|a| a.alloc(min_size, alignment))
+    }
+
+    pub fn alloc_within(&self, min_size: A, alignment: A, window: Range<A>) -> Result<(R::Tag, A)> {
+        self.with(|a| a.alloc_within(min_size, alignment, window))
+    }
+
+    pub fn alloc_fixed(&self, base: A, size: A) -> Result<(R::Tag, A)> {
+        self.with(|a| a.alloc_fixed(base, size))
+    }
+
+    pub fn free(&self, base: A, size: A) -> Result<()> {
+        self.with(|a| a.free(base, size))
+    }
+
+    pub fn total_space(&self) -> A {
+        self.with(|a| a.total_space())
+    }
+
+    pub fn space(&self) -> A {
+        self.with(|a| a.space())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.with(|a| a.is_empty())
+    }
+}
+
+impl<R: Default, A, L: Lock<R>> Default for SyncRangeAllocator<R, A, L> {
+    fn default() -> Self {
+        Self::new(R::default())
+    }
+}
+
+impl<R, A, L> fmt::Debug for SyncRangeAllocator<R, A, L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyncRangeAllocator").finish_non_exhaustive()
+    }
+}
+
+impl<A: Address, R: RangeAlloc<A>, L: Lock<R>> RangeAlloc<A> for &SyncRangeAllocator<R, A, L> {
+    type Tag = R::Tag;
+
+    fn add_range(&mut self, base: A, size: A, range_tag: Self::Tag) -> Result<()> {
+        SyncRangeAllocator::add_range(self, base, size, range_tag)
+    }
+
+    fn alloc(&mut self, min_size: A, alignment: A) -> Result<(Self::Tag, A)> {
+        SyncRangeAllocator::alloc(self, min_size, alignment)
+    }
+
+    fn alloc_within(
+        &mut self,
+        min_size: A,
+        alignment: A,
+        window: Range<A>,
+    ) -> Result<(Self::Tag, A)> {
+        SyncRangeAllocator::alloc_within(self, min_size, alignment, window)
+    }
+
+    fn alloc_fixed(&mut self, base: A, size: A) -> Result<(Self::Tag, A)> {
+        SyncRangeAllocator::alloc_fixed(self, base, size)
+    }
+
+    fn free(&mut self, base: A, size: A) -> Result<()> {
+        SyncRangeAllocator::free(self, base, size)
+    }
+
+    fn total_space(&self) -> A {
+        SyncRangeAllocator::total_space(self)
+    }
+
+    fn space(&self) -> A {
+        SyncRangeAllocator::space(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        SyncRangeAllocator::is_empty(self)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use alloc::vec::Vec;
+    use std::{cell::Cell, thread, thread_local};
+
+    use super::{Lock, RawLock, SharedRangeAllocator, SpinLock, SyncRangeAllocator};
+    use crate::{RangeAlloc, RangeAllocator, btree, sharded::ShardedRangeAllocator, testkit};
+
+    thread_local! {
+        static INTERRUPTS: Cell<bool> = const { Cell::new(true) };
+    }
+
+    /// stands in for a platform lock that masks interrupts while it is held, and restores the
+    /// previous mask afterwards
+    struct IrqLock(SpinLock);
+
+    // SAFETY: the spinlock excludes the others
+    unsafe impl RawLock for IrqLock {
+        type State = bool;
+
+        fn new() -> Self {
+            IrqLock(SpinLock::new())
+        }
+
+        fn lock(&self) -> bool {
+            let enabled = INTERRUPTS.replace(false);
+            self.0.lock();
+            enabled
+        }
+
+        fn try_lock(&self) -> Option<bool> {
+            let enabled = INTERRUPTS.replace(false);
+            let res = self.0.try_lock().map(|()| enabled);
+            if res.is_none() {
+                INTERRUPTS.set(enabled);
+            }
+            res
+        }
+
+        unsafe fn unlock(&self, enabled: bool) {
+            // SAFETY: forwarded from the caller
+            unsafe { self.0.unlock(()) };
+            INTERRUPTS.set(enabled);
+        }
+    }
+
+    #[test]
+    fn concurrent_allocations() {
+        let shared = SharedRangeAllocator::new(RangeAllocator::<()>::new());
+        shared
+            .with(|a| a.add_range(0x1000, 0x40_0000, ()))
+            .expect("can add range");
+
+        let allocated: Vec<usize> = thread::scope(|s| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        (0..64)
+                            .map(|_| {
+                                shared
+                                    .with(|a| a.alloc(0x1000, 0x1000))
+                                    .expect("has space")
+                                    .1
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .flat_map(|t| t.join().expect("thread does not panic"))
+                .collect()
+        });
+        let mut unique = allocated.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), 256);
+
+        let guard = shared.lock();
+        assert!(shared.try_lock().is_none());
+        assert_eq!(guard.space(), 0x40_0000 - 256 * 0x1000);
+    }
+
+    fn send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn backends_are_send_and_sync() {
+        type Linear = RangeAllocator<()>;
+        send_sync::<Linear>();
+        send_sync::<btree::RangeAllocator<()>>();
+        send_sync::<SyncRangeAllocator<Linear>>();
+        send_sync::<SyncRangeAllocator<Linear, usize, std::sync::Mutex<Linear>>>();
+        send_sync::<ShardedRangeAllocator<Linear, usize, IrqLock>>();
+    }
+
+    #[test]
+    fn sync_wrapper() {
+        fn hammer<R, L>(a: SyncRangeAllocator<R, usize, L>)
+        where
+            R: RangeAlloc<Tag = ()>,
+            L: Lock<R> + Sync,
+        {
+            testkit::setup(&mut &a);
+            let allocated: Vec<usize> = thread::scope(|s| {
+                let threads: Vec<_> = (0..4)
+                    .map(|_| {
+                        s.spawn(|| {
+                            (0..64)
+                                .map(|_| a.alloc(0x1000, 0x1000).expect("has space").1)
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                    .collect();
+                threads
+                    .into_iter()
+                    .flat_map(|t| t.join().expect("thread does not panic"))
+                    .collect()
+            });
+            assert_eq!(a.space(), a.total_space() - 256 * 0x1000);
+            for x in allocated {
+                a.free(x, 0x1000).expect("can free");
+            }
+            testkit::alloc_different_configurations(&mut &a);
+            assert!(a.into_inner().is_empty());
+        }
+
+        hammer(SyncRangeAllocator::<RangeAllocator<()>>::default());
+        #[cfg(feature = "std")]
+        hammer(SyncRangeAllocator::<_, usize, std::sync::Mutex<_>>::new(
+            btree::RangeAllocator::<()>::new(),
+        ));
+        hammer(SyncRangeAllocator::<
+            _,
+            usize,
+            SharedRangeAllocator<_, IrqLock>,
+        >::new(RangeAllocator::<()>::new()));
+    }
+
+    #[test]
+    fn raw_lock_state() {
+        let shared = SharedRangeAllocator::with_lock(IrqLock::new(), RangeAllocator::<()>::new());
+        shared.with(|_| {
+            assert!(!INTERRUPTS.get());
+            assert!(shared.try_lock().is_none());
+            // a failed attempt leaves the mask alone
+            assert!(!INTERRUPTS.get());
+        });
+        assert!(INTERRUPTS.get());
+
+        // taken with interrupts already masked, they stay masked
+        INTERRUPTS.set(false);
+        drop(shared.try_lock().expect("is unlocked"));
+        assert!(!INTERRUPTS.get());
+    }
+}
@@ -0,0 +1,184 @@
+//! handing out many ranges of the same size
+//!
+//! [`Slab`] takes large ranges, the slabs, from a backend and cuts them into equally sized slots,
+//! e.g. for descriptors or page table pages. Taking a slot pops it off a free list, so it does not
+//! search the backend's free blocks at all, and giving it back only looks up its slab. Slabs stay
+//! with the [`Slab`] when all of their slots are free, until [`shrink`](Slab::shrink) returns them
+//! to the backend.
+
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+
+use crate::{Error, ErrorKind, RangeAlloc, Result, address::Address};
+
+/// a slab taken from the backend
+struct SlabState<Tag> {
+    tag: Tag,
+    /// number of slots in use
+    used: usize,
+    /// for every slot, whether it is in use
+    in_use: Vec<bool>,
+}
+
+/// an allocator of equally sized slots on top of a backend
+pub struct Slab<R: RangeAlloc<A>, A: Address = usize> {
+    inner: R,
+    slot_size: A,
+    alignment: A,
+    slots_per_slab: usize,
+    slab_size: A,
+    /// the free slots of all slabs, the next one to hand out last
+    free: Vec<A>,
+    /// every slab taken from the backend, by base
+    slabs: BTreeMap<A, SlabState<R::Tag>>,
+}
+
+impl<R: RangeAlloc> Slab<R> {
+    /// slots fitting a `T` each, aligned for it
+    pub fn of<T>(inner: R, slots_per_slab: usize) -> Result<Self> {
+        let layout = core::alloc::Layout::new::<T>().pad_to_align();
+        Self::new(inner, layout.size(), layout.align(), slots_per_slab)
+    }
+}
+
+impl<A: Address, R: RangeAlloc<A>> Slab<R, A> {
+    /// slots of `slot_size` at multiples of `alignment`, taken from the backend
+    /// `slots_per_slab` at a time. `slot_size` has to be a non-zero multiple of `alignment`,
+    /// which has to be a power of two
+    pub fn new(inner: R, slot_size: A, alignment: A, slots_per_slab: usize) -> Result<Self> {
+        if slot_size == A::ZERO || slots_per_slab == 0 {
+            return Err(Error::new(ErrorKind::InvalidSize));
+        }
+        if !alignment.is_power_of_two() || slot_size.round_down(alignment) != slot_size {
+            return Err(Error::new(ErrorKind::InvalidAlignment));
+        }
+        let slab_size = slot_size
+            .to_u64()
+            .checked_mul(slots_per_slab as u64)
+            .and_then(|size| A::try_from(size).ok())
+            .ok_or(Error::new(ErrorKind::Overflow))?;
+        Ok(Slab {
+            inner,
+            slot_size,
+            alignment,
+            slots_per_slab,
+            slab_size,
+            free: Vec::new(),
+            slabs: BTreeMap::new(),
+        })
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    /// the backend. Slabs that are still held stay allocated in it
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    pub fn slot_size(&self) -> A {
+        self.slot_size
+    }
+
+    /// number of slots in use
+    pub fn len(&self) -> usize {
+        self.slabs.values().map(|slab| slab.used).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.free.len() == self.capacity()
+    }
+
+    /// number of slots in all slabs held, used or not
+    pub fn capacity(&self) -> usize {
+        self.slabs.len() * self.slots_per_slab
+    }
+
+    /// a free slot, taking a new slab from the backend if there is none. Returns the tag of the
+    /// region the slab was taken from and the base of the slot
+    pub fn alloc(&mut self) -> Result<(R::Tag, A)>
+    where
+        R::Tag: Clone,
+    {
+        if self.free.is_empty() {
+            self.grow()?;
+        }
+        let base = self.free.pop().expect("invariant: grown if empty");
+        let (slab, index) = self.slot(base).expect("invariant: free slots are in slabs");
+        let slab = self
+            .slabs
+            .get_mut(&slab)
+            .expect("invariant: slot was found");
+        slab.in_use[index] = true;
+        slab.used += 1;
+        Ok((slab.tag.clone(), base))
+    }
+
+    /// gives back the slot at `base`
+    pub fn free(&mut self, base: A) -> Result<()> {
+        let (slab, index) = self.slot(base).ok_or(Error::new(ErrorKind::NotOwned))?;
+        let slab = self
+            .slabs
+            .get_mut(&slab)
+            .expect("invariant: slot was found");
+        if !slab.in_use[index] {
+            return Err(Error::new(ErrorKind::DoubleFree));
+        }
+        slab.in_use[index] = false;
+        slab.used -= 1;
+        self.free.push(base);
+        Ok(())
+    }
+
+    /// returns every slab without used slots to the backend, and how many there were
+    pub fn shrink(&mut self) -> Result<usize> {
+        let empty: Vec<A> = self
+            .slabs
+            .iter()
+            .filter(|(_, slab)| slab.used == 0)
+            .map(|(&base, _)| base)
+            .collect();
+        for &base in &empty {
+            self.inner.free(base, self.slab_size)?;
+            self.slabs.remove(&base);
+        }
+        let slabs = &self.slabs;
+        let slab_size = self.slab_size;
+        self.free.retain(|&slot| {
+            slabs
+                .range(..=slot)
+                .next_back()
+                .is_some_and(|(&base, _)| slot - base < slab_size)
+        });
+        Ok(empty.len())
+    }
+
+    fn grow(&mut self) -> Result<()> {
+        let (tag, base) = self.inner.alloc(self.slab_size, self.alignment)?;
+        self.slabs.insert(
+            base,
+            SlabState {
+                tag,
+                used: 0,
+                in_use: vec![false; self.slots_per_slab],
+            },
+        );
+        // pushed from the top, so the lowest slot is handed out first
+        let mut slot = base + (self.slab_size - self.slot_size);
+        self.free.push(slot);
+        while slot > base {
+            slot -= self.slot_size;
+            self.free.push(slot);
+        }
+        Ok(())
+    }
+
+    /// the slab the slot at `base` belongs to, and its index there
+    fn slot(&self, base: A) -> Option<(A, usize)> {
+        let (&slab, _) = self.slabs.range(..=base).next_back()?;
+        let offset = (base - slab).to_u64();
+        let slot_size = self.slot_size.to_u64();
+        let index = usize::try_from(offset / slot_size).ok()?;
+        (offset.is_multiple_of(slot_size) && index < self.slots_per_slab).then_some((slab, index))
+    }
+}
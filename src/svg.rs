@@ -0,0 +1,228 @@
+//! drawing how a trace uses the address space over time
+//!
+//! [`timeline`] replays a [`Trace`] and renders every allocation as a box in an SVG image. Time,
+//! counted in operations, runs from left to right and addresses from top to bottom. The regions
+//! are stacked without the gaps between them, and boxes are colored by the tag of the region they
+//! were allocated from. Holes that open up and are never filled again show fragmentation creeping
+//! in.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::{fmt::Write, ops::Range};
+
+use crate::{
+    RangeAlloc,
+    trace::{Trace, TraceOp},
+};
+
+/// fill colors, picked by region tag
+const PALETTE: [&str; 8] = [
+    "#4e79a7", "#f28e2b", "#e15759", "#76b7b2", "#59a14f", "#edc948", "#b07aa1", "#ff9da7",
+];
+
+/// room for the region labels left of the plot
+const LABEL_WIDTH: f64 = 120.0;
+
+/// an allocation and the operations it was live for
+struct Lifetime {
+    id: u64,
+    tag: u64,
+    base: u64,
+    size: u64,
+    start: usize,
+    end: usize,
+}
+
+/// replays `trace` against `a` and renders the allocations as an SVG image of `width` by
+/// `height` pixels. `fail` markers and `expect` lines are ignored, so any backend and policy can be
+/// drawn
+pub fn timeline<R>(trace: &Trace, mut a: R, width: u32, height: u32) -> String
+where
+    R: RangeAlloc<u64, Tag = u64>,
+{
+    let mut regions = Vec::new();
+    let mut live = BTreeMap::new();
+    let mut done = Vec::new();
+    let mut time = 0;
+    for op in &trace.ops {
+        let allocated = match *op {
+            TraceOp::Add { region, base, size } => {
+                if a.add_range(base, size, region).is_ok() {
+                    regions.push(base..base + size);
+                }
+                None
+            }
+            TraceOp::Alloc {
+                id,
+                size,
+                alignment,
+                ..
+            } => Some((id, size, a.alloc(size, alignment))),
+            TraceOp::AllocFixed { id, base, size, .. } => {
+                Some((id, size, a.alloc_fixed(base, size)))
+            }
+            TraceOp::Free { id } => {
+                if let Some(mut lifetime) = live.remove(&id) {
+                    let Lifetime { base, size, .. } = lifetime;
+                    let _ = a.free(base, size);
+                    lifetime.end = time;
+                    done.push(lifetime);
+                }
+                None
+            }
+            TraceOp::Expect { .. } => continue,
+        };
+        if let Some((id, size, Ok((tag, base)))) = allocated {
+            let lifetime = Lifetime {
+                id,
+                tag,
+                base,
+                size,
+                start: time,
+                end: time,
+            };
+            live.insert(id, lifetime);
+        }
+        time += 1;
+    }
+    done.extend(live.into_values().map(|lifetime| Lifetime {
+        end: time,
+        ..lifetime
+    }));
+    regions.sort_by_key(|region: &Range<u64>| region.start);
+
+    Plot::new(regions, time, width, height).render(&done)
+}
+
+/// maps times and addresses to pixels
+struct Plot {
+    regions: Vec<Range<u64>>,
+    /// for every region, the total size of the regions before it
+    offsets: Vec<u64>,
+    /// scales
+    x: f64,
+    y: f64,
+    width: u32,
+    height: u32,
+}
+
+impl Plot {
+    fn new(regions: Vec<Range<u64>>, duration: usize, width: u32, height: u32) -> Self {
+        let mut offsets = Vec::with_capacity(regions.len());
+        let mut total = 0;
+        for region in &regions {
+            offsets.push(total);
+            total += region.end - region.start;
+        }
+        Plot {
+            regions,
+            offsets,
+            x: (f64::from(width) - LABEL_WIDTH).max(1.0) / duration.max(1) as f64,
+            y: f64::from(height) / total.max(1) as f64,
+            width,
+            height,
+        }
+    }
+
+    fn x(&self, time: usize) -> f64 {
+        LABEL_WIDTH + time as f64 * self.x
+    }
+
+    /// addresses outside every region, which a working backend never hands out, end up at the
+    /// nearest region
+    fn y(&self, addr: u64) -> f64 {
+        let i = self
+            .regions
+            .partition_point(|region| region.end <= addr)
+            .min(self.regions.len().saturating_sub(1));
+        let offset = self.regions.get(i).map_or(0, |region| {
+            self.offsets[i] + addr.clamp(region.start, region.end) - region.start
+        });
+        offset as f64 * self.y
+    }
+
+    fn render(&self, allocations: &[Lifetime]) -> String {
+        let mut svg = String::new();
+        // writing to a `String` cannot fail
+        let _ = self.write(&mut svg, allocations);
+        svg
+    }
+
+    fn write(&self, svg: &mut String, allocations: &[Lifetime]) -> core::fmt::Result {
+        let (width, height) = (self.width, self.height);
+        writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}" font-family="monospace" font-size="11">"#
+        )?;
+        writeln!(
+            svg,
+            r##"<rect width="{width}" height="{height}" fill="#ffffff"/>"##
+        )?;
+        for region in &self.regions {
+            let top = self.y(region.start);
+            writeln!(
+                svg,
+                r##"<line x1="0" y1="{top:.1}" x2="{width}" y2="{top:.1}" stroke="#999999"/>"##
+            )?;
+            writeln!(
+                svg,
+                r#"<text x="4" y="{:.1}">{:#x}</text>"#,
+                top + 12.0,
+                region.start
+            )?;
+        }
+        for allocation in allocations {
+            let Lifetime {
+                id,
+                tag,
+                base,
+                size,
+                start,
+                end,
+            } = *allocation;
+            let (left, top) = (self.x(start), self.y(base));
+            let (right, bottom) = (self.x(end), self.y(base + size));
+            writeln!(
+                svg,
+                r#"<rect x="{left:.1}" y="{top:.1}" width="{:.1}" height="{:.1}" fill="{}"><title>{id}: {base:#x}+{size:#x}, ops {start}..{end}</title></rect>"#,
+                right - left,
+                (bottom - top).max(0.5),
+                PALETTE[(tag % PALETTE.len() as u64) as usize],
+            )?;
+        }
+        writeln!(svg, "</svg>")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::timeline;
+    use crate::{btree, trace::Trace};
+
+    #[test]
+    fn boxes() {
+        let trace: Trace = "add 1 0x1000 0x4000
+            add 2 0x100000 0x4000
+            alloc 1 0x1000 0x1000
+            alloc_fixed 2 0x100000 0x2000
+            free 1
+            alloc 3 0x1000 0x1000 fail
+            expect 3 0x1000"
+            .parse()
+            .expect("trace is valid");
+        let svg = timeline(
+            &trace,
+            btree::RangeAllocator::<u64, u64>::default(),
+            520,
+            800,
+        );
+
+        assert!(svg.starts_with("<svg ") && svg.trim_end().ends_with("</svg>"));
+        assert_eq!(svg.matches("<title>").count(), 3);
+        // the regions are stacked, each taking half of the height
+        assert!(svg.contains(r#"<text x="4" y="412.0">0x100000</text>"#));
+        // allocation 1 lives from op 2 to op 4 of 6, 400 pixels wide
+        assert!(svg.contains(
+            r##"<rect x="253.3" y="0.0" width="133.3" height="100.0" fill="#f28e2b"><title>1: 0x1000+0x1000, ops 2..4</title></rect>"##
+        ));
+    }
+}
@@ -34,6 +34,8 @@ random.shuffle(regions)
 
 free_bytes = 0
 
+print("range-alloc-trace v1")
+
 for base, size in regions:
     print(f"add {base} {base} {size}")
     free_bytes += size
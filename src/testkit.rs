@@ -0,0 +1,105 @@
+//! workloads for testing and benchmarking allocators
+//!
+//! the helpers the crate's own tests and benchmarks are built from, enabled with the `testkit`
+//! feature so crates wrapping or implementing [`RangeAlloc`] can run the same workloads. They
+//! panic when the allocator misbehaves, like a failing assertion would.
+//!
+//! ```ignore
+//! use range_alloc::testkit::{alloc_different_configurations, new_linear, setup};
+//!
+//! let mut a = MyWrapper::new(new_linear());
+//! setup(&mut a);
+//! alloc_different_configurations(&mut a);
+//! assert_eq!(a.space(), a.total_space());
+//! ```
+
+use alloc::vec::Vec;
+use core::hint::black_box;
+
+use crate::{RangeAlloc, btree, linear};
+
+/// a linear backend with the default settings
+pub fn new_linear() -> linear::RangeAllocator<()> {
+    linear::RangeAllocator::new()
+}
+
+/// a btree backend with the default settings
+pub fn new_btree() -> btree::RangeAllocator<()> {
+    btree::RangeAllocator::new()
+}
+
+/// adds the two regions the other helpers expect: 16 MiB at `0x7ff000` and 512 KiB at
+/// `0xfff0000`, both with the default tag
+pub fn setup<R: RangeAlloc + ?Sized>(a: &mut R)
+where
+    R::Tag: Default,
+{
+    a.add_range(0x7ff000, 4096 * 4096, Default::default())
+        .expect("can add range");
+
+    a.add_range(0xfff0000, 4096 * 128, Default::default())
+        .expect("can add range");
+}
+
+/// allocates `n` times from `a`, picking the sizes and alignments from `sizes` and `alignments`,
+/// which both wrap around. Returns base and size of the allocations that succeeded
+pub fn allocate_n<R: RangeAlloc + ?Sized>(
+    a: &mut R,
+    sizes: impl Iterator<Item = usize> + Clone,
+    alignments: impl Iterator<Item = usize> + Clone,
+    n: usize,
+) -> Vec<(usize, usize)> {
+    let mut sizes = sizes.cycle();
+    let mut alignments = alignments.cycle();
+
+    let mut positions = Vec::with_capacity(n);
+    for _ in 0..n {
+        let size = sizes.next().unwrap();
+        let Ok((_, x)) = a.alloc(size, alignments.next().unwrap()) else {
+            continue;
+        };
+
+        positions.push((x, size));
+    }
+
+    positions
+}
+
+/// allocates two pages aligned to 16 MiB and frees them again. Needs a region with room for both,
+/// e.g. the first one of [`setup`]
+pub fn alloc_aligned<R: RangeAlloc + ?Sized>(a: &mut R) {
+    let (_, x) = a.alloc(black_box(4096), 4096 * 4096).expect("can allocate");
+    let (_, y) = a.alloc(black_box(4096), 4096 * 4096).expect("can allocate");
+    a.free(x, 4096).expect("can free again");
+    a.free(y, 4096).expect("can free again");
+}
+
+/// makes 500 allocations of mixed sizes and alignments, skipping the ones that do not fit, and
+/// frees all of them again
+pub fn alloc_different_configurations<R: RangeAlloc + ?Sized>(a: &mut R) {
+    const N: usize = 500;
+
+    let sizes = [10, 3, 5, 6, 2, 9, 1, 4, 8, 7].map(|x| x * 4096);
+    let alignments = [8, 2, 9, 1, 3, 0, 6, 5, 7].map(|x| 4096 << x);
+
+    let mut sizes = sizes.iter().copied().cycle();
+    let mut alignments = alignments.iter().copied().cycle();
+
+    let mut positions = [(0, 0); N];
+
+    for pos in positions.iter_mut() {
+        let size = sizes.next().unwrap();
+        let Ok((_, x)) = a.alloc(size, alignments.next().unwrap()) else {
+            continue;
+        };
+
+        *pos = (x, size);
+    }
+
+    for pos in positions {
+        if pos.0 == 0 {
+            continue;
+        }
+        a.free(pos.0, pos.1).expect("can free");
+    }
+}
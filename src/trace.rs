@@ -0,0 +1,594 @@
+//! the trace format used by `testdata` and the replay tests
+//!
+//! a trace is a text file with one operation per line:
+//!
+//! ```text
+//! range-alloc-trace v1
+//! # comments start with `#`, blank lines are ignored
+//! add <region-id> <base> <size>
+//! alloc <allocation-id> <size> <alignment> [fail]
+//! alloc_fixed <allocation-id> <base> <size> [fail]
+//! free <allocation-id>
+//! expect <allocation-id> <base>
+//! ```
+//!
+//! numbers are unsigned 64-bit integers, either decimal or hexadecimal with a `0x` prefix. `fail`
+//! marks operations that are expected to fail, and `expect` asserts where a live allocation was
+//! placed. The header line is optional, traces without it are read as version 1.
+//!
+//! with the `record` feature, [`Recorder`] writes such a trace of the operations on a backend.
+//! [`replay`](crate::replay) runs one against any allocator.
+
+use alloc::vec::Vec;
+use core::{fmt, ops::Range, str::FromStr};
+
+use crate::{RangeAlloc, Result, address::Address, linear};
+
+/// the version written by [`Trace`]'s `Display` implementation and the newest one understood
+pub const VERSION: u32 = 1;
+
+const HEADER: &str = "range-alloc-trace";
+
+/// a single line of a trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceOp {
+    Add {
+        region: u64,
+        base: u64,
+        size: u64,
+    },
+    Alloc {
+        id: u64,
+        size: u64,
+        alignment: u64,
+        fail: bool,
+    },
+    AllocFixed {
+        id: u64,
+        base: u64,
+        size: u64,
+        fail: bool,
+    },
+    Free {
+        id: u64,
+    },
+    Expect {
+        id: u64,
+        base: u64,
+    },
+}
+
+impl fmt::Display for TraceOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fail = |fail: bool| if fail { " fail" } else { "" };
+        match *self {
+            TraceOp::Add { region, base, size } => write!(f, "add {region} {base:#x} {size:#x}"),
+            TraceOp::Alloc {
+                id,
+                size,
+                alignment,
+                fail: failing,
+            } => write!(f, "alloc {id} {size:#x} {alignment:#x}{}", fail(failing)),
+            TraceOp::AllocFixed {
+                id,
+                base,
+                size,
+                fail: failing,
+            } => write!(f, "alloc_fixed {id} {base:#x} {size:#x}{}", fail(failing)),
+            TraceOp::Free { id } => write!(f, "free {id}"),
+            TraceOp::Expect { id, base } => write!(f, "expect {id} {base:#x}"),
+        }
+    }
+}
+
+/// a parsed trace
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Trace {
+    pub ops: Vec<TraceOp>,
+}
+
+/// why a trace could not be parsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    UnknownCommand,
+    UnsupportedVersion,
+    /// the header appeared after the first operation
+    MisplacedHeader,
+    MissingArgument,
+    InvalidNumber,
+    TrailingArgument,
+}
+
+/// a parse error and the (1-based) line it occurred on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub kind: ParseErrorKind,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.kind {
+            ParseErrorKind::UnknownCommand => "unknown command",
+            ParseErrorKind::UnsupportedVersion => "unsupported version",
+            ParseErrorKind::MisplacedHeader => "header after the first operation",
+            ParseErrorKind::MissingArgument => "missing argument",
+            ParseErrorKind::InvalidNumber => "invalid number",
+            ParseErrorKind::TrailingArgument => "unexpected argument",
+        };
+        write!(f, "line {}: {reason}", self.line)
+    }
+}
+
+impl core::error::Error for ParseError {}
+
+fn parse_u64(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+type Args<'a> = core::str::SplitWhitespace<'a>;
+
+fn num(args: &mut Args) -> core::result::Result<u64, ParseErrorKind> {
+    let arg = args.next().ok_or(ParseErrorKind::MissingArgument)?;
+    parse_u64(arg).ok_or(ParseErrorKind::InvalidNumber)
+}
+
+/// the optional `fail` marker at the end of an allocation
+fn fail(args: &mut Args) -> core::result::Result<bool, ParseErrorKind> {
+    match args.next() {
+        None => Ok(false),
+        Some("fail") => Ok(true),
+        Some(_) => Err(ParseErrorKind::TrailingArgument),
+    }
+}
+
+impl TraceOp {
+    /// parses a line that is neither blank, a comment nor the header
+    fn parse(line: &str) -> core::result::Result<TraceOp, ParseErrorKind> {
+        let mut args = line.split_whitespace();
+        let a = &mut args;
+        let op = match a.next().ok_or(ParseErrorKind::MissingArgument)? {
+            "add" => TraceOp::Add {
+                region: num(a)?,
+                base: num(a)?,
+                size: num(a)?,
+            },
+            "alloc" => TraceOp::Alloc {
+                id: num(a)?,
+                size: num(a)?,
+                alignment: num(a)?,
+                fail: fail(a)?,
+            },
+            "alloc_fixed" => TraceOp::AllocFixed {
+                id: num(a)?,
+                base: num(a)?,
+                size: num(a)?,
+                fail: fail(a)?,
+            },
+            "free" => TraceOp::Free { id: num(a)? },
+            "expect" => TraceOp::Expect {
+                id: num(a)?,
+                base: num(a)?,
+            },
+            _ => return Err(ParseErrorKind::UnknownCommand),
+        };
+        if args.next().is_some() {
+            return Err(ParseErrorKind::TrailingArgument);
+        }
+        Ok(op)
+    }
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Trace::default()
+    }
+
+    pub fn push(&mut self, op: TraceOp) {
+        self.ops.push(op);
+    }
+
+    pub fn parse(s: &str) -> core::result::Result<Trace, ParseError> {
+        let mut trace = Trace::new();
+        for (i, line) in s.lines().enumerate() {
+            let error = |kind| ParseError { line: i + 1, kind };
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(version) = line.strip_prefix(HEADER) {
+                if !trace.ops.is_empty() {
+                    return Err(error(ParseErrorKind::MisplacedHeader));
+                }
+                let version = version
+                    .trim()
+                    .strip_prefix('v')
+                    .and_then(|v| v.parse().ok());
+                match version {
+                    Some(v) if (1..=VERSION).contains(&v) => continue,
+                    _ => return Err(error(ParseErrorKind::UnsupportedVersion)),
+                }
+            }
+
+            trace.push(TraceOp::parse(line).map_err(error)?);
+        }
+        Ok(trace)
+    }
+}
+
+impl FromStr for Trace {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
+        Trace::parse(s)
+    }
+}
+
+/// writes the trace in the current version, including the header
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{HEADER} v{VERSION}")?;
+        for op in &self.ops {
+            writeln!(f, "{op}")?;
+        }
+        Ok(())
+    }
+}
+
+/// how the sizes of generated allocations are distributed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sizes {
+    /// any size in the range, in bytes
+    Uniform(Range<u64>),
+    /// `page << order` for an order below `orders`, each order equally likely
+    PowersOfTwo { page: u64, orders: u32 },
+}
+
+/// the shape of a workload made by [`generate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenConfig {
+    pub regions: usize,
+    /// the size of every region in pages, the gaps between them are up to as large. Like all
+    /// ranges here, it must not be empty
+    pub region_pages: Range<u64>,
+    /// allocations and frees after the regions are added
+    pub ops: usize,
+    /// how many of the operations are allocations rather than frees, out of 100. An operation is
+    /// always an allocation while nothing is live
+    pub alloc_percent: u32,
+    pub sizes: Sizes,
+    /// the alignments to choose from, each equally likely
+    pub alignments: Vec<u64>,
+}
+
+/// a workload like the one in `testdata/gen2`
+impl Default for GenConfig {
+    fn default() -> Self {
+        GenConfig {
+            regions: 6,
+            region_pages: 2..129,
+            ops: 200,
+            alloc_percent: 50,
+            sizes: Sizes::Uniform(1..0x10_0000),
+            alignments: [0x1000, 0x2000, 0x3000].into(),
+        }
+    }
+}
+
+/// xorshift, so a seed always makes the same trace
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// a number in `range`, which must not be empty
+    fn in_range(&mut self, range: Range<u64>) -> u64 {
+        range.start + self.next() % (range.end - range.start)
+    }
+}
+
+/// a random workload shaped by `config`, the same for the same seed. Regions are placed at
+/// random page-aligned addresses in ascending order but added shuffled. Allocations that ask for
+/// more than the pages left free are marked `fail`, others may still fail on a fragmented
+/// allocator, so the trace is meant to be replayed without checking the layout
+pub fn generate(seed: u64, config: &GenConfig) -> Trace {
+    let mut rng = Rng(seed.max(1));
+    let page = linear::BASE_PAGE_SIZE as u64;
+    let mut trace = Trace::new();
+
+    let mut regions = Vec::with_capacity(config.regions);
+    let mut base = rng.in_range(2..1 << 20) * page;
+    for _ in 0..config.regions {
+        let size = rng.in_range(config.region_pages.clone()) * page;
+        regions.push((base, size));
+        base += size + rng.in_range(0..config.region_pages.end) * page;
+    }
+    for i in (1..regions.len()).rev() {
+        regions.swap(i, rng.in_range(0..i as u64 + 1) as usize);
+    }
+    let mut free: u64 = regions.iter().map(|&(_, size)| size).sum();
+    for (region, (base, size)) in (0..).zip(regions) {
+        trace.push(TraceOp::Add { region, base, size });
+    }
+
+    let mut live: Vec<(u64, u64)> = Vec::new();
+    for id in 1..=config.ops as u64 {
+        if !live.is_empty() && rng.in_range(0..100) >= u64::from(config.alloc_percent) {
+            let (id, size) = live.swap_remove(rng.in_range(0..live.len() as u64) as usize);
+            free += size;
+            trace.push(TraceOp::Free { id });
+            continue;
+        }
+        let size = match config.sizes {
+            Sizes::Uniform(ref range) => rng.in_range(range.clone()),
+            Sizes::PowersOfTwo { page, orders } => page << rng.in_range(0..u64::from(orders)),
+        };
+        let alignment = config.alignments[rng.in_range(0..config.alignments.len() as u64) as usize];
+        let pages = size.div_ceil(page) * page;
+        let fail = pages > free;
+        if !fail {
+            free -= pages;
+            live.push((id, pages));
+        }
+        trace.push(TraceOp::Alloc {
+            id,
+            size,
+            alignment,
+            fail,
+        });
+    }
+    trace
+}
+
+/// records the operations on a backend as a trace, e.g. to replay a real workload in a test
+///
+/// the trace is written line by line as the operations happen. Every region gets the next region
+/// id and every allocation the next allocation id, successful allocations are followed by an
+/// `expect` of where they were placed. Allocations restricted to a window are written as
+/// `alloc_fixed` at the place they got, the format has no windows. Failed additions and frees
+/// change nothing and are left out, as are frees of allocations made before recording started.
+#[cfg(feature = "record")]
+pub struct Recorder<R, W, A = usize> {
+    inner: R,
+    out: W,
+    /// the first write that failed, nothing is written after it
+    error: Option<std::io::Error>,
+    next_region: u64,
+    next_id: u64,
+    /// the id of every live allocation, by base
+    live: alloc::collections::BTreeMap<A, u64>,
+}
+
+#[cfg(feature = "record")]
+impl<A: Address, R: RangeAlloc<A>, W: std::io::Write> Recorder<R, W, A> {
+    /// records the operations on `inner` to `out`, starting with the header
+    pub fn new(inner: R, mut out: W) -> Self {
+        let error = writeln!(out, "{HEADER} v{VERSION}").err();
+        Recorder {
+            inner,
+            out,
+            error,
+            next_region: 0,
+            next_id: 0,
+            live: alloc::collections::BTreeMap::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    /// flushes the trace and returns the backend and the writer, or the first write error
+    pub fn finish(mut self) -> std::io::Result<(R, W)> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()?;
+        Ok((self.inner, self.out))
+    }
+
+    fn write(&mut self, op: TraceOp) {
+        if self.error.is_none() {
+            self.error = writeln!(self.out, "{op}").err();
+        }
+    }
+
+    /// writes an allocation made by `op`, which gets the id, and where it was placed
+    fn allocated<T>(&mut self, result: &Result<(T, A)>, op: impl FnOnce(u64, bool) -> TraceOp) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.write(op(id, result.is_err()));
+        if let Ok((_, base)) = *result {
+            self.live.insert(base, id);
+            self.write(TraceOp::Expect {
+                id,
+                base: base.to_u64(),
+            });
+        }
+    }
+}
+
+#[cfg(feature = "record")]
+impl<A: Address, R: RangeAlloc<A>, W: std::io::Write> RangeAlloc<A> for Recorder<R, W, A> {
+    type Tag = R::Tag;
+
+    fn add_range(&mut self, base: A, size: A, range_tag: Self::Tag) -> Result<()> {
+        self.inner.add_range(base, size, range_tag)?;
+        let region = self.next_region;
+        self.next_region += 1;
+        self.write(TraceOp::Add {
+            region,
+            base: base.to_u64(),
+            size: size.to_u64(),
+        });
+        Ok(())
+    }
+
+    fn alloc(&mut self, min_size: A, alignment: A) -> Result<(Self::Tag, A)> {
+        let result = self.inner.alloc(min_size, alignment);
+        self.allocated(&result, |id, fail| TraceOp::Alloc {
+            id,
+            size: min_size.to_u64(),
+            alignment: alignment.to_u64(),
+            fail,
+        });
+        result
+    }
+
+    fn alloc_within(
+        &mut self,
+        min_size: A,
+        alignment: A,
+        window: Range<A>,
+    ) -> Result<(Self::Tag, A)> {
+        let result = self.inner.alloc_within(min_size, alignment, window);
+        if let Ok((_, base)) = result {
+            self.allocated(&result, |id, fail| TraceOp::AllocFixed {
+                id,
+                base: base.to_u64(),
+                size: min_size.to_u64(),
+                fail,
+            });
+        }
+        result
+    }
+
+    fn alloc_fixed(&mut self, base: A, size: A) -> Result<(Self::Tag, A)> {
+        let result = self.inner.alloc_fixed(base, size);
+        self.allocated(&result, |id, fail| TraceOp::AllocFixed {
+            id,
+            base: base.to_u64(),
+            size: size.to_u64(),
+            fail,
+        });
+        result
+    }
+
+    fn free(&mut self, base: A, size: A) -> Result<()> {
+        self.inner.free(base, size)?;
+        if let Some(id) = self.live.remove(&base) {
+            self.write(TraceOp::Free { id });
+        }
+        Ok(())
+    }
+
+    fn total_space(&self) -> A {
+        self.inner.total_space()
+    }
+
+    fn space(&self) -> A {
+        self.inner.space()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let trace = Trace::parse(
+            "# a comment\nrange-alloc-trace v1\n\nadd 1 0x1000 4096 # trailing comment\nalloc 2 4096 0x2000 fail\nfree 2\n",
+        )
+        .expect("valid trace");
+        assert_eq!(
+            trace.ops,
+            [
+                TraceOp::Add {
+                    region: 1,
+                    base: 0x1000,
+                    size: 0x1000
+                },
+                TraceOp::Alloc {
+                    id: 2,
+                    size: 0x1000,
+                    alignment: 0x2000,
+                    fail: true
+                },
+                TraceOp::Free { id: 2 },
+            ]
+        );
+
+        let err = |s: &str| Trace::parse(s).unwrap_err();
+        assert_eq!(err("add 1 2").kind, ParseErrorKind::MissingArgument);
+        assert_eq!(err("free 1\nfree x").line, 2);
+        assert_eq!(err("free 1 fail").kind, ParseErrorKind::TrailingArgument);
+        assert_eq!(
+            err("range-alloc-trace v2").kind,
+            ParseErrorKind::UnsupportedVersion
+        );
+        assert_eq!(
+            err("free 1\nrange-alloc-trace v1").kind,
+            ParseErrorKind::MisplacedHeader
+        );
+        assert_eq!(err("realloc 1").kind, ParseErrorKind::UnknownCommand);
+    }
+
+    #[test]
+    fn generate() {
+        let config = GenConfig {
+            sizes: Sizes::PowersOfTwo {
+                page: 0x1000,
+                orders: 4,
+            },
+            ..GenConfig::default()
+        };
+        let trace = super::generate(7, &config);
+        assert_eq!(trace, super::generate(7, &config));
+        assert_ne!(trace, super::generate(8, &config));
+        assert_eq!(trace.ops.len(), config.regions + config.ops);
+        assert_eq!(trace.to_string().parse::<Trace>(), Ok(trace.clone()));
+
+        let sizes = trace.ops.iter().filter_map(|op| match *op {
+            TraceOp::Alloc { size, .. } => Some(size),
+            _ => None,
+        });
+        assert!(
+            sizes
+                .into_iter()
+                .all(|size| [1, 2, 4, 8].contains(&(size / 0x1000)))
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let trace = Trace {
+            ops: [
+                TraceOp::Add {
+                    region: 0,
+                    base: u64::MAX - 0xfff,
+                    size: 0x1000,
+                },
+                TraceOp::AllocFixed {
+                    id: 1,
+                    base: u64::MAX - 0xfff,
+                    size: 0x1000,
+                    fail: false,
+                },
+                TraceOp::Expect {
+                    id: 1,
+                    base: u64::MAX - 0xfff,
+                },
+            ]
+            .into(),
+        };
+        let text = trace.to_string();
+        assert!(text.starts_with("range-alloc-trace v1\n"));
+        assert_eq!(text.parse::<Trace>(), Ok(trace));
+    }
+}
@@ -0,0 +1,134 @@
+//! allocating and freeing a batch of ranges all or nothing
+//!
+//! [`RangeAlloc::begin`] starts a [`Txn`], which is an allocator itself and records every
+//! allocation and free made through it. [`commit`](Txn::commit) keeps them,
+//! [`rollback`](Txn::rollback) undoes them in reverse order. A transaction that is dropped without
+//! being committed is rolled back, so returning early with `?` undoes the batch.
+//!
+//! ```ignore
+//! let mut txn = a.begin();
+//! let (_, ring) = txn.alloc(0x4000, 0x1000)?;
+//! let (_, doorbell) = txn.alloc_fixed(0xfee0_0000, 0x1000)?; // frees `ring` again on error
+//! txn.commit();
+//! ```
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::{RangeAlloc, Result, address::Address};
+
+/// an operation a transaction undoes, with the base and size it was made with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op<A> {
+    Alloc(A, A),
+    Free(A, A),
+}
+
+/// a batch of allocations and frees on `R` that is committed or rolled back as a whole
+#[derive(Debug)]
+pub struct Txn<'a, R: RangeAlloc<A> + ?Sized, A: Address = usize> {
+    inner: &'a mut R,
+    /// the successful operations, oldest first
+    log: Vec<Op<A>>,
+}
+
+impl<'a, A: Address, R: RangeAlloc<A> + ?Sized> Txn<'a, R, A> {
+    pub fn new(inner: &'a mut R) -> Self {
+        Txn {
+            inner,
+            log: Vec::new(),
+        }
+    }
+
+    /// number of allocations and frees a rollback would undo
+    pub fn len(&self) -> usize {
+        self.log.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.log.is_empty()
+    }
+
+    /// keeps everything done in the transaction
+    pub fn commit(mut self) {
+        self.log.clear();
+    }
+
+    /// undoes everything done in the transaction, newest first. Nothing else can have touched
+    /// the allocator in the meantime, so this only fails if the backend cannot free what it
+    /// allocated or allocate what was freed. The operations not undone yet are kept then
+    pub fn rollback(mut self) -> Result<()> {
+        self.undo()
+    }
+
+    fn undo(&mut self) -> Result<()> {
+        while let Some(op) = self.log.pop() {
+            let undone = match op {
+                Op::Alloc(base, size) => self.inner.free(base, size),
+                Op::Free(base, size) => self.inner.alloc_fixed(base, size).map(|_| ()),
+            };
+            if let Err(e) = undone {
+                self.log.push(op);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<A: Address, R: RangeAlloc<A> + ?Sized> Drop for Txn<'_, R, A> {
+    fn drop(&mut self) {
+        // there is no one to report a failure to, `rollback` does
+        let _ = self.undo();
+    }
+}
+
+impl<A: Address, R: RangeAlloc<A> + ?Sized> RangeAlloc<A> for Txn<'_, R, A> {
+    type Tag = R::Tag;
+
+    /// adds the region right away. Rolling back does not remove it again
+    fn add_range(&mut self, base: A, size: A, range_tag: Self::Tag) -> Result<()> {
+        self.inner.add_range(base, size, range_tag)
+    }
+
+    fn alloc(&mut self, min_size: A, alignment: A) -> Result<(Self::Tag, A)> {
+        let (tag, base) = self.inner.alloc(min_size, alignment)?;
+        self.log.push(Op::Alloc(base, min_size));
+        Ok((tag, base))
+    }
+
+    fn alloc_within(
+        &mut self,
+        min_size: A,
+        alignment: A,
+        window: Range<A>,
+    ) -> Result<(Self::Tag, A)> {
+        let (tag, base) = self.inner.alloc_within(min_size, alignment, window)?;
+        self.log.push(Op::Alloc(base, min_size));
+        Ok((tag, base))
+    }
+
+    fn alloc_fixed(&mut self, base: A, size: A) -> Result<(Self::Tag, A)> {
+        let (tag, base) = self.inner.alloc_fixed(base, size)?;
+        self.log.push(Op::Alloc(base, size));
+        Ok((tag, base))
+    }
+
+    fn free(&mut self, base: A, size: A) -> Result<()> {
+        self.inner.free(base, size)?;
+        self.log.push(Op::Free(base, size));
+        Ok(())
+    }
+
+    fn total_space(&self) -> A {
+        self.inner.total_space()
+    }
+
+    fn space(&self) -> A {
+        self.inner.space()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
@@ -0,0 +1,146 @@
+//! validated sizes and alignments
+//!
+//! the allocators take plain integers at their API boundary and convert them into these types
+//! once, so the rest of the code can rely on the invariants instead of re-checking them.
+
+use crate::{Error, ErrorKind, Result, address::Address};
+
+/// a non-zero size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Size<A = usize>(A);
+
+impl<A: Address> Size<A> {
+    pub fn new(size: A) -> Result<Size<A>> {
+        if size == A::ZERO {
+            return Err(Error::new(ErrorKind::InvalidSize));
+        }
+        Ok(Size(size))
+    }
+
+    pub fn get(self) -> A {
+        self.0
+    }
+
+    /// rounds the size up to a multiple of `alignment`, failing on overflow
+    pub fn round_up(self, alignment: Alignment<A>) -> Result<Size<A>> {
+        Size::new(alignment.align_up(self.get())?)
+    }
+}
+
+/// a power of two. An alignment of 0 is accepted and means the same as 1: no requirement
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Alignment<A = usize>(A);
+
+impl<A: Address> Alignment<A> {
+    /// the alignment every address satisfies
+    pub const ONE: Alignment<A> = Alignment(A::ONE);
+
+    /// the granularity both allocators round allocations to by default
+    pub const BASE_PAGE: Alignment<A> = Alignment(A::BASE_PAGE);
+
+    pub fn new(alignment: A) -> Result<Alignment<A>> {
+        if alignment == A::ZERO {
+            return Ok(Alignment::ONE);
+        }
+        if !alignment.is_power_of_two() {
+            return Err(Error::new(ErrorKind::InvalidAlignment));
+        }
+        Ok(Alignment(alignment))
+    }
+
+    pub fn get(self) -> A {
+        self.0
+    }
+
+    /// the smallest multiple of the alignment that is `>= n`, failing on overflow
+    pub fn align_up(self, n: A) -> Result<A> {
+        let mask = self.get() - A::ONE;
+        n.checked_add(mask)
+            .map(|n| n & !mask)
+            .ok_or_else(|| Error::new(ErrorKind::Overflow))
+    }
+
+    pub fn is_aligned(self, n: A) -> bool {
+        n & (self.get() - A::ONE) == A::ZERO
+    }
+}
+
+impl TryFrom<usize> for Size {
+    type Error = Error;
+    fn try_from(size: usize) -> Result<Size> {
+        Size::new(size)
+    }
+}
+
+impl TryFrom<usize> for Alignment {
+    type Error = Error;
+    fn try_from(alignment: usize) -> Result<Alignment> {
+        Alignment::new(alignment)
+    }
+}
+
+impl From<Size> for usize {
+    fn from(size: Size) -> usize {
+        size.get()
+    }
+}
+
+impl From<Alignment> for usize {
+    fn from(alignment: Alignment) -> usize {
+        alignment.get()
+    }
+}
+
+/// as the plain number, checked to be a power of two when deserialized
+#[cfg(feature = "serde")]
+impl<A: serde::Serialize> serde::Serialize for Alignment<A> {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> core::result::Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, A: Address + serde::Deserialize<'de>> serde::Deserialize<'de> for Alignment<A> {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> core::result::Result<Self, D::Error> {
+        Alignment::new(A::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alignment() {
+        assert_eq!(Alignment::new(0usize).unwrap(), Alignment::ONE);
+        assert!(Alignment::new(3usize).is_err());
+        let a = Alignment::new(4096usize).unwrap();
+        assert_eq!(a.align_up(1).unwrap(), 4096);
+        assert_eq!(a.align_up(4096).unwrap(), 4096);
+        assert!(a.align_up(usize::MAX).is_err());
+        assert!(a.is_aligned(8192));
+        assert!(!a.is_aligned(8193));
+    }
+
+    #[test]
+    fn size() {
+        assert!(Size::new(0usize).is_err());
+        let s = Size::new(5000usize).unwrap();
+        assert_eq!(
+            s.round_up(Alignment::new(4096).unwrap()).unwrap().get(),
+            8192
+        );
+    }
+
+    #[test]
+    fn narrow_address() {
+        let a = Alignment::<u32>::BASE_PAGE;
+        assert!(a.align_up(u32::MAX - 1).is_err());
+        assert_eq!(Size::new(1u32).unwrap().round_up(a).unwrap().get(), 4096);
+    }
+}
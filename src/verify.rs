@@ -0,0 +1,64 @@
+//! comparing the allocator's view of the address space with an external source of truth, e.g.
+//! the page tables
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::{address::Address, collections::RangeSet};
+
+/// a range on which the allocator and the external source of truth disagree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Discrepancy<A = usize> {
+    /// the allocator handed this range out, but the external source does not know about it
+    AllocatedButUnmapped(Range<A>),
+    /// the external source uses this range, but the allocator considers it free
+    MappedButFree(Range<A>),
+    /// the external source uses this range, but it is not part of any region of the allocator
+    MappedOutsideRegions(Range<A>),
+}
+
+impl<A> Discrepancy<A> {
+    pub fn range(&self) -> &Range<A> {
+        match self {
+            Discrepancy::AllocatedButUnmapped(r)
+            | Discrepancy::MappedButFree(r)
+            | Discrepancy::MappedOutsideRegions(r) => r,
+        }
+    }
+}
+
+/// computes the discrepancies between an allocator consisting of `regions` of which `free` and
+/// `reserved` are not allocated, and the externally known allocations `external`.
+///
+/// reserved ranges are never reported, whether they show up in `external` or not. The result is
+/// sorted by address
+pub fn diff<A: Address>(
+    regions: &RangeSet<A>,
+    free: &RangeSet<A>,
+    reserved: &RangeSet<A>,
+    external: impl IntoIterator<Item = Range<A>>,
+) -> Vec<Discrepancy<A>> {
+    let external: RangeSet<A> = external.into_iter().collect();
+    let allocated = regions.subtract(free).subtract(reserved);
+
+    let mut discrepancies: Vec<_> = allocated
+        .subtract(&external)
+        .iter()
+        .map(Discrepancy::AllocatedButUnmapped)
+        .chain(
+            external
+                .intersect(free)
+                .iter()
+                .map(Discrepancy::MappedButFree),
+        )
+        .chain(
+            external
+                .subtract(regions)
+                .iter()
+                .map(Discrepancy::MappedOutsideRegions),
+        )
+        .collect();
+
+    discrepancies.sort_by_key(|d| d.range().start);
+    discrepancies
+}